@@ -0,0 +1,138 @@
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use nbody::geometry::bh_tree::BHTree;
+use nbody::geometry::octree::Refinement;
+use nbody::{Point, Vec3d};
+
+const GRAPH_SIZE: f64 = 100.;
+const SIZES: [usize; 3] = [1_000, 10_000, 50_000];
+const THETAS: [f64; 3] = [0.3, 0.5, 0.8];
+
+fn random_points(n: usize) -> Vec<Point> {
+    let mut rng = StdRng::seed_from_u64(1337);
+    return (0..n)
+        .map(|i| {
+            let x = rng.gen_range(-GRAPH_SIZE..GRAPH_SIZE);
+            let y = rng.gen_range(-GRAPH_SIZE..GRAPH_SIZE);
+            let z = rng.gen_range(-GRAPH_SIZE..GRAPH_SIZE);
+            Point::new(rng.gen_range(1e9..1e10), x, y, z, Vec3d::new_zero()).with_id(i as u64)
+        })
+        .collect();
+}
+
+fn build_tree(theta: f64, points: &[Point]) -> BHTree {
+    return build_refined_tree(theta, Refinement::default(), points);
+}
+
+fn build_refined_tree(theta: f64, refinement: Refinement, points: &[Point]) -> BHTree {
+    let mut bht = BHTree::new(
+        theta,
+        2. * GRAPH_SIZE + 1.,
+        -GRAPH_SIZE,
+        -GRAPH_SIZE,
+        -GRAPH_SIZE,
+    )
+    .with_refinement(refinement);
+    bht.add_points(points.iter().copied()).unwrap();
+    return bht;
+}
+
+fn bench_build(c: &mut Criterion) {
+    let mut group = c.benchmark_group("tree_build");
+    for n in SIZES {
+        let points = random_points(n);
+        group.throughput(Throughput::Elements(n as u64));
+        group.bench_with_input(BenchmarkId::new("bulk", n), &points, |b, points| {
+            b.iter(|| build_tree(0.5, black_box(points)));
+        });
+        group.bench_with_input(BenchmarkId::new("one_by_one", n), &points, |b, points| {
+            b.iter(|| {
+                let mut bht = BHTree::new(
+                    0.5,
+                    2. * GRAPH_SIZE + 1.,
+                    -GRAPH_SIZE,
+                    -GRAPH_SIZE,
+                    -GRAPH_SIZE,
+                );
+                for p in black_box(points) {
+                    bht.add_point(*p).unwrap();
+                }
+                return bht;
+            });
+        });
+    }
+    group.finish();
+}
+
+fn bench_force(c: &mut Criterion) {
+    let mut group = c.benchmark_group("single_force");
+    for theta in THETAS {
+        let bht = build_tree(theta, &random_points(10_000));
+        let probe = Point::new(1e9, 1., 2., 3., Vec3d::new_zero()).with_id(u64::MAX);
+        group.bench_with_input(BenchmarkId::from_parameter(theta), &bht, |b, bht| {
+            b.iter(|| bht.force_on(black_box(probe)));
+        });
+    }
+    group.finish();
+}
+
+fn bench_step(c: &mut Criterion) {
+    let mut group = c.benchmark_group("full_step");
+    group.sample_size(10);
+    for n in SIZES {
+        for theta in THETAS {
+            let bht = build_tree(theta, &random_points(n));
+            group.throughput(Throughput::Elements(n as u64));
+            group.bench_with_input(
+                BenchmarkId::new(format!("theta={}", theta), n),
+                &bht,
+                |b, bht| b.iter(|| bht.next(0.1)),
+            );
+        }
+    }
+    group.finish();
+}
+
+fn bench_buckets(c: &mut Criterion) {
+    let mut group = c.benchmark_group("bucket_size");
+    group.sample_size(10);
+    let points = random_points(10_000);
+    for bucket_size in [1, 4, 8, 16, 32] {
+        let refinement = Refinement {
+            bucket_size: bucket_size,
+            ..Refinement::default()
+        };
+        let bht = build_refined_tree(0.5, refinement, &points);
+        group.bench_with_input(BenchmarkId::from_parameter(bucket_size), &bht, |b, bht| {
+            b.iter(|| bht.next(0.1))
+        });
+    }
+    group.finish();
+}
+
+fn bench_output(c: &mut Criterion) {
+    let mut group = c.benchmark_group("output");
+    for n in SIZES {
+        let bht = build_tree(0.5, &random_points(n));
+        group.throughput(Throughput::Elements(n as u64));
+        group.bench_with_input(BenchmarkId::new("csv", n), &bht, |b, bht| {
+            b.iter(|| bht.write_csv(std::io::sink()).unwrap());
+        });
+        group.bench_with_input(BenchmarkId::new("json", n), &bht, |b, bht| {
+            b.iter(|| serde_json::to_writer(std::io::sink(), bht).unwrap());
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bench_build,
+    bench_force,
+    bench_step,
+    bench_buckets,
+    bench_output
+);
+criterion_main!(benches);
@@ -0,0 +1,123 @@
+#![no_main]
+
+use std::collections::HashSet;
+
+use arbitrary::Arbitrary;
+use libfuzzer_sys::fuzz_target;
+
+use nbody::geometry::bh_tree::BHTree;
+use nbody::geometry::boundary::Boundary;
+use nbody::geometry::octree::Refinement;
+use nbody::{NbodyError, Point, Vec3d};
+
+// Values that trees tend to get wrong and that random bits would almost never hit: signed zeros,
+// the faces of a box of side 4, the smallest and largest magnitudes, and the non-finite ones.
+const SPECIAL: [f64; 18] = [
+    0.,
+    -0.,
+    1.,
+    -1.,
+    2.,
+    -2.,
+    1.9999999999999998,
+    -2.0000000000000004,
+    f64::EPSILON,
+    f64::MIN_POSITIVE,
+    5e-324,
+    1e300,
+    -1e300,
+    f64::MAX,
+    f64::MIN,
+    f64::NAN,
+    f64::INFINITY,
+    f64::NEG_INFINITY,
+];
+
+#[derive(Arbitrary, Debug, Clone, Copy)]
+enum Value {
+    Bits(u64),
+    Special(u8),
+}
+
+impl Value {
+    fn get(self) -> f64 {
+        return match self {
+            Value::Bits(bits) => f64::from_bits(bits),
+            Value::Special(i) => SPECIAL[i as usize % SPECIAL.len()],
+        };
+    }
+}
+
+#[derive(Arbitrary, Debug)]
+struct Input {
+    bucket_size: u8,
+    max_depth: u8,
+    planar: bool,
+    // Side of a periodic box centred on the origin, or an open tree if None.
+    periodic: Option<Value>,
+    // Mass and position of each particle.
+    particles: Vec<[Value; 4]>,
+}
+
+// Adds arbitrary particles one at a time. Each must either go in or be turned away with an error,
+// and whatever went in must all be in the tree afterwards, coincident particles merged, with the
+// tree's bookkeeping intact. Building the same particles in bulk must give the same tree.
+fuzz_target!(|input: Input| {
+    let boundary = match input.periodic.map(Value::get) {
+        None => Boundary::Open,
+        Some(box_size) if box_size > 0. && box_size.is_finite() => {
+            Boundary::Periodic { box_size: box_size }
+        }
+        Some(_) => return,
+    };
+    let refinement = Refinement {
+        bucket_size: input.bucket_size as usize,
+        max_depth: input.max_depth as usize,
+    };
+    let empty = || {
+        return BHTree::new_bounded(0.5, boundary)
+            .with_planar(input.planar)
+            .with_refinement(refinement);
+    };
+
+    let mut tree = empty();
+    let mut added = vec![];
+    for (i, [m, x, y, z]) in input.particles.iter().enumerate() {
+        let p = Point::new(1., x.get(), y.get(), z.get(), Vec3d::new_zero())
+            .with_mass(m.get())
+            .with_id(i as u64);
+        let p = if input.planar { p.flattened() } else { p };
+        match tree.add_point(p) {
+            Ok(()) => added.push(p),
+            Err(NbodyError::InvalidMass { .. }) => {
+                assert!(!p.mass().is_finite() || p.mass() < 0.)
+            }
+            Err(NbodyError::OutOfBounds { .. }) => {
+                let (x, y, z) = p.position();
+                let far = [x, y, z].iter().any(|v| !(v.abs() < 1e300));
+                assert!(far || boundary != Boundary::Open, "{} turned away", p);
+            }
+            Err(e) => panic!("{}", e),
+        }
+    }
+    tree.check_invariants().unwrap();
+
+    // -0 and 0 are the same place.
+    let place = |p: &Point| {
+        let (x, y, z) = p.position();
+        return [x + 0., y + 0., z + 0.].map(f64::to_bits);
+    };
+    let tracers = added.iter().filter(|p| p.is_tracer()).count();
+    let places: HashSet<_> = added.iter().filter(|p| !p.is_tracer()).map(place).collect();
+    assert_eq!(tree.len(), tracers + places.len());
+    let mass: f64 = added.iter().map(|p| p.mass()).sum();
+    let kept: f64 = tree.iter().map(|p| p.mass()).sum();
+    if mass.is_finite() {
+        assert!((kept - mass).abs() <= 1e-9 * mass, "{} of {}", kept, mass);
+    }
+
+    let mut bulk = empty();
+    bulk.add_points(added.iter().copied()).unwrap();
+    bulk.check_invariants().unwrap();
+    assert_eq!(bulk.len(), tree.len());
+});
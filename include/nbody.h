@@ -0,0 +1,141 @@
+#ifndef NBODY_H
+#define NBODY_H
+
+/* Generated by cbindgen from src/ffi.rs; don't edit by hand. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * Returned by functions that succeed.
+ */
+#define NBODY_OK 0
+
+/**
+ * Returned when a required pointer is null.
+ */
+#define NBODY_NULL_POINTER -1
+
+/**
+ * Returned when the simulation fails; the details are logged.
+ */
+#define NBODY_SIMULATION_ERROR -2
+
+/**
+ * Opaque handle to a simulation, owned by the caller until passed to `nbody_simulation_free`.
+ */
+typedef struct NbodySimulation NbodySimulation;
+
+
+
+
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * Creates an empty simulation with time step `dt`, gravitational constant `g` and opening angle
+ * `theta`. Free it with `nbody_simulation_free`. Returns null if the simulation can't be built.
+ */
+struct NbodySimulation *nbody_simulation_new(double dt, double g, double theta);
+
+/**
+ * Destroys a simulation. Passing null does nothing.
+ *
+ * # Safety
+ *
+ * `sim` must be null or come from `nbody_simulation_new`, and must not be used afterwards.
+ */
+void nbody_simulation_free(struct NbodySimulation *sim);
+
+/**
+ * Adds `n` particles. `masses` holds `n` values; `positions` and `velocities` hold `3 * n`, as
+ * x, y, z per particle. New particles get ids following the existing ones, in array order.
+ *
+ * # Safety
+ *
+ * `sim` must be a live simulation and the arrays must be valid for the lengths above.
+ */
+int32_t nbody_simulation_add_particles(struct NbodySimulation *sim,
+                                       size_t n,
+                                       const double *masses,
+                                       const double *positions,
+                                       const double *velocities);
+
+/**
+ * Replaces the external particles with `n` others, positioned by a code coupled to this one:
+ * they pull on the simulation's particles but aren't moved by it. Arrays as for
+ * `nbody_simulation_add_particles`. The `i`-th particle keeps the id the `i`-th had on the
+ * previous call, and any more get ids following the existing ones.
+ *
+ * # Safety
+ *
+ * `sim` must be a live simulation and the arrays must be valid for the lengths above.
+ */
+int32_t nbody_simulation_set_external(struct NbodySimulation *sim,
+                                      size_t n,
+                                      const double *masses,
+                                      const double *positions,
+                                      const double *velocities);
+
+/**
+ * Advances the simulation by `steps` steps.
+ *
+ * # Safety
+ *
+ * `sim` must be a live simulation.
+ */
+int32_t nbody_simulation_step(struct NbodySimulation *sim, uint64_t steps);
+
+/**
+ * Number of particles, which can drop as particles merge.
+ *
+ * # Safety
+ *
+ * `sim` must be a live simulation.
+ */
+size_t nbody_simulation_len(const struct NbodySimulation *sim);
+
+/**
+ * Number of steps taken so far.
+ *
+ * # Safety
+ *
+ * `sim` must be a live simulation.
+ */
+uint64_t nbody_simulation_steps(const struct NbodySimulation *sim);
+
+/**
+ * Simulated time since the start of the run.
+ *
+ * # Safety
+ *
+ * `sim` must be a live simulation.
+ */
+double nbody_simulation_time(const struct NbodySimulation *sim);
+
+/**
+ * Copies the state of up to `capacity` particles, ordered by id, into the given arrays and
+ * returns how many were copied. `ids` and `masses` need room for `capacity` values, `positions`
+ * and `velocities` for `3 * capacity`. Any of them may be null to skip that quantity.
+ *
+ * # Safety
+ *
+ * `sim` must be a live simulation and each non-null array must be valid for the length above.
+ */
+size_t nbody_simulation_copy_state(const struct NbodySimulation *sim,
+                                   size_t capacity,
+                                   uint64_t *ids,
+                                   double *masses,
+                                   double *positions,
+                                   double *velocities);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* NBODY_H */
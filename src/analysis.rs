@@ -0,0 +1,624 @@
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use crate::binaries::{find_binaries, Binary};
+use crate::geometry::bh_tree::BHTree;
+use crate::ic::file::load_snapshot;
+use crate::output::container::{ContainerReader, FrameInfo, CONTAINER_FILE};
+use crate::output::list_snapshots;
+use crate::output::manifest::RunManifest;
+use crate::{NbodyError, Point, Vec3d};
+
+/// The snapshots an earlier run left in its output directory, either as one file per snapshot or
+/// in a snapshot container.
+pub enum StoredRun {
+    // Snapshot files by step, and the run's time step if its run.json gives one, for snapshots
+    // that don't record their time.
+    Files(Vec<(u64, PathBuf)>, Option<f64>),
+    Container(ContainerReader, Vec<FrameInfo>),
+}
+
+/// One snapshot read back from a stored run. The time is missing when only the step was stored.
+#[derive(Debug, Clone)]
+pub struct StoredSnapshot {
+    pub step: u64,
+    pub time: Option<f64>,
+    pub points: Vec<Point>,
+}
+
+impl StoredRun {
+    pub fn open(dir: &Path) -> Result<StoredRun, Box<dyn Error>> {
+        let container = dir.join(CONTAINER_FILE);
+        if container.exists() {
+            let reader = ContainerReader::open(&container)?;
+            let frames = reader.frames().to_vec();
+            return Ok(StoredRun::Container(reader, frames));
+        }
+        let files = list_snapshots(dir)?;
+        if files.is_empty() {
+            return Err(format!("no snapshots found in {}", dir.display()).into());
+        }
+        // Older snapshot files don't record the time, but it follows from the step if dt never
+        // changed.
+        let dt = RunManifest::read(dir)
+            .ok()
+            .and_then(|m| m.config.get("dt").and_then(|dt| dt.as_f64()));
+        return Ok(StoredRun::Files(files, dt));
+    }
+
+    pub fn len(&self) -> usize {
+        return match self {
+            StoredRun::Files(files, _) => files.len(),
+            StoredRun::Container(_, frames) => frames.len(),
+        };
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.len() == 0;
+    }
+
+    pub fn load(&mut self, i: usize) -> Result<StoredSnapshot, Box<dyn Error>> {
+        return match self {
+            StoredRun::Files(files, dt) => {
+                let (step, path) = &files[i];
+                let (points, time) = load_snapshot(path)?;
+                Ok(StoredSnapshot {
+                    step: *step,
+                    time: time.or(dt.map(|dt| *step as f64 * dt)),
+                    points: points,
+                })
+            }
+            StoredRun::Container(reader, frames) => {
+                let frame = frames[i];
+                Ok(StoredSnapshot {
+                    step: frame.step,
+                    time: Some(frame.time),
+                    points: reader.read(&frame)?,
+                })
+            }
+        };
+    }
+}
+
+/// Bulk quantities of one snapshot, in SI units apart from the time, which is in the units the run
+/// used. Radii are measured from the snapshot's centre, as chosen by `Analysis::center`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SnapshotSummary {
+    pub step: u64,
+    pub time: Option<f64>,
+    pub particles: usize,
+    pub center_x: f64,
+    pub center_y: f64,
+    pub center_z: f64,
+    pub mass: f64,
+    pub kinetic: f64,
+    pub potential: f64,
+    pub energy: f64,
+    // 2K / |W|, which is 1 for a system in virial equilibrium.
+    pub virial_ratio: f64,
+    // Radii enclosing 10%, 50% and 90% of the mass.
+    pub r10: f64,
+    pub r50: f64,
+    pub r90: f64,
+    // One-dimensional, mass-weighted velocity dispersion about the centre-of-mass velocity.
+    pub dispersion: f64,
+    // Core radius and density, when measuring from the density centre.
+    pub core_radius: Option<f64>,
+    pub core_density: Option<f64>,
+}
+
+/// One spherical shell of a snapshot's radial profile.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Shell {
+    pub step: u64,
+    pub inner: f64,
+    pub outer: f64,
+    pub particles: usize,
+    pub density: f64,
+    // One-dimensional, mass-weighted velocity dispersion about the shell's mean velocity.
+    pub dispersion: f64,
+}
+
+/// The point radii and profiles are measured from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Center {
+    Mass,
+    // Density-weighted mean position of the particles (Casertano & Hut 1985), with densities
+    // smoothed over this many neighbours. Unlike the centre of mass it stays on the core when
+    // escapers or a companion pull the bulk of the mass elsewhere.
+    Density(usize),
+}
+
+/// Recomputes derived quantities from the snapshots of an earlier run without simulating again.
+#[derive(Debug, Clone)]
+pub struct Analysis {
+    // Opening angle of the trees the potential energy is summed with.
+    pub theta: f64,
+    // Shells in each radial profile, spaced evenly in log radius.
+    pub bins: usize,
+    pub center: Center,
+    // Catalogue bound pairs up to this semi-major axis in `binaries.csv`.
+    pub max_binary_axis: Option<f64>,
+}
+
+impl Analysis {
+    // Summarises every snapshot in `dir` and writes `analysis.csv`, `profiles.csv` and, if asked
+    // for, `binaries.csv` into `out`.
+    pub fn run(&self, dir: &Path, out: &Path) -> Result<Vec<SnapshotSummary>, Box<dyn Error>> {
+        let mut run = StoredRun::open(dir)?;
+        info!("analysing {} snapshots in {}", run.len(), dir.display());
+        fs::create_dir_all(out)?;
+        let mut summaries = csv::Writer::from_path(out.join("analysis.csv"))?;
+        let mut profiles = csv::Writer::from_path(out.join("profiles.csv"))?;
+        let mut binaries = match self.max_binary_axis {
+            Some(_) => Some(csv::Writer::from_path(out.join("binaries.csv"))?),
+            None => None,
+        };
+        let mut all = vec![];
+        for i in 0..run.len() {
+            let (summary, shells, pairs) = self.summarize(run.load(i)?)?;
+            summaries.serialize(&summary)?;
+            for shell in shells {
+                profiles.serialize(shell)?;
+            }
+            if let Some(binaries) = binaries.as_mut() {
+                for pair in pairs {
+                    binaries.serialize(pair)?;
+                }
+            }
+            all.push(summary);
+        }
+        summaries.flush()?;
+        profiles.flush()?;
+        if let Some(binaries) = binaries.as_mut() {
+            binaries.flush()?;
+        }
+        return Ok(all);
+    }
+
+    pub fn summarize(
+        &self,
+        snapshot: StoredSnapshot,
+    ) -> Result<(SnapshotSummary, Vec<Shell>, Vec<Binary>), NbodyError> {
+        let StoredSnapshot { step, time, points } = snapshot;
+        let particles = points.len();
+        let tree = BHTree::from_points(self.theta, points)?;
+        let points = tree.points();
+        let (mass, com, velocity) = center_of_mass(&points);
+        let core = match self.center {
+            Center::Mass => None,
+            Center::Density(k) => core(&points, &tree.densities(k)),
+        };
+        let center = core.map_or(com, |c| c.center);
+        let kinetic: f64 = points.iter().map(|p| p.kinetic_energy()).sum();
+        let spread: f64 = points
+            .iter()
+            .map(|p| p.mass() * (p.velocity() - velocity).magnitude_squared())
+            .sum();
+        let radii = lagrangian_radii(&points, center, &[0.1, 0.5, 0.9]);
+        let shells = radial_profile(step, &points, center, self.bins);
+        let potential: f64 = points
+            .iter()
+            .zip(tree.potentials())
+            .map(|(p, phi)| 0.5 * p.mass() * phi)
+            .sum();
+        let (center_x, center_y, center_z) = center.position();
+        let summary = SnapshotSummary {
+            step: step,
+            time: time,
+            particles: particles,
+            center_x: center_x,
+            center_y: center_y,
+            center_z: center_z,
+            mass: mass,
+            kinetic: kinetic,
+            potential: potential,
+            energy: kinetic + potential,
+            virial_ratio: 2. * kinetic / potential.abs(),
+            r10: radii[0],
+            r50: radii[1],
+            r90: radii[2],
+            dispersion: (spread / (3. * mass)).sqrt(),
+            core_radius: core.map(|c| c.radius),
+            core_density: core.map(|c| c.density),
+        };
+        let binaries = match self.max_binary_axis {
+            Some(a) => find_binaries(step, time, &tree, a),
+            None => vec![],
+        };
+        return Ok((summary, shells, binaries));
+    }
+}
+
+// Total mass, centre of mass and centre-of-mass velocity.
+fn center_of_mass(points: &[Point]) -> (f64, Vec3d, Vec3d) {
+    let mut mass = 0.;
+    let mut position = Vec3d::new_zero();
+    let mut momentum = Vec3d::new_zero();
+    for p in points {
+        let (x, y, z) = p.position();
+        mass += p.mass();
+        position += p.mass() * Vec3d::new(x, y, z);
+        momentum += p.momentum();
+    }
+    if mass == 0. {
+        return (0., Vec3d::new_zero(), Vec3d::new_zero());
+    }
+    return (mass, position / mass, momentum / mass);
+}
+
+/// The dense centre of a cluster, as defined by Casertano & Hut (1985).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Core {
+    // Mean position weighted by density.
+    pub center: Vec3d,
+    // Root-mean-square distance from the centre, weighted by density squared.
+    pub radius: f64,
+    // Mean density weighted by density.
+    pub density: f64,
+}
+
+// The core of `points`, given the density at each of them. None if every density is zero.
+pub fn core(points: &[Point], densities: &[f64]) -> Option<Core> {
+    let mut weight = 0.;
+    let mut squared = 0.;
+    let mut center = Vec3d::new_zero();
+    for (p, rho) in points.iter().zip(densities) {
+        let (x, y, z) = p.position();
+        weight += rho;
+        squared += rho * rho;
+        center += *rho * Vec3d::new(x, y, z);
+    }
+    if weight <= 0. {
+        return None;
+    }
+    let center = center / weight;
+    let spread: f64 = points
+        .iter()
+        .zip(densities)
+        .map(|(p, rho)| rho * rho * distance(p, center).powi(2))
+        .sum();
+    return Some(Core {
+        center: center,
+        radius: (spread / squared).sqrt(),
+        density: squared / weight,
+    });
+}
+
+/// The core of a run at one step, with the time in the units the run uses.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CoreSample {
+    pub step: u64,
+    pub time: f64,
+    pub radius: f64,
+    pub density: f64,
+}
+
+// Where a run logs its core, one `CoreSample` per row.
+pub const CORE_FILE: &str = "core.csv";
+
+/// Follows a cluster's core through a run and notes when it collapses, i.e. when the core density
+/// first climbs past a threshold.
+#[derive(Debug, Clone)]
+pub struct CoreTracker {
+    // Neighbours densities are smoothed over.
+    neighbors: usize,
+    // Core density in SI units.
+    threshold: Option<f64>,
+    collapse: Option<CoreSample>,
+}
+
+impl CoreTracker {
+    pub fn new(neighbors: usize) -> CoreTracker {
+        return CoreTracker {
+            neighbors: neighbors,
+            threshold: None,
+            collapse: None,
+        };
+    }
+
+    pub fn with_threshold(mut self, density: Option<f64>) -> CoreTracker {
+        self.threshold = density;
+        return self;
+    }
+
+    // Measures the core of `tree`. None if it has no particles to measure.
+    pub fn observe(&mut self, step: u64, time: f64, tree: &BHTree) -> Option<CoreSample> {
+        let core = core(&tree.points(), &tree.densities(self.neighbors))?;
+        let sample = CoreSample {
+            step: step,
+            time: time,
+            radius: core.radius,
+            density: core.density,
+        };
+        let exceeded = self.threshold.is_some_and(|t| sample.density > t);
+        if exceeded && self.collapse.is_none() {
+            info!(
+                step = step,
+                time = time,
+                core_radius = sample.radius,
+                core_density = sample.density;
+                "core collapse"
+            );
+            self.collapse = Some(sample.clone());
+        }
+        return Some(sample);
+    }
+
+    // The first sample past the threshold, once there has been one.
+    pub fn collapse(&self) -> Option<&CoreSample> {
+        return self.collapse.as_ref();
+    }
+}
+
+fn distance(p: &Point, center: Vec3d) -> f64 {
+    let (x, y, z) = p.position();
+    return (Vec3d::new(x, y, z) - center).magnitude();
+}
+
+// The radii about `center` enclosing each of `fractions` of the total mass.
+pub fn lagrangian_radii(points: &[Point], center: Vec3d, fractions: &[f64]) -> Vec<f64> {
+    let mut by_radius: Vec<(f64, f64)> = points
+        .iter()
+        .map(|p| (distance(p, center), p.mass()))
+        .collect();
+    by_radius.sort_by(|a, b| a.0.total_cmp(&b.0));
+    let total: f64 = by_radius.iter().map(|(_, m)| m).sum();
+    return fractions
+        .iter()
+        .map(|f| {
+            let mut enclosed = 0.;
+            for &(r, m) in by_radius.iter() {
+                enclosed += m;
+                if enclosed >= f * total {
+                    return r;
+                }
+            }
+            return by_radius.last().map_or(0., |(r, _)| *r);
+        })
+        .collect();
+}
+
+// Density and velocity dispersion in `bins` shells about `center`, spaced evenly in log radius
+// from the innermost particle to the outermost.
+pub fn radial_profile(step: u64, points: &[Point], center: Vec3d, bins: usize) -> Vec<Shell> {
+    let radii: Vec<f64> = points.iter().map(|p| distance(p, center)).collect();
+    let inner = radii
+        .iter()
+        .copied()
+        .filter(|r| *r > 0.)
+        .fold(f64::MAX, f64::min);
+    let outer = radii.iter().copied().fold(0., f64::max);
+    if bins == 0 || inner >= outer {
+        return vec![];
+    }
+    let ratio = (outer / inner).ln() / bins as f64;
+    let edge = |i: usize| match i {
+        0 => 0.,
+        i if i == bins => outer,
+        i => inner * (ratio * i as f64).exp(),
+    };
+    let shell_of = |r: f64| match r <= inner {
+        true => 0,
+        false => (((r / inner).ln() / ratio) as usize).min(bins - 1),
+    };
+
+    // Mass, momentum and mass-weighted squared speed in each shell; the dispersion follows from
+    // <v^2> - <v>^2.
+    let mut sums = vec![(0, 0., Vec3d::new_zero(), 0.); bins];
+    for (p, r) in points.iter().zip(radii) {
+        let shell = &mut sums[shell_of(r)];
+        shell.0 += 1;
+        shell.1 += p.mass();
+        shell.2 += p.momentum();
+        shell.3 += p.mass() * p.velocity().magnitude_squared();
+    }
+    return sums
+        .into_iter()
+        .enumerate()
+        .map(|(i, (particles, mass, momentum, squared))| {
+            let (inner, outer) = (edge(i), edge(i + 1));
+            let volume = 4. / 3. * std::f64::consts::PI * (outer.powi(3) - inner.powi(3));
+            let dispersion = match mass > 0. {
+                true => {
+                    ((squared / mass - (momentum / mass).magnitude_squared()).max(0.) / 3.).sqrt()
+                }
+                false => 0.,
+            };
+            Shell {
+                step: step,
+                inner: inner,
+                outer: outer,
+                particles: particles,
+                density: mass / volume,
+                dispersion: dispersion,
+            }
+        })
+        .collect();
+}
+
+#[cfg(test)]
+mod test {
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    use crate::analysis::{
+        lagrangian_radii, radial_profile, Analysis, Center, CoreTracker, StoredSnapshot,
+    };
+    use crate::geometry::bh_tree::BHTree;
+    use crate::ic::halo::{Halo, Profile};
+    use crate::output::container::ContainerWriter;
+    use crate::output::{CsvWriter, Retention, SnapshotWriter};
+    use crate::{Point, Vec3d};
+
+    #[test]
+    fn test_lagrangian_radii() {
+        let points: Vec<Point> = (1..=10)
+            .map(|i| Point::new(1., i as f64, 0., 0., Vec3d::new_zero()))
+            .collect();
+        let radii = lagrangian_radii(&points, Vec3d::new_zero(), &[0.1, 0.5, 0.9, 1.]);
+        assert_eq!(radii, vec![1., 5., 9., 10.]);
+    }
+
+    #[test]
+    fn test_radial_profile() {
+        // Two particles per shell moving apart at 2 m/s, so each shell's 1D dispersion is 1/sqrt 3.
+        let points: Vec<Point> = [1., 10., 100.]
+            .iter()
+            .flat_map(|&r| {
+                [
+                    Point::new(1., r, 0., 0., Vec3d::new(1., 0., 0.)),
+                    Point::new(1., -r, 0., 0., Vec3d::new(-1., 0., 0.)),
+                ]
+            })
+            .collect();
+        let shells = radial_profile(7, &points, Vec3d::new_zero(), 2);
+        assert_eq!(shells.len(), 2);
+        assert_eq!((shells[0].inner, shells[1].outer), (0., 100.));
+        assert!((shells[1].inner - 10.).abs() < 1e-9);
+        assert_eq!((shells[0].particles, shells[1].particles), (2, 4));
+        let volume = 4. / 3. * std::f64::consts::PI * shells[0].outer.powi(3);
+        assert!((shells[0].density - 2. / volume).abs() < 1e-12);
+        for shell in shells {
+            assert_eq!(shell.step, 7);
+            assert!((shell.dispersion - (1f64 / 3.).sqrt()).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn test_density_center() {
+        // A heavy, diffuse cloud far off to one side drags the centre of mass away from the halo,
+        // but not its density centre.
+        let mut rng = StdRng::seed_from_u64(2);
+        let mut points = Halo {
+            n: 2000,
+            total_mass: 1e12,
+            profile: Profile::Hernquist,
+            scale_radius: 1.,
+            max_radius: 100.,
+        }
+        .generate(&mut rng);
+        points.extend((0..500).map(|i| {
+            let (y, z) = ((i % 25) as f64 * 2., (i / 25) as f64 * 2.);
+            Point::new(1e9, 200., y - 24., z - 19., Vec3d::new_zero()).with_id(2000 + i)
+        }));
+        let summarize = |center| {
+            let analysis = Analysis {
+                theta: 0.5,
+                bins: 10,
+                center: center,
+                max_binary_axis: None,
+            };
+            let snapshot = StoredSnapshot {
+                step: 0,
+                time: None,
+                points: points.clone(),
+            };
+            return analysis.summarize(snapshot).unwrap();
+        };
+        let (by_mass, _, _) = summarize(Center::Mass);
+        let (by_density, shells, _) = summarize(Center::Density(32));
+        assert!(by_mass.center_x > 50., "{}", by_mass.center_x);
+        let offset = Vec3d::new(
+            by_density.center_x,
+            by_density.center_y,
+            by_density.center_z,
+        );
+        assert!(offset.magnitude() < 0.5, "{:?}", offset);
+        assert!(by_density.r10 < by_mass.r10);
+        // The halo's dispersion falls off outside the scale radius.
+        let inner = shells.iter().find(|s| s.outer > 1.).unwrap();
+        let outer = shells.iter().find(|s| s.inner > 20.).unwrap();
+        assert!(inner.dispersion > outer.dispersion);
+    }
+
+    #[test]
+    fn test_core_collapse() {
+        let cluster = |scale_radius| {
+            let halo = Halo {
+                n: 1000,
+                total_mass: 1e12,
+                profile: Profile::Hernquist,
+                scale_radius: scale_radius,
+                max_radius: 50. * scale_radius,
+            };
+            let points = halo.generate(&mut StdRng::seed_from_u64(3));
+            return BHTree::from_points(0.5, points).unwrap();
+        };
+        let (loose, tight) = (cluster(2.), cluster(1.));
+        let mut tracker = CoreTracker::new(32);
+        let before = tracker.observe(0, 0., &loose).unwrap();
+        let after = tracker.observe(1, 1., &tight).unwrap();
+        assert!(tracker.collapse().is_none());
+        // Halving every length shrinks the core by half and packs it eight times as densely.
+        assert!((before.radius / after.radius - 2.).abs() < 1e-6);
+        assert!((after.density / before.density - 8.).abs() < 1e-6);
+
+        let threshold = (before.density * after.density).sqrt();
+        let mut tracker = CoreTracker::new(32).with_threshold(Some(threshold));
+        tracker.observe(0, 0., &loose);
+        assert!(tracker.collapse().is_none());
+        tracker.observe(1, 1., &tight);
+        tracker.observe(2, 2., &tight);
+        assert_eq!(tracker.collapse(), Some(&after));
+    }
+
+    #[test]
+    fn test_analysis_of_stored_runs() {
+        let halo = Halo {
+            n: 2000,
+            total_mass: 1e12,
+            profile: Profile::Hernquist,
+            scale_radius: 1.,
+            max_radius: 100.,
+        };
+        let tree = BHTree::from_points(0.5, halo.generate(&mut StdRng::seed_from_u64(1))).unwrap();
+        let analysis = Analysis {
+            theta: 0.5,
+            bins: 10,
+            center: Center::Mass,
+            max_binary_axis: Some(0.01),
+        };
+
+        let base = std::env::temp_dir().join(format!("nbody-analysis-{}", std::process::id()));
+        let (files, container) = (base.join("files"), base.join("container"));
+        std::fs::create_dir_all(&files).unwrap();
+        std::fs::create_dir_all(&container).unwrap();
+        let mut csv = CsvWriter::new(files.clone(), Retention::new(None));
+        let mut nbc = ContainerWriter::open(&container.join("snapshots.nbc")).unwrap();
+        for step in [0, 5] {
+            csv.write(step, step as f64, &tree).unwrap();
+            nbc.write(step, step as f64, &tree).unwrap();
+        }
+        csv.flush().unwrap();
+        nbc.flush().unwrap();
+
+        let from_files = analysis.run(&files, &files).unwrap();
+        let from_container = analysis.run(&container, &container).unwrap();
+        let rows = std::fs::read_to_string(files.join("analysis.csv")).unwrap();
+        let shells = std::fs::read_to_string(container.join("profiles.csv")).unwrap();
+        let binaries = files.join("binaries.csv").exists();
+        std::fs::remove_dir_all(&base).unwrap();
+
+        assert_eq!(from_files.len(), 2);
+        assert_eq!(from_files[1].step, 5);
+        assert_eq!(from_files[1].time, Some(5.));
+        assert_eq!(from_container[1].time, Some(5.));
+        assert_eq!(rows.lines().count(), 3);
+        assert_eq!(shells.lines().count(), 1 + 2 * 10);
+        assert!(binaries);
+
+        // The halo is generated in equilibrium, with a half-mass radius of (1 + sqrt 2) scale
+        // radii before truncation.
+        let s = &from_container[0];
+        assert!((s.mass - 1e12).abs() < 1.);
+        assert!((s.virial_ratio - 1.).abs() < 0.15, "{}", s.virial_ratio);
+        assert!((s.r50 - 2.414).abs() < 0.3, "{}", s.r50);
+        assert!(s.r10 < s.r50 && s.r50 < s.r90);
+        assert!((from_files[0].energy - s.energy).abs() < 1e-9 * s.energy.abs());
+    }
+}
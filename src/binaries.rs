@@ -0,0 +1,155 @@
+use std::collections::HashMap;
+
+use serde::Serialize;
+
+use crate::geometry::bh_tree::BHTree;
+use crate::geometry::kepler::OrbitalElements;
+use crate::Point;
+
+/// A gravitationally bound pair, in SI units apart from the time, which is in the units the run
+/// used. The primary is the heavier of the two, and the orbit is the secondary's about it.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Binary {
+    pub step: u64,
+    pub time: Option<f64>,
+    pub primary: u64,
+    pub secondary: u64,
+    pub primary_mass: f64,
+    pub secondary_mass: f64,
+    pub semi_major_axis: f64,
+    pub eccentricity: f64,
+    pub inclination: f64,
+    pub period: f64,
+    // Energy of the pair's relative motion, ignoring everything else. Always negative.
+    pub energy: f64,
+}
+
+// Two-body energy of `p` and `q` in their centre-of-mass frame.
+fn pair_energy(p: &Point, q: &Point, g: f64) -> f64 {
+    let reduced = p.mass() * q.mass() / (p.mass() + q.mass());
+    let v = p.velocity() - q.velocity();
+    return 0.5 * reduced * v.magnitude_squared() - g * p.mass() * q.mass() / p.distance_to(*q);
+}
+
+/// Pairs of massive particles that are bound as two-body systems with a semi-major axis of at most
+/// `max_semi_major_axis`, by primary id. Each particle is paired with the partner it's most bound
+/// to, and a pair only counts when that goes both ways, so no particle is in two binaries and the
+/// inner pair of a hierarchical triple wins over the outer one.
+pub fn find_binaries(
+    step: u64,
+    time: Option<f64>,
+    tree: &BHTree,
+    max_semi_major_axis: f64,
+) -> Vec<Binary> {
+    let g = tree.force_law().g;
+    // A bound orbit never takes its bodies further apart than twice its semi-major axis.
+    let reach = 2. * max_semi_major_axis;
+    let mut best: HashMap<u64, (Point, f64)> = HashMap::new();
+    for p in tree.iter().filter(|p| !p.is_tracer()) {
+        for q in tree.neighbors_within(*p, reach) {
+            if q.is_tracer() || q.id() == p.id() {
+                continue;
+            }
+            let energy = pair_energy(p, &q, g);
+            let a = -g * p.mass() * q.mass() / (2. * energy);
+            if energy >= 0. || a > max_semi_major_axis {
+                continue;
+            }
+            if best.get(&p.id()).is_none_or(|(_, e)| energy < *e) {
+                best.insert(p.id(), (q, energy));
+            }
+        }
+    }
+
+    let mut binaries = vec![];
+    for p in tree.iter() {
+        let (q, energy) = match best.get(&p.id()) {
+            Some(&(q, energy)) => (q, energy),
+            None => continue,
+        };
+        let mutual = best.get(&q.id()).is_some_and(|(r, _)| r.id() == p.id());
+        // Each pair once, from its primary: the heavier one, or the lower id on a tie.
+        let primary = p.mass() > q.mass() || (p.mass() == q.mass() && p.id() < q.id());
+        if !mutual || !primary {
+            continue;
+        }
+        let elements = OrbitalElements::of_pair(&q, p, g);
+        binaries.push(Binary {
+            step: step,
+            time: time,
+            primary: p.id(),
+            secondary: q.id(),
+            primary_mass: p.mass(),
+            secondary_mass: q.mass(),
+            semi_major_axis: elements.semi_major_axis,
+            eccentricity: elements.eccentricity,
+            inclination: elements.inclination,
+            period: elements.period(g * (p.mass() + q.mass())),
+            energy: energy,
+        });
+    }
+    binaries.sort_by_key(|b| b.primary);
+    return binaries;
+}
+
+#[cfg(test)]
+mod test {
+    use crate::binaries::find_binaries;
+    use crate::geometry::bh_tree::BHTree;
+    use crate::geometry::force::ForceLaw;
+    use crate::{Point, Vec3d};
+
+    #[test]
+    fn test_find_binaries() {
+        // With G = 1: a circular pair of separation 1, a circular pair of separation 0.5 with a
+        // light particle passing by, a pair flying apart, a wide circular pair that's bound but
+        // too wide, and a tracer.
+        let circular = |m1: f64, m2: f64, r: f64| ((m1 + m2) / r).sqrt();
+        let mut points = vec![
+            Point::new(
+                3.,
+                0.,
+                0.,
+                0.,
+                Vec3d::new(0., -0.25, 0.) * circular(3., 1., 1.),
+            ),
+            Point::new(
+                1.,
+                1.,
+                0.,
+                0.,
+                Vec3d::new(0., 0.75, 0.) * circular(3., 1., 1.),
+            ),
+        ];
+        let v = circular(1., 1., 0.5) / 2.;
+        points.push(Point::new(1., 50., 0., 0., Vec3d::new(0., -v, 0.)));
+        points.push(Point::new(1., 50.5, 0., 0., Vec3d::new(0., v, 0.)));
+        points.push(Point::new(0.1, 50.25, 1.5, 0., Vec3d::new(1., 0., 0.)));
+        points.push(Point::new(1., 100., 0., 0., Vec3d::new(0., -10., 0.)));
+        points.push(Point::new(1., 101., 0., 0., Vec3d::new(0., 10., 0.)));
+        let v = circular(1., 1., 8.) / 2.;
+        points.push(Point::new(1., 0., 100., 0., Vec3d::new(0., -v, 0.)));
+        points.push(Point::new(1., 8., 100., 0., Vec3d::new(0., v, 0.)));
+        points.push(Point::new(0., 0.5, 0.1, 0., Vec3d::new_zero()));
+        let points: Vec<Point> = points
+            .into_iter()
+            .enumerate()
+            .map(|(i, p)| p.with_id(i as u64))
+            .collect();
+        let tree = BHTree::from_points(0.5, points)
+            .unwrap()
+            .with_force_law(ForceLaw::newtonian(1.));
+
+        let binaries = find_binaries(3, Some(1.5), &tree, 2.);
+        let pairs: Vec<(u64, u64)> = binaries.iter().map(|b| (b.primary, b.secondary)).collect();
+        assert_eq!(pairs, vec![(0, 1), (2, 3)]);
+        let close = |a: f64, b: f64| (a - b).abs() < 1e-9;
+        assert!(close(binaries[0].semi_major_axis, 1.) && binaries[0].eccentricity < 1e-9);
+        assert!(close(binaries[0].energy, -1.5));
+        assert!(close(binaries[1].semi_major_axis, 0.5));
+        assert_eq!((binaries[1].step, binaries[1].time), (3, Some(1.5)));
+
+        // With a larger cutoff the wide pair counts too.
+        assert_eq!(find_binaries(0, None, &tree, 10.).len(), 3);
+    }
+}
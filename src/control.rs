@@ -0,0 +1,488 @@
+use std::error::Error;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crossbeam_channel::{bounded, Receiver, RecvTimeoutError, Sender};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::simulation::Simulation;
+
+// How long an HTTP request waits for the run loop, which only answers between steps.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(60);
+
+// How often a paused run checks whether it has been interrupted.
+const INTERRUPT_POLL: Duration = Duration::from_millis(100);
+
+// How long a client gets to send its request. Connections are served one at a time, so an idle
+// one would otherwise hold up every other.
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+// The largest request head and body accepted, in bytes.
+const MAX_HEAD: usize = 16 << 10;
+const MAX_BODY: usize = 1 << 20;
+
+/// Parameters that can be changed while the simulation runs, in SI. Missing ones are left alone.
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
+pub struct Params {
+    pub dt: Option<f64>,
+    pub theta: Option<f64>,
+}
+
+/// What `GET /status` reports, in SI.
+#[derive(Debug, Clone, Serialize)]
+pub struct Status {
+    pub step: u64,
+    pub time: f64,
+    pub dt: f64,
+    pub theta: f64,
+    pub particles: usize,
+    pub paused: bool,
+    pub kinetic_energy: f64,
+    pub angular_momentum: f64,
+}
+
+#[derive(Debug)]
+enum Command {
+    Status,
+    Pause,
+    Resume,
+    Stop,
+    SetParams(Params),
+    Snapshot,
+}
+
+struct Response {
+    code: u16,
+    content_type: &'static str,
+    body: String,
+}
+
+impl Response {
+    fn json<T: Serialize>(value: &T) -> Response {
+        return Response {
+            code: 200,
+            content_type: "application/json",
+            body: serde_json::to_string(value).unwrap(),
+        };
+    }
+
+    fn error(code: u16, message: &str) -> Response {
+        return Response {
+            code: code,
+            content_type: "application/json",
+            body: serde_json::json!({ "error": message }).to_string(),
+        };
+    }
+}
+
+struct Request {
+    command: Command,
+    reply: Sender<Response>,
+}
+
+/// A small HTTP API for steering a running simulation:
+///
+/// - `GET /status` returns a JSON `Status`.
+/// - `POST /pause`, `POST /resume` (or `/start`) and `POST /stop` control the run.
+/// - `POST /params` with a JSON `Params` body changes dt or theta from the next step.
+/// - `GET /snapshot` downloads the current particles as CSV.
+///
+/// Requests are queued and answered by the run loop between steps through `serve_pending`.
+pub struct ControlServer {
+    addr: SocketAddr,
+    requests: Receiver<Request>,
+    paused: bool,
+    interrupt: Option<Arc<AtomicBool>>,
+}
+
+impl ControlServer {
+    pub fn bind(addr: &str, paused: bool) -> Result<ControlServer, Box<dyn Error>> {
+        let listener = TcpListener::bind(addr)?;
+        let addr = listener.local_addr()?;
+        let (tx, rx) = bounded(16);
+        thread::spawn(move || serve(listener, tx));
+        info!("serving the control API on http://{}", addr);
+        return Ok(ControlServer {
+            addr: addr,
+            requests: rx,
+            paused: paused,
+            interrupt: None,
+        });
+    }
+
+    // A flag that ends a pause as a stop would, e.g. one set by Ctrl-C.
+    pub fn with_interrupt(mut self, interrupt: Arc<AtomicBool>) -> ControlServer {
+        self.interrupt = Some(interrupt);
+        return self;
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        return self.addr;
+    }
+
+    pub fn paused(&self) -> bool {
+        return self.paused;
+    }
+
+    // Answers every queued request, blocking for more while the run is paused. Returns false once
+    // a stop has been requested, or the interrupt flag set during a pause.
+    pub fn serve_pending(&mut self, sim: &mut Simulation) -> bool {
+        loop {
+            let request = if self.paused {
+                match self.requests.recv_timeout(INTERRUPT_POLL) {
+                    Ok(r) => r,
+                    Err(RecvTimeoutError::Timeout) => {
+                        let interrupted = self.interrupt.as_ref();
+                        if interrupted.is_some_and(|i| i.load(Ordering::SeqCst)) {
+                            info!("interrupted while paused at step {}", sim.steps());
+                            return false;
+                        }
+                        continue;
+                    }
+                    Err(RecvTimeoutError::Disconnected) => return true,
+                }
+            } else {
+                match self.requests.try_recv() {
+                    Ok(r) => r,
+                    Err(_) => return true,
+                }
+            };
+
+            let stop = matches!(request.command, Command::Stop);
+            let response = self.handle(request.command, sim);
+            let _ = request.reply.send(response);
+            if stop {
+                return false;
+            }
+        }
+    }
+
+    fn handle(&mut self, command: Command, sim: &mut Simulation) -> Response {
+        match command {
+            Command::Pause => {
+                info!("paused by the control API at step {}", sim.steps());
+                self.paused = true;
+            }
+            Command::Resume => {
+                info!("resumed by the control API at step {}", sim.steps());
+                self.paused = false;
+            }
+            Command::Stop => {
+                info!("stopped by the control API at step {}", sim.steps());
+            }
+            Command::SetParams(params) => {
+                if params.dt.is_some_and(|dt| !dt.is_finite() || dt <= 0.) {
+                    return Response::error(400, "dt must be positive and finite");
+                }
+                if params
+                    .theta
+                    .is_some_and(|theta| !theta.is_finite() || theta < 0.)
+                {
+                    return Response::error(400, "theta must be finite and not negative");
+                }
+                if let Some(dt) = params.dt {
+                    info!("dt changed from {} to {}", sim.dt(), dt);
+                    sim.set_dt(dt);
+                }
+                if let Some(theta) = params.theta {
+                    info!("theta changed from {} to {}", sim.tree().theta(), theta);
+                    if let Err(e) = sim.set_theta(theta) {
+                        return Response::error(500, &e.to_string());
+                    }
+                }
+            }
+            Command::Snapshot => {
+                let mut csv = vec![];
+                if let Err(e) = sim.tree().write_csv(&mut csv) {
+                    return Response::error(500, &e.to_string());
+                }
+                return Response {
+                    code: 200,
+                    content_type: "text/csv",
+                    body: String::from_utf8(csv).unwrap(),
+                };
+            }
+            Command::Status => {}
+        }
+
+        let tree = sim.tree();
+        return Response::json(&Status {
+            step: sim.steps(),
+            time: sim.time(),
+            dt: sim.dt(),
+            theta: tree.theta(),
+            particles: tree.len(),
+            paused: self.paused,
+            kinetic_energy: tree.iter().map(|p| p.kinetic_energy()).sum(),
+            angular_momentum: tree.angular_momentum().magnitude(),
+        });
+    }
+}
+
+fn serve(listener: TcpListener, requests: Sender<Request>) {
+    for stream in listener.incoming() {
+        let mut stream = match stream {
+            Ok(s) => s,
+            Err(e) => {
+                warn!("control connection failed: {}", e);
+                continue;
+            }
+        };
+        if let Err(e) = stream.set_read_timeout(Some(READ_TIMEOUT)) {
+            warn!("failed to set a control connection's read timeout: {}", e);
+            continue;
+        }
+        let response = match read_command(&mut stream) {
+            Ok(command) => {
+                let (tx, rx) = bounded(1);
+                let request = Request {
+                    command: command,
+                    reply: tx,
+                };
+                match requests.send(request) {
+                    Ok(()) => rx
+                        .recv_timeout(REPLY_TIMEOUT)
+                        .unwrap_or_else(|_| Response::error(503, "the simulation is busy")),
+                    Err(_) => Response::error(503, "the simulation has finished"),
+                }
+            }
+            Err(response) => response,
+        };
+
+        let reason = match response.code {
+            200 => "OK",
+            400 => "Bad Request",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            413 => "Payload Too Large",
+            500 => "Internal Server Error",
+            _ => "Service Unavailable",
+        };
+        let text = format!(
+            "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            response.code,
+            reason,
+            response.content_type,
+            response.body.len(),
+            response.body
+        );
+        if let Err(e) = stream.write_all(text.as_bytes()) {
+            warn!("failed to write control response: {}", e);
+        }
+    }
+}
+
+// Reads one HTTP request and maps it to a command, or to the error to send back.
+fn read_command(stream: &mut TcpStream) -> Result<Command, Response> {
+    let mut buf = vec![];
+    let mut chunk = [0u8; 4096];
+    let (head_len, content_length) = loop {
+        let n = stream
+            .read(&mut chunk)
+            .map_err(|e| Response::error(400, &e.to_string()))?;
+        if n == 0 {
+            return Err(Response::error(400, "incomplete request"));
+        }
+        buf.extend_from_slice(&chunk[..n]);
+        if let Some(end) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            let head = String::from_utf8_lossy(&buf[..end]).to_ascii_lowercase();
+            let length = match head.lines().find_map(|l| l.strip_prefix("content-length:")) {
+                Some(v) => v
+                    .trim()
+                    .parse::<usize>()
+                    .map_err(|_| Response::error(400, "invalid content length"))?,
+                None => 0,
+            };
+            if length > MAX_BODY {
+                return Err(Response::error(413, "request body too large"));
+            }
+            break (end + 4, length);
+        }
+        if buf.len() > MAX_HEAD {
+            return Err(Response::error(413, "request head too large"));
+        }
+    };
+    while buf.len() < head_len + content_length {
+        let n = stream
+            .read(&mut chunk)
+            .map_err(|e| Response::error(400, &e.to_string()))?;
+        if n == 0 {
+            break;
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+
+    let head = String::from_utf8_lossy(&buf[..head_len]).to_string();
+    let mut request_line = head.lines().next().unwrap_or("").split_whitespace();
+    let method = request_line.next().unwrap_or("");
+    let path = request_line.next().unwrap_or("");
+    let body = &buf[head_len..];
+    return match (method, path) {
+        ("GET", "/status") => Ok(Command::Status),
+        ("GET", "/snapshot") => Ok(Command::Snapshot),
+        ("POST", "/pause") => Ok(Command::Pause),
+        ("POST", "/resume") | ("POST", "/start") => Ok(Command::Resume),
+        ("POST", "/stop") => Ok(Command::Stop),
+        ("POST", "/params") => serde_json::from_slice(body)
+            .map(Command::SetParams)
+            .map_err(|e| Response::error(400, &e.to_string())),
+        (_, "/status")
+        | (_, "/snapshot")
+        | (_, "/pause")
+        | (_, "/resume")
+        | (_, "/start")
+        | (_, "/stop")
+        | (_, "/params") => Err(Response::error(405, "method not allowed")),
+        _ => Err(Response::error(404, "not found")),
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpStream};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    use crate::control::{Command, ControlServer, Params, MAX_HEAD};
+    use crate::geometry::bh_tree::BHTree;
+    use crate::simulation::Simulation;
+    use crate::{Point, Vec3d};
+
+    fn request(addr: SocketAddr, method: &str, path: &str, body: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(
+            stream,
+            "{} {} HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n{}",
+            method,
+            path,
+            body.len(),
+            body
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        return response;
+    }
+
+    #[test]
+    fn test_control_api() {
+        let points = vec![
+            Point::new(1., 0., 0., 0., Vec3d::new(1., 0., 0.)).with_id(0),
+            Point::new(1., 5., 0., 0., Vec3d::new_zero()).with_id(1),
+        ];
+        let mut sim =
+            Simulation::from_tree(BHTree::from_points(0.5, points).unwrap(), 1., 0).with_time(3.);
+        let mut control = ControlServer::bind("127.0.0.1:0", false).unwrap();
+        let addr = control.local_addr();
+
+        let client = thread::spawn(move || {
+            return [
+                request(addr, "POST", "/params", r#"{"dt": 2.5, "theta": 0.7}"#),
+                request(addr, "POST", "/params", r#"{"dt": -1}"#),
+                request(addr, "POST", "/params", r#"{"dt": Infinity}"#),
+                request(addr, "POST", "/params", r#"{"dt": 1e400}"#),
+                request(addr, "POST", "/params", r#"{"theta": 1e400}"#),
+                request(addr, "GET", "/status", ""),
+                request(addr, "GET", "/snapshot", ""),
+                request(addr, "GET", "/nowhere", ""),
+                request(addr, "POST", "/stop", ""),
+            ];
+        });
+        let mut running = true;
+        while running && !client.is_finished() {
+            running = control.serve_pending(&mut sim);
+            thread::sleep(Duration::from_millis(5));
+        }
+        let responses = client.join().unwrap();
+
+        assert!(!running);
+        assert_eq!(sim.dt(), 2.5);
+        assert_eq!(sim.tree().theta(), 0.7);
+        assert!(responses[0].starts_with("HTTP/1.1 200"));
+        for rejected in &responses[1..5] {
+            assert!(rejected.starts_with("HTTP/1.1 400"), "{}", rejected);
+        }
+        assert!(responses[5].contains(r#""particles":2"#));
+        assert!(responses[5].contains(r#""time":3.0"#));
+        assert!(responses[5].contains(r#""kinetic_energy":0.5"#));
+        assert!(responses[6].contains("text/csv"));
+        assert_eq!(
+            responses[6].lines().filter(|l| l.starts_with("1,")).count(),
+            2
+        );
+        assert!(responses[7].starts_with("HTTP/1.1 404"));
+
+        // JSON can't spell infinity, but a Params built in code can.
+        for params in [
+            Params {
+                dt: Some(f64::INFINITY),
+                theta: None,
+            },
+            Params {
+                dt: None,
+                theta: Some(f64::INFINITY),
+            },
+        ] {
+            assert_eq!(
+                control.handle(Command::SetParams(params), &mut sim).code,
+                400
+            );
+        }
+        assert_eq!(sim.dt(), 2.5);
+        assert_eq!(sim.tree().theta(), 0.7);
+    }
+
+    #[test]
+    fn test_interrupt_while_paused() {
+        let points = vec![Point::new(1., 0., 0., 0., Vec3d::new_zero()).with_id(0)];
+        let mut sim = Simulation::from_tree(BHTree::from_points(0.5, points).unwrap(), 1., 0);
+        let interrupt = Arc::new(AtomicBool::new(false));
+        let mut control = ControlServer::bind("127.0.0.1:0", true)
+            .unwrap()
+            .with_interrupt(interrupt.clone());
+        let flag = interrupt.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            flag.store(true, Ordering::SeqCst);
+        });
+        assert!(!control.serve_pending(&mut sim));
+        assert!(control.paused());
+    }
+
+    #[test]
+    fn test_oversized_requests() {
+        let control = ControlServer::bind("127.0.0.1:0", false).unwrap();
+        let addr = control.local_addr();
+        let send = |text: &[u8]| {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream.write_all(text).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            return response;
+        };
+
+        let huge = format!(
+            "POST /params HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+            usize::MAX
+        );
+        assert!(send(huge.as_bytes()).starts_with("HTTP/1.1 413"));
+        // A head that never ends is cut off once it passes the limit.
+        let long = format!("GET /status HTTP/1.1\r\nX: {}", "a".repeat(MAX_HEAD));
+        assert!(send(long.as_bytes()).starts_with("HTTP/1.1 413"));
+        let garbled = "POST /params HTTP/1.1\r\nContent-Length: lots\r\n\r\n";
+        assert!(send(garbled.as_bytes()).starts_with("HTTP/1.1 400"));
+
+        // A client that connects and says nothing is dropped, and the next one is served.
+        let idle = TcpStream::connect(addr).unwrap();
+        assert!(send(garbled.as_bytes()).starts_with("HTTP/1.1 400"));
+        drop(idle);
+    }
+}
@@ -0,0 +1,97 @@
+use std::fmt;
+
+use thiserror::Error;
+
+/// Errors from building, walking or writing out a tree.
+#[derive(Debug, Error)]
+pub enum NbodyError {
+    // A node's bookkeeping doesn't match what it holds, which means a bug in the tree rather than
+    // bad input.
+    #[error("inconsistent tree node: {0}")]
+    InconsistentNode(String),
+
+    #[error("particle {id} at ({x}, {y}, {z}) is outside the tree's region")]
+    OutOfBounds { id: u64, x: f64, y: f64, z: f64 },
+
+    #[error("particle {id} has mass {mass}; masses must be finite and not negative")]
+    InvalidMass { id: u64, mass: f64 },
+
+    // Force sums leave a particle out by its id, so two particles sharing one would each miss the
+    // other's pull.
+    #[error("more than one particle has id {0}")]
+    DuplicateId(u64),
+
+    // No amount of splitting separates two particles at exactly the same place, so unless they
+    // merge they can't both go in the tree.
+    #[error("particles {0} and {1} are at the same position and may not merge")]
+    Coincident(u64, u64),
+
+    // Only checked for in paranoid mode. See `BHTree::with_paranoid`.
+    #[error("particle {id} got a non-finite {quantity} {culprit}")]
+    NonFinite {
+        id: u64,
+        quantity: &'static str,
+        culprit: Culprit,
+    },
+
+    #[error("snapshot writer thread for {0} panicked")]
+    WriterPanicked(String),
+
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+
+    #[error(transparent)]
+    Csv(#[from] csv::Error),
+}
+
+/// Where a particle's non-finite position, velocity or force came from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Culprit {
+    // It was already non-finite going into the step.
+    Input,
+    // Its pull from one other particle.
+    Particle {
+        id: u64,
+    },
+    // A node far enough away that its centre of mass stood in for its particles.
+    Node {
+        count: u64,
+        mass: f64,
+        x: f64,
+        y: f64,
+        z: f64,
+    },
+    // The Ewald correction for the periodic images.
+    Images,
+    // Whatever the step added on top of the tree's gravity: external potentials, perturbers, drag,
+    // post-Newtonian terms, gas or the particle mesh.
+    External,
+    // Every term was finite, but not their total.
+    Sum,
+    // The force was finite, and the integrator, or a regularized pair's orbit, wasn't.
+    Integrator,
+}
+
+impl fmt::Display for Culprit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return match self {
+            Culprit::Input => write!(f, "before the step started"),
+            Culprit::Particle { id } => write!(f, "from its interaction with particle {}", id),
+            Culprit::Node {
+                count,
+                mass,
+                x,
+                y,
+                z,
+            } => write!(
+                f,
+                "from a node of {} particles with mass {} centred at ({}, {}, {})",
+                count, mass, x, y, z
+            ),
+            Culprit::Images => write!(f, "from the Ewald sum over the periodic images"),
+            Culprit::External => write!(f, "from the forces added on top of the tree's gravity"),
+            Culprit::Sum => write!(f, "from adding up interactions that were each finite"),
+            Culprit::Integrator => write!(f, "while integrating a finite force over the step"),
+        };
+    }
+}
@@ -0,0 +1,371 @@
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::slice;
+
+use log::error;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+
+use crate::geometry::force::ForceLaw;
+use crate::ic::from_arrays;
+use crate::simulation::{Simulation, SimulationBuilder};
+use crate::Point;
+
+/// Opaque handle to a simulation, owned by the caller until passed to `nbody_simulation_free`.
+pub struct NbodySimulation {
+    sim: Simulation,
+}
+
+/// Returned by functions that succeed.
+pub const NBODY_OK: i32 = 0;
+/// Returned when a required pointer is null.
+pub const NBODY_NULL_POINTER: i32 = -1;
+/// Returned when the simulation fails; the details are logged.
+pub const NBODY_SIMULATION_ERROR: i32 = -2;
+
+/// Creates an empty simulation with time step `dt`, gravitational constant `g` and opening angle
+/// `theta`. Free it with `nbody_simulation_free`. Returns null if the simulation can't be built.
+#[no_mangle]
+pub extern "C" fn nbody_simulation_new(dt: f64, g: f64, theta: f64) -> *mut NbodySimulation {
+    return guarded(ptr::null_mut(), || {
+        let sim = SimulationBuilder::new()
+            .theta(theta)
+            .dt(dt)
+            .force_law(ForceLaw::newtonian(g))
+            .build(&mut StdRng::seed_from_u64(0));
+        return match sim {
+            Ok(sim) => Box::into_raw(Box::new(NbodySimulation { sim: sim })),
+            Err(e) => {
+                error!("failed to create a simulation: {}", e);
+                ptr::null_mut()
+            }
+        };
+    });
+}
+
+/// Destroys a simulation. Passing null does nothing.
+///
+/// # Safety
+///
+/// `sim` must be null or come from `nbody_simulation_new`, and must not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn nbody_simulation_free(sim: *mut NbodySimulation) {
+    guarded((), || {
+        if !sim.is_null() {
+            drop(Box::from_raw(sim));
+        }
+    });
+}
+
+/// Adds `n` particles. `masses` holds `n` values; `positions` and `velocities` hold `3 * n`, as
+/// x, y, z per particle. New particles get ids following the existing ones, in array order.
+///
+/// # Safety
+///
+/// `sim` must be a live simulation and the arrays must be valid for the lengths above.
+#[no_mangle]
+pub unsafe extern "C" fn nbody_simulation_add_particles(
+    sim: *mut NbodySimulation,
+    n: usize,
+    masses: *const f64,
+    positions: *const f64,
+    velocities: *const f64,
+) -> i32 {
+    return guarded(NBODY_SIMULATION_ERROR, || {
+        if sim.is_null() {
+            return NBODY_NULL_POINTER;
+        }
+        let points = match read_particles(n, masses, positions, velocities) {
+            Ok(points) => points,
+            Err(code) => return code,
+        };
+        if let Err(e) = (*sim).sim.add_particles(points) {
+            error!("failed to add particles: {}", e);
+            return NBODY_SIMULATION_ERROR;
+        }
+        return NBODY_OK;
+    });
+}
+
+/// Replaces the external particles with `n` others, positioned by a code coupled to this one:
+/// they pull on the simulation's particles but aren't moved by it. Arrays as for
+/// `nbody_simulation_add_particles`. The `i`-th particle keeps the id the `i`-th had on the
+/// previous call, and any more get ids following the existing ones.
+///
+/// # Safety
+///
+/// `sim` must be a live simulation and the arrays must be valid for the lengths above.
+#[no_mangle]
+pub unsafe extern "C" fn nbody_simulation_set_external(
+    sim: *mut NbodySimulation,
+    n: usize,
+    masses: *const f64,
+    positions: *const f64,
+    velocities: *const f64,
+) -> i32 {
+    return guarded(NBODY_SIMULATION_ERROR, || {
+        if sim.is_null() {
+            return NBODY_NULL_POINTER;
+        }
+        let points = match read_particles(n, masses, positions, velocities) {
+            Ok(points) => points,
+            Err(code) => return code,
+        };
+        if let Err(e) = (*sim).sim.set_external(points) {
+            error!("failed to set external particles: {}", e);
+            return NBODY_SIMULATION_ERROR;
+        }
+        return NBODY_OK;
+    });
+}
+
+// The particles in the arrays passed to `nbody_simulation_add_particles`, or the code to return.
+unsafe fn read_particles(
+    n: usize,
+    masses: *const f64,
+    positions: *const f64,
+    velocities: *const f64,
+) -> Result<Vec<Point>, i32> {
+    if masses.is_null() || positions.is_null() || velocities.is_null() {
+        return Err(NBODY_NULL_POINTER);
+    }
+    // No array can be longer than isize::MAX bytes, so a larger `n` can't be what the caller has.
+    let coordinates = match n.checked_mul(3) {
+        Some(len) if len <= isize::MAX as usize / mem::size_of::<f64>() => len,
+        _ => {
+            error!("too many particles: {}", n);
+            return Err(NBODY_SIMULATION_ERROR);
+        }
+    };
+    return from_arrays(
+        slice::from_raw_parts(masses, n),
+        slice::from_raw_parts(positions, coordinates),
+        slice::from_raw_parts(velocities, coordinates),
+    )
+    .map_err(|e| {
+        error!("invalid particles: {}", e);
+        NBODY_SIMULATION_ERROR
+    });
+}
+
+/// Advances the simulation by `steps` steps.
+///
+/// # Safety
+///
+/// `sim` must be a live simulation.
+#[no_mangle]
+pub unsafe extern "C" fn nbody_simulation_step(sim: *mut NbodySimulation, steps: u64) -> i32 {
+    return guarded(NBODY_SIMULATION_ERROR, || {
+        if sim.is_null() {
+            return NBODY_NULL_POINTER;
+        }
+        for _ in 0..steps {
+            if let Err(e) = (*sim).sim.step() {
+                error!("step {} failed: {}", (*sim).sim.steps() + 1, e);
+                return NBODY_SIMULATION_ERROR;
+            }
+        }
+        return NBODY_OK;
+    });
+}
+
+/// Number of particles, which can drop as particles merge.
+///
+/// # Safety
+///
+/// `sim` must be a live simulation.
+#[no_mangle]
+pub unsafe extern "C" fn nbody_simulation_len(sim: *const NbodySimulation) -> usize {
+    return guarded(0, || {
+        if sim.is_null() {
+            return 0;
+        }
+        return (*sim).sim.tree().len();
+    });
+}
+
+/// Number of steps taken so far.
+///
+/// # Safety
+///
+/// `sim` must be a live simulation.
+#[no_mangle]
+pub unsafe extern "C" fn nbody_simulation_steps(sim: *const NbodySimulation) -> u64 {
+    return guarded(0, || {
+        if sim.is_null() {
+            return 0;
+        }
+        return (*sim).sim.steps();
+    });
+}
+
+/// Simulated time since the start of the run.
+///
+/// # Safety
+///
+/// `sim` must be a live simulation.
+#[no_mangle]
+pub unsafe extern "C" fn nbody_simulation_time(sim: *const NbodySimulation) -> f64 {
+    return guarded(0., || {
+        if sim.is_null() {
+            return 0.;
+        }
+        return (*sim).sim.time();
+    });
+}
+
+/// Copies the state of up to `capacity` particles, ordered by id, into the given arrays and
+/// returns how many were copied. `ids` and `masses` need room for `capacity` values, `positions`
+/// and `velocities` for `3 * capacity`. Any of them may be null to skip that quantity.
+///
+/// # Safety
+///
+/// `sim` must be a live simulation and each non-null array must be valid for the length above.
+#[no_mangle]
+pub unsafe extern "C" fn nbody_simulation_copy_state(
+    sim: *const NbodySimulation,
+    capacity: usize,
+    ids: *mut u64,
+    masses: *mut f64,
+    positions: *mut f64,
+    velocities: *mut f64,
+) -> usize {
+    return guarded(0, || {
+        if sim.is_null() {
+            return 0;
+        }
+        let mut points: Vec<Point> = (*sim).sim.tree().points();
+        points.sort_by_key(|p| p.id());
+        points.truncate(capacity);
+
+        for (i, p) in points.iter().enumerate() {
+            if !ids.is_null() {
+                *ids.add(i) = p.id();
+            }
+            if !masses.is_null() {
+                *masses.add(i) = p.mass();
+            }
+            if !positions.is_null() {
+                let (x, y, z) = p.position();
+                ptr::copy_nonoverlapping([x, y, z].as_ptr(), positions.add(3 * i), 3);
+            }
+            if !velocities.is_null() {
+                let (x, y, z) = p.velocity().position();
+                ptr::copy_nonoverlapping([x, y, z].as_ptr(), velocities.add(3 * i), 3);
+            }
+        }
+        return points.len();
+    });
+}
+
+// Runs the body of an entry point, returning `failed` if it panics instead of unwinding into the
+// caller, which would abort the process.
+fn guarded<T>(failed: T, body: impl FnOnce() -> T) -> T {
+    return match panic::catch_unwind(AssertUnwindSafe(body)) {
+        Ok(result) => result,
+        Err(_) => {
+            error!("panicked in a call from C; the simulation may be left half updated");
+            failed
+        }
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use std::ptr;
+
+    use crate::ffi::*;
+
+    #[test]
+    fn test_round_trip() {
+        unsafe {
+            let sim = nbody_simulation_new(0.01, 1., 0.5);
+            let masses = [1., 2.];
+            let positions = [0., 0., 0., 10., 0., 0.];
+            let velocities = [0., 0., 0., 0., 1., 0.];
+            assert_eq!(
+                nbody_simulation_add_particles(
+                    sim,
+                    2,
+                    masses.as_ptr(),
+                    positions.as_ptr(),
+                    velocities.as_ptr()
+                ),
+                NBODY_OK
+            );
+            assert_eq!(
+                nbody_simulation_add_particles(sim, 1, ptr::null(), ptr::null(), ptr::null()),
+                NBODY_NULL_POINTER
+            );
+            assert_eq!(nbody_simulation_len(sim), 2);
+            assert_eq!(nbody_simulation_step(sim, 3), NBODY_OK);
+            assert_eq!(nbody_simulation_steps(sim), 3);
+            assert!((nbody_simulation_time(sim) - 0.03).abs() < 1e-15);
+
+            let mut ids = [0u64; 4];
+            let mut out = [0.; 12];
+            let copied = nbody_simulation_copy_state(
+                sim,
+                4,
+                ids.as_mut_ptr(),
+                ptr::null_mut(),
+                out.as_mut_ptr(),
+                ptr::null_mut(),
+            );
+            assert_eq!(copied, 2);
+            assert_eq!(ids[..2], [0, 1]);
+            // The first particle is pulled towards the second, which moves off along y.
+            assert!(out[0] > 0.);
+            assert!(out[4] > 0.);
+
+            // An external particle joins the tree but stays where it's put.
+            let at = [0., 20., 0.];
+            assert_eq!(
+                nbody_simulation_set_external(
+                    sim,
+                    1,
+                    masses.as_ptr(),
+                    at.as_ptr(),
+                    velocities.as_ptr()
+                ),
+                NBODY_OK
+            );
+            assert_eq!(nbody_simulation_step(sim, 2), NBODY_OK);
+            assert_eq!(nbody_simulation_len(sim), 3);
+            let copied = nbody_simulation_copy_state(
+                sim,
+                4,
+                ids.as_mut_ptr(),
+                ptr::null_mut(),
+                out.as_mut_ptr(),
+                ptr::null_mut(),
+            );
+            assert_eq!((copied, ids[2]), (3, 2));
+            assert_eq!(out[6..9], at);
+
+            // Lengths whose arrays couldn't exist are refused before anything is read.
+            assert_eq!(
+                nbody_simulation_add_particles(
+                    sim,
+                    usize::MAX / 2,
+                    masses.as_ptr(),
+                    positions.as_ptr(),
+                    velocities.as_ptr()
+                ),
+                NBODY_SIMULATION_ERROR
+            );
+            assert_eq!(nbody_simulation_len(sim), 3);
+            nbody_simulation_free(sim);
+        }
+    }
+
+    #[test]
+    fn test_panics_caught() {
+        assert_eq!(guarded(NBODY_SIMULATION_ERROR, || NBODY_OK), NBODY_OK);
+        assert_eq!(
+            guarded(NBODY_SIMULATION_ERROR, || panic!("step blew up")),
+            NBODY_SIMULATION_ERROR
+        );
+    }
+}
@@ -1,3 +1,30 @@
+pub mod balance;
 pub mod bh_tree;
+pub mod boundary;
+pub mod contact;
+#[cfg(not(target_arch = "wasm32"))]
 pub mod db;
+pub mod density;
+pub mod drag;
+pub mod escape;
+pub mod ewald;
+pub mod force;
+pub mod infall;
+pub mod interactions;
+pub mod kepler;
+pub mod kicks;
+pub mod mass_loss;
+pub mod merge;
+pub mod octree;
+pub mod opening;
+pub mod perturber;
+pub mod pm;
+pub mod pn;
+pub mod potential;
+pub mod species;
+pub mod sph;
+pub mod split;
+pub mod summation;
+pub mod thermodynamics;
 pub mod vec3d;
+pub mod vecn;
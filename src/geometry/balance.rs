@@ -0,0 +1,143 @@
+use std::collections::HashMap;
+use std::ops::Range;
+
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+
+use crate::Point;
+
+// Runs of particles to hand each thread, so that a thread that finishes early can take more.
+const RUNS_PER_THREAD: usize = 8;
+
+/// How much work each particle's tree walk took on the previous step, counted in interactions
+/// (particles and nodes summed), by particle id. Walks through dense regions take far longer than
+/// isolated ones, and a particle's walk changes little from one step to the next, so this is a
+/// good guess at how the next force pass will be spread.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct WalkCosts {
+    by_id: HashMap<u64, u32>,
+    mean: u32,
+}
+
+impl WalkCosts {
+    // The interactions counted for each of `points`, in the same order.
+    pub fn record(points: &[Point], interactions: &[u32]) -> WalkCosts {
+        let total: u64 = interactions.iter().map(|n| *n as u64).sum();
+        return WalkCosts {
+            by_id: points
+                .iter()
+                .zip(interactions.iter())
+                .map(|(p, n)| (p.id(), *n))
+                .collect(),
+            mean: total.checked_div(interactions.len() as u64).unwrap_or(1) as u32,
+        };
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.by_id.is_empty();
+    }
+
+    // The expected cost of each of `points`. Particles not seen last step, and every particle
+    // before the first step, are taken to cost the average.
+    pub fn estimate(&self, points: &[Point]) -> Vec<u64> {
+        let mut costs = vec![];
+        self.estimate_into(points, &mut costs);
+        return costs;
+    }
+
+    // Same as `estimate`, into `costs` in place of what was there.
+    pub fn estimate_into(&self, points: &[Point], costs: &mut Vec<u64>) {
+        costs.clear();
+        costs.extend(
+            points
+                .iter()
+                .map(|p| self.by_id.get(&p.id()).copied().unwrap_or(self.mean).max(1) as u64),
+        );
+    }
+}
+
+// Splits `costs` into at most `parts` contiguous runs, each costing about the same. Runs are never
+// empty, and together cover every index in order.
+pub fn split(costs: &[u64], parts: usize) -> Vec<Range<usize>> {
+    let total: u64 = costs.iter().sum();
+    let parts = parts.clamp(1, costs.len().max(1)) as u64;
+    let mut runs = vec![];
+    let mut start = 0;
+    let mut sum = 0;
+    for (i, cost) in costs.iter().enumerate() {
+        sum += cost;
+        // Close the run once it reaches its share of everything up to here.
+        let done = runs.len() as u64 + 1;
+        if sum * parts >= total * done && done < parts {
+            runs.push(start..i + 1);
+            start = i + 1;
+        }
+    }
+    if start < costs.len() {
+        runs.push(start..costs.len());
+    }
+    return runs;
+}
+
+// `f` of each of `points`, in order, computed in parallel in runs of about equal `costs`.
+pub fn map_balanced<T, F>(points: &[Point], costs: &[u64], f: F) -> Vec<T>
+where
+    T: Send,
+    F: Fn(&Point) -> T + Sync,
+{
+    #[cfg(feature = "parallel")]
+    let parts: Vec<Vec<T>> = split(costs, rayon::current_num_threads() * RUNS_PER_THREAD)
+        .into_par_iter()
+        .map(|run| points[run].iter().map(&f).collect())
+        .collect();
+    #[cfg(not(feature = "parallel"))]
+    let parts: Vec<Vec<T>> = split(costs, RUNS_PER_THREAD)
+        .into_iter()
+        .map(|run| points[run].iter().map(&f).collect())
+        .collect();
+    return parts.into_iter().flatten().collect();
+}
+
+#[cfg(test)]
+mod test {
+    use crate::geometry::balance::{split, WalkCosts};
+    use crate::geometry::bh_tree::BHTree;
+    use crate::{Point, Vec3d};
+
+    #[test]
+    fn test_walk_costs() {
+        // Runs end where the running total passes each share, so an expensive particle ends one.
+        assert_eq!(split(&[1, 1, 1, 9, 1, 1, 1], 3), vec![0..4, 4..5, 5..7]);
+        assert_eq!(split(&[10, 1, 1, 1, 1], 2), vec![0..1, 1..5]);
+        assert_eq!(split(&[1; 6], 3), vec![0..2, 2..4, 4..6]);
+        assert_eq!(split(&[1, 1], 5), vec![0..1, 1..2]);
+        assert!(split(&[], 4).is_empty());
+
+        let points: Vec<Point> = (0..3)
+            .map(|i| Point::new(1., i as f64, 0., 0., Vec3d::new_zero()).with_id(i))
+            .collect();
+        let costs = WalkCosts::record(&points, &[2, 4, 9]);
+        let late = Point::new(1., 5., 0., 0., Vec3d::new_zero()).with_id(7);
+        assert_eq!(costs.estimate(&[points[2], late, points[0]]), vec![9, 5, 2]);
+        assert_eq!(WalkCosts::default().estimate(&points), vec![1, 1, 1]);
+
+        // A step measures the walks and leaves the costs on the next tree, where a cluster's
+        // particles cost more than a loner's.
+        let mut points: Vec<Point> = (0..40)
+            .map(|i| Point::new(1e10, (i % 4) as f64, (i / 4) as f64, 0., Vec3d::new_zero()))
+            .collect();
+        points.push(Point::new(1e10, 1e6, 0., 0., Vec3d::new_zero()));
+        let points: Vec<Point> = points
+            .into_iter()
+            .enumerate()
+            .map(|(i, p)| p.with_id(i as u64))
+            .collect();
+        let tree = BHTree::from_points(0.5, points).unwrap();
+        assert!(tree.walk_costs().is_empty());
+        let (next, stats) = tree.step(1.).unwrap();
+        let costs = next.walk_costs().estimate(&next.points());
+        let loner = next.points().iter().position(|p| p.id() == 40).unwrap();
+        assert!(costs.iter().all(|c| *c >= costs[loner]));
+        assert_eq!(stats.interactions, costs.iter().sum::<u64>());
+    }
+}
@@ -1,349 +1,3653 @@
-use std::error::Error;
-use std::thread;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Display, Write as _};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
-use log::{debug, info, trace, warn};
+use log::{debug, trace, warn};
+#[cfg(feature = "parallel")]
 use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
 
+use crate::error::{Culprit, NbodyError};
+use crate::geometry::balance::{map_balanced, WalkCosts};
+use crate::geometry::boundary::Boundary;
+use crate::geometry::contact::{bounce, CollisionModel, CollisionOutcome};
+use crate::geometry::density;
+use crate::geometry::escape::EscaperPolicy;
+use crate::geometry::ewald;
+use crate::geometry::force::ForceLaw;
+use crate::geometry::interactions::InteractionList;
+use crate::geometry::kepler;
+use crate::geometry::merge::{MergePolicy, Schwarzschild};
+use crate::geometry::octree::{bounds, insert_bounded, NodePool, Octant, Refinement, Summary};
+use crate::geometry::opening::{NodeExtent, OpeningCriterion, ThetaSchedule};
+use crate::geometry::species::SpeciesTable;
+use crate::geometry::summation::{CompensatedVec, MassSums};
+#[cfg(feature = "numa")]
+use crate::numa::NumaPools;
 use crate::{Point, Vec3d};
 
+// Without the `parallel` feature the same iterator chains run serially.
+#[cfg(not(feature = "parallel"))]
+pub(crate) trait SerialIter<T> {
+    fn par_iter(&self) -> std::slice::Iter<'_, T>;
+}
+
+#[cfg(not(feature = "parallel"))]
+impl<T> SerialIter<T> for [T] {
+    fn par_iter(&self) -> std::slice::Iter<'_, T> {
+        return self.iter();
+    }
+}
+
+#[cfg(not(feature = "parallel"))]
+trait SerialExtend<T> {
+    fn par_extend<I: IntoIterator<Item = T>>(&mut self, iter: I);
+}
+
+#[cfg(not(feature = "parallel"))]
+impl<T> SerialExtend<T> for Vec<T> {
+    fn par_extend<I: IntoIterator<Item = T>>(&mut self, iter: I) {
+        self.extend(iter);
+    }
+}
+
+/// Bookkeeping from a single call to `BHTree::step`.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct StepStats {
+    pub force_time: Duration,
+    pub integrate_time: Duration,
+    pub build_time: Duration,
+    // Pairs merged by the collision pass, or for landing at exactly the same place.
+    pub merges: usize,
+    // Particles absorbed by walls or dropped as escapers.
+    pub removed: usize,
+    // Close pairs advanced analytically instead of by the integrator.
+    pub regularized_pairs: usize,
+    // How far the centre of mass moved during the step.
+    pub com_drift: f64,
+    // Magnitude of the total angular momentum about the origin after the step.
+    pub angular_momentum: f64,
+    // The step kept the previous tree's nodes instead of building new ones.
+    pub tree_reused: bool,
+    // Particles and nodes summed over by all of the step's tree walks.
+    pub interactions: u64,
+}
+
+/// Buffers kept from one step to the next, so that a long run reuses the memory of each step's
+/// particle lists, and of the nodes of the trees it is done with, instead of allocating them
+/// afresh. Pass the same scratch to every `step_in` and hand it each finished tree through
+/// `recycle`. A CSV writer keeps one for its rows. Buffers are only ever cleared, so they grow to
+/// fit the largest step and stay that size.
+#[derive(Debug, Default)]
+pub struct StepScratch {
+    // The particles going into a step, and where the step moves them.
+    points: Vec<Point>,
+    moved: Vec<Point>,
+    costs: Vec<u64>,
+    forces: Vec<Vec3d>,
+    interactions: Vec<u32>,
+    // Particles on their way into a tree, and the same sorted by Morton code.
+    keyed: Vec<(u64, Point)>,
+    sorted: Vec<Point>,
+    // The nodes of recycled trees.
+    nodes: NodePool<Point, Moments>,
+    // Snapshot rows, handed back by the writer threads once the file is written.
+    rows: Arc<Mutex<Vec<Vec<u8>>>>,
+}
+
+impl StepScratch {
+    pub fn new() -> StepScratch {
+        return StepScratch::default();
+    }
+
+    // Keeps the memory of `tree`'s nodes for building the next tree.
+    pub fn recycle(&mut self, tree: BHTree) {
+        self.nodes.recycle(tree.root);
+    }
+
+    // An empty buffer for a snapshot's rows.
+    fn row_buffer(&self) -> Vec<u8> {
+        return self.rows.lock().unwrap().pop().unwrap_or_default();
+    }
+}
+
+/// Two particles that merged on contact.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Merge {
+    pub survivor: u64,
+    pub absorbed: u64,
+    // The merged particle, which keeps the survivor's id.
+    pub result: Point,
+}
+
+impl Refinement {
+    // Rough peak memory in bytes of stepping `n` particles: the tree, the copies of the particles
+    // and forces a step works on, and the tree it builds for the next step.
+    pub fn estimate_memory(&self, n: usize) -> usize {
+        // Trees come out with about 4n / bucket_size nodes; allow for clustered particles.
+        let nodes = 5 * n / self.bucket_size.max(1) + 1;
+        let tree = std::mem::size_of::<BHTree>()
+            + nodes * std::mem::size_of::<BHNode>()
+            + n * std::mem::size_of::<Point>();
+        return 2 * tree + n * (2 * std::mem::size_of::<Point>() + std::mem::size_of::<Vec3d>());
+    }
+}
+
+/// Lets a step keep the previous tree's nodes and refresh only their centres of mass instead of
+/// building a new tree. Particles stay in the leaf they were in as they drift out of it, which makes
+/// the opening test less accurate, so the tree is rebuilt once any particle is more than `drift`
+/// leaf sizes outside its leaf, or after `max_steps` reuses in a row. Boundaries and the escaper
+/// policy are only applied when the tree is rebuilt.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct TreeReuse {
+    pub drift: f64,
+    pub max_steps: u32,
+}
+
+// What a force or potential calculation carries down the tree.
+struct Traversal<'a> {
+    // The particle acted on. Particles with its id are never counted.
+    target: Point,
+    periodic: Option<f64>,
+    law: &'a ForceLaw,
+    opening: &'a OpeningCriterion,
+    schedule: &'a ThetaSchedule,
+    // Size of the tree's root, which node depths are counted from.
+    root_size: f64,
+    species: &'a SpeciesTable,
+    // The target's own softening, used for nodes' centres of mass.
+    softening: f64,
+    // How many of its own sizes a particle can lie outside the node holding it.
+    reach: f64,
+    compensated: bool,
+    // Particles and nodes summed over so far.
+    interactions: Cell<u32>,
+}
+
+impl Traversal<'_> {
+    fn count(&self, interactions: u32) {
+        self.interactions
+            .set(self.interactions.get() + interactions);
+    }
+
+    // Softening between the target and the particle `q`.
+    fn softening_from(&self, q: &Point) -> f64 {
+        return self
+            .species
+            .pair_softening(self.target.species(), q.species());
+    }
+
+    // `q`, or in a periodic box its image nearest the target.
+    fn image(&self, q: Point) -> Point {
+        return match self.periodic {
+            Some(box_size) => q.nearest_image(self.target, box_size),
+            None => q,
+        };
+    }
+}
+
+thread_local! {
+    // Each thread's interaction list, reused from one particle to the next.
+    static LIST: RefCell<InteractionList> = RefCell::new(InteractionList::new());
+}
+
+/// The shape of a tree, from `BHTree::stats`, for tuning theta and the bucket size.
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct TreeStats {
+    pub theta: f64,
+    // Levels below the root of the deepest leaf.
+    pub depth: usize,
+    pub nodes: usize,
+    pub leaves: usize,
+    // Leaves by the number of particles they hold: `occupancy[k]` leaves hold k particles.
+    pub occupancy: Vec<usize>,
+    // Approximate heap and inline size of the nodes and the particles they hold, in bytes.
+    pub memory_bytes: usize,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct BHTree {
     root: BHNode,
     theta: f64,
     graph_size: f64,
+    #[serde(default)]
+    boundary: Boundary,
+    // Adds the pull of the periodic images beyond the nearest, by Ewald summation.
+    #[serde(default)]
+    ewald: bool,
+    #[serde(default)]
+    force_law: ForceLaw,
+    #[serde(default)]
+    species: SpeciesTable,
+    // Massless particles are kept out of the nodes so they don't contribute to any COM.
+    #[serde(default)]
+    tracers: Vec<Point>,
+    // Mutually nearest pairs closer than this are advanced as isolated Kepler orbits.
+    #[serde(default)]
+    encounter_radius: Option<f64>,
+    #[serde(default)]
+    collisions: CollisionModel,
+    // Not serialized: whoever restores a tree sets the policy again.
+    #[serde(skip, default = "default_merge_policy")]
+    merge_policy: Arc<dyn MergePolicy>,
+    #[serde(default)]
+    escapers: EscaperPolicy,
+    // Far-away particles kept out of the nodes by `EscaperPolicy::Outside`. Their forces are
+    // summed directly.
+    #[serde(default)]
+    outside: Vec<Point>,
+    // Two-dimensional mode: every particle lives in the z = 0 plane and the tree is a quadtree.
+    #[serde(default)]
+    planar: bool,
+    // Merges made building this tree and by the `resolve_collisions` call that produced it.
+    #[serde(skip)]
+    merges: Vec<Merge>,
+    // Every particle's id, for `add_point` to check new ones against. Built the first time it's
+    // needed, and dropped whenever particles are added some other way or merged.
+    #[serde(skip)]
+    ids: Option<HashSet<u64>>,
+    #[serde(default)]
+    refinement: Refinement,
+    #[serde(default)]
+    reuse: Option<TreeReuse>,
+    #[serde(default)]
+    opening: OpeningCriterion,
+    #[serde(default)]
+    theta_schedule: ThetaSchedule,
+    // Sum forces and centres of mass with compensated summation.
+    #[serde(default)]
+    compensated: bool,
+    // Steps in a row that have reused this tree's nodes.
+    #[serde(skip)]
+    reused: u32,
+    // How long each particle's walk was in the step that made this tree, to balance the next one.
+    #[serde(skip)]
+    costs: Arc<WalkCosts>,
+    // Gather each particle's interaction list before summing it, instead of summing during the
+    // walk. Not serialized, like the merge policy.
+    #[serde(skip)]
+    interaction_lists: bool,
+    // Check each step for particles whose position, velocity or force isn't finite. Always on in
+    // debug builds. Not serialized, like the merge policy.
+    #[serde(skip)]
+    paranoid: bool,
+    // Splits the force pass over NUMA nodes. Not serialized, like the merge policy.
+    #[cfg(feature = "numa")]
+    #[serde(skip)]
+    numa: Option<Arc<NumaPools>>,
 }
 
-impl BHTree {
-    pub fn new(theta: f64, graph_size: f64, x: f64, y: f64, z: f64) -> BHTree {
-        info!(theta = theta, graph_size = graph_size; "creating barnes-hut tree");
-        return BHTree {
-            root: BHNode::new(theta, graph_size, x, y, z),
-            theta: theta,
-            graph_size: graph_size,
-        };
+impl BHTree {
+    pub fn new(theta: f64, graph_size: f64, x: f64, y: f64, z: f64) -> BHTree {
+        debug!(theta = theta, graph_size = graph_size; "creating barnes-hut tree");
+        return BHTree {
+            root: BHNode::new(graph_size, x, y, z, Moments::new(theta, false)),
+            theta: theta,
+            graph_size: graph_size,
+            boundary: Boundary::Open,
+            ewald: false,
+            force_law: ForceLaw::default(),
+            species: SpeciesTable::default(),
+            tracers: vec![],
+            encounter_radius: None,
+            collisions: CollisionModel::default(),
+            merge_policy: default_merge_policy(),
+            escapers: EscaperPolicy::default(),
+            outside: vec![],
+            planar: false,
+            merges: vec![],
+            ids: None,
+            refinement: Refinement::default(),
+            reuse: None,
+            opening: OpeningCriterion::default(),
+            theta_schedule: ThetaSchedule::default(),
+            compensated: false,
+            reused: 0,
+            costs: Arc::default(),
+            interaction_lists: false,
+            paranoid: false,
+            #[cfg(feature = "numa")]
+            numa: None,
+        };
+    }
+
+    pub fn with_force_law(mut self, force_law: ForceLaw) -> BHTree {
+        self.set_force_law(force_law);
+        return self;
+    }
+
+    pub fn set_force_law(&mut self, force_law: ForceLaw) {
+        self.force_law = force_law;
+    }
+
+    pub fn force_law(&self) -> ForceLaw {
+        return self.force_law;
+    }
+
+    // Enables two-body regularization of close encounters. Only applies with a Newtonian force law.
+    pub fn with_encounter_radius(mut self, radius: Option<f64>) -> BHTree {
+        self.encounter_radius = radius;
+        return self;
+    }
+
+    pub fn encounter_radius(&self) -> Option<f64> {
+        return self.encounter_radius;
+    }
+
+    // Sets the species table, which decides which particles may merge.
+    pub fn with_species(mut self, species: SpeciesTable) -> BHTree {
+        self.species = species;
+        return self;
+    }
+
+    pub fn species(&self) -> &SpeciesTable {
+        return &self.species;
+    }
+
+    // Sets particle sizes and what happens when they touch.
+    pub fn with_collisions(mut self, collisions: CollisionModel) -> BHTree {
+        self.collisions = collisions;
+        return self;
+    }
+
+    pub fn collisions(&self) -> CollisionModel {
+        return self.collisions;
+    }
+
+    // Replaces the rule for which particles merge, Schwarzschild radii by default.
+    pub fn with_merge_policy(mut self, policy: Arc<dyn MergePolicy>) -> BHTree {
+        self.set_merge_policy(policy);
+        return self;
+    }
+
+    pub fn set_merge_policy(&mut self, policy: Arc<dyn MergePolicy>) {
+        self.merge_policy = policy;
+    }
+
+    #[cfg(feature = "numa")]
+    pub fn with_numa(mut self, numa: Option<Arc<NumaPools>>) -> BHTree {
+        self.set_numa(numa);
+        return self;
+    }
+
+    #[cfg(feature = "numa")]
+    pub fn set_numa(&mut self, numa: Option<Arc<NumaPools>>) {
+        self.numa = numa;
+    }
+
+    // Splits each force calculation in two: a walk that only gathers what the particle interacts
+    // with into a flat list, then one tight loop over the list. See `InteractionList`. Forces
+    // agree with the single-pass walk to rounding.
+    pub fn with_interaction_lists(mut self, lists: bool) -> BHTree {
+        self.set_interaction_lists(lists);
+        return self;
+    }
+
+    pub fn set_interaction_lists(&mut self, lists: bool) {
+        self.interaction_lists = lists;
+    }
+
+    pub fn interaction_lists(&self) -> bool {
+        return self.interaction_lists;
+    }
+
+    // Checks every particle for NaN and infinity in each step: its position and velocity going in,
+    // the force on it, and where the step leaves it. The step fails at the first one found with a
+    // `NbodyError::NonFinite` naming the interaction responsible, rather than carrying the value
+    // on into later steps and snapshots. On regardless in debug builds.
+    pub fn with_paranoid(mut self, paranoid: bool) -> BHTree {
+        self.set_paranoid(paranoid);
+        return self;
+    }
+
+    pub fn set_paranoid(&mut self, paranoid: bool) {
+        self.paranoid = paranoid;
+    }
+
+    pub fn paranoid(&self) -> bool {
+        return self.paranoid || cfg!(debug_assertions);
+    }
+
+    pub fn walk_costs(&self) -> &WalkCosts {
+        return &self.costs;
+    }
+
+    pub fn merge_policy(&self) -> &Arc<dyn MergePolicy> {
+        return &self.merge_policy;
+    }
+
+    // Sets what happens to particles that stray too far. Applied whenever the tree is rebuilt.
+    pub fn with_escapers(mut self, escapers: EscaperPolicy) -> BHTree {
+        self.escapers = escapers;
+        return self;
+    }
+
+    pub fn escapers(&self) -> EscaperPolicy {
+        return self.escapers;
+    }
+
+    // Switches to two dimensions: points are projected onto the z = 0 plane as they're added and
+    // the tree only splits in x and y. Set this before adding points.
+    pub fn with_planar(mut self, planar: bool) -> BHTree {
+        self.planar = planar;
+        self.root.planar = planar;
+        if planar {
+            self.root.zloc = 0.;
+        }
+        return self;
+    }
+
+    pub fn planar(&self) -> bool {
+        return self.planar;
+    }
+
+    // Sets the bucket size and depth limit. Set this before adding points.
+    pub fn with_refinement(mut self, refinement: Refinement) -> BHTree {
+        self.refinement = refinement;
+        return self;
+    }
+
+    pub fn refinement(&self) -> Refinement {
+        return self.refinement;
+    }
+
+    pub fn with_reuse(mut self, reuse: Option<TreeReuse>) -> BHTree {
+        self.reuse = reuse;
+        return self;
+    }
+
+    pub fn reuse(&self) -> Option<TreeReuse> {
+        return self.reuse;
+    }
+
+    pub fn with_opening(mut self, opening: OpeningCriterion) -> BHTree {
+        self.opening = opening;
+        return self;
+    }
+
+    pub fn opening(&self) -> OpeningCriterion {
+        return self.opening;
+    }
+
+    // Varies theta with the depth of the node being tested.
+    pub fn with_theta_schedule(mut self, theta_schedule: ThetaSchedule) -> BHTree {
+        self.theta_schedule = theta_schedule;
+        return self;
+    }
+
+    pub fn theta_schedule(&self) -> ThetaSchedule {
+        return self.theta_schedule;
+    }
+
+    // Carries the rounding error along when summing forces and centres of mass, so that many
+    // small contributions next to a large one aren't lost. Costs some speed and memory. Set this
+    // before adding points.
+    pub fn with_compensated_summation(mut self, compensated: bool) -> BHTree {
+        self.compensated = compensated;
+        self.root.summary.sums = compensated.then(Box::default);
+        return self;
+    }
+
+    pub fn compensated_summation(&self) -> bool {
+        return self.compensated;
+    }
+
+    // Builds an empty tree over the periodic box [-box_size / 2, box_size / 2)^3. Forces use the
+    // nearest periodic image of each node.
+    pub fn new_periodic(theta: f64, box_size: f64) -> BHTree {
+        return BHTree::new_bounded(theta, Boundary::Periodic { box_size: box_size });
+    }
+
+    // Builds an empty tree with the given boundary conditions. Boxed trees cover the whole box;
+    // open ones are fitted to their points on every rebuild.
+    pub fn new_bounded(theta: f64, boundary: Boundary) -> BHTree {
+        let mut bht = match boundary.box_size() {
+            Some(box_size) => {
+                let half = box_size / 2.;
+                BHTree::new(theta, box_size, -half, -half, -half)
+            }
+            None => BHTree::fitted(theta, &[]),
+        };
+        bht.boundary = boundary;
+        return bht;
+    }
+
+    pub fn boundary(&self) -> Boundary {
+        return self.boundary;
+    }
+
+    pub fn periodic(&self) -> Option<f64> {
+        return self.boundary.periodic();
+    }
+
+    // In a periodic box, has every particle pulled on by all the periodic images of the others
+    // rather than only the nearest, using a table of Ewald corrections. The correction is summed
+    // over nodes a small fraction of the box across, so it costs a second, shallower walk per
+    // particle. Has no effect on open boxes, on potentials, or alongside a particle mesh, which
+    // handles the images itself.
+    pub fn with_ewald(mut self, ewald: bool) -> BHTree {
+        self.set_ewald(ewald);
+        return self;
+    }
+
+    pub fn set_ewald(&mut self, ewald: bool) {
+        self.ewald = ewald;
+    }
+
+    pub fn ewald(&self) -> bool {
+        return self.ewald;
+    }
+
+    // Builds a tree whose root region just covers all of the given points.
+    pub fn from_points(theta: f64, points: Vec<Point>) -> Result<BHTree, NbodyError> {
+        return BHTree::from_points_with_species(theta, points, SpeciesTable::default());
+    }
+
+    // Same as `from_points`, with the given species table.
+    pub fn from_points_with_species(
+        theta: f64,
+        points: Vec<Point>,
+        species: SpeciesTable,
+    ) -> Result<BHTree, NbodyError> {
+        let bht = BHTree::fitted(theta, &points).with_species(species);
+        return bht.with_points(points);
+    }
+
+    // An empty tree whose root region just covers the given points.
+    fn fitted(theta: f64, points: &[Point]) -> BHTree {
+        let (lo, graph_size) = bounds(points);
+        return BHTree::new(theta, graph_size, lo, lo, lo);
+    }
+
+    // Adds every point to the tree and returns it.
+    pub fn with_points(mut self, points: Vec<Point>) -> Result<BHTree, NbodyError> {
+        self.add_points(points)?;
+        return Ok(self);
+    }
+
+    // Adds all of `points` in one go, which for large sets is faster than calling `add_point` for
+    // each. They are sorted by Morton code and the nodes built top down, each from one contiguous
+    // run of them, so particles close in space also end up close in memory. Particles already in
+    // the tree are taken out and built in along with the new ones. Checks the same bounds as
+    // `add_point`.
+    pub fn add_points(
+        &mut self,
+        points: impl IntoIterator<Item = Point>,
+    ) -> Result<(), NbodyError> {
+        return self.add_points_in(points, &mut StepScratch::new());
+    }
+
+    // Same as `add_points`, building the nodes from the memory kept in `scratch` where it can.
+    fn add_points_in(
+        &mut self,
+        points: impl IntoIterator<Item = Point>,
+        scratch: &mut StepScratch,
+    ) -> Result<(), NbodyError> {
+        self.ids = None;
+        let mut all = std::mem::take(&mut scratch.sorted);
+        all.clear();
+        all.extend(self.root.iter().copied());
+        let existing = all.len();
+        for p in points {
+            if let Some(p) = self.admit(p, &all)? {
+                all.push(p);
+            }
+        }
+        let mut ids = HashSet::with_capacity(all.len());
+        if let Some(p) = all.iter().chain(self.loose()).find(|p| !ids.insert(p.id())) {
+            return Err(NbodyError::DuplicateId(p.id()));
+        }
+        debug!(
+            "building bht from {} new and {} existing points",
+            all.len() - existing,
+            existing
+        );
+
+        // Coincident points sort next to each other, and are merged as `add_point` would.
+        let morton = |p: &Point| self.root.morton_code(p.position());
+        let mut keyed = std::mem::take(&mut scratch.keyed);
+        keyed.clear();
+        keyed.extend(all.drain(..).map(|p| (morton(&p), p)));
+        // Positions are all finite by now. -0 and 0 are the same place to `add_point`, so they
+        // have to sort together too, which `total_cmp` wouldn't do.
+        let order = |a: f64, b: f64| a.partial_cmp(&b).unwrap_or(std::cmp::Ordering::Equal);
+        keyed.sort_unstable_by(|(ka, a), (kb, b)| {
+            let (ax, ay, az) = a.position();
+            let (bx, by, bz) = b.position();
+            return ka
+                .cmp(kb)
+                .then(order(ax, bx))
+                .then(order(ay, by))
+                .then(order(az, bz));
+        });
+        all.extend(keyed.drain(..).map(|(_, p)| p));
+        let mut merges = vec![];
+        let mut failed = None;
+        all.dedup_by(|p, q| {
+            if failed.is_some() || p.position() != q.position() {
+                return false;
+            }
+            match self.coincide(*q, *p) {
+                Ok(merge) => {
+                    *q = merge.result;
+                    merges.push(merge);
+                    return true;
+                }
+                Err(e) => {
+                    failed = Some(e);
+                    return false;
+                }
+            }
+        });
+        if let Some(e) = failed {
+            return Err(e);
+        }
+        self.merges.extend(merges);
+
+        let mut root = BHNode::new(
+            self.root.region_size,
+            self.root.xloc,
+            self.root.yloc,
+            self.root.zloc,
+            self.root.summary.blank(),
+        );
+        root.planar = self.planar;
+        root.build(&mut all, 0, &self.refinement, &mut scratch.nodes);
+        root.summarize_all();
+        let old = std::mem::replace(&mut self.root, root);
+        scratch.nodes.recycle(old);
+        scratch.keyed = keyed;
+        scratch.sorted = all;
+        debug!("done building bht");
+        return Ok(());
+    }
+
+    // Adds a single point. Its id must not already be in the tree.
+    pub fn add_point(&mut self, p: Point) -> Result<(), NbodyError> {
+        trace!("adding point {}", p);
+        if self.ids.is_none() {
+            self.ids = Some(self.iter().map(|q| q.id()).collect());
+        }
+        if self.ids.as_ref().is_some_and(|ids| ids.contains(&p.id())) {
+            return Err(NbodyError::DuplicateId(p.id()));
+        }
+        let before = self.len();
+        if let Some(p) = self.admit(p, &[])? {
+            let mut found = vec![];
+            self.root.within(p.position(), 0., &mut found);
+            let merge = match found.into_iter().find(|q| q.position() == p.position()) {
+                Some(q) => Some(self.coincide(*q, p)?),
+                None => None,
+            };
+            let p = merge.as_ref().map_or(p, |m| m.result);
+            self.root.add_point(p, 0, &self.refinement)?;
+            self.merges.extend(merge);
+        }
+        // A coincident point merges with the one already there, whose id may not survive.
+        let added = self.len() > before;
+        match self.ids.as_mut() {
+            Some(ids) if added => {
+                ids.insert(p.id());
+            }
+            _ => self.ids = None,
+        }
+        return Ok(());
+    }
+
+    // Checks `p` before it goes in the tree, flattening it in planar mode and growing an open
+    // tree's root to take it in, along with any `pending` particles that are still to be added.
+    // Tracers are set aside here and come back as None.
+    fn admit(&mut self, p: Point, pending: &[Point]) -> Result<Option<Point>, NbodyError> {
+        let p = if self.planar { p.flattened() } else { p };
+        // Point::try_new rejects these, but a mass can also be set or read in directly, and one
+        // would poison the centre of mass of every node above it.
+        if !p.mass().is_finite() || p.mass() < 0. {
+            return Err(NbodyError::InvalidMass {
+                id: p.id(),
+                mass: p.mass(),
+            });
+        }
+        if p.is_tracer() {
+            self.tracers.push(p);
+            return Ok(None);
+        }
+        if !self.fits(p) {
+            // An open tree grows to take in anything its region's size can stay finite for; with
+            // walls or a periodic box the region is the domain, so the boundary should have been
+            // applied first.
+            let (x, y, z) = p.position();
+            let finite = x.is_finite() && y.is_finite() && z.is_finite();
+            if !finite || self.boundary != Boundary::Open || !self.grow_to(p, pending)? {
+                return Err(NbodyError::OutOfBounds {
+                    id: p.id(),
+                    x: x,
+                    y: y,
+                    z: z,
+                });
+            }
+        }
+        return Ok(Some(p));
+    }
+
+    // Whether `p` can go in the root region as it is. An open tree also grows for points on the
+    // root's far faces: once the root has grown around it, such a point lies on a midplane of the
+    // new root, where `child_index` would send a later coincident point to the other side.
+    fn fits(&self, p: Point) -> bool {
+        if self.boundary != Boundary::Open {
+            return self.root.contains(p.position());
+        }
+        let (x, y, z) = p.position();
+        let size = self.root.region_size;
+        let inside = |v: f64, lo: f64| lo <= v && v < lo + size;
+        return inside(x, self.root.xloc)
+            && inside(y, self.root.yloc)
+            && (self.planar || inside(z, self.root.zloc));
+    }
+
+    // Doubles the root region, keeping the current root as one of the new root's children, until
+    // it contains `p`. Nothing is reinserted, so this costs one node per doubling. False, with the
+    // root left as it was, if the region would have to grow past the largest finite size. The
+    // region is kept around the `pending` particles too.
+    fn grow_to(&mut self, p: Point, pending: &[Point]) -> Result<bool, NbodyError> {
+        // Each doubling at most doubles the span of the root and `p`, which is at most twice the
+        // furthest coordinate of either, so this leaves room for all of them.
+        let (x, y, z) = p.position();
+        let r = &self.root;
+        let far = [x, y, z, r.xloc, r.yloc, r.zloc]
+            .iter()
+            .fold(r.region_size, |far, v| far.max(v.abs()));
+        if !(8. * far).is_finite() {
+            return Ok(false);
+        }
+        let mut doublings = 0;
+        while !self.fits(p) {
+            if !(2. * self.root.region_size).is_finite() {
+                return Ok(false);
+            }
+            if !self.root.grows_exactly_toward(p.position()) {
+                // Particles already in the root and later ones at the same place could be sent
+                // to different children of an inexact parent, so the nodes are built again in a
+                // region that grows exactly.
+                if !self.reroot(p, pending)? {
+                    return Ok(false);
+                }
+                continue;
+            }
+            let blank = BHNode::new(0., 0., 0., 0., self.root.summary.blank());
+            let root = std::mem::replace(&mut self.root, blank);
+            self.root = root.grown_toward(p.position());
+            self.graph_size = self.root.region_size;
+            doublings += 1;
+        }
+        debug!(
+            "grew the root region {} times to {} for point {}",
+            doublings,
+            self.graph_size,
+            p.id()
+        );
+        return Ok(true);
+    }
+
+    // Puts the particles in a new root around them, `p` and `pending`: a cube centred on the
+    // origin whose size is a power of two, so that its corners stay exact through the next fifty
+    // or so doublings. False if no finite cube would do.
+    fn reroot(&mut self, p: Point, pending: &[Point]) -> Result<bool, NbodyError> {
+        let far = self
+            .root
+            .iter()
+            .chain(pending)
+            .chain([&p])
+            .flat_map(|q| {
+                let (x, y, z) = q.position();
+                [x, y, if self.planar { 0. } else { z }]
+            })
+            .fold(0., |far: f64, v| far.max(v.abs()));
+        let mut size: f64 = 1.;
+        while size <= 2. * far {
+            size *= 2.;
+            if !size.is_finite() {
+                return Ok(false);
+            }
+        }
+        debug!("rebuilding the tree in a region of size {}", size);
+        let points: Vec<Point> = self.root.iter().copied().collect();
+        let half = size / 2.;
+        let zloc = if self.planar { 0. } else { -half };
+        let mut root = BHNode::new(size, -half, -half, zloc, self.root.summary.blank());
+        root.planar = self.planar;
+        self.root = root;
+        self.graph_size = size;
+        for q in points {
+            self.root.add_point(q, 0, &self.refinement)?;
+        }
+        return Ok(true);
+    }
+
+    // Walks the whole tree checking that every node's count, mass and point agree with its
+    // children and region. Meant for tests and debugging; it visits every node.
+    pub fn check_invariants(&self) -> Result<(), NbodyError> {
+        return self.root.check(0, &self.refinement);
+    }
+
+    // Walks the tree and summarizes its shape.
+    pub fn stats(&self) -> TreeStats {
+        let mut stats = TreeStats {
+            theta: self.theta,
+            memory_bytes: std::mem::size_of::<BHTree>()
+                + (self.tracers.capacity() + self.outside.capacity())
+                    * std::mem::size_of::<Point>(),
+            ..TreeStats::default()
+        };
+        self.root.collect_stats(0, &mut stats);
+        return stats;
+    }
+
+    // Writes the nodes as a Graphviz digraph, labelled with each node's region, count and mass.
+    // Empty children are left out.
+    pub fn write_dot<W: std::io::Write>(&self, mut w: W) -> Result<(), NbodyError> {
+        writeln!(w, "digraph bhtree {{")?;
+        writeln!(w, "  node [shape=box, fontname=monospace];")?;
+        let mut next = 0;
+        self.root.write_dot(&mut w, &mut next)?;
+        writeln!(w, "}}")?;
+        return Ok(());
+    }
+
+    // Writes the nodes, with their regions, centres of mass and particles, as nested JSON.
+    pub fn write_nodes_json<W: std::io::Write>(&self, w: W) -> Result<(), NbodyError> {
+        serde_json::to_writer(w, &self.root).map_err(std::io::Error::from)?;
+        return Ok(());
+    }
+
+    // Total mass, position and velocity of the centre of mass of every particle in the tree.
+    pub fn center_of_mass(&self) -> Point {
+        return self.root.center_of_mass();
+    }
+
+    // Total angular momentum about the origin.
+    pub fn angular_momentum(&self) -> Vec3d {
+        let mut l = Vec3d::new_zero();
+        for p in self.iter() {
+            let (x, y, z) = p.position();
+            l += Vec3d::new(x, y, z).cross(p.momentum());
+        }
+        return l;
+    }
+
+    // The same particles moved by `offset` and with `boost` added to their velocities.
+    pub fn shifted(&self, offset: Vec3d, boost: Vec3d) -> Result<BHTree, NbodyError> {
+        let (dx, dy, dz) = offset.position();
+        let points = self
+            .iter()
+            .map(|p| {
+                let (x, y, z) = p.position();
+                p.with_position(x + dx, y + dy, z + dz)
+                    .with_velocity(p.velocity() + boost)
+            })
+            .collect();
+        return self.rebuild(points);
+    }
+
+    // Number of particles in the tree, after any merges.
+    pub fn len(&self) -> usize {
+        return self.root.count as usize + self.tracers.len() + self.outside.len();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.len() == 0;
+    }
+
+    pub fn next(&self, dt: f64) -> Result<BHTree, NbodyError> {
+        let (bht, _) = self.step(dt)?;
+        return Ok(bht);
+    }
+
+    // Same as `next`, but also reports how long each phase took and how many particles merged.
+    pub fn step(&self, dt: f64) -> Result<(BHTree, StepStats), NbodyError> {
+        return self.step_with(dt, |_| Vec3d::new_zero());
+    }
+
+    // Same as `step`, with `external(p)` added to the tree force on every point.
+    pub fn step_with<F>(&self, dt: f64, external: F) -> Result<(BHTree, StepStats), NbodyError>
+    where
+        F: Fn(&Point) -> Vec3d + Sync,
+    {
+        return self.step_in(dt, &mut StepScratch::new(), external);
+    }
+
+    // Same as `step_with`, working in the buffers kept in `scratch` rather than new ones.
+    pub fn step_in<F>(
+        &self,
+        dt: f64,
+        scratch: &mut StepScratch,
+        external: F,
+    ) -> Result<(BHTree, StepStats), NbodyError>
+    where
+        F: Fn(&Point) -> Vec3d + Sync,
+    {
+        debug!("creating next bht...");
+        let mut stats = StepStats::default();
+
+        debug!("creating new point set");
+        let start = Instant::now();
+        // Tracers are integrated as unit-mass particles, which gives the same trajectory as any
+        // test mass, and have their mass set back to zero afterwards.
+        let proxy = |p: &Point| if p.is_tracer() { p.with_mass(1.) } else { *p };
+        let mut points = std::mem::take(&mut scratch.points);
+        points.clear();
+        points.extend(self.iter().copied());
+        // Each force is summed by one thread walking the tree in a fixed order, and the results
+        // are collected in the order of `points`, so a step gives the same bits however many
+        // threads run it. Parallel work here should stay a map, not a reduction.
+        // Threads are handed runs of particles of about equal cost, going by how long their walks
+        // were last step, so that a few walks through dense regions don't hold up the rest.
+        let mut costs = std::mem::take(&mut scratch.costs);
+        self.costs.estimate_into(&points, &mut costs);
+        let walk = |(force, n): (Vec3d, u32), p: &Point| (force + external(&proxy(p)), n);
+        #[cfg(feature = "numa")]
+        let walks: Vec<(Vec3d, u32)> = match &self.numa {
+            Some(numa) => numa.map(self, &points, &costs, |top, p| {
+                walk(top.force_and_cost(proxy(p)), p)
+            }),
+            None => map_balanced(&points, &costs, |p| walk(self.force_and_cost(proxy(p)), p)),
+        };
+        #[cfg(not(feature = "numa"))]
+        let walks: Vec<(Vec3d, u32)> =
+            map_balanced(&points, &costs, |p| walk(self.force_and_cost(proxy(p)), p));
+        let mut forces = std::mem::take(&mut scratch.forces);
+        let mut interactions = std::mem::take(&mut scratch.interactions);
+        forces.clear();
+        interactions.clear();
+        for (force, n) in walks {
+            forces.push(force);
+            interactions.push(n);
+        }
+        stats.interactions = interactions.iter().map(|n| *n as u64).sum();
+        stats.force_time = start.elapsed();
+        if self.paranoid() {
+            for (p, force) in points.iter().zip(forces.iter()) {
+                let (quantity, culprit) = match non_finite(p) {
+                    Some(quantity) => (quantity, Culprit::Input),
+                    None if !force.is_finite() => {
+                        ("force", self.blame(proxy(p), external(&proxy(p))))
+                    }
+                    None => continue,
+                };
+                return Err(NbodyError::NonFinite {
+                    id: p.id(),
+                    quantity: quantity,
+                    culprit: culprit,
+                });
+            }
+        }
+
+        let start = Instant::now();
+        let mut moved = std::mem::take(&mut scratch.moved);
+        moved.clear();
+        moved.par_extend(points.par_iter().zip(forces.par_iter()).map(|(p, force)| {
+            let moved = proxy(p).apply_force(dt, *force);
+            if p.is_tracer() {
+                moved.with_mass(0.)
+            } else {
+                moved
+            }
+        }));
+        stats.regularized_pairs = self.regularize(&points, &forces, &mut moved, dt);
+        stats.integrate_time = start.elapsed();
+        if self.paranoid() {
+            if let Some((p, quantity)) = moved.iter().find_map(|p| Some((p, non_finite(p)?))) {
+                return Err(NbodyError::NonFinite {
+                    id: p.id(),
+                    quantity: quantity,
+                    culprit: Culprit::Integrator,
+                });
+            }
+        }
+
+        let start = Instant::now();
+        let bht = match self.refit(&moved) {
+            Some(bht) => {
+                stats.tree_reused = true;
+                bht
+            }
+            None => self.rebuild_in(&mut moved, scratch)?,
+        };
+        let mut bht = bht.resolve_collisions()?;
+        bht.costs = Arc::new(WalkCosts::record(&points, &interactions));
+        stats.build_time = start.elapsed();
+        stats.merges = bht.merges().len();
+        stats.removed = self.len().saturating_sub(bht.len() + stats.merges);
+        stats.com_drift = self.center_of_mass().distance_to(bht.center_of_mass());
+        stats.angular_momentum = bht.angular_momentum().magnitude();
+
+        scratch.points = points;
+        scratch.moved = moved;
+        scratch.costs = costs;
+        scratch.forces = forces;
+        scratch.interactions = interactions;
+        return Ok((bht, stats));
+    }
+
+    // Builds a tree over `points` with the same settings as this one, applying the boundary
+    // conditions and then the escaper policy. The root region is fitted to the points unless the
+    // boundaries are walls.
+    pub fn rebuild(&self, points: Vec<Point>) -> Result<BHTree, NbodyError> {
+        let mut points = points;
+        return self.rebuild_in(&mut points, &mut StepScratch::new());
+    }
+
+    // Same as `rebuild`, taking the particles out of `points`, which is left empty, and building
+    // the nodes from the memory kept in `scratch` where it can.
+    fn rebuild_in(
+        &self,
+        points: &mut Vec<Point>,
+        scratch: &mut StepScratch,
+    ) -> Result<BHTree, NbodyError> {
+        let before = points.len();
+        points.retain_mut(|p| match self.boundary.apply(*p) {
+            Some(q) => {
+                *p = q;
+                true
+            }
+            None => false,
+        });
+        if points.len() < before {
+            debug!("{} particles absorbed by the walls", before - points.len());
+        }
+        let (mut inside, outside) = self.escapers.apply(std::mem::take(points));
+        if !outside.is_empty() {
+            debug!("{} particles outside the tree", outside.len());
+        }
+        let template = match self.boundary {
+            Boundary::Open => BHTree::fitted(self.theta, &inside),
+            boundary => BHTree::new_bounded(self.theta, boundary),
+        };
+        let mut bht = template
+            .with_species(self.species.clone())
+            .with_collisions(self.collisions)
+            .with_merge_policy(self.merge_policy.clone())
+            .with_planar(self.planar)
+            .with_compensated_summation(self.compensated)
+            .with_refinement(self.refinement);
+        bht.add_points_in(inside.drain(..), scratch)?;
+        *points = inside;
+        bht.outside = outside;
+        bht.costs = self.costs.clone();
+        bht.interaction_lists = self.interaction_lists;
+        bht.paranoid = self.paranoid;
+        bht.ewald = self.ewald;
+        #[cfg(feature = "numa")]
+        {
+            bht.numa = self.numa.clone();
+        }
+        return Ok(bht
+            .with_force_law(self.force_law)
+            .with_encounter_radius(self.encounter_radius)
+            .with_escapers(self.escapers)
+            .with_reuse(self.reuse)
+            .with_opening(self.opening)
+            .with_theta_schedule(self.theta_schedule));
+    }
+
+    // The tree for `points`, which must be this tree's `points()` after a step and in the same
+    // order, built on this tree's nodes. None if the tree should be rebuilt instead: reuse is off
+    // or used up, the boundaries need applying, or a particle has drifted too far from its leaf.
+    fn refit(&self, points: &[Point]) -> Option<BHTree> {
+        let reuse = self.reuse?;
+        if self.reused >= reuse.max_steps
+            || self.boundary != Boundary::Open
+            || points.len() != self.len()
+        {
+            return None;
+        }
+
+        let nodes = self.root.count as usize;
+        let mut moved = points[..nodes]
+            .iter()
+            .map(|p| if self.planar { p.flattened() } else { *p });
+        let mut root = self.root.refit(&mut moved, reuse.drift)?;
+        root.summarize_all();
+        trace!("reusing the tree for another step");
+        return Some(BHTree {
+            root: root,
+            theta: self.theta,
+            graph_size: self.graph_size,
+            boundary: self.boundary,
+            ewald: self.ewald,
+            force_law: self.force_law,
+            species: self.species.clone(),
+            tracers: points[nodes..nodes + self.tracers.len()].to_vec(),
+            encounter_radius: self.encounter_radius,
+            collisions: self.collisions,
+            merge_policy: self.merge_policy.clone(),
+            escapers: self.escapers,
+            outside: points[nodes + self.tracers.len()..].to_vec(),
+            planar: self.planar,
+            merges: vec![],
+            ids: None,
+            refinement: self.refinement,
+            reuse: self.reuse,
+            opening: self.opening,
+            theta_schedule: self.theta_schedule,
+            compensated: self.compensated,
+            reused: self.reused + 1,
+            costs: self.costs.clone(),
+            interaction_lists: self.interaction_lists,
+            paranoid: self.paranoid,
+            #[cfg(feature = "numa")]
+            numa: self.numa.clone(),
+        });
+    }
+
+    // Merges `p` into `q`, a particle already at exactly the same place, as `resolve_collisions`
+    // would merge them. Fails if the species table, the merge policy or the collision model
+    // wouldn't, since nothing else can separate them. The merged particle stays where they were,
+    // so that it can take `q`'s place in the tree.
+    fn coincide(&self, q: Point, p: Point) -> Result<Merge, NbodyError> {
+        if !matches!(self.contact(q, p), Contact::Merge) {
+            return Err(NbodyError::Coincident(q.id(), p.id()));
+        }
+        warn!("merging coincident points {} and {}", q.id(), p.id());
+        let (x, y, z) = q.position();
+        let result = self.merge_policy.merge(&q, &p).with_position(x, y, z);
+        let absorbed = if result.id() == q.id() {
+            p.id()
+        } else {
+            q.id()
+        };
+        return Ok(Merge {
+            survivor: result.id(),
+            absorbed: absorbed,
+            result: result,
+        });
+    }
+
+    // Merges or bounces touching particles, according to the species table and collision model,
+    // and returns the resulting tree. Pairs are resolved closest first with ties broken by id, and
+    // a particle takes part in at most one collision per pass, so the outcome doesn't depend on the
+    // order the points were inserted in. Passes repeat until nothing more merges.
+    // The merges are added to the new tree's `merges`, after those made building this one.
+    pub fn resolve_collisions(self) -> Result<BHTree, NbodyError> {
+        let mut bht = self;
+        let mut all = std::mem::take(&mut bht.merges);
+        while let Some((points, merges)) = bht.collision_pass()? {
+            debug!("collision pass merged {} pairs", merges.len());
+            let done = merges.is_empty();
+            all.extend(merges);
+            bht = bht.rebuild(points)?;
+            all.append(&mut bht.merges);
+            if done {
+                break;
+            }
+        }
+        bht.merges = all;
+        return Ok(bht);
+    }
+
+    // Merges made building this tree, coincident points included, and by the `resolve_collisions`
+    // call that produced it, in the order they happened.
+    pub fn merges(&self) -> &[Merge] {
+        return &self.merges;
+    }
+
+    // One round of `resolve_collisions`. Returns the updated points and the merges made, or None
+    // if nothing is in contact.
+    fn collision_pass(&self) -> Result<Option<Resolved>, NbodyError> {
+        let points = self.points();
+        let reach = points
+            .iter()
+            .map(|p| self.collisions.radius(p))
+            .fold(0., f64::max);
+        let merge_reach = points
+            .iter()
+            .map(|p| self.merge_policy.reach(p))
+            .fold(0., f64::max);
+        let index: HashMap<_, usize> = points
+            .iter()
+            .enumerate()
+            .map(|(i, p)| (key(p), i))
+            .collect();
+
+        // Every pair in contact is within the summed merge reaches or the summed physical radii of
+        // each other, so searching that far from each point finds them all.
+        let mut pairs: Vec<(f64, usize, usize)> = points
+            .par_iter()
+            .enumerate()
+            .flat_map(|(i, p)| {
+                let radius = (self.merge_policy.reach(p) + merge_reach)
+                    .max(self.collisions.radius(p) + reach);
+                let mut found = vec![];
+                if !p.is_tracer() && radius > 0. {
+                    self.root.within(p.position(), radius, &mut found);
+                }
+                found
+                    .into_iter()
+                    .filter(|q| *q != p)
+                    .filter_map(|q| index.get(&key(q)))
+                    .filter(|&&j| !matches!(self.contact(points[i], points[j]), Contact::Miss))
+                    .map(|&j| (points[i].distance_to(points[j]), i.min(j), i.max(j)))
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        if pairs.is_empty() {
+            return Ok(None);
+        }
+        let ids = |i: usize| (points[i].id(), i);
+        pairs.sort_by(|a, b| {
+            a.0.total_cmp(&b.0)
+                .then(ids(a.1).cmp(&ids(b.1)))
+                .then(ids(a.2).cmp(&ids(b.2)))
+        });
+        pairs.dedup();
+
+        let mut resolved: Vec<Option<Point>> = points.iter().map(|p| Some(*p)).collect();
+        let mut used = vec![false; points.len()];
+        let mut merges = vec![];
+        for (_, i, j) in pairs {
+            if used[i] || used[j] {
+                continue;
+            }
+            used[i] = true;
+            used[j] = true;
+            match self.contact(points[i], points[j]) {
+                Contact::Merge => {
+                    let result = self.merge_policy.merge(&points[i], &points[j]);
+                    let absorbed = if result.id() == points[i].id() {
+                        points[j].id()
+                    } else {
+                        points[i].id()
+                    };
+                    resolved[i] = Some(result);
+                    resolved[j] = None;
+                    merges.push(Merge {
+                        survivor: result.id(),
+                        absorbed: absorbed,
+                        result: result,
+                    });
+                }
+                Contact::Bounce(a, b) => {
+                    resolved[i] = Some(a);
+                    resolved[j] = Some(b);
+                }
+                Contact::Miss => {}
+            }
+        }
+        return Ok(Some((resolved.into_iter().flatten().collect(), merges)));
+    }
+
+    // What happens between two particles after a step: the merge policy goes first, then physical
+    // contact according to the collision model.
+    fn contact(&self, p1: Point, p2: Point) -> Contact {
+        // External particles belong to another code, which wouldn't know they'd changed.
+        if p1.external() || p2.external() {
+            return Contact::Miss;
+        }
+        let merges = self.species.merges(p1.species()) && self.species.merges(p2.species());
+        if merges && self.merge_policy.should_merge(&p1, &p2) {
+            return Contact::Merge;
+        }
+        if !self.collisions.touching(&p1, &p2) {
+            return Contact::Miss;
+        }
+        return match self.collisions.outcome {
+            CollisionOutcome::Merge if merges => Contact::Merge,
+            CollisionOutcome::Bounce { restitution } => {
+                let (a, b) = bounce(p1, p2, restitution);
+                Contact::Bounce(a, b)
+            }
+            _ => Contact::Miss,
+        };
+    }
+
+    // Finds mutually nearest pairs of free, massive points closer than the encounter radius and
+    // overwrites their integrated states in `moved`. The pair's COM follows the summed external
+    // force, where the mutual attraction cancels, and the relative orbit is advanced exactly.
+    // Returns the number of pairs handled.
+    fn regularize(
+        &self,
+        points: &[Point],
+        forces: &[Vec3d],
+        moved: &mut [Point],
+        dt: f64,
+    ) -> usize {
+        let radius = match self.encounter_radius {
+            Some(r) => r,
+            None => return 0,
+        };
+        let law = self.force_law;
+        if law.exponent != 2. || law.yukawa.is_some() {
+            return 0;
+        }
+
+        let eligible = |p: &Point| !p.is_tracer() && !p.pinned();
+        let nearest: Vec<Option<u64>> = points
+            .par_iter()
+            .map(|p| {
+                if !eligible(p) {
+                    return None;
+                }
+                let mut best = None;
+                let other = |q: &Point| q.id() != p.id();
+                self.root.nearest(p.position(), radius, &other, &mut best);
+                best.filter(|(_, q)| eligible(q)).map(|(_, q)| q.id())
+            })
+            .collect();
+        let index: HashMap<u64, usize> = points
+            .iter()
+            .enumerate()
+            .map(|(i, p)| (p.id(), i))
+            .collect();
+
+        let mut pairs = 0;
+        for (i, partner) in nearest.iter().enumerate() {
+            let j = match partner.and_then(|id| index.get(&id)) {
+                Some(&j) => j,
+                None => continue,
+            };
+            // Each pair is handled once, from its lower index, and only if the match is mutual.
+            if j <= i || nearest[j] != Some(points[i].id()) {
+                continue;
+            }
+
+            let (a, b) = (points[i], points[j]);
+            let total = a.mass() + b.mass();
+            let (ax, ay, az) = a.position();
+            let (bx, by, bz) = b.position();
+            let com = Point::new(
+                total,
+                (a.mass() * ax + b.mass() * bx) / total,
+                (a.mass() * ay + b.mass() * by) / total,
+                (a.mass() * az + b.mass() * bz) / total,
+                (a.mass() * a.velocity() + b.mass() * b.velocity()) / total,
+            )
+            .apply_force(dt, forces[i] + forces[j]);
+
+            let rel_pos = Vec3d::new(bx - ax, by - ay, bz - az);
+            let rel_vel = b.velocity() - a.velocity();
+            let (r, v) = kepler::advance(rel_pos, rel_vel, law.g * total, dt);
+
+            let (cx, cy, cz) = com.position();
+            let place = |p: Point, share: f64| {
+                let (dx, dy, dz) = (share * r).position();
+                Point::new(
+                    p.mass(),
+                    cx + dx,
+                    cy + dy,
+                    cz + dz,
+                    com.velocity() + share * v,
+                )
+                .with_id(p.id())
+                .with_species(p.species())
+            };
+            moved[i] = place(a, -b.mass() / total);
+            moved[j] = place(b, a.mass() / total);
+            pairs += 1;
+        }
+        return pairs;
+    }
+
+    // Every particle in the tree, followed by any tracers and then any particles outside it.
+    pub fn points(&self) -> Vec<Point> {
+        let mut points = Vec::with_capacity(self.len());
+        points.extend(self.iter().copied());
+        return points;
+    }
+
+    // The particles in the same order as `points`, borrowed from the tree rather than copied.
+    pub fn iter(&self) -> impl Iterator<Item = &Point> {
+        return self.root.iter().chain(self.loose());
+    }
+
+    // Particles that aren't in the nodes and have to be searched one by one.
+    fn loose(&self) -> impl Iterator<Item = &Point> {
+        return self.tracers.iter().chain(self.outside.iter());
+    }
+
+    pub fn theta(&self) -> f64 {
+        return self.theta;
+    }
+
+    // Rebuilds the tree over the same particles with a different opening angle.
+    pub fn set_theta(&mut self, theta: f64) -> Result<(), NbodyError> {
+        self.theta = theta;
+        *self = self.rebuild(self.points())?;
+        return Ok(());
+    }
+
+    // Every particle within `radius` of `p`, other than `p` itself, closest first. Distances are
+    // straight-line ones and don't wrap around periodic boundaries.
+    pub fn neighbors_within(&self, p: Point, radius: f64) -> Vec<Point> {
+        let mut found = vec![];
+        self.root.within(p.position(), radius, &mut found);
+        found.extend(self.loose().filter(|q| p.distance_to(**q) <= radius));
+        let mut found: Vec<Point> = found.into_iter().filter(|&&q| q != p).copied().collect();
+        found.sort_by(|a, b| p.distance_to(*a).total_cmp(&p.distance_to(*b)));
+        return found;
+    }
+
+    // SPH estimate of the mass density at `p`, smoothed over its `k` nearest neighbours: the
+    // smoothing length is half the distance to the furthest of them.
+    pub fn density_at(&self, p: Point, k: usize) -> f64 {
+        let neighbors = self.k_nearest(p, k);
+        let h = match neighbors.last() {
+            Some(q) => p.distance_to(*q) / 2.,
+            None => return 0.,
+        };
+        let own = p.mass() * density::kernel(0., h);
+        return neighbors.iter().fold(own, |rho, q| {
+            rho + q.mass() * density::kernel(p.distance_to(*q), h)
+        });
+    }
+
+    // Density at every particle, in the same order as `points`.
+    pub fn densities(&self, k: usize) -> Vec<f64> {
+        return self
+            .points()
+            .par_iter()
+            .map(|p| self.density_at(*p, k))
+            .collect();
+    }
+
+    // The `k` particles closest to `p`, other than `p` itself, closest first.
+    pub fn k_nearest(&self, p: Point, k: usize) -> Vec<Point> {
+        let mut best = Vec::with_capacity(k + 1);
+        if k > 0 {
+            let other = |q: &Point| *q != p;
+            self.root.k_nearest(p.position(), k, &other, &mut best);
+            for q in self.loose().filter(|q| other(q)) {
+                insert_bounded(&mut best, k, (p.distance_to(*q), q));
+            }
+        }
+        return best.into_iter().map(|(_, q)| *q).collect();
+    }
+
+    // Net force exerted by the tree on a single point. `p` itself, and any other particle with its
+    // id, is left out.
+    pub fn force_on(&self, p: Point) -> Vec3d {
+        return self.force_and_cost(p).0;
+    }
+
+    // Same as `force_on`, along with the number of particles and nodes summed over.
+    pub fn force_and_cost(&self, p: Point) -> (Vec3d, u32) {
+        let traversal = self.traversal(p);
+        if self.interaction_lists {
+            let (force, n) =
+                self.listed_force(&traversal, |list| self.root.gather(&traversal, list));
+            return (force + self.images_force(&traversal), n);
+        }
+        let force = self.total_force(p, &traversal, self.root.calculate_force(&traversal));
+        return (
+            force + self.images_force(&traversal),
+            traversal.interactions.get(),
+        );
+    }
+
+    // The pull of the periodic images beyond the nearest on the target of `t`, if the tree uses
+    // Ewald summation.
+    fn images_force(&self, t: &Traversal) -> Vec3d {
+        return match t.periodic {
+            Some(box_size) if self.ewald && t.law.short_range.is_none() => {
+                self.root.ewald_force(t, box_size)
+            }
+            _ => Vec3d::new_zero(),
+        };
+    }
+
+    // What made the force on `p` non-finite, given `external`, the part of it from outside the
+    // tree. Goes over the terms of the sum one at a time, so it's only worth calling once the total
+    // has gone wrong.
+    fn blame(&self, p: Point, external: Vec3d) -> Culprit {
+        let t = self.traversal(p);
+        if let Some(culprit) = self.root.blame(&t) {
+            return culprit;
+        }
+        for q in self.outside.iter().filter(|q| q.id() != p.id()) {
+            if !self
+                .force_law
+                .softened_force(p, *q, t.softening_from(q))
+                .is_finite()
+            {
+                return Culprit::Particle { id: q.id() };
+            }
+        }
+        if !self.images_force(&t).is_finite() {
+            return Culprit::Images;
+        }
+        if !external.is_finite() {
+            return Culprit::External;
+        }
+        return Culprit::Sum;
+    }
+
+    // Adds the force from the particles outside the nodes to `nodes`, the force from the nodes.
+    fn total_force(&self, p: Point, traversal: &Traversal, nodes: Vec3d) -> Vec3d {
+        let mut force = ForceSum::new(traversal.compensated);
+        force.add(nodes);
+        for q in self.outside.iter().filter(|q| q.id() != p.id()) {
+            force.add(
+                self.force_law
+                    .softened_force(p, *q, traversal.softening_from(q)),
+            );
+            traversal.count(1);
+        }
+        return force.value();
+    }
+
+    // What `force_on(p)` sums over: the nodes and particles its walk reaches, in the same order,
+    // then the particles outside the nodes.
+    pub fn interaction_list(&self, p: Point) -> InteractionList {
+        let traversal = self.traversal(p);
+        let mut list = InteractionList::new();
+        self.root.gather(&traversal, &mut list);
+        self.gather_outside(&traversal, &mut list);
+        return list;
+    }
+
+    fn gather_outside(&self, t: &Traversal, list: &mut InteractionList) {
+        for q in self.outside.iter().filter(|q| q.id() != t.target.id()) {
+            list.push(*q, t.softening_from(q));
+        }
+    }
+
+    // The force on the target of `t` from the list `gather` fills and the particles outside the
+    // nodes, and how many sources that was. The list is the calling thread's own.
+    fn listed_force<G>(&self, t: &Traversal, gather: G) -> (Vec3d, u32)
+    where
+        G: FnOnce(&mut InteractionList),
+    {
+        return LIST.with(|list| {
+            let mut list = list.borrow_mut();
+            list.clear();
+            gather(&mut list);
+            self.gather_outside(t, &mut list);
+            let force = list.force_on(t.target, &self.force_law, t.compensated);
+            (force, list.len() as u32)
+        });
+    }
+
+    // A copy of the top `depth` levels of the nodes, for walks to start from. See `TopLevels`.
+    pub fn top_levels(&self, depth: usize) -> TopLevels<'_> {
+        return TopLevels {
+            tree: self,
+            root: TopNode::new(&self.root, depth),
+        };
+    }
+
+    // Gravitational potential at `p` per unit mass, from every other particle in the tree. Uses the
+    // same opening criterion and exclusions as `force_on`.
+    pub fn potential_at(&self, p: Point) -> f64 {
+        let traversal = self.traversal(p);
+        let unit = p.with_mass(1.);
+        return self
+            .outside
+            .iter()
+            .filter(|q| q.id() != p.id())
+            .map(|q| {
+                self.force_law
+                    .softened_potential(unit, *q, traversal.softening_from(q))
+            })
+            .sum::<f64>()
+            + self.root.calculate_potential(&traversal);
+    }
+
+    fn traversal(&self, p: Point) -> Traversal<'_> {
+        // Particles in a reused tree can sit a little outside the leaf holding them.
+        let reach = match self.reuse {
+            Some(reuse) if self.reused > 0 => reuse.drift,
+            _ => 0.,
+        };
+        return Traversal {
+            target: p,
+            periodic: self.periodic(),
+            law: &self.force_law,
+            opening: &self.opening,
+            schedule: &self.theta_schedule,
+            root_size: self.root.region_size,
+            species: &self.species,
+            softening: self.species.softening(p.species()),
+            reach: reach,
+            compensated: self.compensated,
+            interactions: Cell::new(0),
+        };
+    }
+
+    // Potential at every particle, in the same order as `points`.
+    pub fn potentials(&self) -> Vec<f64> {
+        return self
+            .points()
+            .par_iter()
+            .map(|p| self.potential_at(*p))
+            .collect();
+    }
+
+    // Synchronously writes every point in the tree as CSV.
+    pub fn write_csv<W: std::io::Write>(&self, w: W) -> Result<(), NbodyError> {
+        let mut wtr = csv::Writer::from_writer(w);
+        wtr.write_record(CSV_HEADER)?;
+        for p in self.iter() {
+            wtr.write_record(csv_record(p))?;
+        }
+        wtr.flush()?;
+        Ok(())
+    }
+
+    pub fn write_to_csv(&self, filename: String) -> Result<(), NbodyError> {
+        return self.write_to_csv_filtered(filename, |_| true);
+    }
+
+    // Same as `write_to_csv`, but only writes the points for which `keep` returns true.
+    pub fn write_to_csv_filtered<F: Fn(&Point) -> bool>(
+        &self,
+        filename: String,
+        keep: F,
+    ) -> Result<(), NbodyError> {
+        let handle = self.write_to_csv_with(filename.clone(), keep, vec![])?;
+        return handle
+            .join()
+            .map_err(|_| NbodyError::WriterPanicked(filename))?;
+    }
+
+    // Same as `write_to_csv_filtered`, with extra per-particle columns appended to each row. Each
+    // column is a name and one value per point, in the same order as `points`. The rows are
+    // written on a background thread; join the returned handle to wait for the file and get any
+    // error from writing it.
+    pub fn write_to_csv_with<F: Fn(&Point) -> bool>(
+        &self,
+        filename: String,
+        keep: F,
+        columns: Vec<(&str, Vec<f64>)>,
+    ) -> Result<JoinHandle<Result<(), NbodyError>>, NbodyError> {
+        let file = std::fs::File::create(&filename)?;
+        let scratch = StepScratch::new();
+        return self.write_csv_with(file, filename, keep, columns, |_| Ok(()), &scratch);
+    }
+
+    // Same as `write_to_csv_with`, into any writer. `finish` gets the writer back once every row
+    // is in it, to end a compressed stream for instance. `name` is only used for logging. The
+    // rows are formatted into a buffer from `scratch`, which gets it back once they're written.
+    pub fn write_csv_with<W, F, D>(
+        &self,
+        w: W,
+        name: String,
+        keep: F,
+        columns: Vec<(&str, Vec<f64>)>,
+        finish: D,
+        scratch: &StepScratch,
+    ) -> Result<JoinHandle<Result<(), NbodyError>>, NbodyError>
+    where
+        W: std::io::Write + Send + 'static,
+        F: Fn(&Point) -> bool,
+        D: FnOnce(W) -> std::io::Result<()> + Send + 'static,
+    {
+        debug!("writing bht to file: {}", name);
+        let mut rows = scratch.row_buffer();
+        {
+            let mut wtr = csv::Writer::from_writer(&mut rows);
+            let mut header: Vec<&str> = CSV_HEADER.to_vec();
+            header.extend(columns.iter().map(|(name, _)| *name));
+            wtr.write_record(header)?;
+
+            let mut field = String::new();
+            for (i, p) in self.iter().enumerate().filter(|(_, p)| keep(p)) {
+                let (x, y, z) = p.position();
+                let (xv, yv, zv) = p.velocity().position();
+                for v in [p.mass(), x, y, z, xv, yv, zv] {
+                    write_field(&mut wtr, &mut field, v)?;
+                }
+                write_field(&mut wtr, &mut field, p.pinned())?;
+                write_field(&mut wtr, &mut field, p.species())?;
+                write_field(&mut wtr, &mut field, p.radius())?;
+                for (_, values) in columns.iter() {
+                    write_field(&mut wtr, &mut field, values[i])?;
+                }
+                wtr.write_record(None::<&[u8]>)?;
+            }
+            wtr.flush()?;
+        }
+
+        let spare = scratch.rows.clone();
+        let handle = thread::spawn(move || {
+            debug!("flushing {}", name.clone());
+            let mut w = w;
+            w.write_all(&rows)?;
+            w.flush()?;
+            finish(w)?;
+            rows.clear();
+            spare.lock().unwrap().push(rows);
+            debug!("done flushing {}", name);
+            return Ok(());
+        });
+
+        Ok(handle)
+    }
+}
+
+// The points left after a collision pass and the merges that produced them.
+type Resolved = (Vec<Point>, Vec<Merge>);
+
+pub(crate) const CSV_HEADER: [&str; 10] = [
+    "mass", "x_pos", "y_pos", "z_pos", "x_vel", "y_vel", "z_vel", "pinned", "species", "radius",
+];
+
+pub(crate) fn csv_record(p: &Point) -> Vec<String> {
+    let (x, y, z) = p.position();
+    let (xv, yv, zv) = p.velocity().position();
+    let mut record: Vec<String> = [p.mass(), x, y, z, xv, yv, zv]
+        .iter()
+        .map(|val| val.to_string())
+        .collect();
+    record.push(p.pinned().to_string());
+    record.push(p.species().to_string());
+    record.push(p.radius().to_string());
+    return record;
+}
+
+// Adds `value` to the row being written, formatted as `csv_record` would, by way of `field`.
+fn write_field<W: std::io::Write, V: Display>(
+    wtr: &mut csv::Writer<W>,
+    field: &mut String,
+    value: V,
+) -> csv::Result<()> {
+    field.clear();
+    write!(field, "{}", value).expect("formatting into a String doesn't fail");
+    return wtr.write_field(field.as_bytes());
+}
+
+enum Contact {
+    Miss,
+    Merge,
+    Bounce(Point, Point),
+}
+
+fn default_merge_policy() -> Arc<dyn MergePolicy> {
+    return Arc::new(Schwarzschild);
+}
+
+// Which of `p`'s position and velocity isn't finite, if either is.
+fn non_finite(p: &Point) -> Option<&'static str> {
+    let (x, y, z) = p.position();
+    if !Vec3d::new(x, y, z).is_finite() {
+        return Some("position");
+    }
+    if !p.velocity().is_finite() {
+        return Some("velocity");
+    }
+    return None;
+}
+
+// m |x|^2 of `p` about the origin.
+fn second_moment(p: Point) -> f64 {
+    let (x, y, z) = p.position();
+    return p.mass() * (x * x + y * y + z * z);
+}
+
+/// A copy of the nodes at the top of a tree, which walks pass through and then continue into the
+/// tree itself below. The thread that makes the copy decides where its memory lives, so a copy
+/// made on each NUMA node saves every walk there from reading the busiest nodes across sockets.
+/// Forces come out the same as from the tree.
+pub struct TopLevels<'a> {
+    tree: &'a BHTree,
+    root: TopNode<'a>,
+}
+
+impl TopLevels<'_> {
+    // Same as `BHTree::force_on`.
+    pub fn force_on(&self, p: Point) -> Vec3d {
+        return self.force_and_cost(p).0;
+    }
+
+    // Same as `BHTree::force_and_cost`.
+    pub fn force_and_cost(&self, p: Point) -> (Vec3d, u32) {
+        let traversal = self.tree.traversal(p);
+        if self.tree.interaction_lists {
+            let (force, n) = self
+                .tree
+                .listed_force(&traversal, |list| self.root.gather(&traversal, list));
+            return (force + self.tree.images_force(&traversal), n);
+        }
+        let nodes = self.root.calculate_force(&traversal);
+        let force = self.tree.total_force(p, &traversal, nodes);
+        return (
+            force + self.tree.images_force(&traversal),
+            traversal.interactions.get(),
+        );
+    }
+}
+
+struct TopNode<'a> {
+    // The node without its particles or children.
+    node: BHNode,
+    original: &'a BHNode,
+    // Empty below the copied levels, and for leaves.
+    children: Vec<TopNode<'a>>,
+}
+
+impl<'a> TopNode<'a> {
+    fn new(original: &'a BHNode, depth: usize) -> TopNode<'a> {
+        let children = match depth {
+            0 => vec![],
+            _ => original
+                .children
+                .iter()
+                .map(|child| TopNode::new(child, depth - 1))
+                .collect(),
+        };
+        return TopNode {
+            node: original.shell(),
+            original: original,
+            children: children,
+        };
+    }
+
+    // Same as `BHNode::calculate_force` on the original, summed in the same order.
+    fn calculate_force(&self, t: &Traversal) -> Vec3d {
+        if self.children.is_empty() {
+            return self.original.calculate_force(t);
+        }
+        if let Some(force) = self.node.summarized_force(t) {
+            return force;
+        }
+        let mut force = ForceSum::new(t.compensated);
+        for child in self.children.iter() {
+            force.add(child.calculate_force(t));
+        }
+        return force.value();
+    }
+
+    // Same as `BHNode::gather` on the original.
+    fn gather(&self, t: &Traversal, list: &mut InteractionList) {
+        if self.children.is_empty() {
+            return self.original.gather(t, list);
+        }
+        if self.node.count == 0 {
+            return;
+        }
+        if let Some(com) = self.node.accepted(t) {
+            return list.push(com, t.softening);
+        }
+        for child in self.children.iter() {
+            child.gather(t, list);
+        }
+    }
+}
+
+// Identifies a point within one pass over a tree. Ids aren't guaranteed unique, but two points
+// can only share an id and a position if they were never told apart by the tree in the first place.
+pub(crate) fn key(p: &Point) -> (u64, [u64; 3]) {
+    let (x, y, z) = p.position();
+    return (p.id(), [x.to_bits(), y.to_bits(), z.to_bits()]);
+}
+
+/// The Barnes-Hut summary of a node: its particles' centre of mass and the second moment of their
+/// masses, which the opening criterion tests against the node's opening angle.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Moments {
+    theta: f64,
+    center_of_mass: Point,
+    // Sum of m |x|^2 over the node's particles, from which `extent` finds their spread about the
+    // centre of mass.
+    #[serde(default)]
+    second_moment: f64,
+    // The sums behind the centre of mass, kept when the tree uses compensated summation.
+    #[serde(default)]
+    sums: Option<Box<MassSums>>,
+}
+
+impl Moments {
+    pub fn new(theta: f64, compensated: bool) -> Moments {
+        return Moments {
+            theta: theta,
+            center_of_mass: Point::new_zero(),
+            second_moment: 0.,
+            sums: compensated.then(Box::default),
+        };
+    }
+
+    // Sets the moments from those of `parts`, each a particle or a child's centre of mass with
+    // its second moment and compensated sums. The centre of mass is the mass-weighted sums of
+    // position and momentum divided by the mass once, rather than a running average nudged along
+    // with each particle, so its velocity is weighted the same as its position and rounding
+    // doesn't build up as particles are added.
+    fn sum<'a>(&mut self, parts: impl Iterator<Item = (Point, f64, Option<&'a MassSums>)>) {
+        let mut sums = self.sums.as_ref().map(|_| Box::<MassSums>::default());
+        let mut mass = 0.;
+        let mut weighted = Vec3d::new_zero();
+        let mut momentum = Vec3d::new_zero();
+        let mut moment = 0.;
+        let mut empty = true;
+        for (com, second, from) in parts {
+            let (x, y, z) = com.position();
+            mass += com.mass();
+            weighted += com.mass() * Vec3d::new(x, y, z);
+            momentum += com.momentum();
+            moment += second;
+            empty = false;
+            match (sums.as_mut(), from) {
+                (Some(sums), Some(from)) => sums.merge(from),
+                (Some(sums), None) => sums.add(com),
+                (None, _) => {}
+            }
+        }
+
+        self.second_moment = moment;
+        self.center_of_mass = match &sums {
+            _ if empty => Point::new_zero(),
+            Some(sums) => sums.center_of_mass(),
+            None => {
+                let (x, y, z) = (weighted / mass).position();
+                Point::new(mass, x, y, z, momentum / mass)
+            }
+        };
+        self.sums = sums;
+        trace!("COM updated to {}", self.center_of_mass);
+    }
+}
+
+impl Summary<Point> for Moments {
+    fn blank(&self) -> Moments {
+        return Moments::new(self.theta, self.sums.is_some());
+    }
+
+    fn of_items(&mut self, items: &[Point]) {
+        self.sum(items.iter().map(|&p| (p, second_moment(p), None)));
+    }
+
+    fn of_children(&mut self, children: &[BHNode]) {
+        self.sum(children.iter().filter(|c| c.count > 0).map(|c| {
+            let moments = &c.summary;
+            (
+                moments.center_of_mass,
+                moments.second_moment,
+                moments.sums.as_deref(),
+            )
+        }));
+    }
+
+    fn check(node: &BHNode) -> Result<(), String> {
+        let com = node.summary.center_of_mass;
+        let parts: Vec<Point> = if node.children.is_empty() {
+            node.items.clone()
+        } else {
+            node.children
+                .iter()
+                .filter(|c| c.count > 0)
+                .map(|c| c.summary.center_of_mass)
+                .collect()
+        };
+        let whose = if node.children.is_empty() {
+            "points'"
+        } else {
+            "children's"
+        };
+        if parts.is_empty() {
+            return Ok(());
+        }
+        let mass: f64 = parts.iter().map(|p| p.mass()).sum();
+        if (mass - com.mass()).abs() > 1e-9 * mass.abs() {
+            return Err(format!("mass differing from its {}", whose));
+        }
+        // The centre of mass should carry the total momentum of the parts, to within rounding.
+        let (total, scale) = parts
+            .iter()
+            .fold((Vec3d::new_zero(), 0.), |(total, scale), p| {
+                (total + p.momentum(), scale + p.momentum().magnitude())
+            });
+        if (total - com.momentum()).magnitude() > 1e-9 * scale {
+            return Err(format!("momentum differing from its {}", whose));
+        }
+        return Ok(());
+    }
+}
+
+/// A node of the Barnes-Hut tree: an octree node summarized by its mass moments.
+pub type BHNode = Octant<Point, Moments>;
+
+impl BHNode {
+    fn center_of_mass(&self) -> Point {
+        return self.summary.center_of_mass;
+    }
+
+    // The size, mass and spread of this node's particles, for the opening criterion.
+    fn extent(&self) -> NodeExtent {
+        let com = self.summary.center_of_mass;
+        let (cx, cy, cz) = com.position();
+        let far = |c: f64, lo: f64| (c - lo).abs().max((lo + self.region_size - c).abs());
+        let dz = if self.planar { 0. } else { far(cz, self.zloc) };
+        let b_max = Vec3d::new(far(cx, self.xloc), far(cy, self.yloc), dz).magnitude();
+        let half = self.region_size / 2.;
+        let oz = if self.planar {
+            0.
+        } else {
+            cz - self.zloc - half
+        };
+        let offset = Vec3d::new(cx - self.xloc - half, cy - self.yloc - half, oz).magnitude();
+        return NodeExtent {
+            size: self.region_size,
+            mass: com.mass(),
+            offset: offset,
+            b_max: b_max,
+            // Rounding can take the difference slightly below zero when the particles coincide.
+            b2: (self.summary.second_moment - second_moment(com)).max(0.),
+        };
+    }
+
+    // The force from the whole node if it's empty or far enough away for its centre of mass to
+    // stand in for its particles, or None if it has to be opened.
+    fn summarized_force(&self, t: &Traversal) -> Option<Vec3d> {
+        if self.count == 0 || self.out_of_range(t) {
+            return Some(Vec3d::new_zero());
+        }
+        // Sufficiently far away to use this node's COM.
+        let com = self.accepted(t)?;
+        t.count(1);
+        return Some(t.law.softened_force(t.target, com, t.softening));
+    }
+
+    // The centre of mass of a non-empty node, if it's sufficiently far away to stand in for the
+    // node's particles. None if the node has to be opened.
+    fn accepted(&self, t: &Traversal) -> Option<Point> {
+        let com = t.image(self.center_of_mass());
+        if !self.may_hold(t)
+            && t.opening.accepts(
+                &self.extent(),
+                com.distance_to(t.target),
+                t.schedule
+                    .theta(self.summary.theta, t.root_size, self.region_size),
+                t.law,
+            )
+        {
+            return Some(com);
+        }
+        return None;
+    }
+
+    fn calculate_force(&self, t: &Traversal) -> Vec3d {
+        let p = t.target;
+        if let Some(force) = self.summarized_force(t) {
+            return force;
+        }
+
+        let mut force = ForceSum::new(t.compensated);
+        // An opened leaf is summed exactly over its bucket.
+        for q in self.items.iter().filter(|q| q.id() != p.id()) {
+            force.add(t.law.softened_force(p, t.image(*q), t.softening_from(q)));
+            t.count(1);
+        }
+        for child in self.children.iter() {
+            force.add(child.calculate_force(t));
+        }
+        return force.value();
+    }
+
+    // Adds what `calculate_force` would sum over to `list`, in the same order.
+    fn gather(&self, t: &Traversal, list: &mut InteractionList) {
+        if self.count == 0 || self.out_of_range(t) {
+            return;
+        }
+        if let Some(com) = self.accepted(t) {
+            return list.push(com, t.softening);
+        }
+        for q in self.items.iter().filter(|q| q.id() != t.target.id()) {
+            list.push(t.image(*q), t.softening_from(q));
+        }
+        for child in self.children.iter() {
+            child.gather(t, list);
+        }
+    }
+
+    // The first of the terms `calculate_force` sums that isn't finite, if any, and where it came
+    // from.
+    fn blame(&self, t: &Traversal) -> Option<Culprit> {
+        if self.count == 0 || self.out_of_range(t) {
+            return None;
+        }
+        let bad =
+            |q: Point, softening: f64| !t.law.softened_force(t.target, q, softening).is_finite();
+        if let Some(com) = self.accepted(t) {
+            if !bad(com, t.softening) {
+                return None;
+            }
+            if let (1, Some(q)) = (self.count, self.iter().next()) {
+                return Some(Culprit::Particle { id: q.id() });
+            }
+            let (x, y, z) = com.position();
+            return Some(Culprit::Node {
+                count: self.count as u64,
+                mass: com.mass(),
+                x: x,
+                y: y,
+                z: z,
+            });
+        }
+        for q in self.items.iter().filter(|q| q.id() != t.target.id()) {
+            if bad(t.image(*q), t.softening_from(q)) {
+                return Some(Culprit::Particle { id: q.id() });
+            }
+        }
+        return self.children.iter().find_map(|child| child.blame(t));
+    }
+
+    fn calculate_potential(&self, t: &Traversal) -> f64 {
+        let p = t.target;
+        if self.count == 0 || self.out_of_range(t) {
+            return 0.;
+        }
+
+        let unit = p.with_mass(1.);
+        if let Some(com) = self.accepted(t) {
+            return t.law.softened_potential(unit, com, t.softening);
+        }
+
+        let bucket: f64 = self
+            .items
+            .iter()
+            .filter(|q| q.id() != p.id())
+            .map(|q| {
+                t.law
+                    .softened_potential(unit, t.image(*q), t.softening_from(q))
+            })
+            .sum();
+        return bucket
+            + self
+                .children
+                .iter()
+                .map(|child| child.calculate_potential(t))
+                .sum::<f64>();
+    }
+
+    // The Ewald correction to the nearest-image force from this node's particles. The correction
+    // changes on the scale of the box, so any node an eighth of the box across or less can stand
+    // in for its particles, unless it might hold the target.
+    fn ewald_force(&self, t: &Traversal, box_size: f64) -> Vec3d {
+        if self.count == 0 {
+            return Vec3d::new_zero();
+        }
+        let p = t.target;
+        let pull = |q: Point| {
+            let (px, py, pz) = p.position();
+            let (qx, qy, qz) = t.image(q).position();
+            let offset = Vec3d::new(px - qx, py - qy, pz - qz);
+            return (t.law.g * p.mass() * q.mass()) * ewald::correction(offset, box_size);
+        };
+        if self.region_size <= box_size / 8. && !self.may_hold(t) {
+            return pull(self.center_of_mass());
+        }
+        let mut force = ForceSum::new(t.compensated);
+        for q in self.items.iter().filter(|q| q.id() != p.id()) {
+            force.add(pull(*q));
+        }
+        for child in self.children.iter() {
+            force.add(child.ewald_force(t, box_size));
+        }
+        return force.value();
+    }
+
+    // Whether every particle of this node is beyond the cutoff of a short-range force law, in a
+    // periodic box counting the nearest image of the node. Allows for particles that have drifted
+    // out of a reused node.
+    fn out_of_range(&self, t: &Traversal) -> bool {
+        let cutoff = match t.law.cutoff() {
+            Some(cutoff) => cutoff,
+            None => return false,
+        };
+        let (x, y, z) = t.target.position();
+        let gap = |v: f64, lo: f64| {
+            let gap = |v: f64| (lo - v).max(v - (lo + self.region_size)).max(0.);
+            match t.periodic {
+                Some(box_size) => gap(v).min(gap(v - box_size)).min(gap(v + box_size)),
+                None => gap(v),
+            }
+        };
+        let (dx, dy, dz) = (gap(x, self.xloc), gap(y, self.yloc), gap(z, self.zloc));
+        return (dx * dx + dy * dy + dz * dz).sqrt() > cutoff + t.reach * self.region_size;
+    }
+
+    // Whether the target of `t` could be one of this node's particles. Such a node is always
+    // opened, so that the target is left out of the sum by id rather than folded into the centre
+    // of mass.
+    fn may_hold(&self, t: &Traversal) -> bool {
+        return self.box_distance(t.target.position()) <= t.reach * self.region_size;
+    }
+
+    // Adds `p` to this node, `depth` levels below the root, splitting full leaves. Only the nodes
+    // on the way down to `p`'s leaf are summarized again.
+    fn add_point(
+        &mut self,
+        p: Point,
+        depth: usize,
+        refinement: &Refinement,
+    ) -> Result<(), NbodyError> {
+        if !self.children.is_empty() {
+            self.add_to_child(p, depth, refinement)?;
+            self.summarize();
+            return Ok(());
+        }
+
+        // No amount of splitting can separate two points at exactly the same position, so the
+        // tree merges them before `p` gets here, and `p` takes the other one's place.
+        if let Some(q) = self.items.iter_mut().find(|q| q.position() == p.position()) {
+            *q = p;
+        } else {
+            self.items.push(p);
+        }
+        if self.items.len() > refinement.bucket_size && depth < refinement.max_depth {
+            self.split(vec![]);
+            for q in std::mem::take(&mut self.items) {
+                self.add_to_child(q, depth, refinement)?;
+            }
+        }
+        self.summarize();
+        return Ok(());
+    }
+
+    fn add_to_child(
+        &mut self,
+        p: Point,
+        depth: usize,
+        refinement: &Refinement,
+    ) -> Result<(), NbodyError> {
+        // There must be children if trying to add a point to one of them.
+        debug_assert!(!self.children.is_empty());
+        let index = self.child_index(p.position());
+        return self.children[index].add_point(p, depth + 1, refinement);
+    }
+
+    fn collect_stats(&self, depth: usize, stats: &mut TreeStats) {
+        stats.nodes += 1;
+        stats.memory_bytes +=
+            std::mem::size_of::<BHNode>() + self.items.capacity() * std::mem::size_of::<Point>();
+        if !self.children.is_empty() {
+            for child in self.children.iter() {
+                child.collect_stats(depth + 1, stats);
+            }
+            return;
+        }
+
+        stats.leaves += 1;
+        stats.depth = stats.depth.max(depth);
+        if stats.occupancy.len() <= self.items.len() {
+            stats.occupancy.resize(self.items.len() + 1, 0);
+        }
+        stats.occupancy[self.items.len()] += 1;
+    }
+
+    // Writes this node and its non-empty descendants as DOT statements. `next` numbers the nodes.
+    fn write_dot<W: std::io::Write>(
+        &self,
+        w: &mut W,
+        next: &mut usize,
+    ) -> Result<usize, NbodyError> {
+        let id = *next;
+        *next += 1;
+        writeln!(
+            w,
+            "  n{} [label=\"({:.3e}, {:.3e}, {:.3e}) size {:.3e}\\ncount {} mass {:.3e}\"];",
+            id,
+            self.xloc,
+            self.yloc,
+            self.zloc,
+            self.region_size,
+            self.count,
+            self.summary.center_of_mass.mass()
+        )?;
+        for child in self.children.iter().filter(|c| c.count > 0) {
+            let child_id = child.write_dot(w, next)?;
+            writeln!(w, "  n{} -> n{};", id, child_id)?;
+        }
+        return Ok(id);
+    }
+}
+
+// A force total, plain or compensated.
+enum ForceSum {
+    Plain(Vec3d),
+    Compensated(CompensatedVec),
+}
+
+impl ForceSum {
+    fn new(compensated: bool) -> ForceSum {
+        if compensated {
+            return ForceSum::Compensated(CompensatedVec::default());
+        }
+        return ForceSum::Plain(Vec3d::new_zero());
+    }
+
+    fn add(&mut self, f: Vec3d) {
+        match self {
+            ForceSum::Plain(sum) => *sum += f,
+            ForceSum::Compensated(sum) => sum.add(f),
+        }
+    }
+
+    fn value(&self) -> Vec3d {
+        return match self {
+            ForceSum::Plain(sum) => *sum,
+            ForceSum::Compensated(sum) => sum.value(),
+        };
+    }
+}
+
+#[cfg(test)]
+mod test_bht {
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+
+    use proptest::prelude::*;
+
+    use crate::error::Culprit;
+    use crate::geometry::bh_tree::{
+        BHNode, BHTree, Point, Refinement, StepScratch, TreeReuse, TreeStats, Vec3d,
+    };
+    use crate::geometry::boundary::Boundary;
+    use crate::geometry::contact::{CollisionModel, CollisionOutcome};
+    use crate::geometry::escape::EscaperPolicy;
+    use crate::geometry::force::ForceLaw;
+    use crate::geometry::kepler;
+    use crate::geometry::opening::{OpeningCriterion, ThetaSchedule};
+    use crate::geometry::species::{Species, SpeciesTable};
+    use crate::geometry::vec3d::G;
+    use crate::NbodyError;
+
+    #[test]
+    fn starts_with_0com() {
+        let bht = BHTree::new(0.5, 1e10, 0., 0., 0.);
+        assert_eq!(bht.root.center_of_mass(), Point::new_zero());
+    }
+
+    #[test]
+    fn test_add_point() {
+        use rand::{thread_rng, Rng};
+        let mut rng = thread_rng();
+        for i in 1..100 {
+            let mut bht = BHTree::new(1.0 / (i as f64), rng.gen_range(1.0..1337.), 0., 0., 0.);
+            let pt = Point::new(1.0, 2.0, 2.0, 2.0, Vec3d::new_zero());
+            bht.add_point(pt).unwrap();
+            assert_eq!(bht.root.xloc, 0.0);
+            assert_eq!(bht.root.yloc, 0.0);
+            assert_eq!(bht.root.zloc, 0.0);
+            assert_eq!(bht.root.zloc, 0.0);
+            assert_eq!(bht.root.center_of_mass(), pt);
+
+            let pt2 = Point::new(1.0, 0.0, 0.0, 0.0, Vec3d::new_zero()).with_id(1);
+            bht.add_point(pt2).unwrap();
+            let expected = Point::new(2.0, 1., 1., 1., Vec3d::new_zero());
+            assert_eq!(bht.root.center_of_mass(), expected);
+
+            let pt3 = Point::new(2.0, 3.0, 3.0, 3.0, Vec3d::new_zero()).with_id(2);
+            bht.add_point(pt3).unwrap();
+            let expected = Point::new(4.0, 2., 2., 2., Vec3d::new_zero());
+            assert_eq!(bht.root.center_of_mass(), expected);
+        }
+    }
+
+    #[test]
+    fn test_bulk_center_of_mass() {
+        let points: Vec<Point> = (0..500)
+            .map(|i| {
+                let a = i as f64;
+                let v = Vec3d::new((a * 0.3).sin(), (a * 0.7).cos(), 1e3 / (1. + a));
+                Point::new(
+                    1. + (i % 11) as f64 * 1e3,
+                    a.cos() * a,
+                    a.sin() * a,
+                    a % 5.,
+                    v,
+                )
+                .with_id(i)
+            })
+            .collect();
+        let mass: f64 = points.iter().map(|p| p.mass()).sum();
+        let momentum = points
+            .iter()
+            .fold(Vec3d::new_zero(), |acc, p| acc + p.momentum());
+
+        // One at a time or all at once, every node's centre of mass carries its particles' mass
+        // and momentum.
+        let bulk = BHTree::from_points(0.5, points.clone()).unwrap();
+        let mut incremental = BHTree::new(0.5, 1., 0., 0., 0.);
+        for p in points {
+            incremental.add_point(p).unwrap();
+        }
+        for bht in [&bulk, &incremental] {
+            bht.check_invariants().unwrap();
+            let com = bht.center_of_mass();
+            assert!((com.mass() - mass).abs() <= 1e-12 * mass);
+            assert!(
+                (com.velocity() - momentum / mass).magnitude()
+                    <= 1e-12 * momentum.magnitude() / mass
+            );
+        }
+        let (x, y, z) = bulk.center_of_mass().position();
+        let (ix, iy, iz) = incremental.center_of_mass().position();
+        assert!(Vec3d::new(x - ix, y - iy, z - iz).magnitude() <= 1e-9);
+    }
+
+    #[test]
+    fn test_step_calculation() {
+        let mut bht = BHTree::new(0.5, 5., 0., 0., 0.);
+        let pt = Point::new(1e9, 2.0, 2.0, 2.0, Vec3d::new_zero());
+        bht.add_point(pt).unwrap();
+        let pt = Point::new(1e9, 0.0, 0.0, 0.0, Vec3d::new_zero()).with_id(1);
+        bht.add_point(pt).unwrap();
+
+        for _ in 1..100 {
+            bht = bht.next(1.0).unwrap();
+        }
+    }
+
+    #[test]
+    fn serdes_test() {
+        let pt = Point::new(1e9, 2.0, 2.0, 2.0, Vec3d::new_zero());
+        let mut bht = BHTree::new(0.5, 5.0, 0., 0., 0.);
+        let pt2 = Point::new(1e9, 1.0, 2.0, 1.0, Vec3d::new_zero()).with_id(1);
+        bht.add_point(pt).unwrap();
+        bht.add_point(pt2).unwrap();
+        let serialized = serde_json::to_string_pretty(&bht).unwrap();
+        let bht2: BHTree = serde_json::from_str(&serialized).unwrap();
+        let rt_serialized = serde_json::to_string_pretty(&bht2).unwrap();
+        assert_eq!(serialized, rt_serialized);
     }
 
-    pub fn add_point(&mut self, p: Point) {
-        trace!("adding point {}", p);
-        self.root.add_point(p);
+    #[test]
+    fn test_periodic_force() {
+        let mut bht = BHTree::new_periodic(0.5, 10.);
+        bht.add_point(Point::new(1e9, 4.5, 0., 0., Vec3d::new_zero()).with_id(1))
+            .unwrap();
+        bht.add_point(Point::new(1e9, 4.6, 0., 0., Vec3d::new_zero()).with_id(2))
+            .unwrap();
+
+        // The nearest images of both points are across the boundary from the probe.
+        let probe = Point::new(1., -4.9, 0., 0., Vec3d::new_zero()).with_id(3);
+        let expected = probe.force_from(Point::new(1e9, -5.5, 0., 0., Vec3d::new_zero()))
+            + probe.force_from(Point::new(1e9, -5.4, 0., 0., Vec3d::new_zero()));
+        let (fx, fy, fz) = bht.force_on(probe).position();
+        let (ex, _, _) = expected.position();
+        assert!(fx < 0.);
+        assert!((fx - ex).abs() < 1e-9 * ex.abs());
+        assert_eq!((fy, fz), (0., 0.));
     }
 
-    pub fn next(&self, dt: f64) -> BHTree {
-        debug!("creating next bht...");
+    #[test]
+    fn test_periodic_wrap() {
+        let mut bht = BHTree::new_periodic(0.5, 10.);
+        bht.add_point(Point::new(1., -4.9, 0., 0., Vec3d::new(-1., 0., 0.)))
+            .unwrap();
+        bht.add_point(Point::new(1., 4.9, 4.9, 0., Vec3d::new(0., 1., 0.)).with_id(1))
+            .unwrap();
 
-        info!("creating new point set");
-        let new_points_iter: Vec<_> = self
-            .root
-            .get_points()
-            .par_iter()
+        // Stepping maps particles that leave one face back in through the opposite one.
+        let next = bht.next(1.).unwrap();
+        let mut xs: Vec<(f64, f64)> = next
+            .points()
+            .iter()
             .map(|p| {
-                let force = self.root.calculate_force(dt, *p);
-                return p.apply_force(dt, force);
+                let (x, y, _) = p.position();
+                (x, y)
             })
             .collect();
+        xs.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert!((xs[0].0 - 4.1).abs() < 1e-9 && xs[0].1.abs() < 1e-9);
+        assert!((xs[1].0 - 4.9).abs() < 1e-9 && (xs[1].1 + 4.1).abs() < 1e-9);
+    }
 
-        let mut min_dim = f64::MAX;
-        let mut max_dim = f64::MIN;
-        for p in &new_points_iter {
-            let (x, y, z) = p.position();
-            min_dim = x.min(min_dim);
-            max_dim = x.max(max_dim);
-            min_dim = y.min(min_dim);
-            max_dim = y.max(max_dim);
-            min_dim = z.min(min_dim);
-            max_dim = z.max(max_dim);
-        }
-        max_dim += 1.;
-        min_dim -= 1.;
+    #[test]
+    fn test_absorbing_and_reflective_walls() {
+        let points = vec![
+            Point::new(1., 4.5, 0., 0., Vec3d::new(1., 0., 0.)).with_id(0),
+            Point::new(1., -3., 0., 0., Vec3d::new_zero()).with_id(1),
+        ];
+        let step = |boundary| {
+            let bht = BHTree::new_bounded(0.5, boundary)
+                .rebuild(points.clone())
+                .unwrap();
+            return bht.step(1.).unwrap();
+        };
+
+        // An absorbed particle is removed, not merged.
+        let (absorbed, stats) = step(Boundary::Absorbing { box_size: 10. });
+        assert_eq!(absorbed.len(), 1);
+        assert_eq!((stats.merges, stats.removed), (0, 1));
+        assert_eq!(absorbed.boundary(), Boundary::Absorbing { box_size: 10. });
 
-        let graph_size = max_dim - min_dim;
-        let mut bht = BHTree::new(self.theta, graph_size, min_dim, min_dim, min_dim);
+        let (reflected, _) = step(Boundary::Reflective { box_size: 10. });
+        assert_eq!(reflected.len(), 2);
+        let p = reflected
+            .points()
+            .into_iter()
+            .find(|p| p.id() == 0)
+            .unwrap();
+        let (x, _, _) = p.position();
+        let (vx, _, _) = p.velocity().position();
+        assert!(x < 5. && vx < 0.);
+    }
+
+    #[test]
+    fn test_planar_tree() {
+        let points = vec![
+            Point::new(1e9, 1., 2., 3., Vec3d::new(0., 0., 1.)).with_id(0),
+            Point::new(1e9, -1., 2., -3., Vec3d::new(1., 0., 1.)).with_id(1),
+            Point::new(1e9, 4., -2., 7., Vec3d::new_zero()).with_id(2),
+        ];
+        let leaves = Refinement {
+            bucket_size: 1,
+            ..Refinement::default()
+        };
+        let bht = BHTree::from_points(0.5, vec![])
+            .unwrap()
+            .with_planar(true)
+            .with_refinement(leaves)
+            .rebuild(points)
+            .unwrap();
+        assert_eq!(bht.len(), 3);
+        assert_eq!(bht.root.children.len(), 4);
 
-        info!("adding {} points to bht", new_points_iter.len());
-        for p in new_points_iter {
-            bht.add_point(p);
+        let next = bht.next(1.).unwrap();
+        assert!(next.planar());
+        for p in next.points() {
+            let (_, _, z) = p.position();
+            let (_, _, vz) = p.velocity().position();
+            assert_eq!((z, vz), (0., 0.));
         }
-        info!("done adding points to bht");
+    }
 
-        return bht;
+    #[test]
+    fn merge_test() {
+        let mut bht = BHTree::new(0.5, 5., 0., 0., 0.);
+
+        assert_eq!(bht.root.count, 0);
+        bht.add_point(Point::new(1., 0., 0., 0., Vec3d::new_zero()))
+            .unwrap();
+        assert_eq!(bht.root.count, 1);
+        bht.add_point(Point::new(1., 1., 0., 0., Vec3d::new_zero()).with_id(1))
+            .unwrap();
+        assert_eq!(bht.root.count, 2);
+
+        bht.add_point(Point::new(1e99, 1., 1., 0., Vec3d::new_zero()).with_id(2))
+            .unwrap();
+        assert_eq!(bht.root.count, 3);
+
+        // Both neighbours are well inside the heavy particle's Schwarzschild radius, so a step
+        // short enough that they can't be flung out of it merges everything into a single node.
+        let next = bht.next(1e-50).unwrap();
+        assert_eq!(next.root.count, 1);
+        assert_eq!(next.len(), 1);
+        next.check_invariants().unwrap();
     }
 
-    pub fn write_to_csv(&self, filename: String) -> Result<(), Box<dyn Error>> {
-        info!("writing bht to file: {}", filename);
-        let mut wtr = csv::Writer::from_path(filename.clone())?;
-        wtr.write_record(&["mass", "x_pos", "y_pos", "z_pos", "x_vel", "y_vel", "z_vel"])?;
+    #[test]
+    fn test_species_merge_and_softening() {
+        let species = SpeciesTable::new(vec![
+            Species::default(),
+            Species {
+                name: "stars".to_string(),
+                softening: 1.,
+                merge: false,
+                color: None,
+            },
+        ]);
+        let points = vec![
+            Point::new(1e99, 0., 0., 0., Vec3d::new_zero()).with_id(0),
+            Point::new(1., 1., 0., 0., Vec3d::new_zero())
+                .with_id(1)
+                .with_species(1),
+        ];
+        let bht = BHTree::from_points_with_species(0.5, points, species).unwrap();
+        assert_eq!(bht.len(), 2);
 
-        let mut record_v: Vec<[String; 7]> = vec![];
-        for p in self.root.get_points().iter() {
-            let (x, y, z) = p.position();
-            let mass = p.mass();
-            let (xv, yv, zv) = p.velocity().position();
-            record_v.push([mass, x, y, z, xv, yv, zv].map(|val| val.to_string()));
+        let probe = Point::new(1., 0., 0., 1., Vec3d::new_zero()).with_species(1);
+        let heavy = Point::new(1e99, 0., 0., 0., Vec3d::new_zero());
+        let softened = bht.force_law().softened_force(probe, heavy, 1.);
+        assert!(softened.magnitude() < probe.force_from(heavy).magnitude());
+    }
+
+    #[test]
+    fn test_cross_species_softening() {
+        let species = SpeciesTable::new(vec![
+            Species::default(),
+            Species {
+                name: "stars".to_string(),
+                softening: 0.5,
+                merge: false,
+                color: None,
+            },
+        ]);
+        let points = vec![
+            Point::new(2e10, 0., 0., 0., Vec3d::new_zero()).with_id(0),
+            Point::new(1e10, 1., 0.5, 0., Vec3d::new_zero())
+                .with_id(1)
+                .with_species(1),
+            Point::new(3e10, -1., 2., 0.25, Vec3d::new_zero()).with_id(2),
+        ];
+        for lists in [false, true] {
+            let bht = BHTree::from_points_with_species(0.5, points.clone(), species.clone())
+                .unwrap()
+                .with_interaction_lists(lists);
+            // Each pair is softened the same way from both ends, so the forces cancel.
+            let total = points
+                .iter()
+                .fold(Vec3d::new_zero(), |sum, p| sum + bht.force_on(*p));
+            let scale = bht.force_on(points[0]).magnitude();
+            assert!(total.magnitude() <= 1e-12 * scale);
+            let unsoftened = points[0].force_from(points[1]) + points[0].force_from(points[2]);
+            assert!(bht.force_on(points[0]).magnitude() < unsoftened.magnitude());
         }
+    }
 
-        thread::spawn(move || {
-            info!("flushing {}", filename.clone());
-            for record in record_v.iter() {
-                wtr.write_record(record).unwrap();
-            }
-            wtr.flush().unwrap();
-            info!("done flushing {}", filename);
-        });
+    #[test]
+    fn test_collision_outcomes() {
+        let points = vec![
+            Point::new(1., 0., 0., 0., Vec3d::new(1., 0., 0.))
+                .with_id(0)
+                .with_radius(0.6),
+            Point::new(1., 1., 0., 0., Vec3d::new(-1., 0., 0.))
+                .with_id(1)
+                .with_radius(0.6),
+        ];
+        let tree = |outcome| {
+            let collisions = CollisionModel {
+                outcome: outcome,
+                density: None,
+            };
+            return BHTree::fitted(0.5, &points)
+                .with_collisions(collisions)
+                .with_points(points.clone())
+                .unwrap()
+                .resolve_collisions()
+                .unwrap();
+        };
 
-        Ok(())
+        assert_eq!(tree(CollisionOutcome::Merge).len(), 1);
+        assert_eq!(tree(CollisionOutcome::PassThrough).len(), 2);
+
+        let bounced = tree(CollisionOutcome::Bounce { restitution: 1. }).points();
+        assert_eq!(bounced.len(), 2);
+        for p in bounced {
+            let (vx, _, _) = p.velocity().position();
+            assert_eq!(vx, if p.id() == 0 { -1. } else { 1. });
+        }
     }
-}
 
-fn should_merge(p1: Point, p2: Point) -> bool {
-    let dist = p1.distance_to(p2);
-    return (dist <= p1.schwarzchild_radius()) || (dist <= p2.schwarzchild_radius());
-}
+    #[test]
+    fn test_collisions_are_order_independent() {
+        let collisions = CollisionModel {
+            outcome: CollisionOutcome::Merge,
+            density: None,
+        };
+        let points: Vec<Point> = [0., 0.9, 1.7, 2.4, 5.]
+            .iter()
+            .enumerate()
+            .map(|(i, &x)| {
+                Point::new(1., x, 0., 0., Vec3d::new(0., i as f64, 0.))
+                    .with_id(i as u64)
+                    .with_radius(0.5)
+            })
+            .collect();
+        let resolve = |points: Vec<Point>| {
+            let mut out = BHTree::fitted(0.5, &points)
+                .with_collisions(collisions)
+                .with_points(points)
+                .unwrap()
+                .resolve_collisions()
+                .unwrap()
+                .points();
+            out.sort_by_key(|p| p.id());
+            return out;
+        };
 
-#[derive(Serialize, Deserialize, Debug)]
-struct Row {
-    time: f64,
-    points: Vec<Point>,
-}
+        let forward = resolve(points.clone());
+        let backward = resolve(points.into_iter().rev().collect());
+        assert_eq!(forward, backward);
+        // The closest pair merges first, leaving its neighbours to merge with each other.
+        let ids: Vec<u64> = forward.iter().map(|p| p.id()).collect();
+        assert_eq!(ids, vec![0, 2, 4]);
+    }
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct BHNode {
-    theta: f64,
-    center_of_mass: Point,
-    point: Option<Point>,
-    count: i32,
-    region_size: f64,
-    xloc: f64,
-    yloc: f64,
-    zloc: f64,
-    children: Vec<BHNode>,
-}
+    #[test]
+    fn test_neighbor_queries() {
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut points: Vec<Point> = (0..200)
+            .map(|i| {
+                let mut c = || rng.gen_range(-10.0..10.0);
+                Point::new(1., c(), c(), c(), Vec3d::new_zero()).with_id(i)
+            })
+            .collect();
+        points.push(Point::new(0., 0.5, 0., 0., Vec3d::new_zero()).with_id(200));
+        let bht = BHTree::from_points(0.5, points.clone()).unwrap();
+        let probe = points[0];
 
-impl BHNode {
-    pub fn new(theta: f64, region_size: f64, x: f64, y: f64, z: f64) -> BHNode {
-        trace!(
-            "creating node {}, {}, {} // region size {}",
-            x,
-            y,
-            z,
-            region_size,
-        );
-        return BHNode {
-            theta: theta,
-            center_of_mass: Point::new_zero(),
-            region_size: region_size,
-            xloc: x,
-            yloc: y,
-            zloc: z,
-            children: vec![],
-            count: 0,
-            point: None,
-        };
+        // Compare against a brute-force scan.
+        let mut expected: Vec<Point> = points[1..].to_vec();
+        expected.sort_by(|a, b| probe.distance_to(*a).total_cmp(&probe.distance_to(*b)));
+        let ids = |v: &[Point]| v.iter().map(|p| p.id()).collect::<Vec<u64>>();
+
+        assert_eq!(ids(&bht.k_nearest(probe, 10)), ids(&expected[..10]));
+        assert!(bht.k_nearest(probe, 0).is_empty());
+        assert_eq!(bht.k_nearest(probe, 500).len(), 200);
+
+        let within: Vec<Point> = expected
+            .iter()
+            .copied()
+            .filter(|p| probe.distance_to(*p) <= 4.)
+            .collect();
+        assert_eq!(ids(&bht.neighbors_within(probe, 4.)), ids(&within));
     }
 
-    fn center_of_mass(&self) -> Point {
-        return self.center_of_mass;
+    #[test]
+    fn test_potential() {
+        let points = vec![
+            Point::new(2e9, 0., 0., 0., Vec3d::new_zero()).with_id(0),
+            Point::new(3e9, 4., 0., 0., Vec3d::new_zero()).with_id(1),
+        ];
+        let bht = BHTree::from_points(0.5, points.clone()).unwrap();
+        let phi = bht.potentials();
+        assert!((phi[0] + G * 3e9 / 4.).abs() < 1e-12 * G * 3e9);
+        assert!((phi[1] + G * 2e9 / 4.).abs() < 1e-12 * G * 2e9);
     }
 
-    fn calculate_force(&self, dt: f64, p: Point) -> Vec3d {
-        if p == self.center_of_mass() || self.count == 0 {
-            return Vec3d::new_zero();
+    #[test]
+    fn test_points_on_region_faces() {
+        // Every corner, edge midpoint and face centre of the root region, which includes points
+        // on the far faces and on the midplanes that every split puts between children.
+        let mut bht = BHTree::new(0.5, 4., 0., 0., 0.);
+        let mut id = 0;
+        for x in [0., 2., 4.] {
+            for y in [0., 2., 4.] {
+                for z in [0., 2., 4.] {
+                    bht.add_point(Point::new(1., x, y, z, Vec3d::new_zero()).with_id(id))
+                        .unwrap();
+                    id += 1;
+                }
+            }
         }
+        assert_eq!(bht.len(), 27);
+        assert_eq!(bht.points().len(), 27);
+        assert_eq!(bht.center_of_mass().mass(), 27.);
+        bht.check_invariants().unwrap();
 
-        let ratio = self.region_size / self.center_of_mass().distance_to(p);
-        if ratio < self.theta {
-            // Sufficiently far away to use this node's COM.
-            return p.force_from(self.center_of_mass());
+        let mut planar = BHTree::new(0.5, 1., -1., -1., -1.).with_planar(true);
+        for (i, (x, y)) in [(-1., -1.), (0., 0.), (-0.5, -0.5), (-0.5, 0.)]
+            .iter()
+            .enumerate()
+        {
+            planar
+                .add_point(Point::new(1., *x, *y, 0., Vec3d::new_zero()).with_id(i as u64))
+                .unwrap();
         }
+        assert_eq!(planar.len(), 4);
+        planar.check_invariants().unwrap();
+    }
 
-        let mut force = Vec3d::new_zero();
-        for child in self.children.iter() {
-            force += child.calculate_force(dt, p);
+    #[test]
+    fn test_root_grows_to_take_in_points() {
+        let mut bht = BHTree::new(0.5, 4., 0., 0., 0.);
+        let inside = [(1., 1., 1.), (3., 3., 1.), (3., 1., 3.)];
+        let outside = [
+            (4.5, 1., 1.),
+            (1., -1e-9, 1.),
+            (-100., 250., -3.),
+            (1e6, 1e6, 1e6),
+        ];
+        for (i, (x, y, z)) in inside.iter().chain(outside.iter()).enumerate() {
+            bht.add_point(Point::new(1., *x, *y, *z, Vec3d::new_zero()).with_id(i as u64))
+                .unwrap();
+            bht.check_invariants().unwrap();
         }
-        return force;
+        assert_eq!(bht.len(), 7);
+        assert!(bht.graph_size >= 1e6);
+
+        // The points end up where they would in a tree built around all of them.
+        let fitted = BHTree::from_points(0.5, bht.points()).unwrap();
+        let com = fitted.center_of_mass();
+        assert!(bht.center_of_mass().distance_to(com) < 1e-6);
+        let probe = Point::new(1., 2., 2., 2., Vec3d::new_zero()).with_id(99);
+        let (a, b) = (bht.force_on(probe), fitted.force_on(probe));
+        assert!((a - b).magnitude() < 1e-3 * b.magnitude());
+
+        let nan = Point::new(1., f64::NAN, 1., 1., Vec3d::new_zero()).with_id(7);
+        assert!(matches!(
+            bht.add_point(nan),
+            Err(NbodyError::OutOfBounds { id: 7, .. })
+        ));
+
+        // A walled box is the whole domain, so it doesn't grow.
+        let mut walled = BHTree::new_bounded(0.5, Boundary::Reflective { box_size: 4. });
+        let p = Point::new(1., 5., 1., 1., Vec3d::new_zero()).with_id(1);
+        assert!(matches!(
+            walled.add_point(p),
+            Err(NbodyError::OutOfBounds { id: 1, .. })
+        ));
     }
 
-    // Returns the delta on the count.
-    fn add_point(&mut self, p: Point) -> i32 {
-        let (oldx, oldy, oldz) = self.center_of_mass.position();
-        let old_mass = self.center_of_mass.mass();
-        let new_mass = self.center_of_mass.mass() + p.mass();
-        let (x, y, z) = p.position();
-        let new_vel =
-            ((self.center_of_mass.velocity() * old_mass) + (p.mass() * p.velocity())) / new_mass;
-        // @tallen this is likely wrong.
-        self.center_of_mass = Point::new(
-            new_mass,
-            (old_mass * oldx + x * p.mass()) / (new_mass),
-            (old_mass * oldy + y * p.mass()) / (new_mass),
-            (old_mass * oldz + z * p.mass()) / (new_mass),
-            new_vel,
-        );
-        trace!("COM updated to {}", self.center_of_mass);
+    #[test]
+    fn test_hostile_points_are_turned_away() {
+        // A mass can be set to anything after the fact.
+        let mut bht = BHTree::new_bounded(0.5, Boundary::Open);
+        let heavy = Point::new(1., 0., 0., 0., Vec3d::new_zero()).with_id(1);
+        for mass in [f64::NAN, f64::INFINITY, -1.] {
+            assert!(matches!(
+                bht.add_point(heavy.with_mass(mass)),
+                Err(NbodyError::InvalidMass { id: 1, .. })
+            ));
+        }
+        assert_eq!(bht.len(), 0);
 
-        self.count += 1;
+        // Growing out to a point this far would overflow the region's size, so it's turned away
+        // with the tree as it was, and the tree still takes in ordinary points.
+        bht.add_point(heavy).unwrap();
+        let far = Point::new(1., 1e308, 1., 1., Vec3d::new_zero()).with_id(2);
+        assert!(matches!(
+            bht.add_point(far),
+            Err(NbodyError::OutOfBounds { id: 2, .. })
+        ));
+        assert!(bht.graph_size < 1e3);
+        bht.add_point(Point::new(1., -3., 5., 1., Vec3d::new_zero()).with_id(3))
+            .unwrap();
+        bht.check_invariants().unwrap();
+        assert_eq!(bht.len(), 2);
 
-        if self.count == 1 {
-            // This is the first point to be inserted into the node, so there's nothing left to do.
-            self.point = Some(p);
-            return 0;
+        // Once the root has grown far enough that its corners round, a particle added at the same
+        // place as one from before still ends up with it and merges.
+        let near = Point::new(1., 0.5, 0.5, 0.5, Vec3d::new_zero());
+        let far = Point::new(1., -3e17, -3e17, -3e17, Vec3d::new_zero()).with_id(5);
+        for p in [near.with_id(4), far, near.with_id(6)] {
+            bht.add_point(p).unwrap();
+            bht.check_invariants().unwrap();
+        }
+        assert_eq!(bht.len(), 4);
+        assert!(bht
+            .iter()
+            .any(|p| p.mass() == 2. && p.position() == near.position()));
+
+        // -0 and 0 are the same place however the points go in.
+        let zeros: Vec<Point> = [0., -0., 0., -0.]
+            .iter()
+            .enumerate()
+            .map(|(i, z)| Point::new(1., *z, 1., -z, Vec3d::new_zero()).with_id(i as u64))
+            .collect();
+        let mut one_by_one = BHTree::new_bounded(0.5, Boundary::Open);
+        for p in zeros.iter() {
+            one_by_one.add_point(*p).unwrap();
         }
+        let mut bulk = BHTree::new_bounded(0.5, Boundary::Open);
+        bulk.add_points(zeros).unwrap();
+        bulk.check_invariants().unwrap();
+        assert_eq!(one_by_one.len(), 1);
+        assert_eq!(bulk.len(), 1);
+    }
+
+    #[test]
+    fn test_paranoid_steps_name_the_culprit() {
+        let at = |id: u64, x: f64| Point::new(1., x, 0., 0., Vec3d::new_zero()).with_id(id);
+        let non_finite = |bht: &BHTree, dt: f64| match bht.step(dt) {
+            Err(NbodyError::NonFinite {
+                id,
+                quantity,
+                culprit,
+            }) => (id, quantity, culprit),
+            other => panic!("{:?}", other.map(|(bht, _)| bht.points())),
+        };
 
-        if self.count == 2 && self.children.is_empty() {
-            if should_merge(self.point.unwrap(), p) {
-                self.point = Some(self.center_of_mass);
-                self.count -= 1;
-                return -1;
+        // Too close for the pull between them to be finite without softening.
+        let close = BHTree::from_points(0.5, vec![at(0, 0.), at(1, 1e-160), at(2, 5.)])
+            .unwrap()
+            .with_paranoid(true);
+        assert!(close.paranoid());
+        let (id, quantity, culprit) = non_finite(&close, 1.);
+        assert_eq!(quantity, "force");
+        assert_eq!(culprit, Culprit::Particle { id: 1 - id });
+        assert_eq!(
+            NbodyError::NonFinite {
+                id: id,
+                quantity: quantity,
+                culprit: culprit
             }
+            .to_string(),
+            format!(
+                "particle {} got a non-finite force from its interaction with particle {}",
+                id,
+                1 - id
+            )
+        );
 
-            self.split();
-            match self.point {
-                Some(local_pt) => self.add_to_child(local_pt),
-                None => panic!("inconsistency in node"),
-            };
-            self.point = None;
+        // A finite force over far too long a step.
+        let pair = BHTree::from_points(0.5, vec![at(0, 0.), at(1, 1.)]).unwrap();
+        let (_, quantity, culprit) = non_finite(&pair, 1e300);
+        assert_eq!((quantity, culprit), ("position", Culprit::Integrator));
+
+        let mut fast = BHTree::from_points(0.5, pair.points()).unwrap();
+        fast.add_point(at(2, 3.).with_velocity(Vec3d::new(f64::NAN, 0., 0.)))
+            .unwrap();
+        let (id, quantity, culprit) = non_finite(&fast, 1.);
+        assert_eq!((id, quantity, culprit), (2, "velocity", Culprit::Input));
+
+        let pushed = pair.step_with(1., |p| match p.id() {
+            1 => Vec3d::new(0., f64::INFINITY, 0.),
+            _ => Vec3d::new_zero(),
+        });
+        assert!(matches!(
+            pushed,
+            Err(NbodyError::NonFinite {
+                id: 1,
+                quantity: "force",
+                culprit: Culprit::External
+            })
+        ));
+
+        // A distant clump stands in for its particles, and the node gets the blame.
+        let clump: Vec<Point> = (0..8)
+            .map(|i| {
+                let corner = |bit: u64| 100. + (i >> bit & 1) as f64;
+                Point::new(1e155, corner(0), corner(1), corner(2), Vec3d::new_zero()).with_id(i)
+            })
+            .collect();
+        let bht = BHTree::from_points(0.5, clump).unwrap();
+        let heavy = at(99, 0.).with_mass(1e300);
+        assert!(!bht.force_on(heavy).is_finite());
+        match bht.blame(heavy, Vec3d::new_zero()) {
+            Culprit::Node { count, mass, x, .. } => {
+                assert_eq!(count, 8);
+                assert!((mass / 8e155 - 1.).abs() < 1e-12);
+                assert!((x - 100.5).abs() < 1e-9);
+            }
+            culprit => panic!("{:?}", culprit),
         }
+    }
 
-        self.add_to_child(p);
-        self.count = 0;
-        self.children.iter().for_each(|x| self.count += x.count);
-        return 0;
+    #[test]
+    fn test_buckets_sum_exactly() {
+        let points: Vec<Point> = (0..6)
+            .map(|i| {
+                let i = i as f64;
+                Point::new(1e9 * (i + 1.), i, i * i, -i, Vec3d::new_zero()).with_id(i as u64)
+            })
+            .collect();
+        let bht = BHTree::from_points(0.5, points.clone()).unwrap();
+        assert!(bht.root.children.is_empty());
+        for p in points.iter() {
+            let expected = points
+                .iter()
+                .filter(|q| q.id() != p.id())
+                .fold(Vec3d::new_zero(), |f, q| f + p.force_from(*q));
+            let force = bht.force_on(*p);
+            assert!(force.distance(expected).magnitude() < 1e-12 * expected.magnitude());
+        }
     }
 
-    fn add_to_child(&mut self, p: Point) -> i32 {
-        // There must be children if trying to add a point to one of them.
-        debug_assert!(!self.children.is_empty());
+    #[test]
+    fn test_depth_limit() {
+        // Far closer together than 32 halvings of the region can separate.
+        let points: Vec<Point> = (0..50)
+            .map(|i| {
+                let x = 1. + i as f64 * 1e-13;
+                Point::new(1., x, 1., 1., Vec3d::new_zero()).with_id(i)
+            })
+            .chain([Point::new(1., 3., 3., 3., Vec3d::new_zero()).with_id(50)])
+            .collect();
+        let leaves = Refinement {
+            bucket_size: 2,
+            max_depth: 32,
+        };
+        let mut bht = BHTree::new(0.5, 4., 0., 0., 0.).with_refinement(leaves);
+        for p in points.iter() {
+            bht.add_point(*p).unwrap();
+        }
+        assert_eq!(bht.len(), 51);
+        bht.check_invariants().unwrap();
 
-        let (x, y, z) = p.position();
-        for child in self.children.iter_mut() {
-            let xcontains = (child.xloc..(child.xloc + child.region_size)).contains(&x);
-            let ycontains = (child.yloc..(child.yloc + child.region_size)).contains(&y);
-            let zcontains = (child.zloc..(child.zloc + child.region_size)).contains(&z);
-            if !xcontains || !ycontains || !zcontains {
-                continue;
+        let mut depth = 0;
+        let mut node = &bht.root;
+        while let Some(child) = node.children.iter().find(|c| c.count >= 50) {
+            node = child;
+            depth += 1;
+        }
+        assert_eq!(depth, 32);
+        assert_eq!(node.items.len(), 50);
+    }
+
+    #[test]
+    fn test_tree_reuse() {
+        let grid: Vec<Point> = (0..125)
+            .map(|i| {
+                let (x, y, z) = ((i % 5) as f64, (i / 5 % 5) as f64, (i / 25) as f64);
+                Point::new(1., x + 0.1 * y, y + 0.1 * z, z + 0.1 * x, Vec3d::new_zero()).with_id(i)
+            })
+            .collect();
+        let build = |reuse| {
+            return BHTree::from_points(0.5, grid.clone())
+                .unwrap()
+                .with_force_law(ForceLaw::newtonian(1.))
+                .with_reuse(reuse);
+        };
+
+        // Slow enough that nothing leaves its leaf, so only the step limit forces a rebuild.
+        let reuse = TreeReuse {
+            drift: 0.1,
+            max_steps: 3,
+        };
+        let mut reused = build(Some(reuse));
+        let mut rebuilt = build(None);
+        let mut kept = vec![];
+        for _ in 0..8 {
+            let (next, stats) = reused.step(1e-3).unwrap();
+            kept.push(stats.tree_reused);
+            reused = next;
+            rebuilt = rebuilt.next(1e-3).unwrap();
+        }
+        assert_eq!(kept, vec![true, true, true, false, true, true, true, false]);
+        assert_eq!(reused.len(), 125);
+        assert_eq!(reused.stats().nodes, rebuilt.stats().nodes);
+        let by_id = |t: &BHTree| {
+            let mut points = t.points();
+            points.sort_by_key(|p| p.id());
+            return points;
+        };
+        // The trees group particles the same way but open different nodes, so the paths agree to
+        // within the opening criterion's error, a small fraction of the ~1e-4 each particle moves.
+        for (a, b) in by_id(&reused).iter().zip(by_id(&rebuilt).iter()) {
+            assert_eq!(a.id(), b.id());
+            assert!(a.distance_to(*b) < 1e-6);
+        }
+
+        // A particle carried out of its leaf forces a rebuild straight away.
+        let mut fast = grid.clone();
+        fast[0] = Point::new(1., 0., 0., 0., Vec3d::new(10., 0., 0.)).with_id(0);
+        let (_, stats) = BHTree::from_points(0.5, fast)
+            .unwrap()
+            .with_reuse(Some(reuse))
+            .step(0.3)
+            .unwrap();
+        assert!(!stats.tree_reused);
+    }
+
+    #[test]
+    fn test_compensated_summation() {
+        // Each light particle is 1e-17 of the heavy one, below half an ulp, so a plain running sum
+        // that starts from the heavy particle drops every one of them.
+        let law = ForceLaw::newtonian(1.);
+        let heavy = Point::new(1e20, 0., 0., 0., Vec3d::new_zero()).with_id(0);
+        let lights: Vec<Point> = (0..2000)
+            .map(|i| {
+                let (x, y) = (1. + (i % 50) as f64 / 50., 1. + (i / 50) as f64 / 50.);
+                Point::new(1e3, x, y, 1., Vec3d::new_zero()).with_id(i + 1)
+            })
+            .collect();
+        let points: Vec<Point> = [heavy].into_iter().chain(lights.iter().copied()).collect();
+        let probe = Point::new(1., -1., 0., 0., Vec3d::new_zero()).with_id(99_999);
+        let far = probe.with_position(-1e6, 0., 0.);
+        let exact = |p: Point| {
+            let light = lights
+                .iter()
+                .fold(Vec3d::new_zero(), |f, q| f + law.force(p, *q));
+            return light + law.force(p, heavy);
+        };
+        let mass = 1e20 + 2e6;
+
+        // One leaf holding everything, filled heavy particle first.
+        let tree = |theta: f64, compensated: bool| {
+            let mut bht = BHTree::fitted(theta, &points)
+                .with_force_law(law)
+                .with_compensated_summation(compensated)
+                .with_refinement(Refinement {
+                    bucket_size: points.len(),
+                    ..Default::default()
+                });
+            for p in points.iter() {
+                bht.add_point(*p).unwrap();
             }
+            return bht;
+        };
+        let (plain, compensated) = (tree(0., false), tree(0., true));
+        assert!(compensated.compensated_summation());
+        let lost = (exact(probe) - law.force(probe, heavy)).magnitude();
+        let error = |bht: &BHTree| (bht.force_on(probe) - exact(probe)).magnitude();
+        assert!(error(&plain) > 0.5 * lost);
+        assert!(error(&compensated) < 1e-6 * lost);
+        assert_eq!(plain.root.center_of_mass().mass(), 1e20);
+        assert!((compensated.root.center_of_mass().mass() - mass).abs() < 1e-15 * mass);
+
+        // Far away the whole tree stands in as its centre of mass, whose mass is off by as much.
+        let (plain, compensated) = (tree(1., false), tree(1., true));
+        let lost = (exact(far) - law.force(far, heavy)).magnitude();
+        let error = |bht: &BHTree| (bht.force_on(far) - exact(far)).magnitude();
+        assert!(error(&plain) > 0.5 * lost);
+        assert!(error(&compensated) < 1e-3 * lost);
+
+        // Bulk building and a rebuild keep the sums.
+        let bulk = BHTree::fitted(1., &points)
+            .with_compensated_summation(true)
+            .with_points(points.clone())
+            .unwrap()
+            .rebuild(points.clone())
+            .unwrap();
+        bulk.check_invariants().unwrap();
+        assert!((bulk.root.center_of_mass().mass() - mass).abs() < 1e-15 * mass);
+    }
+
+    #[test]
+    fn test_self_exclusion_by_id() {
+        let law = ForceLaw::newtonian(1.);
+        let a = Point::new(1., 0., 0., 0., Vec3d::new_zero()).with_id(0);
+        let b = Point::new(1., 1., 0., 0., Vec3d::new_zero()).with_id(1);
+        // Theta is large enough that the root would be accepted as a point mass, self included.
+        let bht = BHTree::from_points(10., vec![a, b])
+            .unwrap()
+            .with_force_law(law);
+        assert_eq!(bht.force_on(a), law.force(a, b));
+        assert_eq!(bht.force_on(b), law.force(b, a));
+        assert_eq!(bht.potential_at(a), law.potential(1., 1., 1.));
+
+        // Anything carrying a particle's id stands in for it, wherever it is.
+        let moved = a.with_position(0.5, 0.5, 0.);
+        assert_eq!(bht.force_on(moved), law.force(moved, b));
+    }
+
+    #[test]
+    fn test_duplicate_ids_rejected() {
+        // Points that were never given ids all have id 0, and would each leave the others out.
+        let points = vec![
+            Point::new(1., 0., 0., 0., Vec3d::new_zero()),
+            Point::new(1., 1., 0., 0., Vec3d::new_zero()),
+        ];
+        assert!(matches!(
+            BHTree::from_points(0.5, points.clone()),
+            Err(NbodyError::DuplicateId(0))
+        ));
+        let mut bht = BHTree::new(0.5, 4., 0., 0., 0.);
+        bht.add_point(points[0]).unwrap();
+        assert!(matches!(
+            bht.add_point(points[1]),
+            Err(NbodyError::DuplicateId(0))
+        ));
+        let tracer = points[1].with_mass(0.);
+        assert!(matches!(
+            BHTree::from_points(0.5, vec![points[0], tracer]),
+            Err(NbodyError::DuplicateId(0))
+        ));
+
+        // Once they have their own ids, each feels the other.
+        let mut bht = BHTree::from_points(0.5, vec![points[0], points[1].with_id(1)]).unwrap();
+        assert!(bht.force_on(points[0]).magnitude() > 0.);
 
-            return child.add_point(p);
+        // Points added one at a time are checked against those built in bulk, and against the
+        // survivors of any coincident merge.
+        let elsewhere = |q: &Point| q.with_position(2., 2., 0.);
+        assert!(matches!(
+            bht.add_point(elsewhere(&points[1].with_id(1))),
+            Err(NbodyError::DuplicateId(1))
+        ));
+        bht.add_point(points[0].with_id(2)).unwrap();
+        assert_eq!(bht.len(), 2);
+        for q in bht.points() {
+            assert!(bht.add_point(elsewhere(&q)).is_err());
         }
+        bht.add_point(elsewhere(&points[0].with_id(3))).unwrap();
+        bht.check_invariants().unwrap();
+    }
 
-        warn!(
-            "point {:?} not contained in any children within range starting @ ({},{},{}) with region size {}",
-            p, self.xloc,self.yloc,self.zloc,   self.region_size,
-        );
-        return 0;
+    #[test]
+    fn test_opening_accounts_for_com_offset() {
+        // The heavy particle pulls the centre of mass of the lower octant into its far corner,
+        // 2.57 from the probe, while the light one sits 1.55 from it. Size over distance alone is
+        // 0.78, below theta, so the octant would be taken as a point mass.
+        let law = ForceLaw::newtonian(1.);
+        let points = [
+            Point::new(10., 1.9, 1.9, 1.9, Vec3d::new_zero()).with_id(0),
+            Point::new(1., 1.05, 1.05, 1.05, Vec3d::new_zero()).with_id(1),
+            Point::new(1., 3., 3., 3., Vec3d::new_zero()).with_id(2),
+        ];
+        let mut bht = BHTree::new(0.8, 4., 0., 0., 0.).with_force_law(law);
+        for p in points.iter() {
+            bht.add_point(*p).unwrap();
+        }
+        let probe = Point::new(1., -0.5, 1.05, 1.05, Vec3d::new_zero()).with_id(9);
+
+        let exact = points
+            .iter()
+            .fold(Vec3d::new_zero(), |f, q| f + law.force(probe, *q));
+        let c = (19. + 1.05) / 11.;
+        let octant = Point::new(11., c, c, c, Vec3d::new_zero());
+        let monopole = law.force(probe, octant) + law.force(probe, points[2]);
+        assert!((monopole - exact).magnitude() > 0.05 * exact.magnitude());
+        assert!((bht.force_on(probe) - exact).magnitude() < 1e-3 * exact.magnitude());
     }
 
-    fn split(&mut self) {
-        // If we're splitting, there should not be children already.
-        debug_assert!(self.children.is_empty());
+    #[test]
+    fn test_opening_criteria() {
+        // Light particles on a grid with one heavy one among them, which the geometric test
+        // underestimates the error of.
+        let points: Vec<Point> = (0..216)
+            .map(|i| {
+                let (x, y, z) = ((i % 6) as f64, (i / 6 % 6) as f64, (i / 36) as f64);
+                let mass = if i == 100 { 1000. } else { 1. };
+                Point::new(
+                    mass,
+                    x + 0.1 * y,
+                    y + 0.1 * z,
+                    z + 0.1 * x,
+                    Vec3d::new_zero(),
+                )
+                .with_id(i)
+            })
+            .collect();
+        let tree = |theta, opening| {
+            return BHTree::from_points(theta, points.clone())
+                .unwrap()
+                .with_refinement(Refinement {
+                    bucket_size: 1,
+                    max_depth: 32,
+                })
+                .with_force_law(ForceLaw::newtonian(1.))
+                .with_opening(opening);
+        };
+        let exact = tree(0., OpeningCriterion::Geometric);
+        let worst = |t: &BHTree| {
+            return points
+                .iter()
+                .map(|p| (t.force_on(*p) - exact.force_on(*p)).magnitude() / p.mass())
+                .fold(0., f64::max);
+        };
 
-        self.children.reserve(8);
-        let child_region = self.region_size / 2.0;
-        for x in [self.xloc, self.xloc + child_region] {
-            for y in [self.yloc, self.yloc + child_region] {
-                for z in [self.zloc, self.zloc + child_region] {
-                    self.children
-                        .push(BHNode::new(self.theta, child_region, x, y, z))
-                }
+        let geometric = worst(&tree(0.7, OpeningCriterion::Geometric));
+        assert!(geometric > 0.1);
+        // The bounds are per node, and a particle accepts several, so the total can exceed them a
+        // little.
+        for tolerance in [1e-1, 1e-3] {
+            for opening in [
+                OpeningCriterion::AbsoluteError {
+                    tolerance: tolerance,
+                },
+                OpeningCriterion::SalmonWarren {
+                    tolerance: tolerance,
+                },
+            ] {
+                let error = worst(&tree(0.7, opening));
+                assert!(error < 2. * tolerance, "{:?}: {}", opening, error);
             }
         }
-        debug_assert_eq!(self.children.len(), 8);
+
+        // Tightening theta only near the root catches much of the error for fewer interactions
+        // than tightening it everywhere.
+        let cost = |t: &BHTree| points.iter().map(|p| t.force_and_cost(*p).1).sum::<u32>();
+        let ramp =
+            tree(0.7, OpeningCriterion::Geometric).with_theta_schedule(ThetaSchedule::Ramp {
+                root: 0.5,
+                levels: 3,
+            });
+        let strict = tree(0.35, OpeningCriterion::Geometric);
+        assert!(worst(&ramp) < 0.75 * geometric, "{}", worst(&ramp));
+        assert!(cost(&ramp) < cost(&strict));
     }
 
-    fn get_points(&self) -> Vec<Point> {
-        if self.children.is_empty() {
-            return vec![self.point.expect("point")];
+    #[test]
+    fn test_add_points_matches_add_point() {
+        let mut points: Vec<Point> = (0..500)
+            .map(|i| {
+                let coord = |k: u64| ((i * k) % 1000) as f64 / 100.;
+                Point::new(
+                    1. + coord(13),
+                    coord(7919),
+                    coord(104729),
+                    coord(15485863),
+                    Vec3d::new_zero(),
+                )
+                .with_id(i)
+            })
+            .collect();
+        // A coincident pair, a tracer and a point the region has to grow for.
+        points.push(points[10].with_id(500));
+        points.push(Point::new(0., 1., 1., 1., Vec3d::new_zero()).with_id(501));
+        points.push(Point::new(1., 25., -3., 4., Vec3d::new_zero()).with_id(502));
+
+        let mut one_by_one = BHTree::new(0.5, 10., 0., 0., 0.);
+        for p in points.iter() {
+            one_by_one.add_point(*p).unwrap();
         }
+        let mut bulk = BHTree::new(0.5, 10., 0., 0., 0.);
+        bulk.add_points(points[..250].to_vec()).unwrap();
+        bulk.add_points(points[250..].to_vec()).unwrap();
+        bulk.check_invariants().unwrap();
 
-        // We're dealing with a branch node.
-        let mut v: Vec<Point> = Vec::new();
-        for c in self.children.iter() {
-            if c.count > 0 {
-                v.append(&mut c.get_points());
+        assert_eq!(bulk.len(), 502);
+        assert_eq!(bulk.graph_size, one_by_one.graph_size);
+        // Same shape, with leaves allocated to fit.
+        let (shape, expected) = (bulk.stats(), one_by_one.stats());
+        assert!(shape.memory_bytes <= expected.memory_bytes);
+        assert_eq!(
+            TreeStats {
+                memory_bytes: 0,
+                ..shape
+            },
+            TreeStats {
+                memory_bytes: 0,
+                ..expected
             }
+        );
+        let by_id = |t: &BHTree| {
+            let mut points = t.points();
+            points.sort_by_key(|p| p.id());
+            return points;
+        };
+        assert_eq!(by_id(&bulk), by_id(&one_by_one));
+        let com = one_by_one.center_of_mass();
+        assert!(bulk.center_of_mass().distance_to(com) < 1e-12);
+        for p in points[..500].iter().step_by(50) {
+            let (a, b) = (bulk.force_on(*p), one_by_one.force_on(*p));
+            assert!((a - b).magnitude() <= 1e-9 * b.magnitude());
         }
 
-        return v;
+        // Walls don't move, so anything outside them is an error.
+        let mut walled = BHTree::new_bounded(0.5, Boundary::Reflective { box_size: 2. });
+        let outside = Point::new(1., 3., 0., 0., Vec3d::new_zero()).with_id(3);
+        assert!(matches!(
+            walled.add_points(vec![outside]),
+            Err(NbodyError::OutOfBounds { id: 3, .. })
+        ));
     }
-}
 
-#[cfg(test)]
-mod test_bht {
-    use crate::geometry::bh_tree::{BHTree, Point, Vec3d};
+    #[test]
+    fn test_iter_borrows_points_in_order() {
+        let empty = BHTree::new(0.5, 4., 0., 0., 0.);
+        assert_eq!(empty.iter().count(), 0);
+
+        let points: Vec<Point> = (0..40)
+            .map(|i| {
+                Point::new(1., i as f64 * 0.1, (i % 3) as f64, 0.5, Vec3d::new_zero()).with_id(i)
+            })
+            .chain([Point::new(0., 1., 1., 1., Vec3d::new_zero()).with_id(40)])
+            .collect();
+        let bht = BHTree::from_points(0.5, points)
+            .unwrap()
+            .with_refinement(Refinement {
+                bucket_size: 2,
+                max_depth: 32,
+            });
+        let bht = bht.rebuild(bht.points()).unwrap();
+        assert_eq!(bht.iter().count(), bht.len());
+        assert!(bht.iter().copied().eq(bht.points()));
+        // Tracers come after the particles in the nodes.
+        assert_eq!(bht.iter().last().unwrap().id(), 40);
+    }
 
     #[test]
-    fn starts_with_0com() {
-        let bht = BHTree::new(0.5, 1e10, 0., 0., 0.);
-        assert_eq!(bht.root.center_of_mass, Point::new_zero());
+    fn test_stats_and_dumps() {
+        let mut bht = BHTree::new(0.7, 4., 0., 0., 0.).with_refinement(Refinement {
+            bucket_size: 2,
+            max_depth: 32,
+        });
+        let empty = bht.stats();
+        assert_eq!((empty.nodes, empty.leaves, empty.depth), (1, 1, 0));
+        assert_eq!(empty.occupancy, vec![1]);
+
+        // Three in one octant overflow its bucket, the fourth sits alone in the opposite one.
+        for (i, (x, y, z)) in [
+            (0.5, 0.5, 0.5),
+            (0.6, 0.5, 0.5),
+            (1.5, 1.5, 1.5),
+            (3., 3., 3.),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            let p = Point::new(1., x, y, z, Vec3d::new_zero()).with_id(i as u64);
+            bht.add_point(p).unwrap();
+        }
+
+        let stats = bht.stats();
+        assert_eq!(stats.theta, 0.7);
+        assert_eq!(stats.nodes, 17);
+        assert_eq!(stats.leaves, 15);
+        assert_eq!(stats.depth, 2);
+        assert_eq!(stats.occupancy, vec![12, 2, 1]);
+        assert_eq!(
+            stats
+                .occupancy
+                .iter()
+                .enumerate()
+                .map(|(k, n)| k * n)
+                .sum::<usize>(),
+            bht.len()
+        );
+        assert!(stats.memory_bytes > 17 * std::mem::size_of::<BHNode>());
+
+        let mut dot = vec![];
+        bht.write_dot(&mut dot).unwrap();
+        let dot = String::from_utf8(dot).unwrap();
+        assert!(dot.starts_with("digraph bhtree {"));
+        // Only non-empty nodes are drawn: the root, two octants and two of the eight below.
+        assert_eq!(dot.matches(" -> ").count(), 4);
+        assert!(dot.contains("count 4 mass 4.000e0"));
+
+        let mut json = vec![];
+        bht.write_nodes_json(&mut json).unwrap();
+        let nodes: serde_json::Value = serde_json::from_slice(&json).unwrap();
+        assert_eq!(nodes["count"], 4);
+        assert_eq!(nodes["children"].as_array().unwrap().len(), 8);
     }
 
     #[test]
-    fn test_add_point() {
-        use rand::{thread_rng, Rng};
-        let mut rng = thread_rng();
-        for i in 1..100 {
-            let mut bht = BHTree::new(1.0 / (i as f64), rng.gen_range(1.0..1337.), 0., 0., 0.);
-            let pt = Point::new(1.0, 2.0, 2.0, 2.0, Vec3d::new_zero());
-            bht.add_point(pt);
-            assert_eq!(bht.root.xloc, 0.0);
-            assert_eq!(bht.root.yloc, 0.0);
-            assert_eq!(bht.root.zloc, 0.0);
-            assert_eq!(bht.root.zloc, 0.0);
-            assert_eq!(bht.root.center_of_mass(), pt);
+    fn test_outside_particles_use_direct_summation() {
+        let points = vec![
+            Point::new(1e9, 0., 0., 0., Vec3d::new_zero()).with_id(0),
+            Point::new(1e9, 1., 0., 0., Vec3d::new_zero()).with_id(1),
+            Point::new(1e9, 1e6, 0., 0., Vec3d::new_zero()).with_id(2),
+        ];
+        let bht = BHTree::from_points(0.5, vec![])
+            .unwrap()
+            .with_escapers(EscaperPolicy::Outside { radius: 100. })
+            .rebuild(points.clone())
+            .unwrap();
+        assert_eq!(bht.len(), 3);
+        assert_eq!(bht.root.count, 2);
+        assert!(bht.root.region_size < 10.);
 
-            let pt2 = Point::new(1.0, 0.0, 0.0, 0.0, Vec3d::new_zero());
-            bht.add_point(pt2);
-            let expected = Point::new(2.0, 1., 1., 1., Vec3d::new_zero());
-            assert_eq!(bht.root.center_of_mass(), expected);
+        // The far particle still feels, and exerts, gravity.
+        let far = bht.force_on(points[2]);
+        let expected = points[2].force_from(points[0]) + points[2].force_from(points[1]);
+        assert!(far.distance(expected).magnitude() < 1e-9 * expected.magnitude());
+        let near = bht.force_on(points[0]);
+        let expected = BHTree::from_points(0.5, points[..2].to_vec())
+            .unwrap()
+            .force_on(points[0])
+            + points[0].force_from(points[2]);
+        assert!(near.distance(expected).magnitude() < 1e-9 * expected.magnitude());
+    }
 
-            let pt3 = Point::new(2.0, 3.0, 3.0, 3.0, Vec3d::new_zero());
-            bht.add_point(pt3);
-            let expected = Point::new(4.0, 2., 2., 2., Vec3d::new_zero());
-            assert_eq!(bht.root.center_of_mass(), expected);
-        }
+    #[test]
+    fn test_tracers_feel_but_exert_no_gravity() {
+        let points = vec![
+            Point::new(1e12, 0., 0., 0., Vec3d::new_zero()).with_id(0),
+            Point::new(1e12, 1., 0., 0., Vec3d::new_zero()).with_id(1),
+            Point::new(0., 100., 0., 0., Vec3d::new_zero()).with_id(2),
+        ];
+        let bht = BHTree::from_points(0.9, points).unwrap();
+        assert_eq!(bht.len(), 3);
+        assert_eq!(bht.root.count, 2);
+        assert_eq!(bht.root.center_of_mass().position(), (0.5, 0., 0.));
+
+        let next = bht.next(1.).unwrap();
+        let points = next.points();
+        let tracer = points.iter().find(|p| p.id() == 2).unwrap();
+        assert!(tracer.is_tracer());
+        let (x, _, _) = tracer.position();
+        assert!(x < 100.);
     }
 
     #[test]
-    fn test_step_calculation() {
-        let mut bht = BHTree::new(0.5, 5., 0., 0., 0.);
-        let pt = Point::new(1e9, 2.0, 2.0, 2.0, Vec3d::new_zero());
-        bht.add_point(pt);
-        let pt = Point::new(1e9, 0.0, 0.0, 0.0, Vec3d::new_zero());
-        bht.add_point(pt);
+    fn test_close_pair_follows_kepler_orbit() {
+        // Equal masses on a circular orbit about their common COM, far from a distant third body.
+        let m = 1e12;
+        let mu = G * 2. * m;
+        let v = (mu / 2.).sqrt() / 2.;
+        let points = vec![
+            Point::new(m, -0.5, 0., 0., Vec3d::new(0., -v, 0.)).with_id(0),
+            Point::new(m, 0.5, 0., 0., Vec3d::new(0., v, 0.)).with_id(1),
+            Point::new(1., 1e6, 0., 0., Vec3d::new_zero()).with_id(2),
+        ];
+        let bht = BHTree::from_points(0.5, points)
+            .unwrap()
+            .with_encounter_radius(Some(5.));
+        let (next, stats) = bht.step(10.).unwrap();
+        assert_eq!(stats.regularized_pairs, 1);
 
-        for _ in 1..100 {
-            bht = bht.next(1.0);
-        }
+        let (r, _) = kepler::advance(Vec3d::new(1., 0., 0.), Vec3d::new(0., 2. * v, 0.), mu, 10.);
+        let points = next.points();
+        let a = points.iter().find(|p| p.id() == 0).unwrap();
+        let b = points.iter().find(|p| p.id() == 1).unwrap();
+        let (ax, ay, az) = a.position();
+        let (bx, by, bz) = b.position();
+        let sep = Vec3d::new(bx - ax, by - ay, bz - az);
+        assert!(sep.distance(r).magnitude() < 1e-9, "{} vs {}", sep, r);
+        assert!(((ax + bx) / 2.).abs() < 1e-9 && ((ay + by) / 2.).abs() < 1e-9);
     }
 
     #[test]
-    fn serdes_test() {
-        let pt = Point::new(1e9, 2.0, 2.0, 2.0, Vec3d::new_zero());
-        let mut bht = BHTree::new(0.5, 5.0, 0., 0., 0.);
-        let pt2 = Point::new(1e9, 1.0, 2.0, 1.0, Vec3d::new_zero());
-        bht.add_point(pt);
-        bht.add_point(pt2);
-        let serialized = serde_json::to_string_pretty(&bht).unwrap();
-        let bht2: BHTree = serde_json::from_str(&serialized).unwrap();
-        let rt_serialized = serde_json::to_string_pretty(&bht2).unwrap();
-        assert_eq!(serialized, rt_serialized);
+    fn test_pinned_point_stays_put() {
+        let anchor = Point::new(1e12, 0., 0., 0., Vec3d::new(1., 0., 0.))
+            .with_id(0)
+            .with_pinned(true);
+        let orbiter = Point::new(1., 10., 0., 0., Vec3d::new(0., 1., 0.)).with_id(1);
+        let mut bht = BHTree::from_points(0.5, vec![anchor, orbiter]).unwrap();
+        for _ in 0..5 {
+            bht = bht.next(0.1).unwrap();
+        }
+
+        let points = bht.points();
+        let anchor = points.iter().find(|p| p.id() == 0).unwrap();
+        let orbiter = points.iter().find(|p| p.id() == 1).unwrap();
+        assert!(anchor.pinned());
+        assert_eq!(anchor.position(), (0., 0., 0.));
+        assert_ne!(orbiter.position(), (10., 0., 0.));
     }
 
     #[test]
-    fn merge_test() {
-        let mut bht = BHTree::new(0.5, 5., 0., 0., 0.);
+    fn test_step_scratch() {
+        let points: Vec<Point> = (0..300)
+            .map(|i| {
+                let a = i as f64 * 0.7;
+                Point::new(
+                    1e10,
+                    a.cos() * i as f64,
+                    a.sin() * i as f64,
+                    (i % 7) as f64,
+                    Vec3d::new_zero(),
+                )
+                .with_id(i)
+            })
+            .collect();
+        let mut plain = BHTree::from_points(0.5, points.clone()).unwrap();
+        let mut reused = BHTree::from_points(0.5, points).unwrap();
+        let mut scratch = StepScratch::new();
+        for _ in 0..4 {
+            plain = plain.next(1.).unwrap();
+            let (next, _) = reused
+                .step_in(1., &mut scratch, |_| Vec3d::new_zero())
+                .unwrap();
+            // The buffers survive the step, and the spent tree's nodes are kept for the next.
+            assert_eq!(scratch.points.len(), 300);
+            assert!(scratch.nodes.buckets.is_empty());
+            scratch.recycle(std::mem::replace(&mut reused, next));
+            assert!(!scratch.nodes.buckets.is_empty() && !scratch.nodes.children.is_empty());
+        }
+        assert_eq!(reused.points(), plain.points());
+        reused.check_invariants().unwrap();
 
-        assert_eq!(bht.root.count, 0);
-        bht.add_point(Point::new(1., 0., 0., 0., Vec3d::new_zero()));
-        assert_eq!(bht.root.count, 1);
-        bht.add_point(Point::new(1., 1., 0., 0., Vec3d::new_zero()));
-        assert_eq!(bht.root.count, 2);
+        // Rows come out the same, and their buffer goes back for the next snapshot.
+        let mut expected = vec![];
+        reused.write_csv(&mut expected).unwrap();
+        for _ in 0..2 {
+            let written = Arc::new(Mutex::new(vec![]));
+            let sink = written.clone();
+            let finish = move |w| {
+                *sink.lock().unwrap() = w;
+                Ok(())
+            };
+            let handle = reused
+                .write_csv_with(
+                    vec![],
+                    "rows".to_string(),
+                    |_| true,
+                    vec![],
+                    finish,
+                    &scratch,
+                )
+                .unwrap();
+            handle.join().unwrap().unwrap();
+            assert_eq!(*written.lock().unwrap(), expected);
+            assert_eq!(scratch.rows.lock().unwrap().len(), 1);
+        }
+    }
 
-        bht.add_point(Point::new(1e99, 1., 1., 0., Vec3d::new_zero()));
-        assert_eq!(bht.root.count, 3);
+    // A particle's mass and position. Coordinates are drawn from a coarse grid some of the time,
+    // so that coincident particles, which the tree merges, turn up regularly.
+    fn particle() -> impl Strategy<Value = (f64, f64, f64, f64)> {
+        let coordinate = prop_oneof![-1e3..1e3_f64, (-2..2_i32).prop_map(|i| i as f64)];
+        return (
+            1e-3..1e3_f64,
+            coordinate.clone(),
+            coordinate.clone(),
+            coordinate,
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn prop_tree_invariants(
+            particles in prop::collection::vec(particle(), 1..200),
+            bucket_size in 1..10_usize,
+            max_depth in 1..40_usize,
+            bulk in any::<bool>(),
+        ) {
+            let points: Vec<Point> = particles
+                .iter()
+                .enumerate()
+                .map(|(i, &(m, x, y, z))| Point::new(m, x, y, z, Vec3d::new_zero()).with_id(i as u64))
+                .collect();
+            let mut bht = BHTree::new(0.5, 1., 0., 0., 0.).with_refinement(Refinement {
+                bucket_size: bucket_size,
+                max_depth: max_depth,
+            });
+            if bulk {
+                bht.add_points(points.clone()).unwrap();
+            } else {
+                for p in points.iter() {
+                    bht.add_point(*p).unwrap();
+                }
+            }
+            prop_assert!(bht.check_invariants().is_ok());
 
-        // This should merge everything into a single node.
-        let next = bht.next(1.);
-        assert_eq!(next.root.count, 1, "wtf");
+            // Particles at the same position merge into one that keeps one of their ids.
+            let mut groups: HashMap<(u64, u64, u64), Vec<Point>> = HashMap::new();
+            for p in points.iter() {
+                let (x, y, z) = p.position();
+                groups.entry((x.to_bits(), y.to_bits(), z.to_bits())).or_default().push(*p);
+            }
+            prop_assert_eq!(bht.len(), groups.len());
+            let by_id: HashMap<u64, Point> = bht.iter().map(|p| (p.id(), *p)).collect();
+            prop_assert_eq!(by_id.len(), groups.len());
+            for group in groups.values() {
+                let found: Vec<&Point> = group.iter().filter_map(|p| by_id.get(&p.id())).collect();
+                prop_assert_eq!(found.len(), 1);
+                let mass: f64 = group.iter().map(|p| p.mass()).sum();
+                prop_assert!((found[0].mass() - mass).abs() <= 1e-12 * mass);
+                prop_assert!(found[0].distance_to(group[0]) <= 1e-12 * (1. + found[0].distance_to(Point::new_zero())));
+            }
+
+            let total: f64 = points.iter().map(|p| p.mass()).sum();
+            let com = bht.center_of_mass();
+            prop_assert!((com.mass() - total).abs() <= 1e-12 * total);
+            let expected = points
+                .iter()
+                .fold(Vec3d::new_zero(), |acc, p| {
+                    let (x, y, z) = p.position();
+                    acc + p.mass() * Vec3d::new(x, y, z)
+                })
+                / total;
+            let (x, y, z) = com.position();
+            prop_assert!((Vec3d::new(x, y, z) - expected).magnitude() <= 1e-9 * (1. + expected.magnitude()));
+        }
     }
 }
@@ -0,0 +1,108 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{Point, Vec3d};
+
+/// Boundary conditions on the cube [-box_size / 2, box_size / 2)^3.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum Boundary {
+    // No walls; the tree grows to cover wherever the particles go.
+    #[default]
+    Open,
+    // Particles leaving through one face come back in through the opposite one, and forces use
+    // the nearest periodic image.
+    Periodic {
+        box_size: f64,
+    },
+    // Walls reflect particles elastically.
+    Reflective {
+        box_size: f64,
+    },
+    // Walls remove any particle that reaches them.
+    Absorbing {
+        box_size: f64,
+    },
+}
+
+impl Boundary {
+    pub fn box_size(&self) -> Option<f64> {
+        return match *self {
+            Boundary::Open => None,
+            Boundary::Periodic { box_size }
+            | Boundary::Reflective { box_size }
+            | Boundary::Absorbing { box_size } => Some(box_size),
+        };
+    }
+
+    // Side of the box if it's periodic.
+    pub fn periodic(&self) -> Option<f64> {
+        return match *self {
+            Boundary::Periodic { box_size } => Some(box_size),
+            _ => None,
+        };
+    }
+
+    // Brings a particle that has just moved back inside the box, or returns None if the walls
+    // absorbed it.
+    pub fn apply(&self, p: Point) -> Option<Point> {
+        let (x, y, z) = p.position();
+        return match *self {
+            Boundary::Open => Some(p),
+            Boundary::Periodic { box_size } => Some(p.wrapped(box_size)),
+            Boundary::Absorbing { box_size } => {
+                let half = box_size / 2.;
+                let inside = |v: f64| (-half..half).contains(&v);
+                (inside(x) && inside(y) && inside(z)).then_some(p)
+            }
+            Boundary::Reflective { box_size } => {
+                let half = box_size / 2.;
+                let (vx, vy, vz) = p.velocity().position();
+                let (x, vx) = reflect(x, vx, half);
+                let (y, vy) = reflect(y, vy, half);
+                let (z, vz) = reflect(z, vz, half);
+                Some(
+                    p.with_position(x, y, z)
+                        .with_velocity(Vec3d::new(vx, vy, vz)),
+                )
+            }
+        };
+    }
+}
+
+// Folds a coordinate back into [-half, half), reversing the velocity component once per bounce.
+fn reflect(v: f64, vel: f64, half: f64) -> (f64, f64) {
+    // Unfolded, the motion repeats every two box widths, going forwards then mirrored.
+    let u = (v + half).rem_euclid(4. * half);
+    let (v, vel) = if u < 2. * half {
+        (u - half, vel)
+    } else {
+        (3. * half - u, -vel)
+    };
+    // A particle sitting exactly on the upper wall isn't inside the half-open box.
+    return (v.min(half * (1. - f64::EPSILON)), vel);
+}
+
+#[cfg(test)]
+mod test {
+    use crate::geometry::boundary::Boundary;
+    use crate::{Point, Vec3d};
+
+    #[test]
+    fn test_walls() {
+        let p = Point::new(1., 6., -1., 0., Vec3d::new(2., -1., 0.));
+        assert_eq!(Boundary::Open.apply(p), Some(p));
+        assert_eq!(Boundary::Absorbing { box_size: 10. }.apply(p), None);
+        let inside = p.with_position(4., -1., 0.);
+        assert_eq!(
+            Boundary::Absorbing { box_size: 10. }.apply(inside),
+            Some(inside)
+        );
+
+        let reflected = Boundary::Reflective { box_size: 10. }.apply(p).unwrap();
+        assert_eq!(reflected.position(), (4., -1., 0.));
+        assert_eq!(reflected.velocity().position(), (-2., -1., 0.));
+
+        let wrapped = Boundary::Periodic { box_size: 10. }.apply(p).unwrap();
+        assert_eq!(wrapped.position(), (-4., -1., 0.));
+        assert_eq!(wrapped.velocity(), p.velocity());
+    }
+}
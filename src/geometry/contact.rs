@@ -0,0 +1,114 @@
+use std::f64::consts::PI;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{Point, Vec3d};
+
+/// What happens when two particles touch.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum CollisionOutcome {
+    Merge,
+    // Reflect the approaching velocity components along the line of centres, scaled by the
+    // coefficient of restitution: 1 is elastic, 0 perfectly inelastic.
+    Bounce { restitution: f64 },
+    PassThrough,
+}
+
+/// Physical sizes of particles and the outcome of their collisions. A particle's radius is its
+/// explicit radius if it has one, or else that of a uniform sphere of `density` if set. Particles
+/// the tree's `MergePolicy` picks out always merge, whatever the outcome.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct CollisionModel {
+    pub outcome: CollisionOutcome,
+    pub density: Option<f64>,
+}
+
+impl Default for CollisionModel {
+    fn default() -> CollisionModel {
+        return CollisionModel {
+            outcome: CollisionOutcome::Merge,
+            density: None,
+        };
+    }
+}
+
+impl CollisionModel {
+    pub fn radius(&self, p: &Point) -> f64 {
+        if p.radius() > 0. {
+            return p.radius();
+        }
+        return match self.density {
+            Some(rho) => (3. * p.mass() / (4. * PI * rho)).cbrt(),
+            None => 0.,
+        };
+    }
+
+    // True if the two particles overlap physically.
+    pub fn touching(&self, a: &Point, b: &Point) -> bool {
+        let reach = self.radius(a) + self.radius(b);
+        return reach > 0. && a.distance_to(*b) <= reach;
+    }
+}
+
+// Velocities after a collision along the line of centres with the given restitution. Particles
+// that are already separating are returned unchanged.
+pub fn bounce(a: Point, b: Point, restitution: f64) -> (Point, Point) {
+    let (ax, ay, az) = a.position();
+    let (bx, by, bz) = b.position();
+    let d = Vec3d::new(bx, by, bz) - Vec3d::new(ax, ay, az);
+    let dist = d.magnitude();
+    if dist == 0. {
+        return (a, b);
+    }
+    let n = d / dist;
+    let closing = a.velocity().dot(n) - b.velocity().dot(n);
+    if closing <= 0. {
+        return (a, b);
+    }
+
+    let total = a.mass() + b.mass();
+    let impulse = (1. + restitution) * closing / total;
+    let va = a.velocity() - (impulse * b.mass()) * n;
+    let vb = b.velocity() + (impulse * a.mass()) * n;
+    return (a.with_velocity(va), b.with_velocity(vb));
+}
+
+#[cfg(test)]
+mod test {
+    use crate::geometry::contact::{bounce, CollisionModel, CollisionOutcome};
+    use crate::{Point, Vec3d};
+
+    #[test]
+    fn test_bounce() {
+        let a = Point::new(1., 0., 0., 0., Vec3d::new(1., 0., 0.));
+        let b = Point::new(1., 1., 0., 0., Vec3d::new(-1., 0., 0.));
+
+        // Equal masses swap velocities in a head-on elastic collision.
+        let (a2, b2) = bounce(a, b, 1.);
+        assert_eq!(a2.velocity().position(), (-1., 0., 0.));
+        assert_eq!(b2.velocity().position(), (1., 0., 0.));
+
+        // A perfectly inelastic one leaves them moving together.
+        let (a2, b2) = bounce(a, b, 0.);
+        assert_eq!(a2.velocity(), b2.velocity());
+
+        // Separating particles are left alone.
+        let (a2, b2) = bounce(a.with_velocity(-a.velocity()), b, 0.5);
+        assert_eq!(a2.velocity().position(), (-1., 0., 0.));
+        assert_eq!(b2.velocity(), b.velocity());
+    }
+
+    #[test]
+    fn test_radii() {
+        let model = CollisionModel {
+            outcome: CollisionOutcome::Merge,
+            density: Some(3. / (4. * std::f64::consts::PI)),
+        };
+        let a = Point::new(8., 0., 0., 0., Vec3d::new_zero());
+        let b = Point::new(1., 2.4, 0., 0., Vec3d::new_zero()).with_radius(0.5);
+        assert!((model.radius(&a) - 2.).abs() < 1e-12);
+        assert_eq!(model.radius(&b), 0.5);
+        assert!(model.touching(&a, &b));
+        assert!(!CollisionModel::default().touching(&a, &b));
+    }
+}
@@ -1,16 +1,166 @@
-/*
-use crate::geometry::bh_tree::BHTree;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
 use log::info;
-use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
 use sled::Db;
 
+use crate::geometry::bh_tree::{BHTree, TreeReuse};
+use crate::geometry::boundary::Boundary;
+use crate::geometry::contact::CollisionModel;
+use crate::geometry::escape::EscaperPolicy;
+use crate::geometry::force::ForceLaw;
+use crate::geometry::octree::Refinement;
+use crate::geometry::opening::{OpeningCriterion, ThetaSchedule};
+use crate::geometry::species::SpeciesTable;
+use crate::{NbodyError, Point};
+
+/// Full simulation state at the start of `step`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Snapshot {
+    pub step: u64,
+    pub time: f64,
+    pub theta: f64,
+    // Only set by snapshots that predate `boundary`.
+    #[serde(default)]
+    pub periodic: Option<f64>,
+    #[serde(default)]
+    pub boundary: Boundary,
+    #[serde(default)]
+    pub force_law: ForceLaw,
+    #[serde(default)]
+    pub species: SpeciesTable,
+    #[serde(default)]
+    pub encounter_radius: Option<f64>,
+    #[serde(default)]
+    pub collisions: CollisionModel,
+    #[serde(default)]
+    pub escapers: EscaperPolicy,
+    #[serde(default)]
+    pub planar: bool,
+    #[serde(default)]
+    pub refinement: Refinement,
+    #[serde(default)]
+    pub reuse: Option<TreeReuse>,
+    #[serde(default)]
+    pub opening: OpeningCriterion,
+    #[serde(default)]
+    pub theta_schedule: ThetaSchedule,
+    #[serde(default)]
+    pub compensated: bool,
+    pub points: Vec<Point>,
+    // Missing from checkpoints written before it was recorded.
+    #[serde(default)]
+    pub state: Option<RunState>,
+}
+
+/// What a checkpoint keeps besides the particles, so that a resumed run carries on as an
+/// uninterrupted one would have: with the same ids and random streams, and without repeating or
+/// skipping anything in its output files.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct RunState {
+    // Lowest id no particle has had yet.
+    pub next_id: u64,
+    pub mass_lost: f64,
+    // Seeds of the kick and infall streams. Each draw is keyed by the seed and a particle id or
+    // arrival index, so the seed is all there is to a stream's position.
+    pub kick_seed: Option<u64>,
+    pub infall_seed: Option<u64>,
+    // When the snapshot schedule is next due by time, in the run's units.
+    pub next_snapshot: f64,
+    // Length in bytes of each of the run directory's event logs, by file name.
+    pub logs: BTreeMap<String, u64>,
+    // The run directory those logs are in, canonicalized. Only a run resumed into the same
+    // directory carries them on.
+    #[serde(default)]
+    pub run_dir: Option<PathBuf>,
+}
+
+impl Snapshot {
+    pub fn from_tree(step: u64, time: f64, tree: &BHTree) -> Snapshot {
+        return Snapshot {
+            step: step,
+            time: time,
+            theta: tree.theta(),
+            periodic: None,
+            boundary: tree.boundary(),
+            force_law: tree.force_law(),
+            species: tree.species().clone(),
+            encounter_radius: tree.encounter_radius(),
+            collisions: tree.collisions(),
+            escapers: tree.escapers(),
+            planar: tree.planar(),
+            refinement: tree.refinement(),
+            reuse: tree.reuse(),
+            opening: tree.opening(),
+            theta_schedule: tree.theta_schedule(),
+            compensated: tree.compensated_summation(),
+            points: tree.points(),
+            state: None,
+        };
+    }
+
+    pub fn with_state(mut self, state: RunState) -> Snapshot {
+        self.state = Some(state);
+        return self;
+    }
+
+    // Writes the snapshot as a JSON checkpoint file that `read` can resume from. The file is
+    // written under a temporary name and renamed, so an interrupted write never leaves a partial
+    // checkpoint behind.
+    pub fn write(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_vec(self)?)?;
+        fs::rename(&tmp, path)?;
+        info!(
+            "wrote checkpoint for step {} to {}",
+            self.step,
+            path.display()
+        );
+        return Ok(());
+    }
+
+    pub fn read(path: &Path) -> Result<Snapshot, Box<dyn Error>> {
+        return Ok(serde_json::from_slice(&fs::read(path)?)?);
+    }
+
+    pub fn to_tree(&self) -> Result<BHTree, NbodyError> {
+        let template = match self.periodic {
+            Some(box_size) => BHTree::new_periodic(self.theta, box_size),
+            None => BHTree::new_bounded(self.theta, self.boundary),
+        };
+        return template
+            .with_force_law(self.force_law)
+            .with_species(self.species.clone())
+            .with_encounter_radius(self.encounter_radius)
+            .with_collisions(self.collisions)
+            .with_escapers(self.escapers)
+            .with_planar(self.planar)
+            .with_compensated_summation(self.compensated)
+            .with_refinement(self.refinement)
+            .with_reuse(self.reuse)
+            .with_opening(self.opening)
+            .with_theta_schedule(self.theta_schedule)
+            .rebuild(self.points.clone());
+    }
+}
+
 pub struct DbHandle {
     db: Db,
 }
 
+// Keys sort by step first, so the last key is always the most recent snapshot.
+fn key(step: u64, time: f64) -> [u8; 16] {
+    let mut k = [0u8; 16];
+    k[..8].copy_from_slice(&step.to_be_bytes());
+    k[8..].copy_from_slice(&time.to_be_bytes());
+    return k;
+}
+
 impl DbHandle {
     pub fn new(path: String) -> Result<DbHandle, sled::Error> {
-        // TODO: Allow it to pick up where it left off somehow.
         info!("creating dbhandle with path {}", path);
         let dbhandle = DbHandle {
             db: sled::open(path)?,
@@ -20,13 +170,99 @@ impl DbHandle {
         return Ok(dbhandle);
     }
 
-    pub fn persist(&self, time: f64, tree: BHTree) {
-        info!("persisting tree state @ t={}", time);
-        tree.points().par_iter().for_each(|p| {
-            self.db
-                .insert(time.to_be_bytes(), p.to_string().as_bytes())
-                .unwrap();
-        });
+    // Parses a `sled://path` URI and opens the database it points at.
+    pub fn from_uri(uri: &str) -> Result<DbHandle, Box<dyn Error>> {
+        let path = uri
+            .strip_prefix("sled://")
+            .ok_or_else(|| format!("unsupported persistence uri: {}", uri))?;
+        return Ok(DbHandle::new(path.to_string())?);
+    }
+
+    pub fn persist(&self, step: u64, time: f64, tree: &BHTree) -> Result<(), Box<dyn Error>> {
+        return self.persist_snapshot(&Snapshot::from_tree(step, time, tree));
+    }
+
+    pub fn persist_snapshot(&self, snapshot: &Snapshot) -> Result<(), Box<dyn Error>> {
+        info!(
+            "persisting tree state @ step={} t={}",
+            snapshot.step, snapshot.time
+        );
+        self.db.insert(
+            key(snapshot.step, snapshot.time),
+            serde_json::to_vec(snapshot)?,
+        )?;
+        self.db.flush()?;
+        return Ok(());
+    }
+
+    // Loads the most recently persisted snapshot so a run can pick up where it left off.
+    pub fn latest(&self) -> Result<Option<Snapshot>, Box<dyn Error>> {
+        return match self.db.last()? {
+            Some((_, v)) => Ok(Some(serde_json::from_slice(&v)?)),
+            None => Ok(None),
+        };
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::geometry::bh_tree::BHTree;
+    use crate::geometry::db::{DbHandle, RunState, Snapshot};
+    use crate::{Point, Vec3d};
+
+    #[test]
+    fn test_persist_and_resume() {
+        let db = DbHandle {
+            db: sled::Config::new().temporary(true).open().unwrap(),
+        };
+        assert!(db.latest().unwrap().is_none());
+
+        let mut bht = BHTree::new(0.5, 5., 0., 0., 0.);
+        bht.add_point(Point::new(1., 1., 1., 1., Vec3d::new_zero()))
+            .unwrap();
+        db.persist(0, 0., &bht).unwrap();
+        bht.add_point(Point::new(1., 3., 3., 3., Vec3d::new(1., 0., 0.)).with_id(1))
+            .unwrap();
+        db.persist(10, 2.5, &bht).unwrap();
+
+        let snapshot = db.latest().unwrap().unwrap();
+        assert_eq!(snapshot.step, 10);
+        assert_eq!(snapshot.time, 2.5);
+        assert_eq!(snapshot.points, bht.points());
+        assert_eq!(snapshot.to_tree().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_checkpoint_file() {
+        let bht = BHTree::from_points(
+            0.5,
+            vec![
+                Point::new(1., 1., 1., 1., Vec3d::new_zero()).with_id(0),
+                Point::new(2., 3., 3., 3., Vec3d::new(1., 0., 0.)).with_id(1),
+            ],
+        )
+        .unwrap();
+        let path =
+            std::env::temp_dir().join(format!("nbody-checkpoint-{}.json", std::process::id()));
+        let state = RunState {
+            next_id: 5,
+            kick_seed: Some(3),
+            logs: [("kicks.csv".to_string(), 120)].into_iter().collect(),
+            ..RunState::default()
+        };
+        Snapshot::from_tree(7, 1.5, &bht)
+            .with_state(state.clone())
+            .write(&path)
+            .unwrap();
+        let restored = Snapshot::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(restored.step, 7);
+        assert_eq!(restored.points, bht.points());
+        assert_eq!(restored.state, Some(state));
+    }
+
+    #[test]
+    fn test_bad_uri() {
+        assert!(DbHandle::from_uri("postgres://nope").is_err());
     }
 }
-*/
@@ -0,0 +1,85 @@
+use std::f64::consts::PI;
+
+/// The standard cubic spline (M4) SPH kernel in three dimensions, with smoothing length `h` and
+/// compact support out to 2h.
+pub fn kernel(r: f64, h: f64) -> f64 {
+    let q = r / h;
+    let sigma = 1. / (PI * h * h * h);
+    if q < 1. {
+        return sigma * (1. - 1.5 * q * q + 0.75 * q * q * q);
+    }
+    if q < 2. {
+        let t = 2. - q;
+        return sigma * 0.25 * t * t * t;
+    }
+    return 0.;
+}
+
+// dW/dr of `kernel`; never positive. Zero for a zero smoothing length, which only an isolated
+// particle gets.
+pub fn kernel_gradient(r: f64, h: f64) -> f64 {
+    if h == 0. {
+        return 0.;
+    }
+    let q = r / h;
+    let sigma = 1. / (PI * h * h * h * h);
+    if q < 1. {
+        return sigma * (-3. * q + 2.25 * q * q);
+    }
+    if q < 2. {
+        let t = 2. - q;
+        return sigma * -0.75 * t * t;
+    }
+    return 0.;
+}
+
+#[cfg(test)]
+mod test {
+    use std::f64::consts::PI;
+
+    use crate::geometry::bh_tree::BHTree;
+    use crate::geometry::density::{kernel, kernel_gradient};
+    use crate::{Point, Vec3d};
+
+    #[test]
+    fn test_kernel_is_normalized() {
+        let h = 0.7;
+        let n = 10000;
+        let dr = 2. * h / n as f64;
+        let integral: f64 = (0..n)
+            .map(|i| {
+                let r = (i as f64 + 0.5) * dr;
+                4. * PI * r * r * kernel(r, h) * dr
+            })
+            .sum();
+        assert!((integral - 1.).abs() < 1e-6, "{}", integral);
+        assert_eq!(kernel(2. * h, h), 0.);
+
+        // The gradient matches a finite difference of the kernel.
+        for r in [0.1, 0.5, 0.9, 1.3, 1.9] {
+            let dr = 1e-6;
+            let numeric = (kernel(r + dr, h) - kernel(r - dr, h)) / (2. * dr);
+            assert!((kernel_gradient(r, h) - numeric).abs() < 1e-6, "{}", r);
+        }
+        assert_eq!(kernel_gradient(2. * h, h), 0.);
+    }
+
+    #[test]
+    fn test_lattice_density() {
+        // Unit masses on a unit lattice have a density of one away from the edges.
+        let mut points = vec![];
+        for x in 0..12 {
+            for y in 0..12 {
+                for z in 0..12 {
+                    let id = (x * 144 + y * 12 + z) as u64;
+                    let p = Point::new(1., x as f64, y as f64, z as f64, Vec3d::new_zero());
+                    points.push(p.with_id(id));
+                }
+            }
+        }
+        let bht = BHTree::from_points(0.5, points).unwrap();
+        let center = Point::new(1., 6., 6., 6., Vec3d::new_zero()).with_id(6 * 144 + 6 * 12 + 6);
+        let rho = bht.density_at(center, 64);
+        assert!((rho - 1.).abs() < 0.1, "{}", rho);
+    }
+}
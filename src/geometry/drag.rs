@@ -0,0 +1,85 @@
+use std::f64::consts::PI;
+
+use crate::geometry::vec3d::G;
+use crate::{Point, Vec3d};
+
+/// A velocity-dependent force applied to every particle, standing in for the gas or background
+/// that isn't simulated directly.
+#[derive(Debug, Clone, Copy)]
+pub enum Drag {
+    // F = -k v.
+    Linear {
+        k: f64,
+    },
+    // Chandrasekhar dynamical friction from a uniform Maxwellian background of the given density
+    // and one-dimensional velocity dispersion.
+    Chandrasekhar {
+        density: f64,
+        dispersion: f64,
+        coulomb_log: f64,
+    },
+}
+
+// Abramowitz & Stegun 7.1.26, accurate to about 1.5e-7.
+fn erf(x: f64) -> f64 {
+    let t = 1. / (1. + 0.3275911 * x.abs());
+    let poly = t
+        * (0.254829592
+            + t * (-0.284496736 + t * (1.421413741 + t * (-1.453152027 + t * 1.061405429))));
+    return (1. - poly * (-x * x).exp()).copysign(x);
+}
+
+impl Drag {
+    pub fn force(&self, p: &Point) -> Vec3d {
+        let v = p.velocity();
+        return match *self {
+            Drag::Linear { k } => -k * v,
+            Drag::Chandrasekhar {
+                density,
+                dispersion,
+                coulomb_log,
+            } => {
+                let speed = v.magnitude();
+                if speed == 0. {
+                    return Vec3d::new_zero();
+                }
+                let x = speed / (2_f64.sqrt() * dispersion);
+                let fraction = erf(x) - 2. * x / PI.sqrt() * (-x * x).exp();
+                let a = 4. * PI * G * G * p.mass() * density * coulomb_log * fraction
+                    / (speed * speed * speed);
+                (-a * p.mass()) * v
+            }
+        };
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::f64::consts::PI;
+
+    use crate::geometry::drag::Drag;
+    use crate::geometry::vec3d::G;
+    use crate::{Point, Vec3d};
+
+    #[test]
+    fn test_drag_opposes_motion() {
+        let p = Point::new(2., 0., 0., 0., Vec3d::new(3., 0., -4.));
+        let f = Drag::Linear { k: 0.5 }.force(&p);
+        assert_eq!(f.position(), (-1.5, 0., 2.));
+
+        // Well above the background dispersion, friction tends to 4 pi G^2 M^2 rho lnL / v^2.
+        let friction = Drag::Chandrasekhar {
+            density: 1e-20,
+            dispersion: 1e-3,
+            coulomb_log: 3.,
+        };
+        let f = friction.force(&p);
+        let expected = 4. * PI * G * G * 4. * 1e-20 * 3. / 25.;
+        assert!((f.magnitude() / expected - 1.).abs() < 1e-6);
+        let (fx, _, fz) = f.position();
+        assert!(fx < 0. && fz > 0.);
+
+        let at_rest = Point::new(2., 0., 0., 0., Vec3d::new_zero());
+        assert_eq!(friction.force(&at_rest).magnitude(), 0.);
+    }
+}
@@ -0,0 +1,105 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{Point, Vec3d};
+
+/// What to do with particles that get further than `radius` from the origin, so a few escapers
+/// don't stretch the root node over a huge, mostly empty region.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum EscaperPolicy {
+    // Leave them be and let the tree grow to cover them.
+    #[default]
+    Keep,
+    // Drop them from the simulation.
+    Remove {
+        radius: f64,
+    },
+    // Mirror them back inside the sphere, reversing their outward velocity.
+    Reflect {
+        radius: f64,
+    },
+    // Keep them out of the octree and include them in forces by direct summation.
+    Outside {
+        radius: f64,
+    },
+}
+
+impl EscaperPolicy {
+    // Splits `points` into those to build the tree from and those to keep outside it. Pinned
+    // particles are never touched.
+    pub fn apply(&self, points: Vec<Point>) -> (Vec<Point>, Vec<Point>) {
+        let radius = match *self {
+            EscaperPolicy::Keep => return (points, vec![]),
+            EscaperPolicy::Remove { radius }
+            | EscaperPolicy::Reflect { radius }
+            | EscaperPolicy::Outside { radius } => radius,
+        };
+
+        let mut inside = Vec::with_capacity(points.len());
+        let mut outside = vec![];
+        for p in points {
+            let (x, y, z) = p.position();
+            let r = Vec3d::new(x, y, z).magnitude();
+            if r <= radius || p.pinned() {
+                inside.push(p);
+                continue;
+            }
+            match *self {
+                EscaperPolicy::Remove { .. } => {}
+                EscaperPolicy::Reflect { .. } => inside.push(reflect(p, radius)),
+                _ => outside.push(p),
+            }
+        }
+        return (inside, outside);
+    }
+}
+
+// Mirrors `p` in the sphere of the given radius, which it must be outside of.
+fn reflect(p: Point, radius: f64) -> Point {
+    let (x, y, z) = p.position();
+    let pos = Vec3d::new(x, y, z);
+    let r = pos.magnitude();
+    let n = pos / r;
+    let (nx, ny, nz) = (n * (2. * radius - r).max(0.)).position();
+    let outward = p.velocity().dot(n);
+    let vel = if outward > 0. {
+        p.velocity() - (2. * outward) * n
+    } else {
+        p.velocity()
+    };
+    return p.with_position(nx, ny, nz).with_velocity(vel);
+}
+
+#[cfg(test)]
+mod test {
+    use crate::geometry::escape::EscaperPolicy;
+    use crate::{Point, Vec3d};
+
+    #[test]
+    fn test_policies() {
+        let points = vec![
+            Point::new(1., 1., 0., 0., Vec3d::new(1., 0., 0.)).with_id(0),
+            Point::new(1., 12., 0., 0., Vec3d::new(3., 1., 0.)).with_id(1),
+            Point::new(1., 0., -15., 0., Vec3d::new_zero())
+                .with_id(2)
+                .with_pinned(true),
+        ];
+
+        let (inside, outside) = EscaperPolicy::Keep.apply(points.clone());
+        assert_eq!((inside.len(), outside.len()), (3, 0));
+
+        let (inside, outside) = EscaperPolicy::Remove { radius: 10. }.apply(points.clone());
+        assert_eq!(
+            inside.iter().map(|p| p.id()).collect::<Vec<_>>(),
+            vec![0, 2]
+        );
+        assert!(outside.is_empty());
+
+        let (inside, _) = EscaperPolicy::Reflect { radius: 10. }.apply(points.clone());
+        assert_eq!(inside[1].position(), (8., 0., 0.));
+        assert_eq!(inside[1].velocity().position(), (-3., 1., 0.));
+
+        let (inside, outside) = EscaperPolicy::Outside { radius: 10. }.apply(points);
+        assert_eq!(inside.len(), 2);
+        assert_eq!(outside[0].id(), 1);
+    }
+}
@@ -0,0 +1,226 @@
+use std::f64::consts::PI;
+use std::sync::OnceLock;
+
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+
+#[cfg(not(feature = "parallel"))]
+use crate::geometry::bh_tree::SerialIter;
+use crate::geometry::force::erfc;
+use crate::Vec3d;
+
+// Intervals the table splits each axis of the first octant of the unit box into.
+const CELLS: usize = 32;
+
+// Splitting parameter between the real and reciprocal space sums, for the unit box. Both sums
+// converge to rounding within a few terms at this value.
+const ALPHA: f64 = 2.;
+
+static TABLE: OnceLock<Vec<[f64; 3]>> = OnceLock::new();
+
+// What has to be added to the nearest-image attraction between two unit masses with `offset`
+// from the source to the target, in a periodic box of side `box_size` with G = 1, to get the pull
+// of every periodic image of the source. `offset` must be the nearest image's, so each component
+// lies within half a box. Interpolated from a table built the first time it's needed.
+pub fn correction(offset: Vec3d, box_size: f64) -> Vec3d {
+    let table = TABLE.get_or_init(build);
+    let (x, y, z) = offset.position();
+    let u = [x / box_size, y / box_size, z / box_size];
+
+    // The correction is odd along each axis and even across the others, so the table only covers
+    // the first octant.
+    let cell = |v: f64| {
+        let t = (v.abs() * 2. * CELLS as f64).min(CELLS as f64);
+        let i = (t.floor() as usize).min(CELLS - 1);
+        return (i, t - i as f64);
+    };
+    let axes = [cell(u[0]), cell(u[1]), cell(u[2])];
+    let mut c = [0.; 3];
+    for corner in 0..8 {
+        let mut index = 0;
+        let mut weight = 1.;
+        for (axis, (i, f)) in axes.iter().enumerate() {
+            let up = corner >> (2 - axis) & 1 == 1;
+            index = index * (CELLS + 1) + if up { i + 1 } else { *i };
+            weight *= if up { *f } else { 1. - f };
+        }
+        for (c, t) in c.iter_mut().zip(table[index]) {
+            *c += weight * t;
+        }
+    }
+    // The tabulated value keeps its own sign, which isn't positive everywhere in the octant, and
+    // flips with the offset's. On an axis it's zero.
+    let sign = |u: f64| if u == 0. { 0. } else { u.signum() };
+    let scale = 1. / (box_size * box_size);
+    return Vec3d::new(
+        scale * c[0] * sign(u[0]),
+        scale * c[1] * sign(u[1]),
+        scale * c[2] * sign(u[2]),
+    );
+}
+
+// The correction on the grid over the first octant of the unit box, x slowest and z fastest.
+fn build() -> Vec<[f64; 3]> {
+    let n = CELLS + 1;
+    let spacing = 0.5 / CELLS as f64;
+    return (0..n * n * n)
+        .collect::<Vec<usize>>()
+        .par_iter()
+        .map(|i| {
+            let u = [i / (n * n), i / n % n, i % n].map(|j| j as f64 * spacing);
+            exact(u)
+        })
+        .collect();
+}
+
+// The correction at `u` in the unit box, summed directly: the Ewald force from all the images,
+// split into real and reciprocal space sums, less the Newtonian force from the nearest.
+fn exact(u: [f64; 3]) -> [f64; 3] {
+    let r2: f64 = u.iter().map(|u| u * u).sum();
+    if r2 == 0. {
+        return [0.; 3];
+    }
+    // The nearest image's real-space term less its Newtonian pull is left over in closed form.
+    let r = r2.sqrt();
+    let mut force = u.map(|u| u / (r2 * r) * remainder(ALPHA * r));
+    for (nx, ny, nz) in lattice(3).filter(|n| *n != (0., 0., 0.)) {
+        let d = [u[0] - nx, u[1] - ny, u[2] - nz];
+        let r = d.iter().map(|d| d * d).sum::<f64>().sqrt();
+        let pull = erfc(ALPHA * r) + 2. * ALPHA * r / PI.sqrt() * (-ALPHA * ALPHA * r * r).exp();
+        for (f, d) in force.iter_mut().zip(d) {
+            *f -= d / (r * r * r) * pull;
+        }
+    }
+    for (hx, hy, hz) in lattice(4) {
+        let h2 = hx * hx + hy * hy + hz * hz;
+        if h2 == 0. || h2 > 10. {
+            continue;
+        }
+        let phase = 2. * PI * (hx * u[0] + hy * u[1] + hz * u[2]);
+        let pull = 2. / h2 * (-PI * PI * h2 / (ALPHA * ALPHA)).exp() * phase.sin();
+        for (f, h) in force.iter_mut().zip([hx, hy, hz]) {
+            *f -= h * pull;
+        }
+    }
+    return force;
+}
+
+// erf(x) - 2 x e^(-x^2) / sqrt(pi), summed as a series since the two terms nearly cancel close
+// in. Good to rounding for the x up to about 2 that the nearest image needs.
+fn remainder(x: f64) -> f64 {
+    let mut sum = 0.;
+    // x^(2n + 1) / n!, with its sign.
+    let mut power = x;
+    for n in 1..60 {
+        power *= -x * x / n as f64;
+        sum -= power * (2 * n) as f64 / (2 * n + 1) as f64;
+    }
+    return 2. / PI.sqrt() * sum;
+}
+
+// Every point of the integer lattice within `extent` along each axis.
+fn lattice(extent: i32) -> impl Iterator<Item = (f64, f64, f64)> {
+    let range = move || -extent..=extent;
+    return range().flat_map(move |x| {
+        range().flat_map(move |y| range().map(move |z| (x as f64, y as f64, z as f64)))
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    use crate::geometry::bh_tree::BHTree;
+    use crate::geometry::ewald::{correction, exact};
+    use crate::geometry::pm::ParticleMesh;
+    use crate::{Point, Vec3d};
+
+    #[test]
+    fn test_ewald() {
+        // Halfway across the box, the images on either side pull equally hard, so the correction
+        // cancels the nearest image's pull.
+        let c = correction(Vec3d::new(0.5, 0., 0.), 1.);
+        assert!((c.position().0 - 4.).abs() < 1e-6, "{}", c);
+        assert!(c.position().1.abs() < 1e-9);
+
+        // Close in the images' pull is nearly uniform, like that of a sphere of the box's mean
+        // density with the opposite sign: 4 pi / 3 r.
+        let (x, _, _) = correction(Vec3d::new(0.01, 0., 0.), 1.).position();
+        assert!(
+            (x - 4. * std::f64::consts::PI / 3. * 0.01).abs() < 1e-4,
+            "{}",
+            x
+        );
+
+        // The table agrees with the direct sum off its grid, picks up the signs of the offset and
+        // scales with the box.
+        let u = [0.123, -0.31, 0.4];
+        let want = exact(u.map(f64::abs));
+        let got = correction(Vec3d::new(u[0] * 3., u[1] * 3., u[2] * 3.), 3.);
+        let (x, y, z) = (9. * got).position();
+        assert!((x - want[0]).abs() < 1e-3 * want[0].abs() + 1e-4);
+        assert!((y + want[1]).abs() < 1e-3 * want[1].abs() + 1e-4);
+        assert!((z - want[2]).abs() < 1e-3 * want[2].abs() + 1e-4);
+
+        // The same holds anywhere in the box, including where the correction points back
+        // toward the source.
+        let mut rng = StdRng::seed_from_u64(3);
+        for _ in 0..200 {
+            let u = [(); 3].map(|_| rng.gen_range(-0.5..0.5));
+            let want = exact(u);
+            let (x, y, z) = correction(Vec3d::new(u[0], u[1], u[2]), 1.).position();
+            for (got, want) in [x, y, z].into_iter().zip(want) {
+                assert!((got - want).abs() < 1e-3 * want.abs() + 1e-3, "{:?}", u);
+            }
+        }
+        let (x, _, _) = correction(Vec3d::new(0.0625, 0., 0.5), 1.).position();
+        assert!(x < 0., "{}", x);
+        assert_eq!(correction(Vec3d::new(0., -0.2, 0.3), 1.).position().0, 0.);
+
+        // Two particles half a box apart are pulled both ways equally.
+        let pair = vec![
+            Point::new(1., -1., 0.5, 0., Vec3d::new_zero()),
+            Point::new(1., 1., 0.5, 0., Vec3d::new_zero()).with_id(1),
+        ];
+        let tree = BHTree::new_periodic(0.5, 4.).rebuild(pair.clone()).unwrap();
+        let nearest = tree.force_on(pair[0]);
+        let tree = tree.with_ewald(true);
+        assert!(tree.ewald());
+        assert!(nearest.magnitude() > 0.1 * tree.force_law().g);
+        assert!(tree.force_on(pair[0]).magnitude() < 1e-6 * nearest.magnitude());
+
+        // In a clumpy box the tree with every image agrees with the particle mesh split with a
+        // short-range tree, which gets the images from the mesh.
+        let mut rng = StdRng::seed_from_u64(5);
+        let points: Vec<Point> = (0..500)
+            .map(|i| {
+                let mut x = || rng.gen_range(-0.5..0.5) + if i % 2 == 0 { 1. } else { -0.3 };
+                Point::new(1., x(), x(), x(), Vec3d::new_zero()).with_id(i)
+            })
+            .collect();
+        let ewald = BHTree::new_periodic(0.3, 4.)
+            .with_ewald(true)
+            .rebuild(points.clone())
+            .unwrap();
+        let mesh = ParticleMesh {
+            cells: 64,
+            split: 1.25 * 4. / 64.,
+        };
+        let field = mesh.field(points.iter(), 4., ewald.force_law().g);
+        let treepm = BHTree::new_periodic(0.3, 4.)
+            .with_force_law(mesh.short_range(ewald.force_law()))
+            .rebuild(points.clone())
+            .unwrap();
+        for p in points.iter().step_by(50) {
+            let want = treepm.force_on(*p) + p.mass() * field.acceleration(p.position());
+            let got = ewald.force_on(*p);
+            assert!(
+                (got - want).magnitude() < 0.05 * want.magnitude(),
+                "{} {}",
+                got,
+                want
+            );
+        }
+    }
+}
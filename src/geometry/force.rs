@@ -0,0 +1,204 @@
+use std::f64::consts::PI;
+
+use serde::{Deserialize, Serialize};
+
+use crate::geometry::vec3d::G;
+use crate::{Point, Vec3d};
+
+/// A Yukawa correction to the Newtonian potential, V(r) = -G m1 m2 / r (1 + alpha e^(-r / lambda)).
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Yukawa {
+    pub alpha: f64,
+    pub lambda: f64,
+}
+
+/// The pairwise attraction between particles. Newtonian gravity is `exponent` 2 with no Yukawa
+/// term; other exponents give a G m1 m2 / r^n force for modified-gravity experiments.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ForceLaw {
+    pub g: f64,
+    pub exponent: f64,
+    pub yukawa: Option<Yukawa>,
+    // Split scale r_s when a particle mesh supplies the long-range part of the force, as in
+    // TreePM. Pair forces then fall off as erfc(r / 2 r_s) and vanish beyond `cutoff`.
+    #[serde(default)]
+    pub short_range: Option<f64>,
+}
+
+// Multiples of the split scale beyond which the short-range force is taken to be zero. The force
+// there is about half a percent of the Newtonian one, the rest coming from the mesh.
+const CUTOFF: f64 = 5.;
+
+impl Default for ForceLaw {
+    fn default() -> ForceLaw {
+        return ForceLaw::newtonian(G);
+    }
+}
+
+impl ForceLaw {
+    pub fn newtonian(g: f64) -> ForceLaw {
+        return ForceLaw {
+            g: g,
+            exponent: 2.,
+            yukawa: None,
+            short_range: None,
+        };
+    }
+
+    // Distance beyond which pairs don't attract at all, when the force is short-range.
+    pub fn cutoff(&self) -> Option<f64> {
+        return self.short_range.map(|rs| CUTOFF * rs);
+    }
+
+    // Magnitude of the attraction between masses m1 and m2 at distance r.
+    pub fn magnitude(&self, m1: f64, m2: f64, r: f64) -> f64 {
+        let denom = if self.exponent == 2. {
+            r * r
+        } else {
+            r.powf(self.exponent)
+        };
+        let mut f = self.g * m1 * m2 / denom;
+        if let Some(y) = self.yukawa {
+            f *= 1. + y.alpha * (1. + r / y.lambda) * (-r / y.lambda).exp();
+        }
+        if let Some(rs) = self.short_range {
+            if r > CUTOFF * rs {
+                return 0.;
+            }
+            let x = r / (2. * rs);
+            f *= erfc(x) + 2. * x / PI.sqrt() * (-x * x).exp();
+        }
+        return f;
+    }
+
+    // Potential energy of masses m1 and m2 at distance r, zero at infinity for exponents above one.
+    // The Yukawa factor is exact for the Newtonian exponent only.
+    pub fn potential(&self, m1: f64, m2: f64, r: f64) -> f64 {
+        let n = self.exponent;
+        let mut u = if n == 2. {
+            -self.g * m1 * m2 / r
+        } else if n == 1. {
+            self.g * m1 * m2 * r.ln()
+        } else {
+            -self.g * m1 * m2 / ((n - 1.) * r.powf(n - 1.))
+        };
+        if let Some(y) = self.yukawa {
+            u *= 1. + y.alpha * (-r / y.lambda).exp();
+        }
+        if let Some(rs) = self.short_range {
+            u *= erfc(r / (2. * rs));
+        }
+        return u;
+    }
+
+    // Potential energy of `p` and `q` with Plummer softening.
+    pub fn softened_potential(&self, p: Point, q: Point, softening: f64) -> f64 {
+        let dist = p.distance_to(q);
+        let r = (dist * dist + softening * softening).sqrt();
+        return self.potential(p.mass(), q.mass(), r);
+    }
+
+    // Force exerted on `p` by `q`.
+    pub fn force(&self, p: Point, q: Point) -> Vec3d {
+        return self.softened_force(p, q, 0.);
+    }
+
+    // Force exerted on `p` by `q` with Plummer softening, i.e. with the distance in the force law
+    // replaced by sqrt(r^2 + softening^2).
+    pub fn softened_force(&self, p: Point, q: Point, softening: f64) -> Vec3d {
+        let dist = p.distance_to(q);
+        let f = if softening > 0. {
+            let s = (dist * dist + softening * softening).sqrt();
+            self.magnitude(p.mass(), q.mass(), s) * dist / s
+        } else {
+            self.magnitude(p.mass(), q.mass(), dist)
+        };
+        let (px, py, pz) = p.position();
+        let (qx, qy, qz) = q.position();
+        return Vec3d::new(
+            (qx - px) / dist * f,
+            (qy - py) / dist * f,
+            (qz - pz) / dist * f,
+        );
+    }
+}
+
+// The complementary error function, to a relative error of 1.2e-7 (Numerical Recipes' erfcc).
+pub(crate) fn erfc(x: f64) -> f64 {
+    let z = x.abs();
+    let t = 1. / (1. + 0.5 * z);
+    let poly = [
+        -1.26551223,
+        1.00002368,
+        0.37409196,
+        0.09678418,
+        -0.18628806,
+        0.27886807,
+        -1.13520398,
+        1.48851587,
+        -0.82215223,
+        0.17087277,
+    ]
+    .iter()
+    .rev()
+    .fold(0., |acc, c| c + t * acc);
+    let r = t * (-z * z + poly).exp();
+    return if x >= 0. { r } else { 2. - r };
+}
+
+#[cfg(test)]
+mod test {
+    use crate::geometry::force::{erfc, ForceLaw, Yukawa};
+    use crate::{Point, Vec3d};
+
+    #[test]
+    fn test_force_laws() {
+        let p = Point::new(2., 0., 0., 0., Vec3d::new_zero());
+        let q = Point::new(3., 2., 0., 0., Vec3d::new_zero());
+
+        let newton = ForceLaw::newtonian(1.);
+        assert_eq!(newton.force(p, q).position(), (1.5, 0., 0.));
+        assert_eq!(newton.force(q, p).position(), (-1.5, 0., 0.));
+
+        let cubic = ForceLaw {
+            exponent: 3.,
+            ..newton
+        };
+        assert_eq!(cubic.magnitude(2., 3., 2.), 0.75);
+
+        assert!((erfc(0.) - 1.).abs() < 1e-7);
+        assert!((erfc(1.) - 0.157299207).abs() < 1e-7);
+        assert!((erfc(-1.) - 1.842700793).abs() < 1e-7);
+
+        // The short-range part of a TreePM split is all of the force close in and none of it
+        // past the cutoff.
+        let short = ForceLaw {
+            short_range: Some(1.),
+            ..newton
+        };
+        assert_eq!(short.cutoff(), Some(5.));
+        assert!((short.magnitude(2., 3., 1e-3) / newton.magnitude(2., 3., 1e-3) - 1.).abs() < 1e-6);
+        assert!(short.magnitude(2., 3., 2.) < newton.magnitude(2., 3., 2.));
+        assert_eq!(short.magnitude(2., 3., 5.1), 0.);
+
+        // The force is minus the derivative of the potential.
+        for law in [newton, cubic, short] {
+            let dr = 1e-6;
+            let slope =
+                (law.potential(2., 3., 2. + dr) - law.potential(2., 3., 2. - dr)) / (2. * dr);
+            assert!((slope - law.magnitude(2., 3., 2.)).abs() < 1e-6);
+        }
+
+        // Far inside the Yukawa length the attraction is boosted by (1 + alpha), far outside it
+        // is Newtonian.
+        let yukawa = |lambda| ForceLaw {
+            yukawa: Some(Yukawa {
+                alpha: 0.5,
+                lambda: lambda,
+            }),
+            ..newton
+        };
+        assert!((yukawa(1e9).magnitude(2., 3., 2.) / 1.5 - 1.5).abs() < 1e-6);
+        assert!((yukawa(1e-3).magnitude(2., 3., 2.) / 1.5 - 1.).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,115 @@
+use std::f64::consts::PI;
+use std::ops::Range;
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::Serialize;
+
+use crate::{Point, Vec3d};
+
+/// A steady stream of particles of mass `mass` falling in from a sphere of radius `radius` about
+/// the origin, `rate` of them per unit time, each starting straight inward at `speed`.
+///
+/// The k-th particle arrives at time k / rate, at a place drawn from its own stream seeded from
+/// `seed` and k, so the stream doesn't depend on the step size or on whether the run was resumed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Infall {
+    pub rate: f64,
+    pub mass: f64,
+    pub radius: f64,
+    pub speed: f64,
+    pub seed: u64,
+}
+
+// Where a run logs the particles injected into it, one `Injection` per row.
+pub const INJECTION_FILE: &str = "injections.csv";
+
+/// A particle added to a running simulation, in SI units.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Injection {
+    pub step: u64,
+    pub time: f64,
+    pub id: u64,
+    pub mass: f64,
+    pub x: f64,
+    pub y: f64,
+    pub z: f64,
+    pub vx: f64,
+    pub vy: f64,
+    pub vz: f64,
+}
+
+impl Injection {
+    pub fn of(step: u64, time: f64, p: &Point) -> Injection {
+        let (x, y, z) = p.position();
+        let (vx, vy, vz) = p.velocity().position();
+        return Injection {
+            step: step,
+            time: time,
+            id: p.id(),
+            mass: p.mass(),
+            x: x,
+            y: y,
+            z: z,
+            vx: vx,
+            vy: vy,
+            vz: vz,
+        };
+    }
+}
+
+impl Infall {
+    // Indices of the particles arriving after `from` and no later than `to`.
+    pub fn arrivals(&self, from: f64, to: f64) -> Range<u64> {
+        let count = |t: f64| (self.rate * t).max(0.).floor() as u64;
+        return count(from)..count(to);
+    }
+
+    // The k-th particle of the stream, still to be given an id.
+    pub fn draw(&self, k: u64) -> Point {
+        let mut seed = [0; 32];
+        seed[..8].copy_from_slice(&self.seed.to_le_bytes());
+        seed[8..16].copy_from_slice(&k.to_le_bytes());
+        let mut rng = StdRng::from_seed(seed);
+
+        let cos_theta: f64 = rng.gen_range(-1. ..=1.);
+        let sin_theta = (1. - cos_theta * cos_theta).sqrt();
+        let phi = rng.gen_range(0. ..2. * PI);
+        let dir = Vec3d::new(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta);
+        let (x, y, z) = (self.radius * dir).position();
+        return Point::new(self.mass, x, y, z, -self.speed * dir);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::geometry::infall::Infall;
+    use crate::Vec3d;
+
+    #[test]
+    fn test_infall() {
+        let infall = Infall {
+            rate: 2.5,
+            mass: 3.,
+            radius: 10.,
+            speed: 4.,
+            seed: 1,
+        };
+
+        // Arrivals over consecutive intervals cover the stream once, whatever the step.
+        assert_eq!(infall.arrivals(0., 1.), 0..2);
+        assert_eq!(infall.arrivals(1., 2.), 2..5);
+        assert_eq!(infall.arrivals(0., 2.), 0..5);
+        assert!(infall.arrivals(0.1, 0.3).is_empty());
+
+        let p = infall.draw(3);
+        assert_eq!(p.mass(), 3.);
+        let (x, y, z) = p.position();
+        assert!(((x * x + y * y + z * z).sqrt() - 10.).abs() < 1e-9);
+        assert!((p.velocity().magnitude() - 4.).abs() < 1e-12);
+        let inward = Vec3d::new(x, y, z).dot(p.velocity());
+        assert!((inward + 40.).abs() < 1e-9);
+        assert_eq!(infall.draw(3).position(), p.position());
+        assert_ne!(infall.draw(4).position(), p.position());
+    }
+}
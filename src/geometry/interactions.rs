@@ -0,0 +1,168 @@
+use crate::geometry::force::ForceLaw;
+use crate::geometry::summation::CompensatedVec;
+use crate::{Point, Vec3d};
+
+// Partial sums kept side by side, so that consecutive terms are independent and the loop can be
+// vectorized without changing the order the terms are added in.
+const LANES: usize = 4;
+
+/// What one particle's force is summed over: the particles and accepted nodes' centres of mass a
+/// tree walk reached, with periodic images already taken and the softening for each, held as flat
+/// arrays. Gathering the list and summing it are kept apart, so that the arithmetic runs as a tight
+/// loop over contiguous memory instead of between pointer chases, and could just as well be handed
+/// to another device.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct InteractionList {
+    x: Vec<f64>,
+    y: Vec<f64>,
+    z: Vec<f64>,
+    mass: Vec<f64>,
+    softening: Vec<f64>,
+}
+
+impl InteractionList {
+    pub fn new() -> InteractionList {
+        return InteractionList::default();
+    }
+
+    pub fn push(&mut self, q: Point, softening: f64) {
+        let (x, y, z) = q.position();
+        self.x.push(x);
+        self.y.push(y);
+        self.z.push(z);
+        self.mass.push(q.mass());
+        self.softening.push(softening);
+    }
+
+    // Empties the list, keeping its memory for the next particle.
+    pub fn clear(&mut self) {
+        self.x.clear();
+        self.y.clear();
+        self.z.clear();
+        self.mass.clear();
+        self.softening.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        return self.mass.len();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.mass.is_empty();
+    }
+
+    // The sources' positions and masses, one array each.
+    pub fn columns(&self) -> (&[f64], &[f64], &[f64], &[f64]) {
+        return (&self.x, &self.y, &self.z, &self.mass);
+    }
+
+    // Net force on `p` from every source, each term as `ForceLaw::softened_force` gives it with the
+    // source's softening. The
+    // terms are added in a fixed order, so the result doesn't depend on how the list is stored,
+    // though it may differ from a tree walk's nested sums in the last bits.
+    pub fn force_on(&self, p: Point, law: &ForceLaw, compensated: bool) -> Vec3d {
+        let (px, py, pz) = p.position();
+        let m = p.mass();
+        let term = |i: usize| {
+            let (dx, dy, dz) = (self.x[i] - px, self.y[i] - py, self.z[i] - pz);
+            let dist = (dx * dx + dy * dy + dz * dz).sqrt();
+            let softening = self.softening[i];
+            let f = if softening > 0. {
+                let s = (dist * dist + softening * softening).sqrt();
+                law.magnitude(m, self.mass[i], s) * dist / s
+            } else {
+                law.magnitude(m, self.mass[i], dist)
+            };
+            (dx / dist * f, dy / dist * f, dz / dist * f)
+        };
+
+        if compensated {
+            let mut sum = CompensatedVec::default();
+            for i in 0..self.len() {
+                let (fx, fy, fz) = term(i);
+                sum.add(Vec3d::new(fx, fy, fz));
+            }
+            return sum.value();
+        }
+
+        let mut lanes = [[0.; 3]; LANES];
+        let whole = self.len() / LANES * LANES;
+        for start in (0..whole).step_by(LANES) {
+            for (lane, sum) in lanes.iter_mut().enumerate() {
+                let (fx, fy, fz) = term(start + lane);
+                sum[0] += fx;
+                sum[1] += fy;
+                sum[2] += fz;
+            }
+        }
+        for (lane, i) in (whole..self.len()).enumerate() {
+            let (fx, fy, fz) = term(i);
+            lanes[lane][0] += fx;
+            lanes[lane][1] += fy;
+            lanes[lane][2] += fz;
+        }
+        let total = |c: usize| lanes.iter().map(|sum| sum[c]).sum();
+        return Vec3d::new(total(0), total(1), total(2));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    use crate::geometry::bh_tree::BHTree;
+    use crate::geometry::force::ForceLaw;
+    use crate::geometry::interactions::InteractionList;
+    use crate::ic::halo::{Halo, Profile};
+    use crate::{Point, Vec3d};
+
+    #[test]
+    fn test_interaction_lists() {
+        let law = ForceLaw::newtonian(1.);
+        let p = Point::new(2., 0., 0., 0., Vec3d::new_zero());
+        let mut list = InteractionList::new();
+        let sources: Vec<Point> = (1..7)
+            .map(|i| Point::new(i as f64, i as f64, 1., -2., Vec3d::new_zero()))
+            .collect();
+        let mut expected = Vec3d::new_zero();
+        for q in sources.iter() {
+            list.push(*q, 0.1);
+            expected += law.softened_force(p, *q, 0.1);
+        }
+        assert_eq!(list.len(), 6);
+        for compensated in [false, true] {
+            let force = list.force_on(p, &law, compensated);
+            assert!(force.distance(expected).magnitude() <= 1e-15 * expected.magnitude());
+        }
+        list.clear();
+        assert!(list.is_empty());
+        assert_eq!(list.force_on(p, &law, false), Vec3d::new_zero());
+
+        // Gathering lists and then summing them matches the walk to rounding, and counts the
+        // same interactions.
+        let points = Halo {
+            n: 1000,
+            total_mass: 1e20,
+            profile: Profile::Hernquist,
+            scale_radius: 1e3,
+            max_radius: 2e4,
+        }
+        .generate(&mut StdRng::seed_from_u64(5));
+        let walked = BHTree::from_points(0.5, points).unwrap();
+        let listed = BHTree::from_points(0.5, walked.points())
+            .unwrap()
+            .with_interaction_lists(true);
+        for p in walked.points().iter().step_by(37) {
+            let (want, walk_cost) = walked.force_and_cost(*p);
+            let (got, list_cost) = listed.force_and_cost(*p);
+            assert!(got.distance(want).magnitude() <= 1e-12 * want.magnitude());
+            assert_eq!(list_cost, walk_cost);
+            assert_eq!(listed.interaction_list(*p).len(), walk_cost as usize);
+        }
+        let (_, walk_stats) = walked.step(1e3).unwrap();
+        let (next, list_stats) = listed.step(1e3).unwrap();
+        assert_eq!(list_stats.interactions, walk_stats.interactions);
+        assert!(next.interaction_lists());
+    }
+}
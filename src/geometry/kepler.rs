@@ -0,0 +1,271 @@
+use std::f64::consts::TAU;
+
+use crate::{Point, Vec3d};
+
+const MAX_ITERATIONS: usize = 50;
+const TOLERANCE: f64 = 1e-12;
+
+// Stumpff functions C(z) and S(z).
+fn stumpff(z: f64) -> (f64, f64) {
+    if z.abs() < 1e-6 {
+        return (
+            0.5 - z / 24. + z * z / 720.,
+            1. / 6. - z / 120. + z * z / 5040.,
+        );
+    }
+    if z > 0. {
+        let s = z.sqrt();
+        return ((1. - s.cos()) / z, (s - s.sin()) / (s * s * s));
+    }
+    let s = (-z).sqrt();
+    return ((s.cosh() - 1.) / -z, (s.sinh() - s) / (s * s * s));
+}
+
+/// Advances the relative position `r0` and velocity `v0` of a two-body system with gravitational
+/// parameter `mu` = G (m1 + m2) by `dt`, exactly, using the universal-variable formulation. Works
+/// for bound and unbound orbits alike.
+pub fn advance(r0: Vec3d, v0: Vec3d, mu: f64, dt: f64) -> (Vec3d, Vec3d) {
+    let r0_mag = r0.magnitude();
+    let vr0 = r0.dot(v0) / r0_mag;
+    let alpha = 2. / r0_mag - v0.dot(v0) / mu;
+    let sqrt_mu = mu.sqrt();
+
+    // Newton iteration on the universal anomaly.
+    let mut chi = sqrt_mu * alpha.abs() * dt;
+    if chi == 0. {
+        chi = sqrt_mu * dt / r0_mag;
+    }
+    for _ in 0..MAX_ITERATIONS {
+        let z = alpha * chi * chi;
+        let (c, s) = stumpff(z);
+        let f = r0_mag * vr0 / sqrt_mu * chi * chi * c
+            + (1. - alpha * r0_mag) * chi * chi * chi * s
+            + r0_mag * chi
+            - sqrt_mu * dt;
+        let df = r0_mag * vr0 / sqrt_mu * chi * (1. - z * s)
+            + (1. - alpha * r0_mag) * chi * chi * c
+            + r0_mag;
+        let step = f / df;
+        chi -= step;
+        if step.abs() <= TOLERANCE * chi.abs().max(1.) {
+            break;
+        }
+    }
+
+    let z = alpha * chi * chi;
+    let (c, s) = stumpff(z);
+    let f = 1. - chi * chi / r0_mag * c;
+    let g = dt - chi * chi * chi / sqrt_mu * s;
+    let r = f * r0 + g * v0;
+    let r_mag = r.magnitude();
+    let fdot = sqrt_mu / (r_mag * r0_mag) * (z * s - 1.) * chi;
+    let gdot = 1. - chi * chi / r_mag * c;
+    let v = fdot * r0 + gdot * v0;
+    return (r, v);
+}
+
+/// The classical elements of a two-body orbit, for the relative motion of one body about the
+/// other. Angles are in radians and measured from the x-y plane and the x axis. Unbound orbits
+/// have a negative semi-major axis and an eccentricity of at least one.
+///
+/// The ascending node is taken to be on the x axis for orbits in the x-y plane, periapsis to be at
+/// the ascending node for circular orbits, and radial orbits to lie in the x-y plane, where these
+/// aren't defined.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OrbitalElements {
+    pub semi_major_axis: f64,
+    pub eccentricity: f64,
+    pub inclination: f64,
+    // Longitude of the ascending node.
+    pub ascending_node: f64,
+    // Argument of periapsis, from the ascending node.
+    pub periapsis: f64,
+    pub true_anomaly: f64,
+}
+
+impl OrbitalElements {
+    // Elements of the orbit with relative position `r` and velocity `v` and gravitational
+    // parameter `mu`.
+    pub fn from_state(r: Vec3d, v: Vec3d, mu: f64) -> OrbitalElements {
+        let r_mag = r.magnitude();
+        let h = r.cross(v);
+        let (hx, hy, hz) = h.position();
+        let ecc = v.cross(h) / mu - r / r_mag;
+        let e = ecc.magnitude();
+        let energy = v.dot(v) / 2. - mu / r_mag;
+
+        let node = Vec3d::new(-hy, hx, 0.);
+        let inclination = match h.magnitude() > 0. {
+            true => (hz / h.magnitude()).clamp(-1., 1.).acos(),
+            false => 0.,
+        };
+        let ascending_node = match node.magnitude() > 0. {
+            true => hx.atan2(-hy).rem_euclid(TAU),
+            false => 0.,
+        };
+        // Angles in the orbital plane are measured from the node line, or the x axis without one.
+        let reference = match node.magnitude() > 0. {
+            true => node.normalize(),
+            false => Vec3d::new(1., 0., 0.),
+        };
+        let normal = h.normalize();
+        let angle = |u: Vec3d| normal.dot(reference.cross(u)).atan2(reference.dot(u));
+        let periapsis = match e > TOLERANCE {
+            true => angle(ecc).rem_euclid(TAU),
+            false => 0.,
+        };
+        return OrbitalElements {
+            semi_major_axis: -mu / (2. * energy),
+            eccentricity: e,
+            inclination: inclination,
+            ascending_node: ascending_node,
+            periapsis: periapsis,
+            true_anomaly: (angle(r) - periapsis).rem_euclid(TAU),
+        };
+    }
+
+    // Elements of the orbit of `p` about `q`, attracting each other with gravitational constant
+    // `g`.
+    pub fn of_pair(p: &Point, q: &Point, g: f64) -> OrbitalElements {
+        let (px, py, pz) = p.position();
+        let (qx, qy, qz) = q.position();
+        let r = Vec3d::new(px - qx, py - qy, pz - qz);
+        return OrbitalElements::from_state(
+            r,
+            p.velocity() - q.velocity(),
+            g * (p.mass() + q.mass()),
+        );
+    }
+
+    pub fn is_bound(&self) -> bool {
+        return self.eccentricity < 1.;
+    }
+
+    // Orbital period for gravitational parameter `mu`, infinite for unbound orbits.
+    pub fn period(&self, mu: f64) -> f64 {
+        if !self.is_bound() {
+            return f64::INFINITY;
+        }
+        return TAU * (self.semi_major_axis.powi(3) / mu).sqrt();
+    }
+}
+
+// `points` in the frame where the centre of mass is at the origin and at rest.
+pub fn barycentric(points: &[Point]) -> Vec<Point> {
+    let mass: f64 = points.iter().map(|p| p.mass()).sum();
+    if mass == 0. {
+        return points.to_vec();
+    }
+    let mut com = Vec3d::new_zero();
+    let mut momentum = Vec3d::new_zero();
+    for p in points {
+        let (x, y, z) = p.position();
+        com += p.mass() * Vec3d::new(x, y, z);
+        momentum += p.momentum();
+    }
+    let (x, y, z) = (com / mass).position();
+    return relative_to(points, &Point::new(mass, x, y, z, momentum / mass));
+}
+
+// `points` in the frame of `center`, e.g. heliocentric ones for the Sun.
+pub fn relative_to(points: &[Point], center: &Point) -> Vec<Point> {
+    let (cx, cy, cz) = center.position();
+    return points
+        .iter()
+        .map(|p| {
+            let (x, y, z) = p.position();
+            p.with_position(x - cx, y - cy, z - cz)
+                .with_velocity(p.velocity() - center.velocity())
+        })
+        .collect();
+}
+
+#[cfg(test)]
+mod test {
+    use std::f64::consts::PI;
+
+    use crate::geometry::kepler::{advance, barycentric, relative_to, OrbitalElements};
+    use crate::geometry::vec3d::G;
+    use crate::ic::solar::bundled_bodies;
+    use crate::{Point, Vec3d};
+
+    #[test]
+    fn test_orbits_close_after_one_period() {
+        let mu = 4.;
+        // Circular orbit of radius 1, and an eccentric one with the same semi-major axis.
+        for v in [2., 2.5] {
+            let r0 = Vec3d::new(1., 0., 0.);
+            let v0 = Vec3d::new(0., v, 0.);
+            let a = 1. / (2. - v * v / mu);
+            let period = 2. * PI * (a * a * a / mu).sqrt();
+
+            let (r, vel) = advance(r0, v0, mu, period);
+            assert!(r.distance(r0).magnitude() < 1e-9, "{}", r);
+            assert!(vel.distance(v0).magnitude() < 1e-9, "{}", vel);
+
+            let (r, _) = advance(r0, v0, mu, period / 2.);
+            let (x, y, _) = r.position();
+            assert!((x + (2. * a - 1.)).abs() < 1e-9 && y.abs() < 1e-9, "{}", r);
+        }
+    }
+
+    #[test]
+    fn test_orbital_elements() {
+        let mu = 4.;
+        let inc: f64 = 0.3;
+        let r0 = Vec3d::new(1., 0., 0.);
+        let v0 = Vec3d::new(0., 2.5 * inc.cos(), 2.5 * inc.sin());
+        let start = OrbitalElements::from_state(r0, v0, mu);
+        let close = |a: f64, b: f64| (a - b).abs() < 1e-9;
+        assert!(close(start.semi_major_axis, 1. / (2. - 2.5 * 2.5 / mu)));
+        assert!(close(start.eccentricity, 2.5 * 2.5 / mu - 1.));
+        assert!(close(start.inclination, inc));
+        assert!(close(start.ascending_node, 0.) && close(start.periapsis, 0.));
+        assert!(close(start.true_anomaly, 0.));
+        assert!(start.is_bound());
+
+        // Only the true anomaly moves along the orbit, and half a period takes it to apoapsis.
+        let (r, v) = advance(r0, v0, mu, start.period(mu) / 2.);
+        let half = OrbitalElements::from_state(r, v, mu);
+        assert!(close(half.semi_major_axis, start.semi_major_axis));
+        assert!(close(half.eccentricity, start.eccentricity));
+        assert!(close(half.inclination, inc) && close(half.periapsis, 0.));
+        assert!(close(half.true_anomaly, PI));
+        let escaping = OrbitalElements::from_state(r0, 3. * v0, mu);
+        assert!(!escaping.is_bound() && escaping.semi_major_axis < 0.);
+        let falling = OrbitalElements::from_state(r0, Vec3d::new(-1., 0., 0.), mu);
+        assert!(close(falling.eccentricity, 1.) && falling.inclination == 0.);
+
+        // The Earth about the Sun, from the bundled ephemeris.
+        let points: Vec<Point> = bundled_bodies()
+            .iter()
+            .enumerate()
+            .map(|(i, b)| b.to_point(i as u64))
+            .collect();
+        let heliocentric = relative_to(&points, &points[0]);
+        assert_eq!(heliocentric[0].position(), (0., 0., 0.));
+        let earth = OrbitalElements::of_pair(&heliocentric[3], &heliocentric[0], G);
+        assert!(
+            (earth.semi_major_axis / 1.496e11 - 1.).abs() < 0.02,
+            "{:?}",
+            earth
+        );
+        assert!(
+            earth.eccentricity < 0.03 && earth.inclination < 1e-3,
+            "{:?}",
+            earth
+        );
+
+        let centered = barycentric(&points);
+        let mass: f64 = centered.iter().map(|p| p.mass()).sum();
+        let mut com = Vec3d::new_zero();
+        let mut momentum = Vec3d::new_zero();
+        for p in &centered {
+            let (x, y, z) = p.position();
+            com += p.mass() * Vec3d::new(x, y, z) / mass;
+            momentum += p.momentum();
+        }
+        assert!(com.magnitude() < 1e-3, "{}", com);
+        assert!(momentum.magnitude() < 1e-6 * mass, "{}", momentum);
+    }
+}
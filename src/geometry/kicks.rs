@@ -0,0 +1,149 @@
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use rand_distr::{Distribution, Normal};
+use serde::Serialize;
+
+use crate::{Point, Vec3d};
+
+/// Random velocity kicks, standing in for the recoil of supernovae. Each particle with a mass in
+/// `[min_mass, max_mass]` goes off once, at a time drawn uniformly from `[min_age, max_age]` of
+/// the run, and gets a kick with a Maxwellian speed distribution of one-dimensional dispersion
+/// `dispersion`, in a random direction.
+///
+/// Every particle's time and kick are drawn from its own stream, seeded from `seed` and its id, so
+/// they don't depend on the order particles come in or on whether the run was resumed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Kicks {
+    pub min_mass: f64,
+    pub max_mass: f64,
+    pub min_age: f64,
+    pub max_age: f64,
+    pub dispersion: f64,
+    pub seed: u64,
+}
+
+// Where a run logs its kicks, one `Kick` per row.
+pub const KICK_FILE: &str = "kicks.csv";
+
+/// A kick given to one particle, in SI units.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Kick {
+    pub step: u64,
+    pub time: f64,
+    pub id: u64,
+    pub mass: f64,
+    pub dvx: f64,
+    pub dvy: f64,
+    pub dvz: f64,
+}
+
+impl Kicks {
+    fn rng(&self, id: u64) -> StdRng {
+        let mut seed = [0; 32];
+        seed[..8].copy_from_slice(&self.seed.to_le_bytes());
+        seed[8..16].copy_from_slice(&id.to_le_bytes());
+        return StdRng::from_seed(seed);
+    }
+
+    // When the particle with this id goes off, if its mass qualifies, and the kick it gets.
+    pub fn draw(&self, p: &Point) -> Option<(f64, Vec3d)> {
+        if p.mass() < self.min_mass || p.mass() > self.max_mass || p.pinned() {
+            return None;
+        }
+        let mut rng = self.rng(p.id());
+        let at = self.min_age + rng.gen::<f64>() * (self.max_age - self.min_age);
+        let normal = Normal::new(0., self.dispersion.max(f64::MIN_POSITIVE)).unwrap();
+        let mut component = || normal.sample(&mut rng);
+        return Some((at, Vec3d::new(component(), component(), component())));
+    }
+
+    // Kicks every particle that goes off after `from` and no later than `to`. Returns the kicked
+    // particles and the kicks, or None if nothing went off.
+    pub fn apply(
+        &self,
+        step: u64,
+        from: f64,
+        to: f64,
+        points: Vec<Point>,
+    ) -> Option<(Vec<Point>, Vec<Kick>)> {
+        let mut kicks = vec![];
+        let kicked = points
+            .into_iter()
+            .map(|p| match self.draw(&p) {
+                Some((at, dv)) if from < at && at <= to => {
+                    let (dvx, dvy, dvz) = dv.position();
+                    kicks.push(Kick {
+                        step: step,
+                        time: to,
+                        id: p.id(),
+                        mass: p.mass(),
+                        dvx: dvx,
+                        dvy: dvy,
+                        dvz: dvz,
+                    });
+                    p.with_velocity(p.velocity() + dv)
+                }
+                _ => p,
+            })
+            .collect();
+        if kicks.is_empty() {
+            return None;
+        }
+        return Some((kicked, kicks));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::geometry::kicks::Kicks;
+    use crate::{Point, Vec3d};
+
+    #[test]
+    fn test_kicks() {
+        let kicks = Kicks {
+            min_mass: 8.,
+            max_mass: 40.,
+            min_age: 10.,
+            max_age: 20.,
+            dispersion: 100.,
+            seed: 7,
+        };
+        let points: Vec<Point> = (0..2000)
+            .map(|i| Point::new((i % 50) as f64, i as f64, 0., 0., Vec3d::new_zero()).with_id(i))
+            .collect();
+        let eligible = points
+            .iter()
+            .filter(|p| (8. ..=40.).contains(&p.mass()))
+            .count();
+
+        // Stepping through the whole window kicks every eligible particle exactly once.
+        let mut all = vec![];
+        let mut current = points.clone();
+        for step in 0..30 {
+            let (from, to) = (step as f64, step as f64 + 1.);
+            if let Some((kicked, given)) = kicks.apply(step, from, to, current.clone()) {
+                assert!(given
+                    .iter()
+                    .all(|k| k.time == to && (10. ..=20.).contains(&to)));
+                current = kicked;
+                all.extend(given);
+            }
+        }
+        assert_eq!(all.len(), eligible);
+        let mut ids: Vec<u64> = all.iter().map(|k| k.id).collect();
+        ids.sort();
+        ids.dedup();
+        assert_eq!(ids.len(), eligible);
+
+        // The draws depend on the seed and id only, not on which other particles are around.
+        let alone = kicks.apply(0, 0., 30., vec![points[20]]).unwrap().1;
+        assert_eq!(alone[0].dvx, all.iter().find(|k| k.id == 20).unwrap().dvx);
+
+        // Each component has the requested dispersion.
+        let n = all.len() as f64;
+        let variance = all.iter().map(|k| k.dvx * k.dvx).sum::<f64>() / n;
+        assert!((variance.sqrt() - 100.).abs() < 10., "{}", variance.sqrt());
+        let moving = current.iter().filter(|p| p.velocity().magnitude() > 0.);
+        assert_eq!(moving.count(), eligible);
+    }
+}
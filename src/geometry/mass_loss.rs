@@ -0,0 +1,111 @@
+use std::error::Error;
+use std::path::Path;
+
+use serde::Deserialize;
+
+/// How particles shed mass over time, e.g. to stellar winds and supernovae. The lost mass leaves
+/// the simulation and particles keep their velocities, as they would if they lost it isotropically
+/// in their own frame. Every particle is taken to be as old as the run.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MassLoss {
+    // Each particle loses this fraction of its mass per unit time: m(t) = m(0) exp(-rate t).
+    Rate { rate: f64 },
+    // The fraction of its initial mass a particle keeps at each age, as (age, fraction) pairs
+    // sorted by age. Interpolated linearly in between, and held at the ends.
+    Table { table: Vec<(f64, f64)> },
+}
+
+#[derive(Deserialize)]
+struct Row {
+    age: f64,
+    fraction: f64,
+}
+
+impl MassLoss {
+    // Reads the entries of a `Table` from a CSV file with `age` and `fraction` columns.
+    pub fn read_table(path: &Path) -> Result<Vec<(f64, f64)>, Box<dyn Error>> {
+        let mut table = vec![];
+        for row in csv::Reader::from_path(path)?.deserialize() {
+            let row: Row = row?;
+            table.push((row.age, row.fraction));
+        }
+        if table.is_empty() {
+            return Err(format!("{} has no rows", path.display()).into());
+        }
+        if table.windows(2).any(|w| w[1].0 < w[0].0) {
+            return Err(format!("ages in {} aren't sorted", path.display()).into());
+        }
+        return Ok(table);
+    }
+
+    // What masses are scaled by over a step from `age` to `age + dt`.
+    pub fn factor(&self, age: f64, dt: f64) -> f64 {
+        return match self {
+            MassLoss::Rate { rate } => (-rate * dt).exp(),
+            MassLoss::Table { table } => {
+                let before = interpolate(table, age);
+                match before > 0. {
+                    true => interpolate(table, age + dt) / before,
+                    false => 1.,
+                }
+            }
+        };
+    }
+}
+
+fn interpolate(table: &[(f64, f64)], age: f64) -> f64 {
+    let after = table.partition_point(|(a, _)| *a <= age);
+    if after == 0 {
+        return table[0].1;
+    }
+    if after == table.len() {
+        return table[after - 1].1;
+    }
+    let (a0, f0) = table[after - 1];
+    let (a1, f1) = table[after];
+    return f0 + (f1 - f0) * (age - a0) / (a1 - a0);
+}
+
+#[cfg(test)]
+mod test {
+    use crate::geometry::bh_tree::BHTree;
+    use crate::geometry::mass_loss::MassLoss;
+    use crate::simulation::Simulation;
+    use crate::{Point, Vec3d};
+
+    #[test]
+    fn test_mass_loss() {
+        let rate = MassLoss::Rate { rate: 0.1 };
+        assert!((rate.factor(5., 2.) - (-0.2f64).exp()).abs() < 1e-15);
+
+        let table = MassLoss::Table {
+            table: vec![(1., 1.), (3., 0.5), (4., 0.25)],
+        };
+        assert_eq!(table.factor(0., 1.), 1.);
+        assert_eq!(table.factor(1., 1.), 0.75);
+        assert_eq!(table.factor(2., 2.), 0.25 / 0.75);
+        assert_eq!(table.factor(4., 10.), 1.);
+
+        let points = vec![
+            Point::new(2e30, 0., 0., 0., Vec3d::new_zero()).with_id(0),
+            Point::new(1e30, 1e12, 0., 0., Vec3d::new(0., 1e3, 0.)).with_id(1),
+            Point::new(1e30, -1e12, 0., 0., Vec3d::new_zero())
+                .with_id(2)
+                .with_pinned(true),
+        ];
+        let mut sim = Simulation::from_tree(BHTree::from_points(0.5, points).unwrap(), 1., 0);
+        sim.set_mass_loss(Some(table));
+        sim.run(3).unwrap();
+        let mut after = sim.tree().points();
+        after.sort_by_key(|p| p.id());
+
+        // Ages 0 to 3 cover the drop from 1 to a half, and pinned particles are left alone.
+        let close = |a: f64, b: f64| (a - b).abs() < 1e-9 * b;
+        assert!(close(after[0].mass(), 1e30));
+        assert!(close(after[1].mass(), 5e29));
+        assert_eq!(after[2].mass(), 1e30);
+        let rs = Point::new(after[0].mass(), 0., 0., 0., Vec3d::new_zero()).schwarzchild_radius();
+        assert_eq!(after[0].schwarzchild_radius(), rs);
+        assert!(close(sim.mass_lost(), 1.5e30));
+    }
+}
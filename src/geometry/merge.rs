@@ -0,0 +1,247 @@
+use std::f64::consts::PI;
+use std::fmt::Debug;
+
+use crate::Point;
+
+/// Decides which pairs of particles merge after a step, and what they merge into. Species that
+/// don't merge (see `SpeciesTable`) are never offered to the policy.
+pub trait MergePolicy: Debug + Send + Sync {
+    fn should_merge(&self, a: &Point, b: &Point) -> bool;
+
+    // The single particle left when `a` and `b` merge.
+    fn merge(&self, a: &Point, b: &Point) -> Point {
+        return merged(*a, *b);
+    }
+
+    // How far a particle reaches for merging: two particles further apart than the sum of their
+    // reaches must never merge. Bounds the neighbour search, so keep it tight.
+    fn reach(&self, p: &Point) -> f64;
+}
+
+/// Merges particles within the larger of their Schwarzschild radii. The default.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Schwarzschild;
+
+impl MergePolicy for Schwarzschild {
+    fn should_merge(&self, a: &Point, b: &Point) -> bool {
+        let dist = a.distance_to(*b);
+        return dist <= a.schwarzchild_radius() || dist <= b.schwarzchild_radius();
+    }
+
+    fn reach(&self, p: &Point) -> f64 {
+        return p.schwarzchild_radius();
+    }
+}
+
+/// Merges particles whose explicit radii overlap.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Touching;
+
+impl MergePolicy for Touching {
+    fn should_merge(&self, a: &Point, b: &Point) -> bool {
+        let reach = a.radius() + b.radius();
+        return reach > 0. && a.distance_to(*b) <= reach;
+    }
+
+    fn reach(&self, p: &Point) -> f64 {
+        return p.radius();
+    }
+}
+
+/// Merges pairs whose combined mass, spread over the sphere with their separation as its radius,
+/// is denser than `density`: the closer and heavier a pair, the sooner it merges.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DensityThreshold {
+    pub density: f64,
+}
+
+impl DensityThreshold {
+    // Radius of the sphere of mass `mass` at the threshold density.
+    fn radius(&self, mass: f64) -> f64 {
+        return (3. * mass / (4. * PI * self.density)).cbrt();
+    }
+}
+
+impl MergePolicy for DensityThreshold {
+    fn should_merge(&self, a: &Point, b: &Point) -> bool {
+        return a.distance_to(*b) <= self.radius(a.mass() + b.mass());
+    }
+
+    // The cube root is subadditive, so the pair's radius never exceeds the sum of these.
+    fn reach(&self, p: &Point) -> f64 {
+        return self.radius(p.mass());
+    }
+}
+
+/// Never merges anything. A tree with this policy rejects particles at exactly the same position,
+/// since nothing else can separate them.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct NeverMerge;
+
+impl MergePolicy for NeverMerge {
+    fn should_merge(&self, _a: &Point, _b: &Point) -> bool {
+        return false;
+    }
+
+    fn reach(&self, _p: &Point) -> f64 {
+        return 0.;
+    }
+}
+
+// The single particle left when `a` and `b` merge. It conserves mass and momentum, keeps the
+// identity of the heavier of the two (the lower id on a tie), and takes the volume of both.
+// Anything swallowed by a pinned particle leaves it where it was, and coincident particles merge
+// where they are rather than at an average that could round, or overflow, away from it.
+pub fn merged(a: Point, b: Point) -> Point {
+    let total = a.mass() + b.mass();
+    let (ax, ay, az) = a.position();
+    let (bx, by, bz) = b.position();
+    let (x, y, z) = match a.position() == b.position() {
+        true => (ax, ay, az),
+        false => (
+            (a.mass() * ax + b.mass() * bx) / total,
+            (a.mass() * ay + b.mass() * by) / total,
+            (a.mass() * az + b.mass() * bz) / total,
+        ),
+    };
+    let survivor = match a.mass().total_cmp(&b.mass()).then(b.id().cmp(&a.id())) {
+        std::cmp::Ordering::Less => b,
+        _ => a,
+    };
+    let mut p = Point::new(
+        total,
+        x,
+        y,
+        z,
+        (a.mass() * a.velocity() + b.mass() * b.velocity()) / total,
+    )
+    .with_id(survivor.id())
+    .with_species(survivor.species())
+    .with_radius((a.radius().powi(3) + b.radius().powi(3)).cbrt());
+    if let Some(anchor) = [a, b].into_iter().find(|q| q.pinned()) {
+        let (x, y, z) = anchor.position();
+        p = p.with_position(x, y, z).with_pinned(true);
+    }
+    return p;
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use crate::error::NbodyError;
+    use crate::geometry::bh_tree::BHTree;
+    use crate::geometry::contact::{CollisionModel, CollisionOutcome};
+    use crate::geometry::merge::{
+        DensityThreshold, MergePolicy, NeverMerge, Schwarzschild, Touching,
+    };
+    use crate::{Point, Vec3d};
+
+    // Merges particles with even ids that are close together, into the lighter of the two.
+    #[derive(Debug)]
+    struct EvenIds;
+
+    impl MergePolicy for EvenIds {
+        fn should_merge(&self, a: &Point, b: &Point) -> bool {
+            let even = a.id().is_multiple_of(2) && b.id().is_multiple_of(2);
+            return even && a.distance_to(*b) <= 1.;
+        }
+
+        fn merge(&self, a: &Point, b: &Point) -> Point {
+            let lighter = if a.mass() <= b.mass() { a } else { b };
+            return lighter.with_mass(a.mass() + b.mass());
+        }
+
+        fn reach(&self, _p: &Point) -> f64 {
+            return 0.5;
+        }
+    }
+
+    #[test]
+    fn test_merge_policies() {
+        // A black hole with a Schwarzschild radius of about 3 km, a 1 km particle 2.5 km from it,
+        // and a second particle touching that one.
+        let points = vec![
+            Point::new(2e30, 0., 0., 0., Vec3d::new_zero()).with_id(0),
+            Point::new(1., 2500., 0., 0., Vec3d::new_zero())
+                .with_id(1)
+                .with_radius(1000.),
+            Point::new(1., 2500., 1500., 0., Vec3d::new_zero())
+                .with_id(2)
+                .with_radius(1000.),
+        ];
+        // Touching particles pass through each other unless the policy merges them.
+        let collisions = CollisionModel {
+            outcome: CollisionOutcome::PassThrough,
+            density: None,
+        };
+        let survivors = |policy: Arc<dyn MergePolicy>| {
+            let tree = BHTree::from_points(0.5, points.clone())
+                .unwrap()
+                .with_collisions(collisions)
+                .with_merge_policy(policy)
+                .resolve_collisions()
+                .unwrap();
+            let mut ids: Vec<u64> = tree.iter().map(|p| p.id()).collect();
+            ids.sort();
+            return ids;
+        };
+
+        // The hole swallows both particles, one per collision pass.
+        assert_eq!(survivors(Arc::new(Schwarzschild)), vec![0]);
+        assert_eq!(survivors(Arc::new(Touching)), vec![0, 1]);
+        assert_eq!(survivors(Arc::new(NeverMerge)), vec![0, 1, 2]);
+        // Light particles 1.5 km apart are far below the threshold density, the hole isn't.
+        let density = DensityThreshold { density: 1e10 };
+        assert!(density.should_merge(&points[0], &points[1]));
+        assert!(!density.should_merge(&points[1], &points[2]));
+        assert!(density.reach(&points[0]) > 2500.);
+
+        let custom = BHTree::from_points(
+            0.5,
+            (0..4)
+                .map(|i| Point::new(1. + i as f64, 0.3 * i as f64, 0., 0., Vec3d::new_zero()))
+                .map(|p| p.with_id(p.mass() as u64 - 1))
+                .collect(),
+        )
+        .unwrap()
+        .with_merge_policy(Arc::new(EvenIds))
+        .resolve_collisions()
+        .unwrap();
+        let mut left: Vec<(u64, f64)> = custom.iter().map(|p| (p.id(), p.mass())).collect();
+        left.sort_by_key(|(id, _)| *id);
+        assert_eq!(left, vec![(0, 4.), (1, 2.), (3, 4.)]);
+        assert_eq!(custom.merges().len(), 1);
+
+        // Coincident particles go through the policy too, whether they're added one at a time or
+        // in bulk, and are rejected where it wouldn't merge them.
+        let at = |id: u64, mass: f64| Point::new(mass, 1., 1., 0., Vec3d::new_zero()).with_id(id);
+        let tree = || BHTree::new(0.5, 4., -2., -2., -2.).with_merge_policy(Arc::new(EvenIds));
+        let mut one_by_one = tree();
+        one_by_one.add_point(at(0, 3.)).unwrap();
+        one_by_one.add_point(at(2, 1.)).unwrap();
+        let mut bulk = tree();
+        bulk.add_points(vec![at(0, 3.), at(2, 1.)]).unwrap();
+        for merged in [&one_by_one, &bulk] {
+            assert_eq!(merged.points(), vec![at(2, 4.)]);
+            assert_eq!(merged.merges().len(), 1);
+            assert_eq!(
+                (merged.merges()[0].survivor, merged.merges()[0].absorbed),
+                (2, 0)
+            );
+        }
+        let mut odd = tree();
+        odd.add_point(at(0, 3.)).unwrap();
+        assert!(matches!(
+            odd.add_point(at(1, 1.)),
+            Err(NbodyError::Coincident(0, 1))
+        ));
+        assert_eq!(odd.len(), 1);
+        assert!(tree().add_points(vec![at(0, 3.), at(1, 1.)]).is_err());
+        let never = BHTree::new(0.5, 4., -2., -2., -2.).with_merge_policy(Arc::new(NeverMerge));
+        assert!(never.with_points(vec![at(0, 3.), at(2, 1.)]).is_err());
+        let mut external = BHTree::new(0.5, 4., -2., -2., -2.);
+        external.add_point(at(0, 3.).with_external(true)).unwrap();
+        assert!(external.add_point(at(2, 1.)).is_err());
+    }
+}
@@ -0,0 +1,791 @@
+use serde::{Deserialize, Serialize};
+
+use crate::error::NbodyError;
+use crate::Point;
+
+/// Anything with a position in space, which an `Octree` can index.
+pub trait HasPosition {
+    fn position(&self) -> (f64, f64, f64);
+}
+
+impl HasPosition for Point {
+    fn position(&self) -> (f64, f64, f64) {
+        return Point::position(*self);
+    }
+}
+
+impl HasPosition for (f64, f64, f64) {
+    fn position(&self) -> (f64, f64, f64) {
+        return *self;
+    }
+}
+
+/// What each node of an octree keeps about the items below it, beyond their count. It is worked
+/// out bottom up: a leaf's from its items, a branch's from its children's. `()` keeps nothing; the
+/// Barnes-Hut tree keeps the mass moments its opening criterion needs.
+pub trait Summary<T>: Sized {
+    // An empty summary for another node of the same tree, carrying over any settings.
+    fn blank(&self) -> Self;
+
+    // Summarizes the items of a leaf.
+    fn of_items(&mut self, items: &[T]);
+
+    // Summarizes a branch from its children, which are summarized already. Empty children's
+    // summaries are blank and should be skipped.
+    fn of_children(&mut self, children: &[Octant<T, Self>]);
+
+    // Why the summary of `node` disagrees with what the node holds, if it does. Only called by
+    // `Octant::check`.
+    fn check(_node: &Octant<T, Self>) -> Result<(), String> {
+        return Ok(());
+    }
+}
+
+impl<T> Summary<T> for () {
+    fn blank(&self) {}
+
+    fn of_items(&mut self, _items: &[T]) {}
+
+    fn of_children(&mut self, _children: &[Octant<T, ()>]) {}
+}
+
+/// How finely the tree subdivides. A leaf splits once it holds more than `bucket_size` items,
+/// unless it is already `max_depth` levels below the root. The depth limit stops nearly coincident
+/// items from splitting nodes without end. In the Barnes-Hut tree, forces from an opened leaf are
+/// summed exactly over its particles.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Refinement {
+    pub bucket_size: usize,
+    pub max_depth: usize,
+}
+
+impl Default for Refinement {
+    fn default() -> Refinement {
+        return Refinement {
+            bucket_size: 8,
+            max_depth: 32,
+        };
+    }
+}
+
+/// One node of an octree: a cubic region of space, and either the items in it, for a leaf, or
+/// the eight children it's split into. Planar nodes split into four children in the z = zloc
+/// plane instead.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Octant<T, S = ()> {
+    #[serde(flatten)]
+    pub(crate) summary: S,
+    // The items of a leaf; empty for branches. Leaves hold up to the bucket size, or any number
+    // once they're at the maximum depth.
+    #[serde(default = "Vec::new")]
+    pub(crate) items: Vec<T>,
+    pub(crate) count: i32,
+    pub(crate) region_size: f64,
+    pub(crate) xloc: f64,
+    pub(crate) yloc: f64,
+    pub(crate) zloc: f64,
+    pub(crate) children: Vec<Octant<T, S>>,
+    #[serde(default)]
+    pub(crate) planar: bool,
+}
+
+impl<T: HasPosition, S: Summary<T>> Octant<T, S> {
+    pub fn new(region_size: f64, x: f64, y: f64, z: f64, summary: S) -> Octant<T, S> {
+        return Octant {
+            summary: summary,
+            items: vec![],
+            count: 0,
+            region_size: region_size,
+            xloc: x,
+            yloc: y,
+            zloc: z,
+            children: vec![],
+            planar: false,
+        };
+    }
+
+    // An empty node of the same tree over the given region.
+    fn sibling(&self, region_size: f64, x: f64, y: f64, z: f64) -> Octant<T, S> {
+        let mut node = Octant::new(region_size, x, y, z, self.summary.blank());
+        node.planar = self.planar;
+        return node;
+    }
+
+    pub fn summary(&self) -> &S {
+        return &self.summary;
+    }
+
+    // How many items are in this node and everything below it.
+    pub fn len(&self) -> usize {
+        return self.count as usize;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.count == 0;
+    }
+
+    // Every item in this node, depth first with children in order.
+    pub fn iter(&self) -> Items<'_, T, S> {
+        return Items {
+            stack: vec![self],
+            bucket: [].iter(),
+        };
+    }
+
+    // Sets this node's count and summary from its items, or for a branch from its children,
+    // which must be summarized already.
+    pub(crate) fn summarize(&mut self) {
+        if self.children.is_empty() {
+            self.count = self.items.len() as i32;
+            self.summary.of_items(&self.items);
+            return;
+        }
+        self.count = self.children.iter().map(|c| c.count).sum();
+        self.summary.of_children(&self.children);
+    }
+
+    // `summarize` for this node and everything below it, children before their parents.
+    pub(crate) fn summarize_all(&mut self) {
+        for child in self.children.iter_mut() {
+            child.summarize_all();
+        }
+        self.summarize();
+    }
+
+    // Distance from `pos` to the closest point of this node's region; zero if it's inside.
+    pub(crate) fn box_distance(&self, pos: (f64, f64, f64)) -> f64 {
+        let (x, y, z) = pos;
+        let gap = |v: f64, lo: f64| (lo - v).max(v - (lo + self.region_size)).max(0.);
+        let (dx, dy, dz) = (gap(x, self.xloc), gap(y, self.yloc), gap(z, self.zloc));
+        return (dx * dx + dy * dy + dz * dz).sqrt();
+    }
+
+    // Whether `pos` lies in this node's region, faces included.
+    pub(crate) fn contains(&self, pos: (f64, f64, f64)) -> bool {
+        let (x, y, z) = pos;
+        let inside = |v: f64, lo: f64| lo <= v && v <= lo + self.region_size;
+        return inside(x, self.xloc) && inside(y, self.yloc) && inside(z, self.zloc);
+    }
+
+    // Which child `pos` belongs in: the one on its side of the midplanes. That always picks exactly
+    // one, including for points on the far faces of the region or a rounding error outside them.
+    // Testing each child's half-open range instead loses such points.
+    pub(crate) fn child_index(&self, pos: (f64, f64, f64)) -> usize {
+        let (x, y, z) = pos;
+        let half = self.region_size / 2.0;
+        let upper = |v: f64, lo: f64| (v >= lo + half) as usize;
+        if self.planar {
+            return 2 * upper(x, self.xloc) + upper(y, self.yloc);
+        }
+        return 4 * upper(x, self.xloc) + 2 * upper(y, self.yloc) + upper(z, self.zloc);
+    }
+
+    // Interleaves the bits of `pos` within this node's region, 21 per axis with x the most
+    // significant, so sorting by the code orders items the way the children are.
+    pub(crate) fn morton_code(&self, pos: (f64, f64, f64)) -> u64 {
+        const CELLS: f64 = (1u64 << 21) as f64;
+        let (x, y, z) = pos;
+        let cell = |v: f64, lo: f64| {
+            let c = ((v - lo) / self.region_size * CELLS).clamp(0., CELLS - 1.);
+            return spread_bits(c as u64);
+        };
+        return cell(x, self.xloc) << 2 | cell(y, self.yloc) << 1 | cell(z, self.zloc);
+    }
+
+    // Fills `children`, which must be empty, with this node's children.
+    pub(crate) fn split(&mut self, children: Vec<Octant<T, S>>) {
+        // If we're splitting, there should not be children already.
+        debug_assert!(self.children.is_empty() && children.is_empty());
+        let mut children = children;
+
+        let child_region = self.region_size / 2.0;
+        let zs: &[f64] = if self.planar {
+            &[self.zloc]
+        } else {
+            &[self.zloc, self.zloc + child_region]
+        };
+        children.reserve(4 * zs.len());
+        for x in [self.xloc, self.xloc + child_region] {
+            for y in [self.yloc, self.yloc + child_region] {
+                for &z in zs {
+                    children.push(self.sibling(child_region, x, y, z));
+                }
+            }
+        }
+        self.children = children;
+        debug_assert_eq!(self.children.len(), if self.planar { 4 } else { 8 });
+    }
+
+    // Fills this empty node with `items`, which are in Morton order or close to it, `depth` levels
+    // below the root. Only the shape is built: the nodes need `summarize_all` afterwards.
+    pub(crate) fn build(
+        &mut self,
+        items: &mut [T],
+        depth: usize,
+        refinement: &Refinement,
+        pool: &mut NodePool<T, S>,
+    ) where
+        T: Clone,
+    {
+        if items.len() <= refinement.bucket_size || depth >= refinement.max_depth {
+            self.items = pool.bucket(items);
+            return;
+        }
+
+        self.split(pool.child_list());
+        let mut runs = [0; 8];
+        let mut in_order = true;
+        let mut last = 0;
+        for item in items.iter() {
+            let index = self.child_index(item.position());
+            in_order &= index >= last;
+            last = index;
+            runs[index] += 1;
+        }
+        if !in_order {
+            // Rounding in the Morton codes can leave an item on the wrong side of a midplane.
+            items.sort_by_key(|item| self.child_index(item.position()));
+        }
+
+        let mut children = std::mem::take(&mut self.children);
+        let mut rest = items;
+        for (child, n) in children.iter_mut().zip(runs) {
+            let (run, tail) = rest.split_at_mut(n);
+            rest = tail;
+            if n == 0 {
+                continue;
+            }
+            child.build(run, depth + 1, refinement, pool);
+        }
+        self.children = children;
+    }
+
+    // Whether `grown_toward` keeps this node's region exactly as one of the new node's children.
+    // Growing downwards takes the size off the lower corner, which rounds once the corner is too
+    // far from a multiple of the size, and then the new node's midplanes miss this one's faces.
+    pub(crate) fn grows_exactly_toward(&self, pos: (f64, f64, f64)) -> bool {
+        let size = if self.region_size > 0. {
+            self.region_size
+        } else {
+            1.
+        };
+        let (x, y, z) = pos;
+        let exact = |v: f64, lo: f64| v >= lo || (lo - size) + size == lo;
+        return exact(x, self.xloc) && exact(y, self.yloc) && (self.planar || exact(z, self.zloc));
+    }
+
+    // A node with twice the region, extending from this one towards `pos`, with this node as one
+    // of its children.
+    pub(crate) fn grown_toward(self, pos: (f64, f64, f64)) -> Octant<T, S> {
+        // A zero-sized region would never grow by doubling.
+        let size = if self.region_size > 0. {
+            self.region_size
+        } else {
+            1.
+        };
+        let (x, y, z) = pos;
+        let lower = |v: f64, lo: f64| if v < lo { lo - size } else { lo };
+        let zloc = if self.planar {
+            self.zloc
+        } else {
+            lower(z, self.zloc)
+        };
+        let mut parent = self.sibling(2. * size, lower(x, self.xloc), lower(y, self.yloc), zloc);
+        if self.count == 0 {
+            return parent;
+        }
+
+        parent.split(vec![]);
+        let upper = |lo: f64, parent_lo: f64| (lo > parent_lo) as usize;
+        let index = if self.planar {
+            2 * upper(self.xloc, parent.xloc) + upper(self.yloc, parent.yloc)
+        } else {
+            4 * upper(self.xloc, parent.xloc)
+                + 2 * upper(self.yloc, parent.yloc)
+                + upper(self.zloc, parent.zloc)
+        };
+        parent.children[index] = self;
+        parent.summarize();
+        return parent;
+    }
+
+    // A node with this one's region and children, holding the next items from `moved` in place of
+    // its own, in `iter` order. None if one of them is more than `drift` leaf sizes outside its
+    // leaf. Like `build`, it leaves the summaries to `summarize_all`.
+    pub(crate) fn refit<I: Iterator<Item = T>>(
+        &self,
+        moved: &mut I,
+        drift: f64,
+    ) -> Option<Octant<T, S>> {
+        let mut node = self.sibling(self.region_size, self.xloc, self.yloc, self.zloc);
+        if self.children.is_empty() {
+            for _ in 0..self.items.len() {
+                let item = moved.next()?;
+                if self.box_distance(item.position()) > drift * self.region_size {
+                    return None;
+                }
+                node.items.push(item);
+            }
+            return Some(node);
+        }
+
+        for child in self.children.iter() {
+            if child.count == 0 {
+                node.children.push(self.sibling(
+                    child.region_size,
+                    child.xloc,
+                    child.yloc,
+                    child.zloc,
+                ));
+                continue;
+            }
+            node.children.push(child.refit(moved, drift)?);
+        }
+        return Some(node);
+    }
+
+    // Updates `best` with the closest item to `pos` within `radius` that `keep` accepts.
+    pub(crate) fn nearest<'a, F: Fn(&T) -> bool>(
+        &'a self,
+        pos: (f64, f64, f64),
+        radius: f64,
+        keep: &F,
+        best: &mut Option<(f64, &'a T)>,
+    ) {
+        if self.count == 0 {
+            return;
+        }
+
+        let limit = best.map_or(radius, |(d, _)| d);
+        if self.box_distance(pos) > limit {
+            return;
+        }
+
+        if self.children.is_empty() {
+            for item in self.items.iter() {
+                let d = distance(pos, item.position());
+                if keep(item) && d <= best.map_or(radius, |(d, _)| d) {
+                    *best = Some((d, item));
+                }
+            }
+            return;
+        }
+        for child in self.children.iter() {
+            child.nearest(pos, radius, keep, best);
+        }
+    }
+
+    // Appends every item within `radius` of `pos` to `found`.
+    pub(crate) fn within<'a>(&'a self, pos: (f64, f64, f64), radius: f64, found: &mut Vec<&'a T>) {
+        if self.count == 0 || self.box_distance(pos) > radius {
+            return;
+        }
+
+        if self.children.is_empty() {
+            found.extend(
+                self.items
+                    .iter()
+                    .filter(|item| distance(pos, item.position()) <= radius),
+            );
+            return;
+        }
+        for child in self.children.iter() {
+            child.within(pos, radius, found);
+        }
+    }
+
+    // Merges the items of this node that `keep` accepts into `best`, the `k` closest to `pos`
+    // found so far.
+    pub(crate) fn k_nearest<'a, F: Fn(&T) -> bool>(
+        &'a self,
+        pos: (f64, f64, f64),
+        k: usize,
+        keep: &F,
+        best: &mut Vec<(f64, &'a T)>,
+    ) {
+        if self.count == 0 {
+            return;
+        }
+        if best.len() == k && self.box_distance(pos) > best[k - 1].0 {
+            return;
+        }
+
+        if self.children.is_empty() {
+            for item in self.items.iter().filter(|item| keep(item)) {
+                insert_bounded(best, k, (distance(pos, item.position()), item));
+            }
+            return;
+        }
+        // Visiting the nearest children first tightens the bound sooner.
+        let mut order: Vec<&Octant<T, S>> = self.children.iter().collect();
+        order.sort_by(|a, b| a.box_distance(pos).total_cmp(&b.box_distance(pos)));
+        for child in order {
+            child.k_nearest(pos, k, keep, best);
+        }
+    }
+
+    // Checks the shape and counts of this node, `depth` levels below the root, and everything
+    // below it against what it holds, and its summary by `Summary::check`.
+    pub(crate) fn check(&self, depth: usize, refinement: &Refinement) -> Result<(), NbodyError> {
+        let fail = |what: &str| {
+            return Err(NbodyError::InconsistentNode(format!(
+                "{} in node @ ({},{},{}) with region size {}",
+                what, self.xloc, self.yloc, self.zloc, self.region_size
+            )));
+        };
+        if self.children.is_empty() {
+            if self.count as usize != self.items.len() {
+                return fail(&format!(
+                    "leaf counting {} of {} items",
+                    self.count,
+                    self.items.len()
+                ));
+            }
+            if self.items.len() > refinement.bucket_size && depth < refinement.max_depth {
+                return fail(&format!(
+                    "{} items in a leaf above the maximum depth",
+                    self.count
+                ));
+            }
+            if self
+                .items
+                .iter()
+                .any(|item| !self.contains(item.position()))
+            {
+                return fail("item outside its leaf");
+            }
+            return S::check(self).or_else(|what| fail(&what));
+        }
+
+        if !self.items.is_empty() {
+            return fail("branch holding items");
+        }
+        if self.children.len() != if self.planar { 4 } else { 8 } {
+            return fail(&format!("{} children", self.children.len()));
+        }
+        if self.count != self.children.iter().map(|c| c.count).sum::<i32>() {
+            return fail("count differing from its children's");
+        }
+        S::check(self).or_else(|what| fail(&what))?;
+        for child in self.children.iter() {
+            child.check(depth + 1, refinement)?;
+        }
+        return Ok(());
+    }
+}
+
+impl<T: Clone, S: Clone> Octant<T, S> {
+    // This node without its items or children.
+    pub(crate) fn shell(&self) -> Octant<T, S> {
+        return Octant {
+            summary: self.summary.clone(),
+            items: vec![],
+            count: self.count,
+            region_size: self.region_size,
+            xloc: self.xloc,
+            yloc: self.yloc,
+            zloc: self.zloc,
+            children: vec![],
+            planar: self.planar,
+        };
+    }
+}
+
+/// Walks the buckets of a node's leaves depth first, children in order.
+pub struct Items<'a, T, S> {
+    stack: Vec<&'a Octant<T, S>>,
+    bucket: std::slice::Iter<'a, T>,
+}
+
+impl<'a, T, S> Iterator for Items<'a, T, S> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<&'a T> {
+        loop {
+            if let Some(item) = self.bucket.next() {
+                return Some(item);
+            }
+            let node = self.stack.pop()?;
+            self.bucket = node.items.iter();
+            self.stack
+                .extend(node.children.iter().rev().filter(|c| c.count > 0));
+        }
+    }
+}
+
+/// Emptied buckets and child lists from the nodes of trees that are done with, to build the next
+/// tree from instead of allocating afresh.
+#[derive(Debug)]
+pub struct NodePool<T, S> {
+    pub(crate) buckets: Vec<Vec<T>>,
+    pub(crate) children: Vec<Vec<Octant<T, S>>>,
+}
+
+impl<T, S> Default for NodePool<T, S> {
+    fn default() -> NodePool<T, S> {
+        return NodePool {
+            buckets: vec![],
+            children: vec![],
+        };
+    }
+}
+
+impl<T: Clone, S> NodePool<T, S> {
+    // Keeps the memory of `node` and everything below it.
+    pub fn recycle(&mut self, mut node: Octant<T, S>) {
+        let mut bucket = std::mem::take(&mut node.items);
+        if bucket.capacity() > 0 {
+            bucket.clear();
+            self.buckets.push(bucket);
+        }
+        let mut children = std::mem::take(&mut node.children);
+        for child in children.drain(..) {
+            self.recycle(child);
+        }
+        if children.capacity() > 0 {
+            self.children.push(children);
+        }
+    }
+
+    // A leaf's bucket holding `items`.
+    fn bucket(&mut self, items: &[T]) -> Vec<T> {
+        let mut bucket = self.buckets.pop().unwrap_or_default();
+        bucket.extend_from_slice(items);
+        return bucket;
+    }
+
+    // An empty list for a node's children.
+    fn child_list(&mut self) -> Vec<Octant<T, S>> {
+        return self.children.pop().unwrap_or_default();
+    }
+}
+
+/// A spatial index over anything with a position, for neighbour searches that have nothing to do
+/// with gravity. The Barnes-Hut tree is the same structure with mass moments as its summary, so
+/// the collision pass, the friends-of-friends finder and the density estimate all search the one
+/// tree.
+#[derive(Debug)]
+pub struct Octree<T, S = ()> {
+    root: Octant<T, S>,
+}
+
+impl<T: HasPosition + Clone> Octree<T> {
+    // Indexes `items` with the default refinement.
+    pub fn new(items: Vec<T>) -> Octree<T> {
+        return Octree::with_summary(items, Refinement::default(), ());
+    }
+}
+
+impl<T: HasPosition + Clone, S: Summary<T>> Octree<T, S> {
+    // Indexes `items` in a cube just covering them, subdivided as `refinement` says. Every node's
+    // summary starts as a `blank` of `summary`.
+    pub fn with_summary(items: Vec<T>, refinement: Refinement, summary: S) -> Octree<T, S> {
+        let (lo, size) = bounds(&items);
+        let mut root = Octant::new(size, lo, lo, lo, summary);
+        let mut items = items;
+        items.sort_by_cached_key(|item| root.morton_code(item.position()));
+        root.build(&mut items, 0, &refinement, &mut NodePool::default());
+        root.summarize_all();
+        return Octree { root: root };
+    }
+
+    pub fn root(&self) -> &Octant<T, S> {
+        return &self.root;
+    }
+
+    pub fn len(&self) -> usize {
+        return self.root.len();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.root.is_empty();
+    }
+
+    // Every item, in Morton order.
+    pub fn iter(&self) -> Items<'_, T, S> {
+        return self.root.iter();
+    }
+
+    // Every item within `radius` of `pos`, closest first.
+    pub fn within(&self, pos: (f64, f64, f64), radius: f64) -> Vec<&T> {
+        let mut found = vec![];
+        self.root.within(pos, radius, &mut found);
+        found.sort_by(|a, b| distance(pos, a.position()).total_cmp(&distance(pos, b.position())));
+        return found;
+    }
+
+    // The closest item to `pos` within `radius` that `keep` accepts.
+    pub fn nearest<F: Fn(&T) -> bool>(
+        &self,
+        pos: (f64, f64, f64),
+        radius: f64,
+        keep: F,
+    ) -> Option<&T> {
+        let mut best = None;
+        self.root.nearest(pos, radius, &keep, &mut best);
+        return best.map(|(_, item)| item);
+    }
+
+    // The `k` items closest to `pos` that `keep` accepts, closest first.
+    pub fn k_nearest<F: Fn(&T) -> bool>(&self, pos: (f64, f64, f64), k: usize, keep: F) -> Vec<&T> {
+        let mut best = Vec::with_capacity(k + 1);
+        if k > 0 {
+            self.root.k_nearest(pos, k, &keep, &mut best);
+        }
+        return best.into_iter().map(|(_, item)| item).collect();
+    }
+}
+
+// The lower corner and size of a cube covering `items` with room to spare, so that none lie on
+// its far faces. With no items it's the cube a single item at the origin would get, from which a
+// tree can grow to wherever items turn up.
+pub(crate) fn bounds<T: HasPosition>(items: &[T]) -> (f64, f64) {
+    if items.is_empty() {
+        return (-1., 2.);
+    }
+    let mut min_dim = f64::MAX;
+    let mut max_dim = f64::MIN;
+    for item in items {
+        let (x, y, z) = item.position();
+        min_dim = x.min(y).min(z).min(min_dim);
+        max_dim = x.max(y).max(z).max(max_dim);
+    }
+    max_dim += 1.;
+    min_dim -= 1.;
+    return (min_dim, max_dim - min_dim);
+}
+
+// Straight-line distance from `a` to `b`.
+fn distance(a: (f64, f64, f64), b: (f64, f64, f64)) -> f64 {
+    let (x, y, z) = (b.0 - a.0, b.1 - a.1, b.2 - a.2);
+    return (x * x + y * y + z * z).sqrt();
+}
+
+// Spreads the low 21 bits of `v` out to every third bit.
+fn spread_bits(v: u64) -> u64 {
+    let mut v = v & 0x1f_ffff;
+    v = (v | v << 32) & 0x1f_0000_0000_ffff;
+    v = (v | v << 16) & 0x1f_0000_ff00_00ff;
+    v = (v | v << 8) & 0x100f_00f0_0f00_f00f;
+    v = (v | v << 4) & 0x10c3_0c30_c30c_30c3;
+    v = (v | v << 2) & 0x1249_2492_4924_9249;
+    return v;
+}
+
+// Adds `candidate` to `best`, which is kept sorted by distance and no longer than `k`.
+pub(crate) fn insert_bounded<T>(best: &mut Vec<(f64, T)>, k: usize, candidate: (f64, T)) {
+    if best.len() == k && best[k - 1].0 <= candidate.0 {
+        return;
+    }
+    let at = best.partition_point(|(d, _)| *d <= candidate.0);
+    best.insert(at, candidate);
+    best.truncate(k);
+}
+
+#[cfg(test)]
+mod test {
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    use crate::geometry::octree::{HasPosition, Octant, Octree, Refinement, Summary};
+
+    // A station with a name, indexed by where it is.
+    #[derive(Debug, Clone, PartialEq)]
+    struct Station {
+        name: usize,
+        at: (f64, f64, f64),
+    }
+
+    impl HasPosition for Station {
+        fn position(&self) -> (f64, f64, f64) {
+            return self.at;
+        }
+    }
+
+    // The highest name below each node.
+    #[derive(Debug, Default)]
+    struct Highest(usize);
+
+    impl Summary<Station> for Highest {
+        fn blank(&self) -> Highest {
+            return Highest::default();
+        }
+
+        fn of_items(&mut self, items: &[Station]) {
+            self.0 = items.iter().map(|s| s.name).max().unwrap_or(0);
+        }
+
+        fn of_children(&mut self, children: &[Octant<Station, Highest>]) {
+            self.0 = children.iter().map(|c| c.summary.0).max().unwrap_or(0);
+        }
+    }
+
+    #[test]
+    fn test_octree() {
+        let mut rng = StdRng::seed_from_u64(11);
+        let stations: Vec<Station> = (0..500)
+            .map(|i| Station {
+                name: i,
+                at: (
+                    rng.gen_range(-10.0..10.),
+                    rng.gen_range(-10.0..10.),
+                    rng.gen_range(0.0..1.),
+                ),
+            })
+            .collect();
+        let tree = Octree::new(stations.clone());
+        assert_eq!(tree.len(), 500);
+        tree.root().check(0, &Refinement::default()).unwrap();
+        let mut names: Vec<usize> = tree.iter().map(|s| s.name).collect();
+        names.sort();
+        assert_eq!(names, (0..500).collect::<Vec<_>>());
+
+        // Every query agrees with looking at each station in turn.
+        let distance = |a: (f64, f64, f64), s: &Station| {
+            let (x, y, z) = s.at;
+            ((x - a.0).powi(2) + (y - a.1).powi(2) + (z - a.2).powi(2)).sqrt()
+        };
+        let by_distance = |pos: (f64, f64, f64)| {
+            let mut all: Vec<&Station> = stations.iter().collect();
+            all.sort_by(|a, b| distance(pos, a).total_cmp(&distance(pos, b)));
+            all
+        };
+        for _ in 0..20 {
+            let pos = (rng.gen_range(-12.0..12.), rng.gen_range(-12.0..12.), 0.5);
+            let all = by_distance(pos);
+            let near: Vec<&Station> = all
+                .iter()
+                .copied()
+                .filter(|s| distance(pos, s) <= 2.)
+                .collect();
+            assert_eq!(tree.within(pos, 2.), near);
+            assert_eq!(tree.k_nearest(pos, 7, |_| true), all[..7].to_vec());
+            let even: Vec<&Station> = all.iter().copied().filter(|s| s.name % 2 == 0).collect();
+            assert_eq!(tree.nearest(pos, 100., |s| s.name % 2 == 0), Some(even[0]));
+            assert_eq!(
+                tree.k_nearest(pos, 3, |s| s.name % 2 == 0),
+                even[..3].to_vec()
+            );
+        }
+        assert_eq!(tree.nearest((100., 0., 0.), 1., |_| true), None);
+        assert!(Octree::<(f64, f64, f64)>::new(vec![])
+            .within((0., 0., 0.), 1.)
+            .is_empty());
+
+        // Summaries are worked out bottom up over the same shape.
+        let summarized = Octree::with_summary(
+            stations,
+            Refinement {
+                bucket_size: 4,
+                max_depth: 32,
+            },
+            Highest::default(),
+        );
+        assert_eq!(summarized.root().summary().0, 499);
+        assert!(summarized
+            .root()
+            .children
+            .iter()
+            .all(|c| c.summary.0 == c.iter().map(|s| s.name).max().unwrap_or(0)));
+    }
+}
@@ -0,0 +1,145 @@
+use serde::{Deserialize, Serialize};
+
+use crate::geometry::force::ForceLaw;
+
+/// Decides when a node is far enough away for its centre of mass to stand in for its particles.
+/// The error estimates assume Newtonian gravity with the force law's G.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum OpeningCriterion {
+    // The Barnes-Hut test: accept when the distance is more than the node's size over theta plus
+    // the offset of its centre of mass from its centre. Without the offset, a node whose mass sits
+    // in its far corner would be accepted by a particle right next to its near face.
+    #[default]
+    Geometric,
+    // Accept when the monopole's estimated acceleration error, G M s^2 / d^4, is below
+    // `tolerance`, in m/s^2. Heavy nodes get opened further out than light ones.
+    AbsoluteError {
+        tolerance: f64,
+    },
+    // Salmon & Warren (1994): accept when the bound on the monopole's acceleration error,
+    // 3 G B2 / (d^2 (d - b)^2), is below `tolerance`, in m/s^2. B2 is the mass-weighted sum of
+    // squared distances from the centre of mass and b the distance from it to the node's farthest
+    // corner. The negative B3 term of the bound is left out, which only makes it stricter.
+    SalmonWarren {
+        tolerance: f64,
+    },
+}
+
+/// How the opening angle varies with a node's depth below the root. The biggest nodes, near the
+/// root, pass their monopole errors on to every particle they're accepted by, so a stricter angle
+/// there tightens the far field for less than lowering theta everywhere would cost.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum ThetaSchedule {
+    // Theta at every level.
+    #[default]
+    Uniform,
+    // Theta times `root` at the root, rising in equal steps to theta at `levels` levels down and
+    // staying there below.
+    Ramp {
+        root: f64,
+        levels: u32,
+    },
+}
+
+/// What an `OpeningCriterion` needs to know about a node.
+#[derive(Debug, Clone, Copy)]
+pub struct NodeExtent {
+    pub size: f64,
+    pub mass: f64,
+    // Distance from the centre of mass to the centre of the node.
+    pub offset: f64,
+    // Distance from the centre of mass to the farthest corner of the node.
+    pub b_max: f64,
+    // Sum of m |x - com|^2 over the node's particles.
+    pub b2: f64,
+}
+
+impl OpeningCriterion {
+    // Whether a node can be treated as a point mass by a particle at distance `d` from its centre
+    // of mass. The error-bounded criteria never accept a node the particle could be inside.
+    pub fn accepts(&self, node: &NodeExtent, d: f64, theta: f64, law: &ForceLaw) -> bool {
+        return match *self {
+            OpeningCriterion::Geometric => d > node.size / theta + node.offset,
+            OpeningCriterion::AbsoluteError { tolerance } => {
+                d > node.b_max
+                    && law.g * node.mass * node.size * node.size / (d * d * d * d) < tolerance
+            }
+            OpeningCriterion::SalmonWarren { tolerance } => {
+                let gap = d - node.b_max;
+                gap > 0. && 3. * law.g * node.b2 / (d * d * gap * gap) < tolerance
+            }
+        };
+    }
+}
+
+impl ThetaSchedule {
+    // The opening angle for a node `size` across, in a tree whose root is `root_size` across and
+    // opened with `theta`.
+    pub fn theta(&self, theta: f64, root_size: f64, size: f64) -> f64 {
+        return match *self {
+            ThetaSchedule::Uniform => theta,
+            ThetaSchedule::Ramp { root, levels } => {
+                let depth = (root_size / size).log2().round().max(0.);
+                let along = match levels > 0 {
+                    true => (depth / levels as f64).min(1.),
+                    false => 1.,
+                };
+                theta * (root + (1. - root) * along)
+            }
+        };
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::geometry::force::ForceLaw;
+    use crate::geometry::opening::{NodeExtent, OpeningCriterion, ThetaSchedule};
+
+    #[test]
+    fn test_criteria() {
+        let law = ForceLaw::newtonian(1.);
+        let node = NodeExtent {
+            size: 1.,
+            mass: 100.,
+            offset: 0.,
+            b_max: 0.9,
+            b2: 10.,
+        };
+
+        let geometric = OpeningCriterion::Geometric;
+        assert!(geometric.accepts(&node, 2.5, 0.5, &law));
+        assert!(!geometric.accepts(&node, 1.5, 0.5, &law));
+        let lopsided = NodeExtent {
+            offset: 0.8,
+            ..node
+        };
+        assert!(!geometric.accepts(&lopsided, 2.5, 0.5, &law));
+        assert!(geometric.accepts(&lopsided, 2.9, 0.5, &law));
+        assert!(!geometric.accepts(&node, 1e9, 0., &law));
+
+        // 100 / d^4 < 1 from d = 3.16.
+        let absolute = OpeningCriterion::AbsoluteError { tolerance: 1. };
+        assert!(!absolute.accepts(&node, 3., 0.5, &law));
+        assert!(absolute.accepts(&node, 3.2, 0.5, &law));
+        let light = NodeExtent { mass: 1., ..node };
+        assert!(absolute.accepts(&light, 1.1, 0.5, &law));
+        assert!(!absolute.accepts(&light, 0.8, 0.5, &law));
+
+        // 30 / (d^2 (d - 0.9)^2) < 1 from d = 2.83.
+        let salmon_warren = OpeningCriterion::SalmonWarren { tolerance: 1. };
+        assert!(!salmon_warren.accepts(&node, 2.8, 0.5, &law));
+        assert!(salmon_warren.accepts(&node, 2.9, 0.5, &law));
+        assert!(!salmon_warren.accepts(&NodeExtent { b2: 0., ..node }, 0.8, 0.5, &law));
+
+        // A ramp from half of theta at the root reaches theta four levels down.
+        assert_eq!(ThetaSchedule::Uniform.theta(0.8, 16., 16.), 0.8);
+        let ramp = ThetaSchedule::Ramp {
+            root: 0.5,
+            levels: 4,
+        };
+        assert_eq!(ramp.theta(0.8, 16., 16.), 0.4);
+        assert!((ramp.theta(0.8, 16., 4.) - 0.6).abs() < 1e-12);
+        assert_eq!(ramp.theta(0.8, 16., 1.), 0.8);
+        assert_eq!(ramp.theta(0.8, 16., 0.125), 0.8);
+    }
+}
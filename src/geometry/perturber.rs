@@ -0,0 +1,160 @@
+use std::error::Error;
+use std::path::Path;
+
+use serde::Deserialize;
+
+use crate::geometry::vec3d::G;
+use crate::Vec3d;
+
+/// A massive body moving along a prescribed path that pulls on every particle without being
+/// pulled back or integrated itself, e.g. a galaxy flying past a cluster. Its pull is softened
+/// over `softening` so particles it passes through aren't flung away.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Perturber {
+    pub trajectory: Trajectory,
+    pub softening: f64,
+}
+
+// A perturber's (time, mass, position).
+pub type Waypoint = (f64, f64, Vec3d);
+
+/// Where a perturber is, and how heavy, at each time.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Trajectory {
+    // A constant mass moving in a straight line, at `position` at time zero.
+    Linear {
+        mass: f64,
+        position: Vec3d,
+        velocity: Vec3d,
+    },
+    // Waypoints sorted by time. Interpolated linearly in between, and held at the ends.
+    Table {
+        table: Vec<Waypoint>,
+    },
+}
+
+#[derive(Deserialize)]
+struct Row {
+    time: f64,
+    mass: f64,
+    x: f64,
+    y: f64,
+    z: f64,
+}
+
+impl Trajectory {
+    // Reads the waypoints of a `Table` from a CSV file with time, mass, x, y and z columns.
+    pub fn read_table(path: &Path) -> Result<Vec<Waypoint>, Box<dyn Error>> {
+        let mut table = vec![];
+        for row in csv::Reader::from_path(path)?.deserialize() {
+            let row: Row = row?;
+            table.push((row.time, row.mass, Vec3d::new(row.x, row.y, row.z)));
+        }
+        if table.is_empty() {
+            return Err(format!("{} has no rows", path.display()).into());
+        }
+        if table.windows(2).any(|w| w[1].0 < w[0].0) {
+            return Err(format!("times in {} aren't sorted", path.display()).into());
+        }
+        return Ok(table);
+    }
+
+    // The mass and position at time `t`.
+    pub fn at(&self, t: f64) -> (f64, Vec3d) {
+        return match self {
+            Trajectory::Linear {
+                mass,
+                position,
+                velocity,
+            } => (*mass, *position + t * *velocity),
+            Trajectory::Table { table } => {
+                let after = table.partition_point(|(time, _, _)| *time <= t);
+                if after == 0 {
+                    return (table[0].1, table[0].2);
+                }
+                if after == table.len() {
+                    return (table[after - 1].1, table[after - 1].2);
+                }
+                let (t0, m0, x0) = table[after - 1];
+                let (t1, m1, x1) = table[after];
+                let f = (t - t0) / (t1 - t0);
+                (m0 + f * (m1 - m0), x0 + f * (x1 - x0))
+            }
+        };
+    }
+}
+
+impl Perturber {
+    pub fn potential(&self, pos: Vec3d, t: f64) -> f64 {
+        let (mass, center) = self.trajectory.at(t);
+        let r2 = center.distance(pos).magnitude_squared() + self.softening * self.softening;
+        return -G * mass / r2.sqrt();
+    }
+
+    pub fn acceleration(&self, pos: Vec3d, t: f64) -> Vec3d {
+        let (mass, center) = self.trajectory.at(t);
+        let d = center.distance(pos);
+        let r2 = d.magnitude_squared() + self.softening * self.softening;
+        if r2 == 0. {
+            return Vec3d::new_zero();
+        }
+        return (-G * mass / (r2 * r2.sqrt())) * d;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::geometry::bh_tree::BHTree;
+    use crate::geometry::perturber::{Perturber, Trajectory};
+    use crate::geometry::vec3d::G;
+    use crate::simulation::Simulation;
+    use crate::{Point, Vec3d};
+
+    #[test]
+    fn test_perturber() {
+        let table = Trajectory::Table {
+            table: vec![
+                (0., 1., Vec3d::new(0., 0., 0.)),
+                (2., 3., Vec3d::new(4., 0., 0.)),
+            ],
+        };
+        assert_eq!(table.at(-1.), (1., Vec3d::new_zero()));
+        assert_eq!(table.at(1.), (2., Vec3d::new(2., 0., 0.)));
+        assert_eq!(table.at(5.), (3., Vec3d::new(4., 0., 0.)));
+        let line = Trajectory::Linear {
+            mass: 1.,
+            position: Vec3d::new(0., 1., 0.),
+            velocity: Vec3d::new(2., 0., 0.),
+        };
+        assert_eq!(line.at(3.), (1., Vec3d::new(6., 1., 0.)));
+
+        // Without softening the pull is Newtonian and follows the perturber along its path.
+        let perturber = Perturber {
+            trajectory: Trajectory::Linear {
+                mass: 1e10,
+                position: Vec3d::new(10., 0., 0.),
+                velocity: Vec3d::new(0., 1., 0.),
+            },
+            softening: 0.,
+        };
+        let (ax, ay, _) = perturber
+            .acceleration(Vec3d::new(0., 5., 0.), 5.)
+            .position();
+        assert!((ax - G * 1e10 / 100.).abs() < 1e-15 && ay == 0.);
+
+        // A particle at rest beside the path is pulled towards the perturber, which stays put.
+        let points = vec![Point::new(1., 0., 0., 0., Vec3d::new_zero()).with_id(0)];
+        let mut sim = Simulation::from_tree(BHTree::from_points(0.5, points).unwrap(), 1., 0);
+        sim.add_perturber(Perturber {
+            trajectory: Trajectory::Linear {
+                mass: 1e10,
+                position: Vec3d::new(10., 0., 0.),
+                velocity: Vec3d::new_zero(),
+            },
+            softening: 1.,
+        });
+        sim.run(3).unwrap();
+        let (vx, vy, _) = sim.tree().iter().next().unwrap().velocity().position();
+        assert!(vx > 0. && vy == 0.);
+    }
+}
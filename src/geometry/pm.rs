@@ -0,0 +1,259 @@
+use std::f64::consts::PI;
+use std::sync::Arc;
+
+use rustfft::num_complex::Complex;
+use rustfft::{Fft, FftPlanner};
+
+use crate::geometry::force::ForceLaw;
+use crate::{Point, Vec3d};
+
+/// Long-range gravity from a particle mesh in a periodic box centred on the origin. Masses are
+/// deposited onto `cells`^3 cells with cloud-in-cell weights, Poisson's equation is solved with
+/// FFTs, and the accelerations are interpolated back to the particles with the same weights.
+///
+/// With a `split` scale r_s above zero the mesh gives only the long-range part of the force,
+/// smoothed by exp(-k^2 r_s^2), and a tree with the `short_range` force law from `short_range`
+/// adds the rest from within a few r_s: TreePM. A split of about 1.25 cells keeps the mesh's own
+/// errors well below the tree's. With a split of zero the mesh gives the whole force, which is
+/// then only resolved down to a couple of cells.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ParticleMesh {
+    pub cells: usize,
+    pub split: f64,
+}
+
+/// The acceleration the mesh works out for one arrangement of particles.
+#[derive(Debug, Clone)]
+pub struct MeshField {
+    cells: usize,
+    box_size: f64,
+    // Acceleration components at the cell centres, indexed x slowest and z fastest.
+    acceleration: [Vec<f64>; 3],
+}
+
+impl ParticleMesh {
+    // `law` cut down to the part of the force the mesh leaves to the tree.
+    pub fn short_range(&self, law: ForceLaw) -> ForceLaw {
+        return ForceLaw {
+            short_range: (self.split > 0.).then_some(self.split),
+            ..law
+        };
+    }
+
+    // The long-range acceleration field of `points` in a periodic box of side `box_size`, for
+    // Newtonian gravity with constant `g`.
+    pub fn field<'a>(
+        &self,
+        points: impl Iterator<Item = &'a Point>,
+        box_size: f64,
+        g: f64,
+    ) -> MeshField {
+        let n = self.cells;
+        let h = box_size / n as f64;
+        let mut density = vec![Complex::new(0., 0.); n * n * n];
+        for p in points {
+            for (i, weight) in cloud_in_cell(n, box_size, p.position()) {
+                density[i].re += weight * p.mass() / (h * h * h);
+            }
+        }
+        let mut planner = FftPlanner::new();
+        transform(&mut density, n, &planner.plan_fft_forward(n));
+
+        // The potential of each mode. With a split, the cloud-in-cell smoothing of both the
+        // deposit and the interpolation is divided back out; that boosts the shortest waves,
+        // which only the split's smoothing keeps in check.
+        let wavenumber = |i: usize| {
+            let i = if i < n.div_ceil(2) {
+                i as f64
+            } else {
+                i as f64 - n as f64
+            };
+            return 2. * PI * i / box_size;
+        };
+        let sinc = |x: f64| if x == 0. { 1. } else { x.sin() / x };
+        let mut potential = density;
+        for (i, phi) in potential.iter_mut().enumerate() {
+            let k = [
+                wavenumber(i / (n * n)),
+                wavenumber(i / n % n),
+                wavenumber(i % n),
+            ];
+            let k2 = k[0] * k[0] + k[1] * k[1] + k[2] * k[2];
+            if k2 == 0. {
+                *phi = Complex::new(0., 0.);
+                continue;
+            }
+            let window: f64 = match self.split > 0. {
+                true => k.iter().map(|k| sinc(k * h / 2.).powi(2)).product(),
+                false => 1.,
+            };
+            *phi *= -4. * PI * g / k2 * (-k2 * self.split * self.split).exp() / (window * window);
+        }
+
+        // Each component is the inverse transform of -i k phi. The Nyquist modes have no
+        // consistent sign, so they're left out.
+        let inverse = planner.plan_fft_inverse(n);
+        let acceleration = [n * n, n, 1].map(|stride| {
+            let mut component: Vec<Complex<f64>> = potential
+                .iter()
+                .enumerate()
+                .map(|(i, phi)| {
+                    let j = i / stride % n;
+                    if n.is_multiple_of(2) && j == n / 2 {
+                        return Complex::new(0., 0.);
+                    }
+                    let k = wavenumber(j);
+                    Complex::new(k * phi.im, -k * phi.re)
+                })
+                .collect();
+            transform(&mut component, n, &inverse);
+            let norm = (n * n * n) as f64;
+            component.iter().map(|a| a.re / norm).collect()
+        });
+        return MeshField {
+            cells: n,
+            box_size: box_size,
+            acceleration: acceleration,
+        };
+    }
+}
+
+impl MeshField {
+    // The acceleration at `pos`, interpolated from the cells around it.
+    pub fn acceleration(&self, pos: (f64, f64, f64)) -> Vec3d {
+        let mut a = [0.; 3];
+        for (i, weight) in cloud_in_cell(self.cells, self.box_size, pos) {
+            for (a, grid) in a.iter_mut().zip(self.acceleration.iter()) {
+                *a += weight * grid[i];
+            }
+        }
+        return Vec3d::new(a[0], a[1], a[2]);
+    }
+}
+
+// The eight cells sharing a particle at `pos` under cloud-in-cell weighting, with their weights.
+fn cloud_in_cell(n: usize, box_size: f64, pos: (f64, f64, f64)) -> [(usize, f64); 8] {
+    let h = box_size / n as f64;
+    // The cell whose centre is just below `v`, and how far past that centre `v` is.
+    let cell = |v: f64| {
+        let u = (v + box_size / 2.) / h - 0.5;
+        let below = u.floor();
+        return ((below as i64).rem_euclid(n as i64) as usize, u - below);
+    };
+    let (x, y, z) = pos;
+    let axes = [cell(x), cell(y), cell(z)];
+    return std::array::from_fn(|corner| {
+        let mut index = 0;
+        let mut weight = 1.;
+        for (axis, (i, f)) in axes.iter().enumerate() {
+            let up = corner >> (2 - axis) & 1 == 1;
+            index = index * n + if up { (i + 1) % n } else { *i };
+            weight *= if up { *f } else { 1. - f };
+        }
+        (index, weight)
+    });
+}
+
+// Transforms a cube of side `n`, indexed x slowest and z fastest, along all three axes.
+fn transform(data: &mut [Complex<f64>], n: usize, fft: &Arc<dyn Fft<f64>>) {
+    // Rows along z are contiguous, and the transform takes them all at once.
+    fft.process(data);
+    let mut line = vec![Complex::new(0., 0.); n];
+    for stride in [n, n * n] {
+        for start in (0..data.len()).filter(|i| i / stride % n == 0) {
+            for (j, c) in line.iter_mut().enumerate() {
+                *c = data[start + j * stride];
+            }
+            fft.process(&mut line);
+            for (j, c) in line.iter().enumerate() {
+                data[start + j * stride] = *c;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    use crate::geometry::bh_tree::BHTree;
+    use crate::geometry::boundary::Boundary;
+    use crate::geometry::force::ForceLaw;
+    use crate::geometry::pm::ParticleMesh;
+    use crate::{Point, Vec3d};
+
+    #[test]
+    fn test_particle_mesh() {
+        // Two particles a few cells apart in a box big enough that their images barely matter.
+        let p = Point::new(1., -0.125, 0.01, 0., Vec3d::new_zero());
+        let q = Point::new(2., 0.125, 0.01, 0., Vec3d::new_zero()).with_id(1);
+        let newton = ForceLaw::newtonian(1.);
+        let exact = newton.force(p, q);
+
+        // On its own the mesh gets the force roughly right.
+        let pm = ParticleMesh {
+            cells: 64,
+            split: 0.,
+        };
+        let field = pm.field([p, q].iter(), 4., 1.);
+        let force = p.mass() * field.acceleration(p.position());
+        assert!(
+            (force - exact).magnitude() < 0.05 * exact.magnitude(),
+            "{}",
+            force
+        );
+        assert_eq!(pm.short_range(newton), newton);
+
+        // Split with the tree, the two parts add up to the Newtonian force more closely, and the
+        // mesh's part is equal and opposite.
+        let treepm = ParticleMesh {
+            cells: 64,
+            split: 1.25 * 4. / 64.,
+        };
+        let field = treepm.field([p, q].iter(), 4., 1.);
+        let law = treepm.short_range(newton);
+        assert_eq!(law.short_range, Some(treepm.split));
+        let long = p.mass() * field.acceleration(p.position());
+        let force = law.force(p, q) + long;
+        assert!(
+            (force - exact).magnitude() < 0.025 * exact.magnitude(),
+            "{}",
+            force
+        );
+        let back = q.mass() * field.acceleration(q.position());
+        assert!((long + back).magnitude() < 1e-3 * long.magnitude());
+
+        // An even sheet of particles, one per cell, pulls on nothing.
+        let sheet: Vec<Point> = (0..64 * 64)
+            .map(|i| {
+                let (x, y) = ((i / 64) as f64, (i % 64) as f64);
+                Point::new(1., x / 16. - 1.97, y / 16. - 1.97, 0.3, Vec3d::new_zero())
+            })
+            .collect();
+        let field = pm.field(sheet.iter(), 4., 1.);
+        assert!(field.acceleration((0.5, -0.2, 0.3)).magnitude() < 1e-6);
+
+        // The tree leaves out whole nodes beyond the cutoff, across the periodic faces too, and
+        // gets the same short-range force as summing every pair.
+        let mut rng = StdRng::seed_from_u64(3);
+        let points: Vec<Point> = (0..2000)
+            .map(|i| {
+                let mut x = || rng.gen_range(-2.0..2.0);
+                Point::new(1., x(), x(), x(), Vec3d::new_zero()).with_id(i)
+            })
+            .collect();
+        let boundary = Boundary::Periodic { box_size: 4. };
+        let tree = BHTree::new_bounded(0.3, boundary)
+            .with_force_law(law)
+            .rebuild(points.clone())
+            .unwrap();
+        let target = Point::new(1., 1.99, -1.99, 0., Vec3d::new_zero()).with_id(5000);
+        let (force, cost) = tree.force_and_cost(target);
+        let direct = points.iter().fold(Vec3d::new_zero(), |f, q| {
+            f + law.force(target, q.nearest_image(target, 4.))
+        });
+        assert!((force - direct).magnitude() < 0.01 * direct.magnitude());
+        assert!(cost < 100, "{}", cost);
+    }
+}
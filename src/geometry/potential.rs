@@ -0,0 +1,154 @@
+use crate::geometry::vec3d::G;
+use crate::Vec3d;
+
+/// A fixed background potential whose acceleration is added to the tree force on every particle,
+/// e.g. a galaxy that a simulated star cluster orbits inside.
+pub trait ExternalPotential: Send + Sync {
+    fn potential(&self, pos: Vec3d) -> f64;
+    fn acceleration(&self, pos: Vec3d) -> Vec3d;
+}
+
+/// Keplerian potential of a point mass fixed at `center`.
+#[derive(Debug, Clone, Copy)]
+pub struct PointMass {
+    pub mass: f64,
+    pub center: Vec3d,
+}
+
+impl ExternalPotential for PointMass {
+    fn potential(&self, pos: Vec3d) -> f64 {
+        return -G * self.mass / self.center.distance(pos).magnitude();
+    }
+
+    fn acceleration(&self, pos: Vec3d) -> Vec3d {
+        let d = self.center.distance(pos);
+        let r = d.magnitude();
+        return (-G * self.mass / (r * r * r)) * d;
+    }
+}
+
+/// Logarithmic halo with a flat rotation curve at speed `v0` outside `core_radius`, flattened
+/// along z by `q`.
+#[derive(Debug, Clone, Copy)]
+pub struct LogarithmicHalo {
+    pub v0: f64,
+    pub core_radius: f64,
+    pub q: f64,
+}
+
+impl LogarithmicHalo {
+    fn m2(&self, pos: Vec3d) -> f64 {
+        let (x, y, z) = pos.position();
+        return self.core_radius * self.core_radius + x * x + y * y + z * z / (self.q * self.q);
+    }
+}
+
+impl ExternalPotential for LogarithmicHalo {
+    fn potential(&self, pos: Vec3d) -> f64 {
+        return 0.5 * self.v0 * self.v0 * self.m2(pos).ln();
+    }
+
+    fn acceleration(&self, pos: Vec3d) -> Vec3d {
+        let (x, y, z) = pos.position();
+        let k = -self.v0 * self.v0 / self.m2(pos);
+        return Vec3d::new(k * x, k * y, k * z / (self.q * self.q));
+    }
+}
+
+/// Miyamoto-Nagai disk in the xy-plane with radial scale `a` and vertical scale `b`.
+#[derive(Debug, Clone, Copy)]
+pub struct MiyamotoNagai {
+    pub mass: f64,
+    pub a: f64,
+    pub b: f64,
+}
+
+impl ExternalPotential for MiyamotoNagai {
+    fn potential(&self, pos: Vec3d) -> f64 {
+        let (x, y, z) = pos.position();
+        let s = self.a + (z * z + self.b * self.b).sqrt();
+        return -G * self.mass / (x * x + y * y + s * s).sqrt();
+    }
+
+    fn acceleration(&self, pos: Vec3d) -> Vec3d {
+        let (x, y, z) = pos.position();
+        let zb = (z * z + self.b * self.b).sqrt();
+        let s = self.a + zb;
+        let d = (x * x + y * y + s * s).sqrt();
+        let k = -G * self.mass / (d * d * d);
+        return Vec3d::new(k * x, k * y, k * z * s / zb);
+    }
+}
+
+/// Isotropic harmonic trap pulling everything towards the origin with angular frequency `omega`.
+#[derive(Debug, Clone, Copy)]
+pub struct HarmonicTrap {
+    pub omega: f64,
+}
+
+impl ExternalPotential for HarmonicTrap {
+    fn potential(&self, pos: Vec3d) -> f64 {
+        let r = pos.magnitude();
+        return 0.5 * self.omega * self.omega * r * r;
+    }
+
+    fn acceleration(&self, pos: Vec3d) -> Vec3d {
+        return (-self.omega * self.omega) * pos;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::geometry::potential::{
+        ExternalPotential, HarmonicTrap, LogarithmicHalo, MiyamotoNagai, PointMass,
+    };
+    use crate::Vec3d;
+
+    // The acceleration should be minus the gradient of the potential.
+    fn check_gradient(p: &dyn ExternalPotential, pos: Vec3d) {
+        let h = 1e-4 * pos.magnitude();
+        let (x, y, z) = pos.position();
+        let d = |dx: f64, dy: f64, dz: f64| {
+            let fwd = p.potential(Vec3d::new(x + dx, y + dy, z + dz));
+            let back = p.potential(Vec3d::new(x - dx, y - dy, z - dz));
+            -(fwd - back) / (2. * h)
+        };
+        let expected = Vec3d::new(d(h, 0., 0.), d(0., h, 0.), d(0., 0., h));
+        let err = p.acceleration(pos).distance(expected).magnitude();
+        assert!(
+            err <= 1e-6 * expected.magnitude(),
+            "{} vs {}",
+            err,
+            expected
+        );
+    }
+
+    #[test]
+    fn test_accelerations_match_potentials() {
+        let pos = Vec3d::new(3., -4., 1.5);
+        check_gradient(
+            &PointMass {
+                mass: 1e12,
+                center: Vec3d::new(1., 1., 0.),
+            },
+            pos,
+        );
+        check_gradient(
+            &LogarithmicHalo {
+                v0: 2.,
+                core_radius: 1.,
+                q: 0.8,
+            },
+            pos,
+        );
+        check_gradient(
+            &MiyamotoNagai {
+                mass: 1e12,
+                a: 3.,
+                b: 0.3,
+            },
+            pos,
+        );
+        check_gradient(&HarmonicTrap { omega: 0.5 }, pos);
+    }
+}
@@ -0,0 +1,78 @@
+use std::error::Error;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+fn default_merge() -> bool {
+    return true;
+}
+
+/// Per-species settings, looked up by the `species` index carried on every point.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Species {
+    pub name: String,
+    // Plummer softening length of this species. A pair of particles is softened by the larger of
+    // their two species' lengths, so the forces between them stay equal and opposite.
+    #[serde(default)]
+    pub softening: f64,
+    // Whether particles of this species may merge on close approach.
+    #[serde(default = "default_merge")]
+    pub merge: bool,
+    // Suggested display colour for visualisation, e.g. "#ffcc00".
+    #[serde(default)]
+    pub color: Option<String>,
+}
+
+impl Default for Species {
+    fn default() -> Species {
+        return Species {
+            name: String::new(),
+            softening: 0.,
+            merge: true,
+            color: None,
+        };
+    }
+}
+
+/// The species in a run. Species without an entry use the defaults.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SpeciesTable {
+    species: Vec<Species>,
+}
+
+impl SpeciesTable {
+    pub fn new(species: Vec<Species>) -> SpeciesTable {
+        return SpeciesTable { species: species };
+    }
+
+    // Reads a JSON array of species; the position in the array is the species index.
+    pub fn load(path: &Path) -> Result<SpeciesTable, Box<dyn Error>> {
+        let species: Vec<Species> = serde_json::from_reader(std::fs::File::open(path)?)?;
+        return Ok(SpeciesTable::new(species));
+    }
+
+    pub fn softening(&self, species: u16) -> f64 {
+        return self
+            .species
+            .get(species as usize)
+            .map_or(0., |s| s.softening);
+    }
+
+    // Softening between particles of species `a` and `b`. Symmetric in the two.
+    pub fn pair_softening(&self, a: u16, b: u16) -> f64 {
+        return self.softening(a).max(self.softening(b));
+    }
+
+    pub fn merges(&self, species: u16) -> bool {
+        return self.species.get(species as usize).is_none_or(|s| s.merge);
+    }
+
+    // Index of the species with the given name.
+    pub fn index_of(&self, name: &str) -> Option<u16> {
+        return self
+            .species
+            .iter()
+            .position(|s| s.name == name)
+            .map(|i| i as u16);
+    }
+}
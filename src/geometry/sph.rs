@@ -0,0 +1,282 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+
+use crate::geometry::bh_tree::BHTree;
+#[cfg(not(feature = "parallel"))]
+use crate::geometry::bh_tree::SerialIter;
+use crate::geometry::density::{kernel, kernel_gradient};
+use crate::geometry::octree::Octree;
+use crate::geometry::thermodynamics::Thermodynamics;
+use crate::{Point, Vec3d};
+
+/// Smoothed-particle hydrodynamics for the particles of one species, which are taken to be gas:
+/// on top of gravity they feel the pressure of the gas around them, and carry an internal energy
+/// that compression and shocks heat. Everything else only feels gravity.
+///
+/// Each gas particle's density is smoothed over its `neighbors` nearest gas neighbours, with a
+/// smoothing length of half the distance to the furthest, as `BHTree::density_at` does. Pressure
+/// forces use the symmetrised kernel gradient and Monaghan's artificial viscosity with
+/// coefficients `alpha` and `beta`, so shocks stay a few smoothing lengths wide. Neighbours are
+/// found within twice a particle's own smoothing length, so a pair with very different smoothing
+/// lengths may only see each other from one side, and momentum is then conserved only roughly.
+/// How pressure follows from density and internal energy, and how much heat the gas keeps, is up
+/// to `thermodynamics`.
+#[derive(Debug, Clone)]
+pub struct Sph {
+    pub species: u16,
+    pub neighbors: usize,
+    pub thermodynamics: Arc<dyn Thermodynamics>,
+    pub alpha: f64,
+    pub beta: f64,
+    // Internal energy per unit mass given to gas particles that don't have one yet.
+    pub initial_energy: f64,
+}
+
+/// The smoothed state of one gas particle.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Gas {
+    pub smoothing: f64,
+    pub density: f64,
+    // Internal energy per unit mass.
+    pub energy: f64,
+    pub pressure: f64,
+    pub sound_speed: f64,
+}
+
+/// The gas particles of a tree, indexed for neighbour searches, with their smoothed state by id.
+#[derive(Debug)]
+pub struct GasState {
+    index: Octree<Point>,
+    gas: HashMap<u64, Gas>,
+}
+
+impl GasState {
+    pub fn get(&self, id: u64) -> Option<&Gas> {
+        return self.gas.get(&id);
+    }
+
+    pub fn len(&self) -> usize {
+        return self.gas.len();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.gas.is_empty();
+    }
+}
+
+/// The hydrodynamic force on a gas particle, and the rate its internal energy per unit mass
+/// changes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Hydro {
+    pub force: Vec3d,
+    pub heating: f64,
+}
+
+impl Sph {
+    pub fn is_gas(&self, p: &Point) -> bool {
+        return p.species() == self.species && !p.is_tracer();
+    }
+
+    // Smooths the gas particles of `tree`, whose internal energies per unit mass are in `energies`
+    // by id. Particles missing from it get the initial energy.
+    pub fn gas(&self, tree: &BHTree, energies: &HashMap<u64, f64>) -> GasState {
+        let points: Vec<Point> = tree.iter().filter(|p| self.is_gas(p)).copied().collect();
+        let index = Octree::new(points);
+        let gas = index
+            .iter()
+            .collect::<Vec<_>>()
+            .par_iter()
+            .map(|p| {
+                let energy = energies.get(&p.id()).copied();
+                let gas = self.smoothed(&index, p, energy.unwrap_or(self.initial_energy));
+                (p.id(), gas)
+            })
+            .collect();
+        return GasState {
+            index: index,
+            gas: gas,
+        };
+    }
+
+    fn smoothed(&self, index: &Octree<Point>, p: &Point, energy: f64) -> Gas {
+        let neighbors = index.k_nearest(p.position(), self.neighbors, |q| q.id() != p.id());
+        let smoothing = neighbors.last().map_or(0., |q| p.distance_to(**q) / 2.);
+        let density = match smoothing > 0. {
+            true => neighbors
+                .iter()
+                .fold(p.mass() * kernel(0., smoothing), |rho, q| {
+                    rho + q.mass() * kernel(p.distance_to(**q), smoothing)
+                }),
+            false => 0.,
+        };
+        return Gas {
+            smoothing: smoothing,
+            density: density,
+            energy: energy,
+            pressure: self.thermodynamics.pressure(density, energy),
+            sound_speed: self.thermodynamics.sound_speed(density, energy),
+        };
+    }
+
+    // The pressure and viscous forces on every gas particle in `state`, and how fast they heat, by
+    // id.
+    pub fn hydro(&self, state: &GasState) -> HashMap<u64, Hydro> {
+        return state
+            .index
+            .iter()
+            .collect::<Vec<_>>()
+            .par_iter()
+            .map(|p| (p.id(), self.hydro_on(state, p)))
+            .collect();
+    }
+
+    fn hydro_on(&self, state: &GasState, p: &Point) -> Hydro {
+        let mut hydro = Hydro {
+            force: Vec3d::new_zero(),
+            heating: 0.,
+        };
+        let a = match state.get(p.id()) {
+            Some(a) if a.density > 0. => *a,
+            _ => return hydro,
+        };
+        let (px, py, pz) = p.position();
+        let own = a.pressure / (a.density * a.density);
+        let mut acceleration = Vec3d::new_zero();
+        for q in state.index.within(p.position(), 2. * a.smoothing) {
+            let b = match state.get(q.id()) {
+                Some(b) if q.id() != p.id() && b.density > 0. => *b,
+                _ => continue,
+            };
+            let (qx, qy, qz) = q.position();
+            let offset = Vec3d::new(px - qx, py - qy, pz - qz);
+            let r = offset.magnitude();
+            if r == 0. {
+                continue;
+            }
+            let gradient =
+                (0.5 * (kernel_gradient(r, a.smoothing) + kernel_gradient(r, b.smoothing)) / r)
+                    * offset;
+            let v = p.velocity() - q.velocity();
+            let approach = v.dot(offset);
+
+            // Only approaching pairs are damped, so the viscosity heats shocks but not expansion.
+            let h = 0.5 * (a.smoothing + b.smoothing);
+            let viscosity = match approach < 0. {
+                true => {
+                    let mu = h * approach / (r * r + 0.01 * h * h);
+                    let c = 0.5 * (a.sound_speed + b.sound_speed);
+                    let rho = 0.5 * (a.density + b.density);
+                    (-self.alpha * c * mu + self.beta * mu * mu) / rho
+                }
+                false => 0.,
+            };
+            let theirs = b.pressure / (b.density * b.density);
+            acceleration += (-q.mass() * (own + theirs + viscosity)) * gradient;
+            hydro.heating += q.mass() * (own + 0.5 * viscosity) * v.dot(gradient);
+        }
+        hydro.force = p.mass() * acceleration;
+        return hydro;
+    }
+
+    // The internal energies of the gas particles in `tree` after a step of `dt` from `state`,
+    // where `hydro` was worked out at the start of the step. Gas that wasn't in `state`, such as
+    // fragments split off during the step, gets the initial energy.
+    pub fn heated(
+        &self,
+        tree: &BHTree,
+        state: &GasState,
+        hydro: &HashMap<u64, Hydro>,
+        dt: f64,
+    ) -> HashMap<u64, f64> {
+        return tree
+            .iter()
+            .filter(|p| self.is_gas(p))
+            .map(|p| {
+                let energy = match state.get(p.id()) {
+                    Some(gas) => {
+                        let heating = hydro.get(&p.id()).map_or(0., |h| h.heating);
+                        self.thermodynamics
+                            .energy_after(gas.density, gas.energy, heating, dt)
+                    }
+                    None => self.initial_energy,
+                };
+                (p.id(), energy)
+            })
+            .collect();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    use crate::geometry::bh_tree::BHTree;
+    use crate::geometry::sph::Sph;
+    use crate::geometry::thermodynamics::Adiabatic;
+    use crate::{Point, Vec3d};
+
+    #[test]
+    fn test_sph() {
+        // A lattice of gas with a star in the middle. The star isn't smoothed, and the gas at the
+        // centre is as dense as the lattice and pushed nowhere.
+        let mut points = vec![Point::new(5., 3.5, 3.5, 3.5, Vec3d::new_zero()).with_id(1000)];
+        for i in 0..8 * 8 * 8 {
+            let (x, y, z) = ((i / 64) as f64, (i / 8 % 8) as f64, (i % 8) as f64);
+            points.push(
+                Point::new(1., x, y, z, Vec3d::new_zero())
+                    .with_id(i)
+                    .with_species(1),
+            );
+        }
+        let tree = BHTree::from_points(0.5, points).unwrap();
+        let sph = Sph {
+            species: 1,
+            neighbors: 32,
+            thermodynamics: Arc::new(Adiabatic { gamma: 5. / 3. }),
+            alpha: 1.,
+            beta: 2.,
+            initial_energy: 1.,
+        };
+        let energies = HashMap::from([(0, 2.)]);
+        let state = sph.gas(&tree, &energies);
+        assert_eq!(state.len(), 512);
+        assert!(state.get(1000).is_none());
+        assert_eq!(state.get(0).unwrap().energy, 2.);
+        let center = 3 * 64 + 3 * 8 + 3;
+        let gas = state.get(center).unwrap();
+        assert!((gas.density - 1.).abs() < 0.15, "{}", gas.density);
+        assert_eq!(gas.energy, 1.);
+        let hydro = sph.hydro(&state);
+        assert!(hydro[&center].force.magnitude() < 0.05);
+        assert_eq!(hydro[&center].heating, 0.);
+
+        // The edges are pushed outwards, and the forces roughly cancel.
+        let corner = hydro[&0].force;
+        let (x, y, z) = corner.position();
+        assert!(x < 0. && y < 0. && z < 0.);
+        let total = hydro.values().fold(Vec3d::new_zero(), |f, h| f + h.force);
+        let sum: f64 = hydro.values().map(|h| h.force.magnitude()).sum();
+        assert!(total.magnitude() < 0.01 * sum, "{} of {}", total, sum);
+
+        // Gas falling inwards is heated by compression and viscosity.
+        let falling: Vec<Point> = tree
+            .iter()
+            .map(|p| {
+                let (x, y, z) = p.position();
+                p.with_velocity(Vec3d::new(3.5 - x, 3.5 - y, 3.5 - z))
+            })
+            .collect();
+        let falling = BHTree::from_points(0.5, falling).unwrap();
+        let state = sph.gas(&falling, &HashMap::new());
+        let heating = sph.hydro(&state);
+        assert!(heating[&center].heating > 0.);
+        assert!(heating[&0].heating > 0.);
+        let after = sph.heated(&falling, &state, &heating, 0.1);
+        assert_eq!(after.len(), 512);
+        assert!(after[&0] > 1.);
+    }
+}
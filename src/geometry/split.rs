@@ -0,0 +1,174 @@
+use std::f64::consts::PI;
+
+use crate::geometry::bh_tree::BHTree;
+use crate::{Point, Vec3d};
+
+/// The inverse of merging: a particle whose SPH density climbs past `density` breaks up into
+/// `fragments` particles of equal mass, a crude stand-in for fragmentation and star formation.
+///
+/// Fragments share their parent's velocity, so mass and momentum are conserved, and sit in
+/// opposite pairs about its position, so the centre of mass doesn't move either. They're spread
+/// over a quarter of the distance to the parent's nearest neighbour. The first fragment keeps the
+/// parent's id and the others get new ones that no particle has had. Pinned particles and tracers
+/// are never split.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Fragmentation {
+    pub density: f64,
+    // Neighbours densities are smoothed over.
+    pub neighbors: usize,
+    pub fragments: usize,
+    // Particles lighter than this are left whole, so splitting can't run away.
+    pub min_mass: f64,
+}
+
+impl Fragmentation {
+    // The particles of `tree` after splitting every one that qualifies, or None if none does. New
+    // fragments are numbered from `next_id`, or above every id in the tree if that's higher.
+    pub fn apply(&self, tree: &BHTree, next_id: u64) -> Option<Vec<Point>> {
+        if self.fragments < 2 {
+            return None;
+        }
+        let points = tree.points();
+        let densities = tree.densities(self.neighbors);
+        let splits = |p: &Point, rho: f64| {
+            rho > self.density && p.mass() >= self.min_mass && !p.pinned() && !p.is_tracer()
+        };
+        if !points
+            .iter()
+            .zip(&densities)
+            .any(|(p, rho)| splits(p, *rho))
+        {
+            return None;
+        }
+
+        let mut next = tree
+            .iter()
+            .map(|p| p.id() + 1)
+            .max()
+            .unwrap_or(0)
+            .max(next_id);
+        let mut out = Vec::with_capacity(points.len());
+        for (p, rho) in points.into_iter().zip(densities) {
+            if !splits(&p, rho) {
+                out.push(p);
+                continue;
+            }
+            let spread = match tree.k_nearest(p, 1).first() {
+                Some(q) => p.distance_to(*q) / 4.,
+                None => 0.,
+            };
+            for (i, offset) in offsets(self.fragments).into_iter().enumerate() {
+                let (x, y, z) = p.position();
+                let (dx, dy, dz) = (spread * offset).position();
+                let id = match i {
+                    0 => p.id(),
+                    _ => {
+                        next += 1;
+                        next - 1
+                    }
+                };
+                out.push(
+                    p.with_mass(p.mass() / self.fragments as f64)
+                        .with_position(x + dx, y + dy, z + dz)
+                        .with_radius(p.radius() / (self.fragments as f64).cbrt())
+                        .with_id(id),
+                );
+            }
+        }
+        return Some(out);
+    }
+}
+
+// `n` unit offsets that sum to zero: opposite pairs along directions spread evenly over the sphere
+// (a Fibonacci lattice), plus one at the centre if `n` is odd.
+fn offsets(n: usize) -> Vec<Vec3d> {
+    let pairs = n / 2;
+    let golden = PI * (3. - 5_f64.sqrt());
+    let mut out = Vec::with_capacity(n);
+    for i in 0..pairs {
+        // Only the upper hemisphere, since each direction brings its opposite along.
+        let z = 1. - (i as f64 + 0.5) / pairs as f64;
+        let r = (1. - z * z).sqrt();
+        let phi = golden * i as f64;
+        let d = Vec3d::new(r * phi.cos(), r * phi.sin(), z);
+        out.push(d);
+        out.push(-d);
+    }
+    if n % 2 == 1 {
+        out.push(Vec3d::new_zero());
+    }
+    return out;
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+
+    use crate::geometry::bh_tree::BHTree;
+    use crate::geometry::split::Fragmentation;
+    use crate::{Point, Vec3d};
+
+    #[test]
+    fn test_fragmentation() {
+        // A tight clump of 8 heavy particles in a sparse field of light ones.
+        let mut points: Vec<Point> = (0..8)
+            .map(|i| {
+                let (x, y, z) = ((i & 1) as f64, ((i >> 1) & 1) as f64, (i >> 2) as f64);
+                Point::new(1e3, x, y, z, Vec3d::new(1., 0., 0.))
+            })
+            .collect();
+        points.extend((0..64).map(|i| {
+            let (x, y, z) = ((i % 4) as f64, (i / 4 % 4) as f64, (i / 16) as f64);
+            Point::new(1., 100. * x + 50., 100. * y, 100. * z, Vec3d::new_zero())
+        }));
+        let points: Vec<Point> = points
+            .into_iter()
+            .enumerate()
+            .map(|(i, p)| p.with_id(i as u64))
+            .collect();
+        let tree = BHTree::from_points(0.5, points.clone()).unwrap();
+        let fragmentation = Fragmentation {
+            density: 1.,
+            neighbors: 7,
+            fragments: 3,
+            min_mass: 500.,
+        };
+
+        let split = fragmentation.apply(&tree, 0).unwrap();
+        assert_eq!(split.len(), 72 + 8 * 2);
+        let ids: HashSet<u64> = split.iter().map(|p| p.id()).collect();
+        assert_eq!(ids.len(), split.len());
+        assert!(ids.is_superset(&(0..72).collect()));
+        // Ids that particles gone from the tree had aren't handed out again.
+        let later = fragmentation.apply(&tree, 100).unwrap();
+        let new: HashSet<u64> = later
+            .iter()
+            .map(|p| p.id())
+            .filter(|id| *id >= 72)
+            .collect();
+        assert_eq!(new, (100..116).collect());
+        let light: Vec<&Point> = split.iter().filter(|p| p.mass() == 1.).collect();
+        assert_eq!(light.len(), 64);
+
+        let totals = |points: &[Point]| {
+            let mass: f64 = points.iter().map(|p| p.mass()).sum();
+            let mut momentum = Vec3d::new_zero();
+            let mut moment = Vec3d::new_zero();
+            for p in points {
+                let (x, y, z) = p.position();
+                momentum += p.momentum();
+                moment += p.mass() * Vec3d::new(x, y, z);
+            }
+            return (mass, momentum, moment / mass);
+        };
+        let (mass, momentum, com) = totals(&points);
+        let (mass2, momentum2, com2) = totals(&split);
+        assert!((mass - mass2).abs() < 1e-9);
+        assert!((momentum - momentum2).magnitude() < 1e-9);
+        assert!((com - com2).magnitude() < 1e-9);
+
+        // The fragments are too light to split again.
+        let again = BHTree::from_points(0.5, split).unwrap();
+        assert_eq!(fragmentation.apply(&again, 0), None);
+    }
+}
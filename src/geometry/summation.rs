@@ -0,0 +1,130 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{Point, Vec3d};
+
+/// A running sum that carries the rounding error of every addition along with it (Neumaier's
+/// variant of Kahan summation), so that adding many small terms to a large total loses almost
+/// nothing.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct CompensatedSum {
+    sum: f64,
+    compensation: f64,
+}
+
+impl CompensatedSum {
+    pub fn add(&mut self, v: f64) {
+        let t = self.sum + v;
+        if self.sum.abs() >= v.abs() {
+            self.compensation += (self.sum - t) + v;
+        } else {
+            self.compensation += (v - t) + self.sum;
+        }
+        self.sum = t;
+    }
+
+    // Adds another running sum, error included.
+    pub fn merge(&mut self, other: &CompensatedSum) {
+        self.add(other.sum);
+        self.add(other.compensation);
+    }
+
+    pub fn value(&self) -> f64 {
+        return self.sum + self.compensation;
+    }
+}
+
+/// A `CompensatedSum` per component.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct CompensatedVec {
+    x: CompensatedSum,
+    y: CompensatedSum,
+    z: CompensatedSum,
+}
+
+impl CompensatedVec {
+    pub fn add(&mut self, v: Vec3d) {
+        let (x, y, z) = v.position();
+        self.x.add(x);
+        self.y.add(y);
+        self.z.add(z);
+    }
+
+    pub fn merge(&mut self, other: &CompensatedVec) {
+        self.x.merge(&other.x);
+        self.y.merge(&other.y);
+        self.z.merge(&other.z);
+    }
+
+    pub fn value(&self) -> Vec3d {
+        return Vec3d::new(self.x.value(), self.y.value(), self.z.value());
+    }
+}
+
+/// The mass of a set of particles and their mass-weighted position and velocity, from which their
+/// centre of mass follows to within rounding of the final division.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct MassSums {
+    mass: CompensatedSum,
+    position: CompensatedVec,
+    momentum: CompensatedVec,
+}
+
+impl MassSums {
+    pub fn add(&mut self, p: Point) {
+        let (x, y, z) = p.position();
+        self.mass.add(p.mass());
+        self.position.add(p.mass() * Vec3d::new(x, y, z));
+        self.momentum.add(p.momentum());
+    }
+
+    pub fn merge(&mut self, other: &MassSums) {
+        self.mass.merge(&other.mass);
+        self.position.merge(&other.position);
+        self.momentum.merge(&other.momentum);
+    }
+
+    pub fn center_of_mass(&self) -> Point {
+        let mass = self.mass.value();
+        let (x, y, z) = (self.position.value() / mass).position();
+        return Point::new(mass, x, y, z, self.momentum.value() / mass);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::geometry::summation::{CompensatedSum, MassSums};
+    use crate::{Point, Vec3d};
+
+    #[test]
+    fn test_compensated_sums() {
+        // Each 1e-16 is below half an ulp of 1, so a plain sum never moves.
+        let mut plain = 1.;
+        let mut sum = CompensatedSum::default();
+        sum.add(1.);
+        for _ in 0..1_000_000 {
+            plain += 1e-16;
+            sum.add(1e-16);
+        }
+        assert_eq!(plain, 1.);
+        assert!((sum.value() - (1. + 1e-10)).abs() < 1e-15);
+
+        // A big term that cancels is handled too, which plain Kahan summation gets wrong.
+        let mut sum = CompensatedSum::default();
+        for v in [1., 1e100, 1., -1e100] {
+            sum.add(v);
+        }
+        assert_eq!(sum.value(), 2.);
+
+        let mut heavy = MassSums::default();
+        heavy.add(Point::new(1e20, 1., 0., 0., Vec3d::new(1., 0., 0.)));
+        let mut light = MassSums::default();
+        for _ in 0..1000 {
+            light.add(Point::new(1e5, 2., 0., 0., Vec3d::new_zero()));
+        }
+        heavy.merge(&light);
+        let com = heavy.center_of_mass();
+        assert_eq!(com.mass(), 1e20 + 1e8);
+        let (x, _, _) = com.position();
+        assert!((x - (1e20 + 2e8) / (1e20 + 1e8)).abs() < 1e-15);
+    }
+}
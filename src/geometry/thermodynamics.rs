@@ -0,0 +1,114 @@
+use std::fmt::Debug;
+
+/// How SPH gas turns density and internal energy per unit mass into pressure, and how its internal
+/// energy changes over a step. Compression and shocks heat the gas at the rate SPH works out;
+/// implementations decide what of that heat the gas keeps, and may heat or cool it further.
+pub trait Thermodynamics: Debug + Send + Sync {
+    fn pressure(&self, density: f64, energy: f64) -> f64;
+
+    fn sound_speed(&self, density: f64, energy: f64) -> f64;
+
+    // The internal energy after `dt` for gas at `density` with `energy`, which SPH heats at
+    // `heating` per unit time. Keeps all the heat by default.
+    fn energy_after(&self, _density: f64, energy: f64, heating: f64, dt: f64) -> f64 {
+        return (energy + heating * dt).max(0.);
+    }
+}
+
+/// Gas held at a fixed sound speed, so P = c^2 rho and heating is radiated away at once. The
+/// internal energy never changes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Isothermal {
+    pub sound_speed: f64,
+}
+
+impl Thermodynamics for Isothermal {
+    fn pressure(&self, density: f64, _energy: f64) -> f64 {
+        return self.sound_speed * self.sound_speed * density;
+    }
+
+    fn sound_speed(&self, _density: f64, _energy: f64) -> f64 {
+        return self.sound_speed;
+    }
+
+    fn energy_after(&self, _density: f64, energy: f64, _heating: f64, _dt: f64) -> f64 {
+        return energy;
+    }
+}
+
+/// An ideal gas with adiabatic index `gamma`, P = (gamma - 1) rho u, that keeps all its heat.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Adiabatic {
+    pub gamma: f64,
+}
+
+impl Thermodynamics for Adiabatic {
+    fn pressure(&self, density: f64, energy: f64) -> f64 {
+        return (self.gamma - 1.) * density * energy;
+    }
+
+    fn sound_speed(&self, _density: f64, energy: f64) -> f64 {
+        return (self.gamma * (self.gamma - 1.) * energy.max(0.)).sqrt();
+    }
+}
+
+/// An ideal gas that cools exponentially towards `floor` with an e-folding time of `timescale`,
+/// the simple cooling often used to study when a collapsing disc fragments. Heat from SPH is
+/// added first, then cooled, so that steps much longer than the cooling time stay stable.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cooling {
+    pub gamma: f64,
+    pub timescale: f64,
+    // Internal energy per unit mass the gas cools to, e.g. that of the background radiation.
+    pub floor: f64,
+}
+
+impl Thermodynamics for Cooling {
+    fn pressure(&self, density: f64, energy: f64) -> f64 {
+        return Adiabatic { gamma: self.gamma }.pressure(density, energy);
+    }
+
+    fn sound_speed(&self, density: f64, energy: f64) -> f64 {
+        return Adiabatic { gamma: self.gamma }.sound_speed(density, energy);
+    }
+
+    fn energy_after(&self, _density: f64, energy: f64, heating: f64, dt: f64) -> f64 {
+        let heated = (energy + heating * dt).max(0.);
+        if heated <= self.floor {
+            return heated;
+        }
+        return self.floor + (heated - self.floor) * (-dt / self.timescale).exp();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::geometry::thermodynamics::{Adiabatic, Cooling, Isothermal, Thermodynamics};
+
+    #[test]
+    fn test_thermodynamics() {
+        let adiabatic = Adiabatic { gamma: 5. / 3. };
+        assert!((adiabatic.pressure(2., 3.) - 4.).abs() < 1e-12);
+        assert!((adiabatic.sound_speed(2., 0.9) - 1.).abs() < 1e-12);
+        assert_eq!(adiabatic.energy_after(1., 2., 3., 0.5), 3.5);
+        assert_eq!(adiabatic.energy_after(1., 2., -10., 0.5), 0.);
+
+        let isothermal = Isothermal { sound_speed: 2. };
+        assert_eq!(isothermal.pressure(3., 100.), 12.);
+        assert_eq!(isothermal.sound_speed(3., 100.), 2.);
+        assert_eq!(isothermal.energy_after(3., 100., 5., 1.), 100.);
+
+        // Cooling halves the excess over the floor every timescale * ln 2, and leaves gas below
+        // the floor alone.
+        let cooling = Cooling {
+            gamma: 5. / 3.,
+            timescale: 1. / 2f64.ln(),
+            floor: 1.,
+        };
+        assert_eq!(cooling.pressure(2., 3.), adiabatic.pressure(2., 3.));
+        assert!((cooling.energy_after(1., 5., 0., 1.) - 3.).abs() < 1e-12);
+        assert!((cooling.energy_after(1., 3., 2., 1.) - 3.).abs() < 1e-12);
+        assert_eq!(cooling.energy_after(1., 0.5, 0., 1.), 0.5);
+        assert!((cooling.energy_after(1., 5., 0., 1e6) - 1.).abs() < 1e-12);
+    }
+}
@@ -1,8 +1,11 @@
 use serde::{Deserialize, Serialize};
 use std::fmt::Display;
 
-const G: f64 = 6.67430e-11;
-const C: f64 = 299792458.0;
+use crate::error::NbodyError;
+use crate::geometry::force::ForceLaw;
+
+pub const G: f64 = 6.67430e-11;
+pub const C: f64 = 299792458.0;
 
 #[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Vec3d {
@@ -42,10 +45,77 @@ impl Vec3d {
         return (self.x, self.y, self.z);
     }
 
+    pub fn is_finite(&self) -> bool {
+        return self.x.is_finite() && self.y.is_finite() && self.z.is_finite();
+    }
+
     pub fn distance(self, other: Vec3d) -> Vec3d {
         let (ox, oy, oz) = other.position();
         return Vec3d::new(ox - self.x, oy - self.y, oz - self.z);
     }
+
+    pub fn magnitude_squared(&self) -> f64 {
+        return self.dot(*self);
+    }
+
+    pub fn dot(self, other: Vec3d) -> f64 {
+        return self.x * other.x + self.y * other.y + self.z * other.z;
+    }
+
+    pub fn cross(self, other: Vec3d) -> Vec3d {
+        return Vec3d::new(
+            self.y * other.z - self.z * other.y,
+            self.z * other.x - self.x * other.z,
+            self.x * other.y - self.y * other.x,
+        );
+    }
+
+    // Unit vector in the same direction. The zero vector stays zero.
+    pub fn normalize(self) -> Vec3d {
+        let m = self.magnitude();
+        if m == 0. {
+            return self;
+        }
+        return self / m;
+    }
+}
+
+impl std::ops::Sub<Vec3d> for Vec3d {
+    type Output = Vec3d;
+    fn sub(self, rhs: Vec3d) -> Self::Output {
+        return Vec3d::new(self.x - rhs.x, self.y - rhs.y, self.z - rhs.z);
+    }
+}
+
+impl std::ops::SubAssign<Vec3d> for Vec3d {
+    fn sub_assign(&mut self, rhs: Vec3d) {
+        self.x -= rhs.x;
+        self.y -= rhs.y;
+        self.z -= rhs.z;
+    }
+}
+
+impl std::ops::Neg for Vec3d {
+    type Output = Vec3d;
+    fn neg(self) -> Self::Output {
+        return Vec3d::new(-self.x, -self.y, -self.z);
+    }
+}
+
+impl std::ops::MulAssign<f64> for Vec3d {
+    fn mul_assign(&mut self, s: f64) {
+        self.x *= s;
+        self.y *= s;
+        self.z *= s;
+    }
+}
+
+impl std::ops::DivAssign<f64> for Vec3d {
+    fn div_assign(&mut self, s: f64) {
+        self.x /= s;
+        self.y /= s;
+        self.z /= s;
+    }
 }
 
 impl std::ops::AddAssign<Vec3d> for Vec3d {
@@ -92,6 +162,23 @@ impl std::ops::Div<f64> for Vec3d {
 
 #[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Point {
+    #[serde(default)]
+    id: u64,
+    // Pinned points exert gravity but are never moved by the integrator, which stops them unless
+    // they're external.
+    #[serde(default)]
+    pinned: bool,
+    // External points are positioned by the caller before each step, as the particles of another
+    // code coupled to this one. They exert gravity like any other but are pinned, and never merge
+    // or bounce.
+    #[serde(default)]
+    external: bool,
+    // Index into the run's species table.
+    #[serde(default)]
+    species: u16,
+    // Physical radius used for collisions; zero for a point particle.
+    #[serde(default)]
+    radius: f64,
     mass: f64,
     vel: Vec3d,
     schwarzchild_radius: f64,
@@ -108,25 +195,144 @@ impl Display for Point {
 }
 
 impl Point {
+    // A mass of zero makes a tracer: it feels gravity but exerts none. Panics if the mass is
+    // negative or not finite; anything built from input should go through `try_new`.
     pub fn new(mass: f64, x: f64, y: f64, z: f64, velocity: Vec3d) -> Point {
-        if mass <= 0.0 {
-            println!("FAILED MASS {}", mass);
-            assert!(false);
+        return match Point::try_new(mass, x, y, z, velocity) {
+            Ok(p) => p,
+            Err(e) => panic!("{}", e),
+        };
+    }
+
+    // Same as `new`, with a mass that is negative or not finite returned as an error.
+    pub fn try_new(
+        mass: f64,
+        x: f64,
+        y: f64,
+        z: f64,
+        velocity: Vec3d,
+    ) -> Result<Point, NbodyError> {
+        if !mass.is_finite() || mass < 0.0 {
+            return Err(NbodyError::InvalidMass { id: 0, mass: mass });
         }
-        return Point {
+        return Ok(Point {
+            id: 0,
+            pinned: false,
+            external: false,
+            species: 0,
+            radius: 0.,
             mass: mass,
             vel: velocity,
             x: x,
             y: y,
             z: z,
             schwarzchild_radius: 2.0 * G * mass / (C * C),
-        };
+        });
     }
 
     pub fn new_zero() -> Point {
         return Point::new(1.0, 0., 0., 0., Vec3d::new_zero());
     }
 
+    pub fn with_id(self, id: u64) -> Point {
+        return Point { id: id, ..self };
+    }
+
+    pub fn with_mass(self, mass: f64) -> Point {
+        return Point {
+            mass: mass,
+            schwarzchild_radius: 2.0 * G * mass / (C * C),
+            ..self
+        };
+    }
+
+    pub fn with_pinned(self, pinned: bool) -> Point {
+        return Point {
+            pinned: pinned,
+            ..self
+        };
+    }
+
+    // Marking a point external also pins it; unmarking it leaves it pinned.
+    pub fn with_external(self, external: bool) -> Point {
+        return Point {
+            external: external,
+            pinned: self.pinned || external,
+            ..self
+        };
+    }
+
+    pub fn with_species(self, species: u16) -> Point {
+        return Point {
+            species: species,
+            ..self
+        };
+    }
+
+    pub fn with_radius(self, radius: f64) -> Point {
+        return Point {
+            radius: radius,
+            ..self
+        };
+    }
+
+    pub fn with_velocity(self, vel: Vec3d) -> Point {
+        return Point { vel: vel, ..self };
+    }
+
+    pub fn with_position(self, x: f64, y: f64, z: f64) -> Point {
+        return Point {
+            x: x,
+            y: y,
+            z: z,
+            ..self
+        };
+    }
+
+    // Projects the point onto the z = 0 plane, dropping any motion out of it.
+    pub fn flattened(self) -> Point {
+        let (vx, vy, _) = self.vel.position();
+        return self
+            .with_position(self.x, self.y, 0.)
+            .with_velocity(Vec3d::new(vx, vy, 0.));
+    }
+
+    // Maps the point back into the periodic box [-box_size / 2, box_size / 2)^3.
+    pub fn wrapped(self, box_size: f64) -> Point {
+        let wrap = |v: f64| v - box_size * (v / box_size + 0.5).floor();
+        return self.with_position(wrap(self.x), wrap(self.y), wrap(self.z));
+    }
+
+    // The periodic image of this point closest to `other`.
+    pub fn nearest_image(self, other: Point, box_size: f64) -> Point {
+        let shift = |v: f64, o: f64| v - box_size * ((v - o) / box_size).round();
+        return self.with_position(
+            shift(self.x, other.x),
+            shift(self.y, other.y),
+            shift(self.z, other.z),
+        );
+    }
+
+    pub fn id(&self) -> u64 {
+        return self.id;
+    }
+
+    pub fn pinned(&self) -> bool {
+        return self.pinned;
+    }
+
+    pub fn external(&self) -> bool {
+        return self.external;
+    }
+
+    pub fn species(&self) -> u16 {
+        return self.species;
+    }
+
+    pub fn radius(&self) -> f64 {
+        return self.radius;
+    }
+
     pub fn schwarzchild_radius(&self) -> f64 {
         return self.schwarzchild_radius;
     }
@@ -135,12 +341,34 @@ impl Point {
         return self.mass;
     }
 
+    pub fn is_tracer(&self) -> bool {
+        return self.mass == 0.;
+    }
+
     pub fn velocity(&self) -> Vec3d {
         return self.vel;
     }
 
+    pub fn momentum(&self) -> Vec3d {
+        return self.mass * self.vel;
+    }
+
+    pub fn kinetic_energy(&self) -> f64 {
+        return 0.5 * self.mass * self.vel.magnitude_squared();
+    }
+
+    // Advances the point by `dt` under `force` with a semi-implicit Euler step: the velocity is
+    // kicked first and the position drifts with the new velocity, which keeps orbits' energy
+    // bounded rather than drifting. A pinned point stays where it is, at rest; an external one keeps
+    // the velocity its caller gave it.
     pub fn apply_force(self, dt: f64, force: Vec3d) -> Point {
-        let a = force / self.mass * dt;
+        if self.external {
+            return self;
+        }
+        if self.pinned {
+            return self.with_velocity(Vec3d::new_zero());
+        }
+        let a = force / self.mass;
         let v = self.vel + (a * dt);
         let (vx, vy, vz) = v.position();
         return Point::new(
@@ -149,17 +377,15 @@ impl Point {
             self.y + vy * dt,
             self.z + vz * dt,
             v,
-        );
+        )
+        .with_id(self.id)
+        .with_species(self.species)
+        .with_radius(self.radius);
     }
 
+    // Newtonian force exerted on this point by `p`.
     pub fn force_from(self, p: Point) -> Vec3d {
-        let dist = self.distance_to(p);
-        let mass: f64 = self.mass;
-        let f = G * mass * p.mass() / (dist * dist);
-        let x = (p.x - self.x) / dist * f;
-        let y = (p.y - self.y) / dist * f;
-        let z = (p.z - self.z) / dist * f;
-        return Vec3d::new(x, y, z);
+        return ForceLaw::default().force(self, p);
     }
 
     pub fn position(self) -> (f64, f64, f64) {
@@ -188,6 +414,29 @@ mod test {
         assert_eq!(p1.distance_to(p2), 6.0);
     }
 
+    #[test]
+    fn test_vector_ops() {
+        let a = Vec3d::new(1., 0., 0.);
+        let b = Vec3d::new(0., 2., 0.);
+        assert_eq!((a - b).position(), (1., -2., 0.));
+        assert_eq!((-b).position(), (0., -2., 0.));
+        assert_eq!(a.cross(b).position(), (0., 0., 2.));
+        assert_eq!(a.dot(b), 0.);
+        assert_eq!(b.magnitude_squared(), 4.);
+        assert_eq!(b.normalize().position(), (0., 1., 0.));
+        assert_eq!(Vec3d::new_zero().normalize(), Vec3d::new_zero());
+
+        let mut c = a;
+        c -= b;
+        c *= 2.;
+        c /= 4.;
+        assert_eq!(c.position(), (0.5, -1., 0.));
+
+        let p = Point::new(2., 0., 0., 0., Vec3d::new(3., 4., 0.));
+        assert_eq!(p.momentum().position(), (6., 8., 0.));
+        assert_eq!(p.kinetic_energy(), 25.);
+    }
+
     #[test]
     fn test_force() {
         let origin = Point::new(1., 0., 0., 0., Vec3d::new_zero());
@@ -197,6 +446,28 @@ mod test {
         assert_eq!(x, 1.0);
         assert_eq!(y, 0.0);
         assert_eq!(z, 0.0);
+
+        // Pinned points stay put and come to rest, whatever velocity they were given.
+        let moving = Point::new(1., 2., 0., 0., Vec3d::new(1., 0., 0.));
+        let after = moving.with_pinned(true).apply_force(1.0, force);
+        assert_eq!(after.position(), (2., 0., 0.));
+        assert_eq!(after.velocity(), Vec3d::new_zero());
+        // External ones keep theirs, whichever flag is set first.
+        let external = moving.with_external(true);
+        assert_eq!(external.apply_force(1.0, force), external);
+        let both = moving.with_pinned(true).with_external(true);
+        assert_eq!(both, external);
+        assert_eq!(both.apply_force(1.0, force), external);
+    }
+
+    #[test]
+    fn test_periodic_images() {
+        let p = Point::new(1., 6., -7., 2., Vec3d::new_zero()).wrapped(10.);
+        assert_eq!(p.position(), (-4., 3., 2.));
+
+        let other = Point::new(1., 4.5, 0., 0., Vec3d::new_zero());
+        let image = Point::new(1., -4.5, 1., 0., Vec3d::new_zero()).nearest_image(other, 10.);
+        assert_eq!(image.position(), (5.5, 1., 0.));
     }
 
     #[test]
@@ -0,0 +1,161 @@
+use std::ops::{Add, AddAssign, Div, Mul};
+
+use crate::Vec3d;
+
+/// The vector operations shared by every dimension, so geometry can be written once for 2D and 3D.
+pub trait Vector:
+    Copy + Add<Output = Self> + AddAssign + Mul<f64, Output = Self> + Div<f64, Output = Self>
+{
+    const DIM: usize;
+
+    fn zero() -> Self;
+
+    fn component(&self, i: usize) -> f64;
+
+    fn dot(self, other: Self) -> f64 {
+        return (0..Self::DIM)
+            .map(|i| self.component(i) * other.component(i))
+            .sum();
+    }
+
+    fn norm(self) -> f64 {
+        return self.dot(self).sqrt();
+    }
+}
+
+/// A vector with `D` components.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct VecN<const D: usize> {
+    components: [f64; D],
+}
+
+pub type Vec2d = VecN<2>;
+
+impl<const D: usize> VecN<D> {
+    pub fn new(components: [f64; D]) -> VecN<D> {
+        return VecN {
+            components: components,
+        };
+    }
+
+    pub fn components(&self) -> [f64; D] {
+        return self.components;
+    }
+}
+
+impl Vec2d {
+    pub fn new_2d(x: f64, y: f64) -> Vec2d {
+        return VecN::new([x, y]);
+    }
+}
+
+impl<const D: usize> Add for VecN<D> {
+    type Output = VecN<D>;
+
+    fn add(self, rhs: VecN<D>) -> VecN<D> {
+        return VecN::new(std::array::from_fn(|i| {
+            self.components[i] + rhs.components[i]
+        }));
+    }
+}
+
+impl<const D: usize> AddAssign for VecN<D> {
+    fn add_assign(&mut self, rhs: VecN<D>) {
+        *self = *self + rhs;
+    }
+}
+
+impl<const D: usize> Mul<f64> for VecN<D> {
+    type Output = VecN<D>;
+
+    fn mul(self, s: f64) -> VecN<D> {
+        return VecN::new(self.components.map(|c| c * s));
+    }
+}
+
+impl<const D: usize> Mul<VecN<D>> for f64 {
+    type Output = VecN<D>;
+
+    fn mul(self, v: VecN<D>) -> VecN<D> {
+        return v * self;
+    }
+}
+
+impl<const D: usize> Div<f64> for VecN<D> {
+    type Output = VecN<D>;
+
+    fn div(self, s: f64) -> VecN<D> {
+        return VecN::new(self.components.map(|c| c / s));
+    }
+}
+
+impl<const D: usize> Vector for VecN<D> {
+    const DIM: usize = D;
+
+    fn zero() -> VecN<D> {
+        return VecN::new([0.; D]);
+    }
+
+    fn component(&self, i: usize) -> f64 {
+        return self.components[i];
+    }
+}
+
+impl Vector for Vec3d {
+    const DIM: usize = 3;
+
+    fn zero() -> Vec3d {
+        return Vec3d::new_zero();
+    }
+
+    fn component(&self, i: usize) -> f64 {
+        let (x, y, z) = self.position();
+        return [x, y, z][i];
+    }
+}
+
+impl From<Vec3d> for VecN<3> {
+    fn from(v: Vec3d) -> VecN<3> {
+        let (x, y, z) = v.position();
+        return VecN::new([x, y, z]);
+    }
+}
+
+impl From<VecN<3>> for Vec3d {
+    fn from(v: VecN<3>) -> Vec3d {
+        let [x, y, z] = v.components;
+        return Vec3d::new(x, y, z);
+    }
+}
+
+// Mass-weighted mean of the given positions.
+pub fn center_of_mass<V: Vector>(bodies: &[(f64, V)]) -> V {
+    let mut total = 0.;
+    let mut sum = V::zero();
+    for &(m, v) in bodies {
+        total += m;
+        sum += v * m;
+    }
+    return sum / total;
+}
+
+#[cfg(test)]
+mod test {
+    use crate::geometry::vecn::{center_of_mass, Vec2d, VecN, Vector};
+    use crate::Vec3d;
+
+    #[test]
+    fn test_same_code_in_two_and_three_dimensions() {
+        let flat = center_of_mass(&[(1., Vec2d::new_2d(0., 0.)), (3., Vec2d::new_2d(4., 8.))]);
+        assert_eq!(flat.components(), [3., 6.]);
+        assert_eq!(Vec2d::new_2d(3., 4.).norm(), 5.);
+
+        let solid = center_of_mass(&[(1., Vec3d::new(0., 0., 0.)), (3., Vec3d::new(4., 8., -4.))]);
+        assert_eq!(solid.position(), (3., 6., -3.));
+
+        let v = Vec3d::new(1., 2., 2.);
+        let n: VecN<3> = v.into();
+        assert_eq!(n.norm(), Vector::norm(v));
+        assert_eq!(Vec3d::from(n), v);
+    }
+}
@@ -0,0 +1,142 @@
+use std::collections::HashMap;
+
+use serde::Serialize;
+
+use crate::geometry::bh_tree::{key, BHTree};
+use crate::{Point, Vec3d};
+
+/// A bound-structure candidate: particles connected by chains of neighbours closer than the
+/// linking length.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Group {
+    pub members: Vec<u64>,
+    pub mass: f64,
+    pub center: Vec3d,
+    pub velocity: Vec3d,
+    // One-dimensional, mass-weighted velocity dispersion about `velocity`.
+    pub dispersion: f64,
+}
+
+fn find(parent: &mut [usize], i: usize) -> usize {
+    let mut root = i;
+    while parent[root] != root {
+        root = parent[root];
+    }
+    // Path compression.
+    let mut i = i;
+    while parent[i] != root {
+        let next = parent[i];
+        parent[i] = root;
+        i = next;
+    }
+    return root;
+}
+
+/// Friends-of-friends groups of at least `min_members` massive particles, largest first. Tracers
+/// are left out.
+pub fn find_groups(tree: &BHTree, linking_length: f64, min_members: usize) -> Vec<Group> {
+    let points: Vec<Point> = tree.iter().filter(|p| !p.is_tracer()).copied().collect();
+    let index: HashMap<_, usize> = points
+        .iter()
+        .enumerate()
+        .map(|(i, p)| (key(p), i))
+        .collect();
+
+    let mut parent: Vec<usize> = (0..points.len()).collect();
+    for (i, p) in points.iter().enumerate() {
+        for q in tree.neighbors_within(*p, linking_length) {
+            if let Some(&j) = index.get(&key(&q)) {
+                let (a, b) = (find(&mut parent, i), find(&mut parent, j));
+                if a != b {
+                    parent[a.max(b)] = a.min(b);
+                }
+            }
+        }
+    }
+
+    let mut members: HashMap<usize, Vec<Point>> = HashMap::new();
+    for (i, p) in points.iter().enumerate() {
+        let root = find(&mut parent, i);
+        members.entry(root).or_default().push(*p);
+    }
+
+    let mut groups: Vec<Group> = members
+        .into_values()
+        .filter(|m| m.len() >= min_members.max(1))
+        .map(|m| summarize(&m))
+        .collect();
+    groups.sort_by(|a, b| {
+        b.members
+            .len()
+            .cmp(&a.members.len())
+            .then(a.members[0].cmp(&b.members[0]))
+    });
+    return groups;
+}
+
+fn summarize(points: &[Point]) -> Group {
+    let mass: f64 = points.iter().map(|p| p.mass()).sum();
+    let mut center = Vec3d::new_zero();
+    let mut velocity = Vec3d::new_zero();
+    for p in points {
+        let (x, y, z) = p.position();
+        center += p.mass() * Vec3d::new(x, y, z);
+        velocity += p.mass() * p.velocity();
+    }
+    let center = center / mass;
+    let velocity = velocity / mass;
+
+    let spread: f64 = points
+        .iter()
+        .map(|p| {
+            let dv = p.velocity().distance(velocity).magnitude();
+            p.mass() * dv * dv
+        })
+        .sum();
+    let mut ids: Vec<u64> = points.iter().map(|p| p.id()).collect();
+    ids.sort();
+    return Group {
+        members: ids,
+        mass: mass,
+        center: center,
+        velocity: velocity,
+        dispersion: (spread / (3. * mass)).sqrt(),
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use crate::geometry::bh_tree::BHTree;
+    use crate::groups::find_groups;
+    use crate::{Point, Vec3d};
+
+    #[test]
+    fn test_friends_of_friends() {
+        let mut points = vec![];
+        // A chain of five particles 1 apart, a pair, and an isolated particle.
+        for i in 0..5 {
+            let v = Vec3d::new(0., if i % 2 == 0 { 1. } else { -1. }, 0.);
+            points.push(Point::new(1., i as f64, 0., 0., v).with_id(i));
+        }
+        points.push(Point::new(2., 20., 20., 0., Vec3d::new_zero()).with_id(5));
+        points.push(Point::new(2., 20.5, 20., 0., Vec3d::new_zero()).with_id(6));
+        points.push(Point::new(1., -20., 0., 0., Vec3d::new_zero()).with_id(7));
+        let bht = BHTree::from_points(0.5, points).unwrap();
+
+        let groups = find_groups(&bht, 1.2, 2);
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[0].members, vec![0, 1, 2, 3, 4]);
+        assert_eq!(groups[0].mass, 5.);
+        assert_eq!(groups[0].center.position(), (2., 0., 0.));
+        assert_eq!(groups[1].members, vec![5, 6]);
+        assert_eq!(groups[1].dispersion, 0.);
+
+        // Bulk velocity is 0.2 in y, so each particle deviates by 0.8 or 1.2.
+        let (_, vy, _) = groups[0].velocity.position();
+        assert!((vy - 0.2).abs() < 1e-12);
+        let expected = ((3. * 0.64 + 2. * 1.44) / 15_f64).sqrt();
+        assert!((groups[0].dispersion - expected).abs() < 1e-12);
+
+        assert_eq!(find_groups(&bht, 0.1, 1).len(), 8);
+    }
+}
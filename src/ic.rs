@@ -0,0 +1,152 @@
+use rand::{Rng, RngCore};
+use rand_distr::{Distribution, Normal};
+
+use crate::{Point, Vec3d};
+
+pub mod collision;
+pub mod disk;
+pub mod file;
+pub mod frame;
+pub mod grid;
+pub mod halo;
+pub mod solar;
+
+/// The particles produced by an initial-condition generator.
+pub type ParticleSet = Vec<Point>;
+
+/// A source of starting particles. Implement this to plug a custom generator into a
+/// `SimulationBuilder`; ids should start at zero and be unique within the set.
+pub trait InitialConditions {
+    fn generate(&self, rng: &mut dyn RngCore) -> ParticleSet;
+
+    // How many particles `generate` makes, if that's known without running it, so a run can check
+    // it fits in memory before it starts.
+    fn count(&self) -> Option<usize> {
+        return None;
+    }
+}
+
+impl<T: InitialConditions + ?Sized> InitialConditions for Box<T> {
+    fn generate(&self, rng: &mut dyn RngCore) -> ParticleSet {
+        return (**self).generate(rng);
+    }
+
+    fn count(&self) -> Option<usize> {
+        return (**self).count();
+    }
+}
+
+// A ready-made particle set, e.g. one loaded from a file, generates itself.
+impl InitialConditions for ParticleSet {
+    fn generate(&self, _rng: &mut dyn RngCore) -> ParticleSet {
+        return self.clone();
+    }
+
+    fn count(&self) -> Option<usize> {
+        return Some(self.len());
+    }
+}
+
+// Builds particles from parallel arrays, as handed over by the language bindings: one mass and
+// three position and velocity components (x, y, z) per particle. Ids follow the array order.
+pub fn from_arrays(
+    masses: &[f64],
+    positions: &[f64],
+    velocities: &[f64],
+) -> Result<ParticleSet, String> {
+    let n = masses.len();
+    if positions.len() != 3 * n || velocities.len() != 3 * n {
+        return Err(format!(
+            "expected {} position and velocity components for {} masses, got {} and {}",
+            3 * n,
+            n,
+            positions.len(),
+            velocities.len()
+        ));
+    }
+    // Point::new panics on these, which would unwind out through the bindings.
+    if let Some((i, m)) = masses
+        .iter()
+        .enumerate()
+        .find(|(_, m)| !m.is_finite() || **m < 0.)
+    {
+        return Err(format!(
+            "particle {} has mass {}; masses must be finite and not negative",
+            i, m
+        ));
+    }
+    return Ok((0..n)
+        .map(|i| {
+            let x = &positions[3 * i..3 * i + 3];
+            let v = &velocities[3 * i..3 * i + 3];
+            Point::new(masses[i], x[0], x[1], x[2], Vec3d::new(v[0], v[1], v[2])).with_id(i as u64)
+        })
+        .collect());
+}
+
+/// Particles spread uniformly through a sphere, at rest, with log-normally distributed masses.
+#[derive(Debug, Clone)]
+pub struct UniformSphere {
+    pub n: u64,
+    pub radius: f64,
+    pub mass_base: f64,
+    pub mass_mean: f64,
+    pub mass_stddev: f64,
+}
+
+impl UniformSphere {
+    pub fn generate<R: Rng>(&self, rng: &mut R) -> Vec<Point> {
+        let normal = Normal::new(self.mass_mean, self.mass_stddev).unwrap();
+        let r = self.radius;
+
+        let mut points = Vec::with_capacity(self.n as usize);
+        for id in 0..self.n {
+            let mut x: f64 = rng.gen_range(-r..r);
+            let mut y: f64 = rng.gen_range(-r..r);
+            let mut z: f64 = rng.gen_range(-r..r);
+            while (x * x + y * y + z * z).sqrt() > r {
+                x = rng.gen_range(-r..r);
+                y = rng.gen_range(-r..r);
+                z = rng.gen_range(-r..r);
+            }
+
+            let mass = self.mass_base.powf(normal.sample(rng).max(1.0));
+            points.push(Point::new(mass, x, y, z, Vec3d::new_zero()).with_id(id));
+        }
+        return points;
+    }
+}
+
+impl InitialConditions for UniformSphere {
+    fn generate(&self, mut rng: &mut dyn RngCore) -> ParticleSet {
+        return UniformSphere::generate(self, &mut rng);
+    }
+
+    fn count(&self) -> Option<usize> {
+        return Some(self.n as usize);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::ic::from_arrays;
+
+    #[test]
+    fn test_from_arrays() {
+        let points = from_arrays(
+            &[1., 2.],
+            &[0., 0., 0., 1., 2., 3.],
+            &[0., 0., 0., 0., 1., 0.],
+        )
+        .unwrap();
+        assert_eq!(points[1].id(), 1);
+        assert_eq!(points[1].mass(), 2.);
+        assert_eq!(points[1].position(), (1., 2., 3.));
+        assert_eq!(points[1].velocity().position(), (0., 1., 0.));
+
+        assert!(from_arrays(&[1.], &[0., 0.], &[0., 0., 0.]).is_err());
+        for mass in [f64::NAN, -1., f64::INFINITY] {
+            assert!(from_arrays(&[1., mass], &[0.; 6], &[0.; 6]).is_err());
+        }
+    }
+}
@@ -0,0 +1,150 @@
+use rand::RngCore;
+
+use crate::ic::{InitialConditions, ParticleSet};
+use crate::{Point, Vec3d};
+
+/// Places two pre-built systems on a collision course. Each system is recentred on its own center
+/// of mass, the second is rotated by `inclination` about the x-axis and then `azimuth` about the
+/// z-axis, and the pair is set up in the zero-momentum frame: separated by `separation` along x,
+/// offset by `impact_parameter` along y, and approaching each other at `relative_velocity`.
+#[derive(Debug, Clone)]
+pub struct Collision {
+    pub separation: f64,
+    pub impact_parameter: f64,
+    pub relative_velocity: f64,
+    // Radians.
+    pub inclination: f64,
+    pub azimuth: f64,
+}
+
+// Mass-weighted mean position and velocity of a set of points.
+fn center_of_mass(points: &[Point]) -> (f64, Vec3d, Vec3d) {
+    let mut mass = 0.;
+    let mut pos = Vec3d::new_zero();
+    let mut vel = Vec3d::new_zero();
+    for p in points {
+        let (x, y, z) = p.position();
+        mass += p.mass();
+        pos += p.mass() * Vec3d::new(x, y, z);
+        vel += p.mass() * p.velocity();
+    }
+    return (mass, pos / mass, vel / mass);
+}
+
+fn rotate(v: Vec3d, inclination: f64, azimuth: f64) -> Vec3d {
+    let (x, y, z) = v.position();
+    let (si, ci) = inclination.sin_cos();
+    let (y, z) = (y * ci - z * si, y * si + z * ci);
+    let (sa, ca) = azimuth.sin_cos();
+    return Vec3d::new(x * ca - y * sa, x * sa + y * ca, z);
+}
+
+// Moves a system so its COM sits at `pos` moving with `vel`, rotating it first if requested, and
+// shifts its ids up by `id_offset`.
+fn place(
+    points: Vec<Point>,
+    pos: Vec3d,
+    vel: Vec3d,
+    rot: (f64, f64),
+    id_offset: u64,
+) -> Vec<Point> {
+    let (_, com_pos, com_vel) = center_of_mass(&points);
+    return points
+        .into_iter()
+        .map(|p| {
+            let (x, y, z) = p.position();
+            let rel_pos = Vec3d::new(x, y, z) - com_pos;
+            let rel_vel = p.velocity() - com_vel;
+            let (x, y, z) = (rotate(rel_pos, rot.0, rot.1) + pos).position();
+            let v = rotate(rel_vel, rot.0, rot.1) + vel;
+            Point::new(p.mass(), x, y, z, v).with_id(p.id() + id_offset)
+        })
+        .collect();
+}
+
+impl Collision {
+    pub fn combine(&self, a: Vec<Point>, b: Vec<Point>) -> Vec<Point> {
+        let (ma, _, _) = center_of_mass(&a);
+        let (mb, _, _) = center_of_mass(&b);
+        let total = ma + mb;
+
+        // Split the offsets by mass so the combined COM stays at rest at the origin.
+        let rel_pos = Vec3d::new(self.separation, self.impact_parameter, 0.);
+        let rel_vel = Vec3d::new(-self.relative_velocity, 0., 0.);
+        let a_pos = (-mb / total) * rel_pos;
+        let a_vel = (-mb / total) * rel_vel;
+        let b_pos = (ma / total) * rel_pos;
+        let b_vel = (ma / total) * rel_vel;
+
+        let id_offset = a.iter().map(|p| p.id() + 1).max().unwrap_or(0);
+        let mut points = place(a, a_pos, a_vel, (0., 0.), 0);
+        points.append(&mut place(
+            b,
+            b_pos,
+            b_vel,
+            (self.inclination, self.azimuth),
+            id_offset,
+        ));
+        return points;
+    }
+}
+
+/// Generates two systems and puts them on a collision course with `collision`.
+pub struct CollidingSystems {
+    pub collision: Collision,
+    pub first: Box<dyn InitialConditions>,
+    pub second: Box<dyn InitialConditions>,
+}
+
+impl InitialConditions for CollidingSystems {
+    fn generate(&self, rng: &mut dyn RngCore) -> ParticleSet {
+        let a = self.first.generate(rng);
+        let b = self.second.generate(rng);
+        return self.collision.combine(a, b);
+    }
+
+    fn count(&self) -> Option<usize> {
+        return Some(self.first.count()? + self.second.count()?);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::ic::collision::{center_of_mass, Collision};
+    use crate::{Point, Vec3d};
+
+    #[test]
+    fn test_combine() {
+        let a = vec![
+            Point::new(1., 1., 0., 0., Vec3d::new(0., 1., 0.)).with_id(0),
+            Point::new(1., -1., 0., 0., Vec3d::new(0., -1., 0.)).with_id(1),
+        ];
+        let b = vec![
+            Point::new(2., 5., 5., 5., Vec3d::new(3., 0., 0.)).with_id(0),
+            Point::new(2., 5., 7., 5., Vec3d::new(3., 0., 0.)).with_id(1),
+        ];
+        let c = Collision {
+            separation: 30.,
+            impact_parameter: 3.,
+            relative_velocity: 2.,
+            inclination: 1.,
+            azimuth: 0.5,
+        };
+        let points = c.combine(a, b);
+
+        let ids: Vec<u64> = points.iter().map(|p| p.id()).collect();
+        assert_eq!(ids, vec![0, 1, 2, 3]);
+
+        let (mass, pos, vel) = center_of_mass(&points);
+        assert_eq!(mass, 6.);
+        assert!(pos.magnitude() < 1e-12);
+        assert!(vel.magnitude() < 1e-12);
+
+        let (_, a_pos, a_vel) = center_of_mass(&points[..2]);
+        let (_, b_pos, b_vel) = center_of_mass(&points[2..]);
+        let (dx, dy, dz) = a_pos.distance(b_pos).position();
+        assert!((dx - 30.).abs() < 1e-12 && (dy - 3.).abs() < 1e-12 && dz.abs() < 1e-12);
+        let (dvx, _, _) = a_vel.distance(b_vel).position();
+        assert!((dvx + 2.).abs() < 1e-12);
+    }
+}
@@ -0,0 +1,116 @@
+use rand::{Rng, RngCore};
+use rand_distr::{Distribution, Normal};
+
+use crate::geometry::vec3d::G;
+use crate::ic::{InitialConditions, ParticleSet};
+use crate::{Point, Vec3d};
+
+/// An exponential disk in the xy-plane with surface density proportional to exp(-R / scale_length)
+/// and a sech^2 vertical profile. Particles are put on circular orbits set by the mass enclosed
+/// within their cylindrical radius, optionally perturbed by a velocity dispersion.
+#[derive(Debug, Clone)]
+pub struct ExponentialDisk {
+    pub n: u64,
+    pub total_mass: f64,
+    pub scale_length: f64,
+    pub scale_height: f64,
+    // Particles are not placed beyond this cylindrical radius.
+    pub max_radius: f64,
+    pub clockwise: bool,
+    // Velocity dispersion as a fraction of the local circular velocity.
+    pub dispersion: f64,
+}
+
+impl ExponentialDisk {
+    pub fn generate<R: Rng>(&self, rng: &mut R) -> Vec<Point> {
+        let mass = self.total_mass / self.n as f64;
+
+        // The enclosed-mass profile of an exponential disk is a Gamma(2) distribution in R, which
+        // is the sum of two exponential variates.
+        let mut radii: Vec<f64> = (0..self.n)
+            .map(|_| loop {
+                let u1: f64 = 1. - rng.gen::<f64>();
+                let u2: f64 = 1. - rng.gen::<f64>();
+                let r = -self.scale_length * (u1 * u2).ln();
+                if r <= self.max_radius {
+                    break r;
+                }
+            })
+            .collect();
+        radii.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+        let dir = if self.clockwise { -1. } else { 1. };
+        let mut points = Vec::with_capacity(self.n as usize);
+        for (i, r) in radii.into_iter().enumerate() {
+            let phi = rng.gen_range(0.0..std::f64::consts::TAU);
+            let u = (2. * rng.gen::<f64>() - 1.).max(-1. + f64::EPSILON);
+            let z = self.scale_height * u.atanh();
+            let (sin, cos) = phi.sin_cos();
+
+            // Radii are sorted, so everything before this particle is enclosed by it.
+            let enclosed = mass * i as f64;
+            let v_circ = if r > 0. {
+                (G * enclosed / r).sqrt()
+            } else {
+                0.
+            };
+            let mut vel = Vec3d::new(-sin * v_circ * dir, cos * v_circ * dir, 0.);
+
+            if self.dispersion > 0. && v_circ > 0. {
+                let normal = Normal::new(0., self.dispersion * v_circ).unwrap();
+                vel += Vec3d::new(normal.sample(rng), normal.sample(rng), normal.sample(rng));
+            }
+
+            points.push(Point::new(mass, r * cos, r * sin, z, vel).with_id(i as u64));
+        }
+        return points;
+    }
+}
+
+impl InitialConditions for ExponentialDisk {
+    fn generate(&self, mut rng: &mut dyn RngCore) -> ParticleSet {
+        return ExponentialDisk::generate(self, &mut rng);
+    }
+
+    fn count(&self) -> Option<usize> {
+        return Some(self.n as usize);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    use crate::geometry::vec3d::G;
+    use crate::ic::disk::ExponentialDisk;
+
+    #[test]
+    fn test_circular_velocities() {
+        let disk = ExponentialDisk {
+            n: 1000,
+            total_mass: 1e12,
+            scale_length: 10.,
+            scale_height: 1.,
+            max_radius: 50.,
+            clockwise: false,
+            dispersion: 0.,
+        };
+        let points = disk.generate(&mut StdRng::seed_from_u64(7));
+        assert_eq!(points.len(), 1000);
+
+        let m = 1e12 / 1000.;
+        for (i, p) in points.iter().enumerate() {
+            let (x, y, _) = p.position();
+            let r = (x * x + y * y).sqrt();
+            assert!(r <= 50.);
+
+            let (vx, vy, vz) = p.velocity().position();
+            let expected = (G * m * i as f64 / r).sqrt();
+            assert!((p.velocity().magnitude() - expected).abs() <= 1e-9 * expected);
+            assert_eq!(vz, 0.);
+            // Counter-clockwise rotation has non-negative angular momentum about z.
+            assert!(x * vy - y * vx >= 0.);
+        }
+    }
+}
@@ -0,0 +1,310 @@
+use std::error::Error;
+use std::path::Path;
+
+use log::info;
+use serde::Deserialize;
+
+use crate::error::NbodyError;
+use crate::output::compress::{self, Compression};
+use crate::{Point, Vec3d};
+
+/// One particle per row. Column names match the CSV snapshots written by the simulation, so an
+/// output file can be fed straight back in; the short `x,y,z,vx,vy,vz` names are accepted too.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ParticleRecord {
+    #[serde(default)]
+    pub id: Option<u64>,
+    pub mass: f64,
+    #[serde(alias = "x")]
+    pub x_pos: f64,
+    #[serde(alias = "y")]
+    pub y_pos: f64,
+    #[serde(alias = "z")]
+    pub z_pos: f64,
+    #[serde(alias = "vx")]
+    pub x_vel: f64,
+    #[serde(alias = "vy")]
+    pub y_vel: f64,
+    #[serde(alias = "vz")]
+    pub z_vel: f64,
+    #[serde(default)]
+    pub pinned: bool,
+    #[serde(default)]
+    pub external: bool,
+    #[serde(default)]
+    pub species: u16,
+    #[serde(default)]
+    pub radius: f64,
+    // Simulated time of the snapshot the row comes from, in the run's units.
+    #[serde(default)]
+    pub time: Option<f64>,
+}
+
+impl ParticleRecord {
+    // Rows without an explicit id are numbered by their position in the file.
+    fn to_point(&self, row: usize) -> Result<Point, NbodyError> {
+        let id = self.id.unwrap_or(row as u64);
+        let vel = Vec3d::new(self.x_vel, self.y_vel, self.z_vel);
+        let p =
+            Point::try_new(self.mass, self.x_pos, self.y_pos, self.z_pos, vel).map_err(|_| {
+                NbodyError::InvalidMass {
+                    id: id,
+                    mass: self.mass,
+                }
+            })?;
+        return Ok(p
+            .with_id(id)
+            .with_pinned(self.pinned)
+            .with_external(self.external)
+            .with_species(self.species)
+            .with_radius(self.radius));
+    }
+}
+
+/// Reads particles from a file, picking the format from its extension: `.csv`, `.parquet` (with
+/// the `parquet` feature) or `.h5`/`.hdf5` (with the `hdf5` feature).
+pub fn load(path: &Path) -> Result<Vec<Point>, Box<dyn Error>> {
+    return Ok(load_snapshot(path)?.0);
+}
+
+// Same as `load`, also returning the time of the snapshot if the file records one.
+pub fn load_snapshot(path: &Path) -> Result<(Vec<Point>, Option<f64>), Box<dyn Error>> {
+    // The extension under any compression suffix.
+    let ext = match Compression::of_path(path) {
+        Compression::None => path.extension(),
+        _ => Path::new(path.file_stem().unwrap_or_default()).extension(),
+    }
+    .and_then(|e| e.to_str())
+    .map(|e| e.to_ascii_lowercase());
+    let records = match ext.as_deref() {
+        Some("csv") => load_csv(path)?,
+        #[cfg(feature = "parquet")]
+        Some("parquet") | Some("pq") => load_parquet(path)?,
+        #[cfg(not(feature = "parquet"))]
+        Some("parquet") | Some("pq") => {
+            return Err("parquet support requires building with --features parquet".into());
+        }
+        #[cfg(feature = "hdf5")]
+        Some("h5") | Some("hdf5") => load_hdf5(path)?,
+        #[cfg(not(feature = "hdf5"))]
+        Some("h5") | Some("hdf5") => {
+            return Err("HDF5 support requires building with --features hdf5".into());
+        }
+        _ => {
+            return Err(format!("unrecognised initial conditions file {}", path.display()).into());
+        }
+    };
+
+    let points: Vec<Point> = records
+        .iter()
+        .enumerate()
+        .map(|(row, r)| r.to_point(row))
+        .collect::<Result<_, _>>()?;
+    info!("loaded {} particles from {}", points.len(), path.display());
+    return Ok((points, records.first().and_then(|r| r.time)));
+}
+
+// Reads the particles of a CSV file one row at a time, for files too large to load at once. Rows
+// are numbered as in `load`.
+pub fn stream_csv(
+    path: &Path,
+) -> Result<impl Iterator<Item = Result<Point, NbodyError>>, Box<dyn Error>> {
+    let rdr = csv::Reader::from_reader(compress::open(path)?);
+    return Ok(rdr.into_deserialize().enumerate().map(|(row, r)| {
+        r.map_err(NbodyError::from)
+            .and_then(|r: ParticleRecord| r.to_point(row))
+    }));
+}
+
+fn load_csv(path: &Path) -> Result<Vec<ParticleRecord>, Box<dyn Error>> {
+    let mut rdr = csv::Reader::from_reader(compress::open(path)?);
+    let mut records = vec![];
+    for record in rdr.deserialize() {
+        records.push(record?);
+    }
+    return Ok(records);
+}
+
+#[cfg(feature = "parquet")]
+fn load_parquet(path: &Path) -> Result<Vec<ParticleRecord>, Box<dyn Error>> {
+    use parquet::file::reader::SerializedFileReader;
+
+    let reader = SerializedFileReader::try_from(path)?;
+    let mut records = vec![];
+    for row in reader {
+        records.push(serde_json::from_value(row?.to_json_value())?);
+    }
+    return Ok(records);
+}
+
+// Reads one-dimensional datasets in the root group, one per column and named like the CSV columns,
+// all of the same length. The snapshot time, if any, is the root group's `time` attribute.
+#[cfg(feature = "hdf5")]
+fn load_hdf5(path: &Path) -> Result<Vec<ParticleRecord>, Box<dyn Error>> {
+    use hdf5_pure::{AttrValue, File};
+
+    let file = File::open(path)?;
+    let root = file.root();
+    let names = root.datasets()?;
+    let find = |name: &str, alias: &str| {
+        return names
+            .iter()
+            .find(|n| n.as_str() == name || n.as_str() == alias)
+            .map(|n| file.dataset(n));
+    };
+    let column = |name: &str, alias: &str| -> Result<Vec<f64>, Box<dyn Error>> {
+        return match find(name, alias) {
+            Some(dataset) => Ok(dataset?.read_f64()?),
+            None => Err(format!("{} has no {} dataset", path.display(), name).into()),
+        };
+    };
+    let mass = column("mass", "mass")?;
+    let n = mass.len();
+    let [x, y, z, vx, vy, vz] = [
+        ("x_pos", "x"),
+        ("y_pos", "y"),
+        ("z_pos", "z"),
+        ("x_vel", "vx"),
+        ("y_vel", "vy"),
+        ("z_vel", "vz"),
+    ]
+    .map(|(name, alias)| column(name, alias));
+    let (x, y, z, vx, vy, vz) = (x?, y?, z?, vx?, vy?, vz?);
+    let id = find("id", "id").map(|d| d?.read_u64()).transpose()?;
+    let pinned = find("pinned", "pinned").map(|d| d?.read_u8()).transpose()?;
+    let external = find("external", "external")
+        .map(|d| d?.read_u8())
+        .transpose()?;
+    let species = find("species", "species")
+        .map(|d| d?.read_u16())
+        .transpose()?;
+    let radius = find("radius", "radius")
+        .map(|d| d?.read_f64())
+        .transpose()?;
+    let mut lengths = vec![x.len(), y.len(), z.len(), vx.len(), vy.len(), vz.len()];
+    lengths.extend(id.as_ref().map(Vec::len));
+    lengths.extend(pinned.iter().chain(external.iter()).map(Vec::len));
+    lengths.extend(species.as_ref().map(Vec::len));
+    lengths.extend(radius.as_ref().map(Vec::len));
+    if let Some(len) = lengths.into_iter().find(|len| *len != n) {
+        return Err(format!(
+            "{} has {} masses but a column of {}",
+            path.display(),
+            n,
+            len
+        )
+        .into());
+    }
+    let time = match root.attrs()?.get("time") {
+        Some(AttrValue::F64(t)) => Some(*t),
+        Some(AttrValue::F32(t)) => Some(*t as f64),
+        _ => None,
+    };
+
+    return Ok((0..n)
+        .map(|i| ParticleRecord {
+            id: id.as_ref().map(|c| c[i]),
+            mass: mass[i],
+            x_pos: x[i],
+            y_pos: y[i],
+            z_pos: z[i],
+            x_vel: vx[i],
+            y_vel: vy[i],
+            z_vel: vz[i],
+            pinned: pinned.as_ref().is_some_and(|c| c[i] != 0),
+            external: external.as_ref().is_some_and(|c| c[i] != 0),
+            species: species.as_ref().map_or(0, |c| c[i]),
+            radius: radius.as_ref().map_or(0., |c| c[i]),
+            time: time,
+        })
+        .collect());
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+
+    use crate::ic::file::load;
+    #[cfg(feature = "hdf5")]
+    use crate::ic::file::load_snapshot;
+
+    #[test]
+    fn test_load_csv() {
+        let path = std::env::temp_dir().join(format!("nbody-ic-{}.csv", std::process::id()));
+        let mut f = std::fs::File::create(&path).unwrap();
+        writeln!(f, "mass,x_pos,y_pos,z_pos,x_vel,y_vel,z_vel").unwrap();
+        writeln!(f, "1.5,1,2,3,4,5,6").unwrap();
+        writeln!(f, "2.5,-1,-2,-3,0,0,0").unwrap();
+        drop(f);
+
+        let points = load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(points.len(), 2);
+        assert_eq!(points[0].mass(), 1.5);
+        assert_eq!(points[0].position(), (1., 2., 3.));
+        assert_eq!(points[0].velocity().position(), (4., 5., 6.));
+        assert_eq!(points[1].id(), 1);
+        assert_eq!(points[1].position(), (-1., -2., -3.));
+
+        // A particle that's both pinned and external keeps the velocity it was given.
+        let mut f = std::fs::File::create(&path).unwrap();
+        writeln!(f, "id,mass,x,y,z,vx,vy,vz,pinned,external").unwrap();
+        writeln!(f, "3,1,0,0,0,1,2,3,true,true").unwrap();
+        drop(f);
+        let points = load(&path).unwrap();
+        assert!(points[0].pinned() && points[0].external());
+        assert_eq!(points[0].velocity().position(), (1., 2., 3.));
+
+        // A bad mass is an error rather than a panic.
+        for mass in ["-1", "inf", "NaN"] {
+            let mut f = std::fs::File::create(&path).unwrap();
+            writeln!(f, "id,mass,x,y,z,vx,vy,vz").unwrap();
+            writeln!(f, "7,{},0,0,0,0,0,0", mass).unwrap();
+            drop(f);
+            let e = load(&path).unwrap_err();
+            assert!(e.to_string().contains("particle 7"), "{}", e);
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(feature = "hdf5")]
+    #[test]
+    fn test_load_hdf5() {
+        use hdf5_pure::{AttrValue, FileBuilder};
+
+        let path = std::env::temp_dir().join(format!("nbody-ic-{}.h5", std::process::id()));
+        let mut builder = FileBuilder::new();
+        builder.create_dataset("mass").with_f64_data(&[1.5, 2.5]);
+        for (name, values) in [
+            ("x", [1., -1.]),
+            ("y", [2., -2.]),
+            ("z", [3., -3.]),
+            ("vx", [4., 0.]),
+            ("vy", [5., 0.]),
+            ("z_vel", [6., 0.]),
+        ] {
+            builder.create_dataset(name).with_f64_data(&values);
+        }
+        builder.create_dataset("id").with_u64_data(&[10, 11]);
+        builder.create_dataset("pinned").with_u8_data(&[0, 1]);
+        builder.set_attr("time", AttrValue::F64(2.5));
+        builder.write(&path).unwrap();
+
+        let (points, time) = load_snapshot(&path).unwrap();
+        assert_eq!(time, Some(2.5));
+        assert_eq!(points.len(), 2);
+        assert_eq!(points[0].id(), 10);
+        assert_eq!(points[0].mass(), 1.5);
+        assert_eq!(points[0].position(), (1., 2., 3.));
+        assert_eq!(points[0].velocity().position(), (4., 5., 6.));
+        assert!(points[1].pinned() && !points[0].pinned());
+
+        // Every column has to be there, with a value per particle.
+        let mut builder = FileBuilder::new();
+        builder.create_dataset("mass").with_f64_data(&[1.]);
+        builder.write(&path).unwrap();
+        assert!(load(&path).unwrap_err().to_string().contains("x_pos"));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
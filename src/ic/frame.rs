@@ -0,0 +1,133 @@
+use rand::RngCore;
+
+use crate::ic::{InitialConditions, ParticleSet};
+use crate::{Point, Vec3d};
+
+/// A Galilean transformation to another inertial frame: every position is shifted by `offset` and
+/// every velocity by `velocity`. Masses, ids and everything else are left alone.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Boost {
+    pub offset: Vec3d,
+    pub velocity: Vec3d,
+}
+
+impl Boost {
+    // The boost into the frame where `center` sits at the origin, at rest.
+    pub fn into_frame_of(center: &Point) -> Boost {
+        let (x, y, z) = center.position();
+        return Boost {
+            offset: Vec3d::new(-x, -y, -z),
+            velocity: -center.velocity(),
+        };
+    }
+
+    // The boost that undoes this one.
+    pub fn inverse(&self) -> Boost {
+        return Boost {
+            offset: -self.offset,
+            velocity: -self.velocity,
+        };
+    }
+
+    pub fn apply(&self, points: ParticleSet) -> ParticleSet {
+        let (dx, dy, dz) = self.offset.position();
+        return points
+            .into_iter()
+            .map(|p| {
+                let (x, y, z) = p.position();
+                p.with_position(x + dx, y + dy, z + dz)
+                    .with_velocity(p.velocity() + self.velocity)
+            })
+            .collect();
+    }
+}
+
+/// Motion added to a whole system on top of its particles' own: a bulk `velocity`, and solid-body
+/// rotation at `angular_velocity` (radians per unit time, about its direction) around the
+/// system's centre of mass.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BulkMotion {
+    pub velocity: Vec3d,
+    pub angular_velocity: Vec3d,
+}
+
+impl BulkMotion {
+    pub fn apply(&self, points: ParticleSet) -> ParticleSet {
+        let mass: f64 = points.iter().map(|p| p.mass()).sum();
+        let mut com = Vec3d::new_zero();
+        if mass > 0. {
+            for p in points.iter() {
+                let (x, y, z) = p.position();
+                com += p.mass() * Vec3d::new(x, y, z);
+            }
+            com /= mass;
+        }
+        return points
+            .into_iter()
+            .map(|p| {
+                let (x, y, z) = p.position();
+                let spin = self.angular_velocity.cross(Vec3d::new(x, y, z) - com);
+                p.with_velocity(p.velocity() + self.velocity + spin)
+            })
+            .collect();
+    }
+}
+
+/// Generates a system and sets it moving with `motion`.
+pub struct MovingSystem {
+    pub motion: BulkMotion,
+    pub system: Box<dyn InitialConditions>,
+}
+
+impl InitialConditions for MovingSystem {
+    fn generate(&self, rng: &mut dyn RngCore) -> ParticleSet {
+        return self.motion.apply(self.system.generate(rng));
+    }
+
+    fn count(&self) -> Option<usize> {
+        return self.system.count();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    use crate::ic::frame::{Boost, BulkMotion, MovingSystem};
+    use crate::ic::InitialConditions;
+    use crate::{Point, Vec3d};
+
+    #[test]
+    fn test_frames() {
+        let points = vec![
+            Point::new(1., 1., 0., 0., Vec3d::new(0., 1., 0.)).with_id(0),
+            Point::new(3., 5., 0., 0., Vec3d::new(2., 0., 0.)).with_id(1),
+        ];
+
+        let boost = Boost::into_frame_of(&points[1]);
+        let moved = boost.apply(points.clone());
+        assert_eq!(moved[1].position(), (0., 0., 0.));
+        assert_eq!(moved[1].velocity().position(), (0., 0., 0.));
+        assert_eq!(moved[0].position(), (-4., 0., 0.));
+        assert_eq!(moved[0].velocity().position(), (-2., 1., 0.));
+        assert_eq!(moved[0].id(), 0);
+        assert_eq!(boost.inverse().apply(moved), points);
+
+        // Rotating about z at 0.5 rad per unit time around the centre of mass at x = 4, then
+        // drifting along z.
+        let motion = BulkMotion {
+            velocity: Vec3d::new(0., 0., 1.),
+            angular_velocity: Vec3d::new(0., 0., 0.5),
+        };
+        let system = MovingSystem {
+            motion: motion,
+            system: Box::new(points),
+        };
+        let moving = system.generate(&mut StdRng::seed_from_u64(0));
+        assert_eq!(moving[0].velocity().position(), (0., -0.5, 1.));
+        assert_eq!(moving[1].velocity().position(), (2., 0.5, 1.));
+        let momentum = moving[0].momentum() + moving[1].momentum();
+        assert_eq!(momentum.position(), (6., 1., 4.));
+    }
+}
@@ -0,0 +1,53 @@
+use rand::{Rng, RngCore};
+use rand_distr::{Distribution, Normal};
+
+use crate::ic::{InitialConditions, ParticleSet};
+use crate::{Point, Vec3d};
+
+/// A cold, uniform cubic lattice of equal-mass particles filling the periodic box
+/// [-box_size / 2, box_size / 2)^3, each displaced by a small random perturbation to seed
+/// structure formation.
+#[derive(Debug, Clone)]
+pub struct PerturbedGrid {
+    // Particles per side; the grid holds n_side^3 particles.
+    pub n_side: u64,
+    pub box_size: f64,
+    pub total_mass: f64,
+    // Standard deviation of the displacement in units of the grid spacing.
+    pub amplitude: f64,
+}
+
+impl PerturbedGrid {
+    pub fn generate<R: Rng>(&self, rng: &mut R) -> Vec<Point> {
+        let n = self.n_side * self.n_side * self.n_side;
+        let mass = self.total_mass / n as f64;
+        let spacing = self.box_size / self.n_side as f64;
+        let origin = -self.box_size / 2. + spacing / 2.;
+        let normal = Normal::new(0., (self.amplitude * spacing).max(f64::MIN_POSITIVE)).unwrap();
+
+        let mut points = Vec::with_capacity(n as usize);
+        for i in 0..self.n_side {
+            for j in 0..self.n_side {
+                for k in 0..self.n_side {
+                    let x = origin + i as f64 * spacing + normal.sample(rng);
+                    let y = origin + j as f64 * spacing + normal.sample(rng);
+                    let z = origin + k as f64 * spacing + normal.sample(rng);
+                    let id = (i * self.n_side + j) * self.n_side + k;
+                    let p = Point::new(mass, x, y, z, Vec3d::new_zero()).with_id(id);
+                    points.push(p.wrapped(self.box_size));
+                }
+            }
+        }
+        return points;
+    }
+}
+
+impl InitialConditions for PerturbedGrid {
+    fn generate(&self, mut rng: &mut dyn RngCore) -> ParticleSet {
+        return PerturbedGrid::generate(self, &mut rng);
+    }
+
+    fn count(&self) -> Option<usize> {
+        return Some(self.n_side.pow(3) as usize);
+    }
+}
@@ -0,0 +1,272 @@
+use std::f64::consts::PI;
+
+use rand::{Rng, RngCore};
+
+use crate::geometry::vec3d::G;
+use crate::ic::{InitialConditions, ParticleSet};
+use crate::{Point, Vec3d};
+
+const RADIAL_BINS: usize = 2000;
+const ENERGY_BINS: usize = 500;
+const QUADRATURE_STEPS: usize = 200;
+
+/// Spherical density profiles with a scale radius `a`.
+#[derive(Debug, Clone, Copy)]
+pub enum Profile {
+    // rho ~ 1 / ((r/a) (1 + r/a)^3)
+    Hernquist,
+    // rho ~ 1 / ((r/a) (1 + r/a)^2)
+    Nfw,
+}
+
+impl Profile {
+    fn density_shape(&self, x: f64) -> f64 {
+        return match self {
+            Profile::Hernquist => 1. / (x * (1. + x).powi(3)),
+            Profile::Nfw => 1. / (x * (1. + x).powi(2)),
+        };
+    }
+
+    // Integral of 4 pi x^2 density_shape(x) from 0 to x.
+    fn mass_shape(&self, x: f64) -> f64 {
+        return match self {
+            Profile::Hernquist => 4. * PI * x * x / (2. * (1. + x).powi(2)),
+            Profile::Nfw => 4. * PI * ((1. + x).ln() - x / (1. + x)),
+        };
+    }
+}
+
+/// An isotropic dark-matter halo truncated at `max_radius`. Radii are drawn from the enclosed mass
+/// profile and speeds from the ergodic distribution function obtained by Eddington inversion of
+/// the truncated density and its self-consistent potential.
+#[derive(Debug, Clone)]
+pub struct Halo {
+    pub n: u64,
+    pub total_mass: f64,
+    pub profile: Profile,
+    pub scale_radius: f64,
+    pub max_radius: f64,
+}
+
+// Radial tables, ordered by increasing radius.
+struct Tables {
+    r: Vec<f64>,
+    mass: Vec<f64>,
+    psi: Vec<f64>,
+    // Distribution function sampled at `energy`, which increases from psi_min to psi_max.
+    energy: Vec<f64>,
+    df: Vec<f64>,
+}
+
+// Linear interpolation of ys at x over increasing xs, clamped at the ends.
+fn interpolate(xs: &[f64], ys: &[f64], x: f64) -> f64 {
+    if x <= xs[0] {
+        return ys[0];
+    }
+    if x >= xs[xs.len() - 1] {
+        return ys[ys.len() - 1];
+    }
+    let i = xs.partition_point(|v| *v <= x);
+    let t = (x - xs[i - 1]) / (xs[i] - xs[i - 1]);
+    return ys[i - 1] + t * (ys[i] - ys[i - 1]);
+}
+
+impl Halo {
+    fn tables(&self) -> Tables {
+        let a = self.scale_radius;
+        let x_max = self.max_radius / a;
+        let scale = self.total_mass / (a * a * a * self.profile.mass_shape(x_max));
+
+        let r_min = 1e-4 * a;
+        let step = (self.max_radius / r_min).ln() / (RADIAL_BINS - 1) as f64;
+        let r: Vec<f64> = (0..RADIAL_BINS)
+            .map(|i| r_min * (step * i as f64).exp())
+            .collect();
+        let mass: Vec<f64> = r
+            .iter()
+            .map(|r| scale * a * a * a * self.profile.mass_shape(r / a))
+            .collect();
+        let rho: Vec<f64> = r
+            .iter()
+            .map(|r| scale * self.profile.density_shape(r / a))
+            .collect();
+
+        // Psi(r) = G M(r) / r + G * integral from r to r_max of 4 pi rho r' dr'.
+        let mut outer = vec![0.; RADIAL_BINS];
+        for i in (0..RADIAL_BINS - 1).rev() {
+            let f0 = 4. * PI * rho[i] * r[i];
+            let f1 = 4. * PI * rho[i + 1] * r[i + 1];
+            outer[i] = outer[i + 1] + 0.5 * (f0 + f1) * (r[i + 1] - r[i]);
+        }
+        let psi: Vec<f64> = (0..RADIAL_BINS)
+            .map(|i| G * mass[i] / r[i] + G * outer[i])
+            .collect();
+
+        // Work with rho(psi) on increasing psi, i.e. decreasing radius.
+        let psi_inc: Vec<f64> = psi.iter().rev().copied().collect();
+        let rho_inc: Vec<f64> = rho.iter().rev().copied().collect();
+        let drho: Vec<f64> = (0..RADIAL_BINS)
+            .map(|i| {
+                let lo = i.saturating_sub(1);
+                let hi = (i + 1).min(RADIAL_BINS - 1);
+                (rho_inc[hi] - rho_inc[lo]) / (psi_inc[hi] - psi_inc[lo])
+            })
+            .collect();
+
+        // F(E) = integral from psi_min to E of (drho/dpsi) / sqrt(E - psi) dpsi, and the
+        // Eddington formula gives f(E) = F'(E) / (sqrt(8) pi^2). Substituting psi = E - u^2 removes
+        // the integrable singularity at the upper limit.
+        let psi_min = psi_inc[0];
+        let psi_max = psi_inc[RADIAL_BINS - 1];
+        let energy: Vec<f64> = (0..ENERGY_BINS)
+            .map(|j| psi_min + (psi_max - psi_min) * j as f64 / (ENERGY_BINS - 1) as f64)
+            .collect();
+        let big_f: Vec<f64> = energy
+            .iter()
+            .map(|e| {
+                let u_max = (e - psi_min).max(0.).sqrt();
+                let du = u_max / QUADRATURE_STEPS as f64;
+                let mut sum = 0.;
+                for k in 0..=QUADRATURE_STEPS {
+                    let u = k as f64 * du;
+                    let w = if k == 0 || k == QUADRATURE_STEPS {
+                        0.5
+                    } else {
+                        1.
+                    };
+                    sum += w * 2. * interpolate(&psi_inc, &drho, e - u * u);
+                }
+                sum * du
+            })
+            .collect();
+        let df: Vec<f64> = (0..ENERGY_BINS)
+            .map(|j| {
+                let lo = j.saturating_sub(1);
+                let hi = (j + 1).min(ENERGY_BINS - 1);
+                let d = (big_f[hi] - big_f[lo]) / (energy[hi] - energy[lo]);
+                (d / (8_f64.sqrt() * PI * PI)).max(0.)
+            })
+            .collect();
+
+        return Tables {
+            r: r,
+            mass: mass,
+            psi: psi,
+            energy: energy,
+            df: df,
+        };
+    }
+
+    pub fn generate<R: Rng>(&self, rng: &mut R) -> Vec<Point> {
+        let t = self.tables();
+        let m = self.total_mass / self.n as f64;
+        let df = |e: f64| {
+            if e < t.energy[0] {
+                return 0.;
+            }
+            return interpolate(&t.energy, &t.df, e);
+        };
+
+        let mut points = Vec::with_capacity(self.n as usize);
+        for id in 0..self.n {
+            let r = interpolate(&t.mass, &t.r, rng.gen::<f64>() * self.total_mass);
+            let psi = interpolate(&t.r, &t.psi, r);
+            let v_max = (2. * psi).sqrt();
+
+            // Rejection sample the speed from v^2 f(psi - v^2 / 2).
+            let pdf = |v: f64| v * v * df(psi - 0.5 * v * v);
+            let peak = (1..50)
+                .map(|i| pdf(v_max * i as f64 / 50.))
+                .fold(0., f64::max)
+                * 1.1;
+            let v = if peak <= 0. {
+                0.
+            } else {
+                loop {
+                    let v = rng.gen::<f64>() * v_max;
+                    if rng.gen::<f64>() * peak <= pdf(v) {
+                        break v;
+                    }
+                }
+            };
+
+            let (x, y, z) = random_direction(rng);
+            let (vx, vy, vz) = random_direction(rng);
+            points.push(
+                Point::new(m, r * x, r * y, r * z, Vec3d::new(v * vx, v * vy, v * vz)).with_id(id),
+            );
+        }
+        return points;
+    }
+}
+
+impl InitialConditions for Halo {
+    fn generate(&self, mut rng: &mut dyn RngCore) -> ParticleSet {
+        return Halo::generate(self, &mut rng);
+    }
+
+    fn count(&self) -> Option<usize> {
+        return Some(self.n as usize);
+    }
+}
+
+fn random_direction<R: Rng>(rng: &mut R) -> (f64, f64, f64) {
+    let cos_theta: f64 = rng.gen_range(-1.0..1.0);
+    let sin_theta = (1. - cos_theta * cos_theta).sqrt();
+    let phi = rng.gen_range(0.0..std::f64::consts::TAU);
+    return (sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta);
+}
+
+#[cfg(test)]
+mod test {
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    use crate::geometry::vec3d::G;
+    use crate::ic::halo::{Halo, Profile};
+
+    // 2K / |W| should be close to one for a system in equilibrium.
+    fn virial_ratio(halo: &Halo) -> f64 {
+        let points = halo.generate(&mut StdRng::seed_from_u64(3));
+        let mut kinetic = 0.;
+        let mut potential = 0.;
+        for (i, p) in points.iter().enumerate() {
+            let v = p.velocity().magnitude();
+            kinetic += 0.5 * p.mass() * v * v;
+            for q in &points[i + 1..] {
+                potential -= G * p.mass() * q.mass() / p.distance_to(*q);
+            }
+        }
+        return 2. * kinetic / potential.abs();
+    }
+
+    #[test]
+    fn test_hernquist_virial() {
+        let halo = Halo {
+            n: 2000,
+            total_mass: 1e12,
+            profile: Profile::Hernquist,
+            scale_radius: 1.,
+            max_radius: 100.,
+        };
+        let ratio = virial_ratio(&halo);
+        assert!((ratio - 1.).abs() < 0.15, "virial ratio {}", ratio);
+    }
+
+    #[test]
+    fn test_nfw_virial() {
+        let halo = Halo {
+            n: 2000,
+            total_mass: 1e12,
+            profile: Profile::Nfw,
+            scale_radius: 1.,
+            max_radius: 10.,
+        };
+        let ratio = virial_ratio(&halo);
+        assert!((ratio - 1.).abs() < 0.15, "virial ratio {}", ratio);
+        for p in halo.generate(&mut StdRng::seed_from_u64(5)) {
+            let (x, y, z) = p.position();
+            assert!((x * x + y * y + z * z).sqrt() <= 10. + 1e-9);
+        }
+    }
+}
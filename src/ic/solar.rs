@@ -0,0 +1,185 @@
+use std::error::Error;
+use std::path::Path;
+
+use log::info;
+use rand::{Rng, RngCore};
+use serde::Deserialize;
+
+use crate::error::NbodyError;
+use crate::geometry::vec3d::G;
+use crate::ic::{InitialConditions, ParticleSet};
+use crate::{Point, Vec3d};
+
+const AU: f64 = 1.495978707e11;
+const SUN_MASS: f64 = 1.98847e30;
+const ASTEROID_MASS: f64 = 1e15;
+
+/// A named body with a heliocentric state vector in SI units (kg, m, m/s).
+#[derive(Debug, Clone, Deserialize)]
+pub struct Body {
+    pub name: String,
+    pub mass: f64,
+    pub x: f64,
+    pub y: f64,
+    pub z: f64,
+    pub vx: f64,
+    pub vy: f64,
+    pub vz: f64,
+}
+
+impl Body {
+    pub fn to_point(&self, id: u64) -> Point {
+        let vel = Vec3d::new(self.vx, self.vy, self.vz);
+        return Point::new(self.mass, self.x, self.y, self.z, vel).with_id(id);
+    }
+}
+
+// Approximate heliocentric ecliptic state vectors at J2000.0, as (name, mass, position in km,
+// velocity in km/s).
+const EPHEMERIS: [(&str, f64, [f64; 3], [f64; 3]); 9] = [
+    ("Sun", SUN_MASS, [0., 0., 0.], [0., 0., 0.]),
+    (
+        "Mercury",
+        3.3011e23,
+        [-2.1052e7, -6.6407e7, -3.4924e6],
+        [36.665, -12.303, -4.368],
+    ),
+    (
+        "Venus",
+        4.8675e24,
+        [-1.0750e8, -3.3660e6, 6.1590e6],
+        [0.889, -35.160, -0.532],
+    ),
+    (
+        "Earth",
+        5.9722e24,
+        [-2.6566e7, 1.4462e8, -3.0e2],
+        [-29.780, -5.430, 0.],
+    ),
+    (
+        "Mars",
+        6.4171e23,
+        [2.0699e8, -3.1402e6, -5.1254e6],
+        [1.180, 26.290, 0.520],
+    ),
+    (
+        "Jupiter",
+        1.89819e27,
+        [5.9782e8, 4.3895e8, -1.5256e7],
+        [-7.893, 11.174, 0.130],
+    ),
+    (
+        "Saturn",
+        5.6834e26,
+        [9.5885e8, 9.8248e8, -5.4313e7],
+        [-7.428, 6.738, 0.179],
+    ),
+    (
+        "Uranus",
+        8.6810e25,
+        [2.1579e9, -2.0549e9, -3.6168e7],
+        [4.636, 4.614, -0.043],
+    ),
+    (
+        "Neptune",
+        1.02413e26,
+        [2.5150e9, -3.7389e9, 1.9017e7],
+        [4.475, 3.063, -0.165],
+    ),
+];
+
+// The Sun and the eight planets from the bundled ephemeris table.
+pub fn bundled_bodies() -> Vec<Body> {
+    return EPHEMERIS
+        .iter()
+        .map(|(name, mass, pos, vel)| Body {
+            name: name.to_string(),
+            mass: *mass,
+            x: pos[0] * 1e3,
+            y: pos[1] * 1e3,
+            z: pos[2] * 1e3,
+            vx: vel[0] * 1e3,
+            vy: vel[1] * 1e3,
+            vz: vel[2] * 1e3,
+        })
+        .collect();
+}
+
+// Reads bodies from a CSV with a `name,mass,x,y,z,vx,vy,vz` header, in SI units.
+pub fn load_csv(path: &Path) -> Result<Vec<Body>, Box<dyn Error>> {
+    let mut rdr = csv::Reader::from_path(path)?;
+    let mut bodies = vec![];
+    for (row, record) in rdr.deserialize().enumerate() {
+        let body: Body = record?;
+        if !body.mass.is_finite() || body.mass < 0. {
+            return Err(NbodyError::InvalidMass {
+                id: row as u64,
+                mass: body.mass,
+            }
+            .into());
+        }
+        bodies.push(body);
+    }
+    info!("loaded {} bodies from {}", bodies.len(), path.display());
+    return Ok(bodies);
+}
+
+/// Sun + planets, optionally with a synthetic main asteroid belt on circular, slightly inclined
+/// orbits between 2.1 and 3.3 AU.
+pub struct SolarSystem {
+    pub bodies: Vec<Body>,
+    pub asteroids: u64,
+}
+
+impl SolarSystem {
+    pub fn generate<R: Rng>(&self, rng: &mut R) -> Vec<Point> {
+        let mut points: Vec<Point> = self
+            .bodies
+            .iter()
+            .enumerate()
+            .map(|(id, b)| b.to_point(id as u64))
+            .collect();
+
+        let first_id = points.len() as u64;
+        for id in first_id..first_id + self.asteroids {
+            let r = rng.gen_range(2.1..3.3) * AU;
+            let phi = rng.gen_range(0.0..std::f64::consts::TAU);
+            let inc: f64 = rng.gen_range(-0.1..0.1);
+            let v = (G * SUN_MASS / r).sqrt();
+            let (sp, cp) = phi.sin_cos();
+            let (si, ci) = inc.sin_cos();
+            let vel = Vec3d::new(-sp * v, cp * v * ci, cp * v * si);
+            let p = Point::new(ASTEROID_MASS, r * cp, r * sp * ci, r * sp * si, vel);
+            points.push(p.with_id(id));
+        }
+        return points;
+    }
+}
+
+impl InitialConditions for SolarSystem {
+    fn generate(&self, mut rng: &mut dyn RngCore) -> ParticleSet {
+        return SolarSystem::generate(self, &mut rng);
+    }
+
+    fn count(&self) -> Option<usize> {
+        return Some(self.bodies.len() + self.asteroids as usize);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::geometry::vec3d::G;
+    use crate::ic::solar::{bundled_bodies, SUN_MASS};
+
+    #[test]
+    fn test_planets_near_circular_speed() {
+        let bodies = bundled_bodies();
+        assert_eq!(bodies.len(), 9);
+        for b in &bodies[1..] {
+            let r = (b.x * b.x + b.y * b.y + b.z * b.z).sqrt();
+            let v = (b.vx * b.vx + b.vy * b.vy + b.vz * b.vz).sqrt();
+            let v_circ = (G * SUN_MASS / r).sqrt();
+            assert!((v / v_circ - 1.).abs() < 0.25, "{} is not bound", b.name);
+        }
+    }
+}
@@ -0,0 +1,29 @@
+pub mod analysis;
+pub mod binaries;
+pub mod control;
+pub mod error;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod geometry;
+pub mod groups;
+pub mod ic;
+pub mod metrics;
+#[cfg(feature = "numa")]
+pub mod numa;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod out_of_core;
+pub mod output;
+#[cfg(feature = "python")]
+pub mod python;
+pub mod repl;
+pub mod simulation;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod sweep;
+pub mod timings;
+pub mod units;
+pub mod verify;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+
+pub use crate::error::NbodyError;
+pub use crate::geometry::vec3d::{Point, Vec3d};
@@ -1,66 +1,2214 @@
 use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
 
-use log::info;
-use rand::{thread_rng, Rng};
-use rand_distr::{Distribution, Normal};
+use clap::{Parser, Subcommand, ValueEnum};
+use indicatif::{ProgressBar, ProgressStyle};
+use log::{debug, info, warn};
+use rand::rngs::StdRng;
+use rand::{thread_rng, Rng, SeedableRng};
+use serde::Serialize;
 
-use crate::geometry::bh_tree::BHTree;
-use crate::geometry::vec3d::{Point, Vec3d};
-
-pub mod geometry;
+use nbody::analysis::{Analysis, Center, CoreTracker, CORE_FILE};
+use nbody::control::ControlServer;
+#[cfg(feature = "render")]
+use nbody::geometry::bh_tree::BHTree;
+use nbody::geometry::bh_tree::TreeReuse;
+use nbody::geometry::boundary::Boundary;
+use nbody::geometry::contact::{CollisionModel, CollisionOutcome};
+use nbody::geometry::db::{DbHandle, RunState, Snapshot};
+use nbody::geometry::drag::Drag;
+use nbody::geometry::escape::EscaperPolicy;
+use nbody::geometry::force::{ForceLaw, Yukawa};
+use nbody::geometry::infall::{Infall, INJECTION_FILE};
+use nbody::geometry::kicks::{Kicks, KICK_FILE};
+use nbody::geometry::mass_loss::MassLoss;
+use nbody::geometry::merge::{DensityThreshold, NeverMerge, Schwarzschild, Touching};
+use nbody::geometry::octree::Refinement;
+use nbody::geometry::opening::{OpeningCriterion, ThetaSchedule};
+use nbody::geometry::perturber::{Perturber, Trajectory};
+use nbody::geometry::pm::ParticleMesh;
+use nbody::geometry::pn::PostNewtonian;
+use nbody::geometry::potential::{HarmonicTrap, LogarithmicHalo, MiyamotoNagai, PointMass};
+use nbody::geometry::species::SpeciesTable;
+use nbody::geometry::sph::Sph;
+use nbody::geometry::split::Fragmentation;
+use nbody::geometry::thermodynamics::{Adiabatic, Cooling, Isothermal, Thermodynamics};
+use nbody::ic::collision::{CollidingSystems, Collision};
+use nbody::ic::disk::ExponentialDisk;
+use nbody::ic::file::{load as load_particles, stream_csv};
+use nbody::ic::frame::{BulkMotion, MovingSystem};
+use nbody::ic::grid::PerturbedGrid;
+use nbody::ic::halo::{Halo, Profile};
+use nbody::ic::solar::{bundled_bodies, load_csv, SolarSystem};
+use nbody::ic::{InitialConditions, UniformSphere};
+use nbody::metrics::{available_memory, MetricsExporter};
+use nbody::out_of_core::OutOfCore;
+use nbody::output::compress::Compression;
+use nbody::output::container::{ContainerWriter, CONTAINER_FILE};
+use nbody::output::manifest::{utc_timestamp, RunManifest};
+use nbody::output::orbits::OrbitWriter;
+#[cfg(feature = "render")]
+use nbody::output::render::{Camera, ColorBy, PngWriter, Renderer, VideoWriter};
+use nbody::output::{
+    list_snapshots, log_offsets, prepare_run_dir, rewind_logs, BinaryCatalogWriter, CsvWriter,
+    FilteredWriter, GroupCatalogWriter, OutputFilter, Region, RegionShape, Retention,
+    SnapshotSchedule, SnapshotWriter,
+};
+use nbody::repl::Repl;
+use nbody::simulation::{Simulation, SimulationBuilder};
+use nbody::sweep::{Sweep, Vary};
+use nbody::timings::Timings;
+use nbody::units::{InUnits, Units};
+use nbody::verify::Verify;
+use nbody::{NbodyError, Point, Vec3d};
 
 const THETA: f64 = 0.5;
 const GRAPH_SIZE: f64 = 100.;
 const NUM_POINTS: u64 = 500000;
 const TIME_STEP: f64 = 0.5;
-const STEPS: i32 = 10000;
+const STEPS: u64 = 10000;
 const PARTICLE_MASS_BASE: f64 = 1e10;
 const MASS_DIST_MEAN: f64 = 1.0;
 const MASS_DIST_STDDEV: f64 = 0.1;
 
+#[derive(ValueEnum, Serialize, Clone, Copy, Debug)]
+enum IcKind {
+    /// Uniform sphere of particles at rest.
+    Sphere,
+    /// Rotating exponential disk.
+    Disk,
+    /// Two rotating disks on a collision course.
+    Collision,
+    /// Isotropic Hernquist halo.
+    Hernquist,
+    /// Isotropic NFW halo truncated at its virial radius.
+    Nfw,
+    /// Sun and planets from an ephemeris table, in SI units.
+    Solar,
+    /// Cold perturbed uniform grid filling the simulation box.
+    Grid,
+}
+
+#[derive(ValueEnum, Serialize, Clone, Copy, Debug)]
+enum PotentialKind {
+    /// Point mass at the origin.
+    PointMass,
+    /// Logarithmic halo with a flat rotation curve.
+    LogHalo,
+    /// Miyamoto-Nagai disk in the xy-plane.
+    MiyamotoNagai,
+    /// Harmonic trap centered on the origin.
+    Harmonic,
+}
+
+#[derive(ValueEnum, Serialize, Clone, Copy, Debug)]
+enum MergeKind {
+    /// Particles within a Schwarzschild radius of each other merge.
+    Schwarzschild,
+    /// Particles whose explicit radii overlap merge.
+    Touching,
+    /// Pairs denser than --merge-density merge.
+    Density,
+    /// Nothing merges, and coincident particles are an error.
+    Never,
+}
+
+#[derive(ValueEnum, Serialize, Clone, Copy, Debug)]
+enum PostNewtonianKind {
+    /// The 1PN terms, which make orbits precess.
+    Conservative,
+    /// The 2.5PN radiation reaction, which makes them shrink.
+    Radiation,
+    /// Both.
+    Full,
+}
+
+#[derive(ValueEnum, Serialize, Clone, Copy, Debug)]
+enum CollisionKind {
+    /// Touching particles merge.
+    Merge,
+    /// Touching particles bounce off each other.
+    Bounce,
+    /// Touching particles pass through each other.
+    PassThrough,
+}
+
+#[derive(ValueEnum, Serialize, Clone, Copy, Debug)]
+enum BoundaryKind {
+    /// No walls.
+    Open,
+    /// Particles wrap around to the opposite face.
+    Periodic,
+    /// Walls reflect particles.
+    Reflective,
+    /// Walls remove particles that reach them.
+    Absorbing,
+}
+
+#[derive(ValueEnum, Serialize, Clone, Copy, Debug)]
+enum EscaperKind {
+    /// Drop particles beyond the escape radius.
+    Remove,
+    /// Reflect particles back off the escape sphere.
+    Reflect,
+    /// Keep far particles out of the tree and sum their forces directly.
+    Outside,
+}
+
+#[derive(ValueEnum, Serialize, Clone, Copy, Debug)]
+enum OpeningKind {
+    /// Open nodes whose size over distance is at least theta.
+    Geometric,
+    /// Open nodes whose estimated monopole error exceeds --opening-tolerance.
+    AbsoluteError,
+    /// Open nodes whose Salmon-Warren error bound exceeds --opening-tolerance.
+    SalmonWarren,
+}
+
+#[cfg(feature = "render")]
+#[derive(ValueEnum, Serialize, Clone, Copy, Debug)]
+enum ColorKind {
+    /// Colour by speed.
+    Speed,
+    /// Colour by mass, on a log scale.
+    Mass,
+    /// Colour by SPH density over 32 neighbours, on a log scale.
+    Density,
+}
+
+#[derive(ValueEnum, Serialize, Clone, Copy, Debug)]
+enum SnapshotFormat {
+    /// One CSV file per snapshot.
+    Csv,
+    /// Every snapshot appended to one snapshots.nbc file, indexed by step and time.
+    Container,
+}
+
+#[derive(ValueEnum, Serialize, Clone, Copy, Debug)]
+enum CompressionKind {
+    None,
+    Gzip,
+    Zstd,
+}
+
+#[derive(ValueEnum, Serialize, Clone, Copy, Debug)]
+enum RegionKind {
+    /// A sphere of radius --output-radius.
+    Sphere,
+    /// A cube of half-width --output-radius.
+    Cube,
+}
+
+#[derive(ValueEnum, Serialize, Clone, Copy, Debug)]
+enum UnitSystem {
+    /// Metres, kilograms and seconds.
+    Si,
+    /// Kiloparsecs, solar masses and megayears.
+    Galactic,
+    /// Dimensionless units with G = 1.
+    Nbody,
+}
+
+impl UnitSystem {
+    fn units(self) -> Units {
+        return match self {
+            UnitSystem::Si => Units::SI,
+            UnitSystem::Galactic => Units::GALACTIC,
+            UnitSystem::Nbody => Units::nbody(),
+        };
+    }
+}
+
+#[derive(Parser, Serialize, Debug)]
+#[command(about = "Barnes-Hut n-body simulation")]
+struct Args {
+    /// Integration time step.
+    #[arg(long, default_value_t = TIME_STEP)]
+    dt: f64,
+
+    /// Barnes-Hut opening angle; smaller is more accurate and slower.
+    #[arg(long, default_value_t = THETA)]
+    theta: f64,
+
+    /// Units for the time step, masses, lengths and velocities given on the command line or in
+    /// --ic-file. The simulation itself runs in SI.
+    #[arg(long, value_enum, default_value_t = UnitSystem::Si, global = true)]
+    units: UnitSystem,
+
+    /// Number of steps to run.
+    #[arg(long, default_value_t = STEPS)]
+    steps: u64,
+
+    /// Seed for generated initial conditions. Runs with the same seed and options give the same
+    /// results bit for bit, whatever the number of threads. A random seed is used and logged if
+    /// none is given.
+    #[arg(long)]
+    seed: Option<u64>,
+
+    /// Use periodic boundaries on a box of side 2 * GRAPH_SIZE centered on the origin. Short for
+    /// --boundary periodic.
+    #[arg(long, conflicts_with = "boundary")]
+    periodic: bool,
+
+    /// Run in two dimensions: particles are projected onto the z = 0 plane and the tree becomes a
+    /// quadtree. Combine with --force-exponent 1 for true two-dimensional gravity.
+    #[arg(long)]
+    planar: bool,
+
+    /// Boundary conditions on a box of side 2 * GRAPH_SIZE centered on the origin.
+    #[arg(long, value_enum, default_value_t = BoundaryKind::Open)]
+    boundary: BoundaryKind,
+
+    /// Most particles a tree leaf holds before it splits. Forces from within a leaf are summed
+    /// exactly.
+    #[arg(long, default_value_t = Refinement::default().bucket_size as u64, value_parser = clap::value_parser!(u64).range(1..))]
+    bucket_size: u64,
+
+    /// Deepest the tree may go; leaves at this depth hold any number of particles.
+    #[arg(long, default_value_t = Refinement::default().max_depth)]
+    max_depth: usize,
+
+    /// Keep the tree between steps, refreshing only its centres of mass, until a particle drifts
+    /// more than this many leaf sizes outside its leaf.
+    #[arg(long, value_name = "LEAVES")]
+    reuse_drift: Option<f64>,
+
+    /// When a tree node is far enough away to be treated as a point mass.
+    #[arg(long, value_enum, default_value_t = OpeningKind::Geometric)]
+    opening: OpeningKind,
+
+    /// Largest acceleration error accepted per node by the error-bounded opening criteria.
+    #[arg(long, value_name = "ACCEL", default_value_t = 1e-9)]
+    opening_tolerance: f64,
+
+    /// Scale theta by this at the root of the tree, rising to the full theta --theta-levels levels
+    /// down, to tighten the far field without lowering theta everywhere.
+    #[arg(long, value_name = "FACTOR")]
+    theta_root: Option<f64>,
+
+    /// Levels below the root over which --theta-root ramps up to the full theta.
+    #[arg(long, default_value_t = 3, requires = "theta_root")]
+    theta_levels: u32,
+
+    /// Sum forces and centres of mass with compensated (Kahan) summation, so that light particles
+    /// next to very heavy ones aren't lost to rounding. Slower.
+    #[arg(long)]
+    compensated_summation: bool,
+
+    /// Most steps in a row that reuse the tree before it is rebuilt anyway.
+    #[arg(long, default_value_t = 10, requires = "reuse_drift")]
+    reuse_max_steps: u32,
+
+    /// Gravitational constant, in the chosen units. Defaults to the physical value.
+    #[arg(long)]
+    g: Option<f64>,
+
+    /// Exponent n of the G m1 m2 / r^n attraction.
+    #[arg(long, default_value_t = 2.)]
+    force_exponent: f64,
+
+    /// Strength of a Yukawa correction to the potential, -G m1 m2 / r (1 + alpha e^(-r / lambda)).
+    #[arg(long, requires = "yukawa_lambda")]
+    yukawa_alpha: Option<f64>,
+
+    /// Range of the Yukawa correction, in the chosen length unit.
+    #[arg(long, requires = "yukawa_alpha")]
+    yukawa_lambda: Option<f64>,
+
+    /// Solve for long-range gravity on a mesh of this many cells a side, leaving the tree only
+    /// the short-range force (TreePM). Needs a periodic boundary.
+    #[arg(long, value_name = "CELLS", conflicts_with_all = ["force_exponent", "yukawa_alpha"])]
+    pm_cells: Option<usize>,
+
+    /// Scale, in mesh cells, over which the force passes from the tree to the mesh. Zero leaves
+    /// all of it to the mesh.
+    #[arg(
+        long,
+        value_name = "CELLS",
+        default_value_t = 1.25,
+        requires = "pm_cells"
+    )]
+    pm_split: f64,
+
+    /// Pull on each particle with every periodic image of the others, by Ewald summation, rather
+    /// than only the nearest. Needs a periodic boundary.
+    #[arg(long, conflicts_with_all = ["pm_cells", "force_exponent", "yukawa_alpha"])]
+    ewald: bool,
+
+    /// Add a pinned particle of this mass at the origin, e.g. a central black hole that attracts
+    /// everything but never moves.
+    #[arg(long, value_name = "MASS")]
+    central_mass: Option<f64>,
+
+    /// Advance mutually nearest pairs closer than this distance analytically as two-body orbits.
+    #[arg(long, value_name = "R")]
+    encounter_radius: Option<f64>,
+
+    /// Which particles merge after each step.
+    #[arg(long, value_enum, default_value_t = MergeKind::Schwarzschild)]
+    merge_policy: MergeKind,
+
+    /// Threshold for --merge-policy density: the pair's combined mass over the volume of the
+    /// sphere their separation spans, in mass per length cubed.
+    #[arg(long, value_name = "RHO", required_if_eq("merge_policy", "density"))]
+    merge_density: Option<f64>,
+
+    /// What happens when particles touch. Particles --merge-policy picks out always merge.
+    #[arg(long, value_enum, default_value_t = CollisionKind::Merge)]
+    collision: CollisionKind,
+
+    /// Coefficient of restitution for bouncing collisions: 1 is elastic, 0 perfectly inelastic.
+    #[arg(long, default_value_t = 1.)]
+    restitution: f64,
+
+    /// Every particle but the pinned ones loses this fraction of its mass per unit time.
+    #[arg(long, value_name = "RATE", conflicts_with = "mass_loss_table")]
+    mass_loss_rate: Option<f64>,
+
+    /// CSV file with age and fraction columns: the fraction of its initial mass a particle keeps
+    /// at each age, with every particle as old as the run.
+    #[arg(long, value_name = "PATH")]
+    mass_loss_table: Option<PathBuf>,
+
+    /// Kick particles in the --kick-min-mass to --kick-max-mass range once each, at a random time
+    /// up to --kick-max-age, with speeds drawn from a Maxwellian of this one-dimensional
+    /// dispersion. Kicks are logged to kicks.csv in SI units.
+    #[arg(long, value_name = "V", requires = "kick_max_age")]
+    kick_dispersion: Option<f64>,
+
+    /// Lightest particle that gets a kick.
+    #[arg(long, value_name = "M", default_value_t = 0.)]
+    kick_min_mass: f64,
+
+    /// Heaviest particle that gets a kick.
+    #[arg(long, value_name = "M", default_value_t = f64::INFINITY)]
+    kick_max_mass: f64,
+
+    /// Earliest time in the run a kick can happen.
+    #[arg(long, value_name = "T", default_value_t = 0.)]
+    kick_min_age: f64,
+
+    /// Latest time in the run a kick can happen.
+    #[arg(long, value_name = "T")]
+    kick_max_age: Option<f64>,
+
+    /// Seed for the kicks [default: --seed, or 0]
+    #[arg(long)]
+    kick_seed: Option<u64>,
+
+    /// Inject this many particles per unit time, falling in from a sphere of radius
+    /// --infall-radius about the origin. Injections are logged to injections.csv in SI units.
+    #[arg(long, value_name = "RATE", requires_all = ["infall_mass", "infall_radius"])]
+    infall_rate: Option<f64>,
+
+    /// Mass of each infalling particle.
+    #[arg(long, value_name = "M")]
+    infall_mass: Option<f64>,
+
+    /// Radius of the sphere infalling particles start on.
+    #[arg(long, value_name = "R")]
+    infall_radius: Option<f64>,
+
+    /// Speed infalling particles start with, straight towards the origin.
+    #[arg(long, value_name = "V", default_value_t = 0.)]
+    infall_speed: f64,
+
+    /// Seed for where infalling particles start [default: --seed, or 0]
+    #[arg(long)]
+    infall_seed: Option<u64>,
+
+    /// Split particles whose SPH density exceeds this, in mass per length cubed, into
+    /// --split-into particles of equal mass after every step.
+    #[arg(long, value_name = "RHO")]
+    split_density: Option<f64>,
+
+    /// Fragments each split particle becomes.
+    #[arg(long, default_value_t = 2, value_parser = clap::value_parser!(u64).range(2..))]
+    split_into: u64,
+
+    /// Neighbours to smooth densities over when deciding which particles split.
+    #[arg(long, default_value_t = 32)]
+    split_neighbors: usize,
+
+    /// Leave particles lighter than this whole, so splitting stops somewhere.
+    #[arg(long, value_name = "M", default_value_t = 0.)]
+    split_min_mass: f64,
+
+    /// Treat particles of this species as gas, feeling SPH pressure forces as well as gravity.
+    #[arg(long, value_name = "SPECIES")]
+    sph_species: Option<u16>,
+
+    /// Neighbours to smooth gas densities over.
+    #[arg(long, default_value_t = 32)]
+    sph_neighbors: usize,
+
+    /// Keep the gas isothermal at this sound speed rather than treating it as an ideal gas.
+    #[arg(long, value_name = "C")]
+    sound_speed: Option<f64>,
+
+    /// Adiabatic index of the gas, when it isn't isothermal.
+    #[arg(long, default_value_t = 5. / 3.)]
+    gamma: f64,
+
+    /// Cool the gas exponentially towards --cooling-floor with this e-folding time.
+    #[arg(long, value_name = "T", conflicts_with = "sound_speed")]
+    cooling_time: Option<f64>,
+
+    /// Internal energy per unit mass cooling gas settles at, in velocity squared.
+    #[arg(
+        long,
+        value_name = "U",
+        default_value_t = 0.,
+        requires = "cooling_time"
+    )]
+    cooling_floor: f64,
+
+    /// Internal energy per unit mass the gas starts with, in velocity squared.
+    #[arg(long, value_name = "U", default_value_t = 0.)]
+    gas_energy: f64,
+
+    /// Linear coefficient of the artificial viscosity in gas shocks.
+    #[arg(long, default_value_t = 1.)]
+    viscosity_alpha: f64,
+
+    /// Quadratic coefficient of the artificial viscosity in gas shocks.
+    #[arg(long, default_value_t = 2.)]
+    viscosity_beta: f64,
+
+    /// Give particles without an explicit radius that of a uniform sphere of this density.
+    #[arg(long, value_name = "RHO")]
+    particle_density: Option<f64>,
+
+    /// Move the centre of mass back to the origin, at rest, after every step. Ignored while any
+    /// particle is pinned.
+    #[arg(long)]
+    recenter: bool,
+
+    /// Handle particles further than this from the origin according to --escapers, instead of
+    /// growing the tree to cover them.
+    #[arg(long, value_name = "R")]
+    escape_radius: Option<f64>,
+
+    /// What to do with particles beyond --escape-radius.
+    #[arg(long, value_enum, default_value_t = EscaperKind::Remove)]
+    escapers: EscaperKind,
+
+    /// Fixed background potential acting on every particle.
+    #[arg(long, value_enum)]
+    potential: Option<PotentialKind>,
+
+    /// Mass of the point-mass or Miyamoto-Nagai potential.
+    #[arg(long, default_value_t = 1e16)]
+    potential_mass: f64,
+
+    /// Core radius of the logarithmic halo, or radial scale of the Miyamoto-Nagai disk.
+    #[arg(long, default_value_t = 10.)]
+    potential_scale: f64,
+
+    /// Vertical scale of the Miyamoto-Nagai disk.
+    #[arg(long, default_value_t = 1.)]
+    potential_scale_height: f64,
+
+    /// Circular speed of the logarithmic halo at large radii.
+    #[arg(long, default_value_t = 1.)]
+    potential_velocity: f64,
+
+    /// Angular frequency of the harmonic trap.
+    #[arg(long, default_value_t = 1e-3)]
+    potential_omega: f64,
+
+    /// Add a perturber of this mass that moves in a straight line, through --perturber-position
+    /// at time zero with --perturber-velocity, and pulls on every particle without being pulled
+    /// back, e.g. a galaxy flying by.
+    #[arg(long, value_name = "M", conflicts_with = "perturber_file")]
+    perturber_mass: Option<f64>,
+
+    /// Where the perturber is at time zero.
+    #[arg(
+        long,
+        value_name = "X,Y,Z",
+        value_delimiter = ',',
+        default_value = "0,0,0"
+    )]
+    perturber_position: Vec<f64>,
+
+    /// Velocity of the perturber.
+    #[arg(
+        long,
+        value_name = "VX,VY,VZ",
+        value_delimiter = ',',
+        default_value = "0,0,0"
+    )]
+    perturber_velocity: Vec<f64>,
+
+    /// CSV file with time, mass, x, y and z columns giving the perturber's path instead, followed
+    /// linearly between rows.
+    #[arg(long, value_name = "PATH")]
+    perturber_file: Option<PathBuf>,
+
+    /// Softening length of the perturber's pull.
+    #[arg(long, value_name = "LENGTH", default_value_t = 0.)]
+    perturber_softening: f64,
+
+    /// Linear drag coefficient k, applying F = -k v to every particle.
+    #[arg(long, value_name = "K", conflicts_with = "friction_density")]
+    drag: Option<f64>,
+
+    /// Density of the background for Chandrasekhar dynamical friction.
+    #[arg(long, value_name = "RHO")]
+    friction_density: Option<f64>,
+
+    /// One-dimensional velocity dispersion of the dynamical friction background.
+    #[arg(long, default_value_t = 1.)]
+    friction_dispersion: f64,
+
+    /// Coulomb logarithm for dynamical friction.
+    #[arg(long, default_value_t = 3.)]
+    coulomb_log: f64,
+
+    /// Post-Newtonian corrections to the attraction between close, massive pairs.
+    #[arg(long, value_enum)]
+    post_newtonian: Option<PostNewtonianKind>,
+
+    /// Pairs closer than this many times the sum of their Schwarzschild radii get the
+    /// post-Newtonian corrections.
+    #[arg(long, default_value_t = 1000.)]
+    pn_within: f64,
+
+    /// Lightest particle that gets the post-Newtonian corrections.
+    #[arg(long, default_value_t = 0.)]
+    pn_min_mass: f64,
+
+    /// JSON array of species settings (name, softening, merge, color); particles refer to them by
+    /// index through the `species` column of --ic-file.
+    #[arg(long, value_name = "PATH")]
+    species: Option<PathBuf>,
+
+    /// How snapshots are stored. The extra CSV columns and --compress only apply to CSV.
+    #[arg(long, value_enum, default_value_t = SnapshotFormat::Csv)]
+    snapshot_format: SnapshotFormat,
+
+    /// Compress CSV snapshots as they are written, to out-<step>.csv.gz or out-<step>.csv.zst.
+    /// Needs the compression feature.
+    #[arg(long, value_enum, default_value_t = CompressionKind::None)]
+    compress: CompressionKind,
+
+    /// Only write particles of these species (by name) to snapshots.
+    #[arg(long, value_name = "NAME", value_delimiter = ',', requires = "species")]
+    output_species: Option<Vec<String>>,
+
+    /// Only write about this fraction of particles to snapshots, the same ones every time, picked
+    /// by id.
+    #[arg(long, value_name = "FRACTION")]
+    output_sample: Option<f64>,
+
+    /// Only write particles inside this region of interest to snapshots.
+    #[arg(long, value_enum)]
+    output_region: Option<RegionKind>,
+
+    /// Centre of --output-region.
+    #[arg(
+        long,
+        value_name = "X,Y,Z",
+        value_delimiter = ',',
+        default_value = "0,0,0",
+        requires = "output_region"
+    )]
+    output_center: Vec<f64>,
+
+    /// Radius, or half-width, of --output-region.
+    #[arg(long, value_name = "LENGTH", default_value_t = GRAPH_SIZE, requires = "output_region")]
+    output_radius: f64,
+
+    /// Move --output-region onto the densest particle (SPH density over 32 neighbours) of every
+    /// snapshot, following the main clump as it moves.
+    #[arg(long, requires = "output_region")]
+    output_track_densest: bool,
+
+    /// Add an SPH density column to CSV snapshots, smoothing over this many nearest neighbours.
+    #[arg(long, value_name = "K", num_args = 0..=1, default_missing_value = "32")]
+    density_neighbors: Option<usize>,
+
+    /// Add a column with each particle's gravitational potential per unit mass to CSV snapshots.
+    #[arg(long)]
+    output_potential: bool,
+
+    /// Write a friends-of-friends group catalog with this linking length alongside each snapshot.
+    #[arg(long, value_name = "L")]
+    fof_linking_length: Option<f64>,
+
+    /// Smallest group to include in the catalog.
+    #[arg(long, default_value_t = 20)]
+    fof_min_members: usize,
+
+    /// Write a catalog of bound pairs with semi-major axes up to this alongside each snapshot.
+    #[arg(long, value_name = "A")]
+    binary_max_axis: Option<f64>,
+
+    /// Initial conditions to generate.
+    #[arg(long, value_enum, default_value_t = IcKind::Sphere)]
+    ic: IcKind,
+
+    /// Load particles (mass, position, velocity per row) from a CSV, Parquet or HDF5 file instead
+    /// of generating them. HDF5 files hold one dataset per CSV column in the root group.
+    #[arg(long, value_name = "PATH")]
+    ic_file: Option<PathBuf>,
+
+    /// Set the initial conditions moving as a whole at this velocity.
+    #[arg(
+        long,
+        value_name = "VX,VY,VZ",
+        value_delimiter = ',',
+        default_value = "0,0,0"
+    )]
+    bulk_velocity: Vec<f64>,
+
+    /// Set the initial conditions rotating as a solid body about their centre of mass, at this
+    /// angular velocity in radians per unit time.
+    #[arg(
+        long,
+        value_name = "WX,WY,WZ",
+        value_delimiter = ',',
+        default_value = "0,0,0"
+    )]
+    bulk_rotation: Vec<f64>,
+
+    /// Total mass of the disk.
+    #[arg(long, default_value_t = 5e15)]
+    disk_mass: f64,
+
+    /// Radial scale length of the disk.
+    #[arg(long, default_value_t = 20.)]
+    disk_scale_length: f64,
+
+    /// Vertical scale height (thickness) of the disk.
+    #[arg(long, default_value_t = 2.)]
+    disk_scale_height: f64,
+
+    /// Rotate the disk clockwise when viewed from +z.
+    #[arg(long)]
+    disk_clockwise: bool,
+
+    /// Velocity dispersion as a fraction of the circular velocity.
+    #[arg(long, default_value_t = 0.)]
+    disk_dispersion: f64,
+
+    /// Total mass of the halo.
+    #[arg(long, default_value_t = 5e15)]
+    halo_mass: f64,
+
+    /// Scale radius of the halo.
+    #[arg(long, default_value_t = 10.)]
+    halo_scale_radius: f64,
+
+    /// NFW concentration, i.e. the virial radius in units of the scale radius.
+    #[arg(long, default_value_t = 10.)]
+    halo_concentration: f64,
+
+    /// Heliocentric state vectors (name,mass,x,y,z,vx,vy,vz in SI units) to use instead of the
+    /// bundled ephemeris table.
+    #[arg(long, value_name = "PATH")]
+    solar_csv: Option<PathBuf>,
+
+    /// Number of synthetic main-belt asteroids to add to the solar system.
+    #[arg(long, default_value_t = 0)]
+    asteroids: u64,
+
+    /// Particles per side of the grid.
+    #[arg(long, default_value_t = (NUM_POINTS as f64).cbrt().round() as u64)]
+    grid_side: u64,
+
+    /// Total mass of the grid.
+    #[arg(long, default_value_t = 5e15)]
+    grid_mass: f64,
+
+    /// Standard deviation of the grid perturbations, in units of the grid spacing.
+    #[arg(long, default_value_t = 0.1)]
+    grid_amplitude: f64,
+
+    /// Initial distance between the two colliding systems along x.
+    #[arg(long, default_value_t = 4. * GRAPH_SIZE)]
+    collision_separation: f64,
+
+    /// Initial offset between the two colliding systems along y.
+    #[arg(long, default_value_t = GRAPH_SIZE / 2.)]
+    collision_impact: f64,
+
+    /// Speed at which the two colliding systems approach each other.
+    #[arg(long, default_value_t = 1.)]
+    collision_velocity: f64,
+
+    /// Tilt of the second system about the x-axis, in degrees.
+    #[arg(long, default_value_t = 0.)]
+    collision_inclination: f64,
+
+    /// Rotation of the second system about the z-axis, in degrees.
+    #[arg(long, default_value_t = 0.)]
+    collision_azimuth: f64,
+
+    /// Write a snapshot every N steps.
+    #[arg(long, value_name = "N")]
+    snapshot_every: Option<u64>,
+
+    /// Write a snapshot every T units of simulation time.
+    #[arg(long, value_name = "T", value_parser = positive_finite)]
+    snapshot_dt: Option<f64>,
+
+    /// Only keep the last K snapshots on disk.
+    #[arg(long, value_name = "K", value_parser = clap::value_parser!(u64).range(1..))]
+    keep_last: Option<u64>,
+
+    /// Write the position and velocity of these particles to orbit-<id>.csv after every step, in
+    /// SI units.
+    #[arg(long, value_name = "ID", value_delimiter = ',')]
+    track_ids: Vec<u64>,
+
+    /// Measure the core radius and density every N steps and log them to core.csv, in SI units.
+    #[arg(long, value_name = "N")]
+    core_every: Option<u64>,
+
+    /// Neighbours to smooth densities over when measuring the core.
+    #[arg(long, default_value_t = 32)]
+    core_neighbors: usize,
+
+    /// Report core collapse once the core density exceeds this, in mass per length cubed.
+    #[arg(long, value_name = "DENSITY", requires = "core_every")]
+    core_collapse_density: Option<f64>,
+
+    /// End the run, as if it had finished, once the core collapses.
+    #[arg(long, requires = "core_collapse_density")]
+    stop_at_core_collapse: bool,
+
+    /// Rewrite OpenMetrics text to this file after every step.
+    #[arg(long, value_name = "PATH")]
+    metrics_file: Option<PathBuf>,
+
+    /// Serve OpenMetrics text over HTTP on this address, e.g. 0.0.0.0:9100.
+    #[arg(long, value_name = "ADDR")]
+    metrics_addr: Option<String>,
+
+    /// Serve an HTTP control API on this address, e.g. 127.0.0.1:8081, to pause, resume or stop
+    /// the run, change dt and theta, and download snapshots.
+    #[arg(long, value_name = "ADDR")]
+    control_addr: Option<String>,
+
+    /// Wait for POST /resume on the control API before taking the first step.
+    #[arg(long, requires = "control_addr")]
+    start_paused: bool,
+
+    /// Persist snapshots to a sled database, e.g. sled://state.db, and resume from the latest one.
+    #[arg(long, value_name = "URI")]
+    persist: Option<String>,
+
+    /// Directory for snapshots, run.json and checkpoints. Created if missing.
+    #[arg(long, value_name = "DIR", default_value = "output")]
+    out_dir: PathBuf,
+
+    /// Put this run in a new subdirectory of --out-dir named after its UTC start time, e.g.
+    /// output/20260101-120000.
+    #[arg(long)]
+    timestamped: bool,
+
+    /// Overwrite a run already in the output directory, deleting its snapshots. Resumed runs
+    /// carry on in their directory without it.
+    #[arg(long)]
+    force: bool,
+
+    /// Keep the particles in files in this directory instead of in memory, for systems too large
+    /// to fit in RAM. Much slower, and only gravity is integrated: nothing merges, and boundaries,
+    /// external forces and the other per-step physics are ignored. Only CSV snapshots are
+    /// written. --ic-file is streamed in, so it needn't fit in memory either.
+    #[arg(long, value_name = "DIR", conflicts_with_all = ["resume", "persist"])]
+    out_of_core: Option<PathBuf>,
+
+    /// Particles per grid cell to aim for with --out-of-core. Each cell is stepped with its
+    /// neighbours' particles in memory, about 27 times this many.
+    #[arg(long, value_name = "N", default_value_t = 1 << 20, requires = "out_of_core")]
+    out_of_core_chunk: usize,
+
+    /// Start the run even if it looks like it needs more memory than is available.
+    #[arg(long)]
+    ignore_memory_check: bool,
+
+    /// Where to write a checkpoint when the run is interrupted with Ctrl-C [default:
+    /// <out-dir>/checkpoint.json]
+    #[arg(long, value_name = "PATH")]
+    checkpoint: Option<PathBuf>,
+
+    /// Resume from a checkpoint written by an interrupted run.
+    #[arg(long, value_name = "PATH", conflicts_with = "persist")]
+    resume: Option<PathBuf>,
+
+    /// Write the final tree's nodes to this file for inspection: Graphviz DOT if it ends in .dot
+    /// or .gv, nested JSON otherwise.
+    #[arg(long, value_name = "PATH")]
+    dump_tree: Option<PathBuf>,
+
+    /// Compute each force in two passes: a tree walk that gathers the particles and nodes to sum
+    /// over into flat arrays, then a tight loop over them. Agrees with the default to rounding.
+    #[arg(long)]
+    interaction_lists: bool,
+
+    /// Check every particle's position, velocity and force for NaN and infinity each step, and
+    /// stop at the first bad one with the interaction that caused it, rather than writing it out.
+    /// Always on in debug builds.
+    #[arg(long)]
+    paranoid: bool,
+
+    /// Split the force pass over the machine's NUMA nodes, each with its own pinned threads, share
+    /// of the particles and copy of the top of the tree. Helps on multi-socket machines.
+    #[cfg(feature = "numa")]
+    #[arg(long)]
+    numa: bool,
+
+    /// Also write snapshots into this SQLite database.
+    #[cfg(feature = "sqlite")]
+    #[arg(long, value_name = "PATH")]
+    sqlite: Option<PathBuf>,
+
+    /// Render each snapshot to a PNG in this directory.
+    #[cfg(feature = "render")]
+    #[arg(long, value_name = "DIR")]
+    render_png: Option<PathBuf>,
+
+    /// Pipe rendered snapshots to ffmpeg to encode this video, e.g. run.mp4.
+    #[cfg(feature = "render")]
+    #[arg(long, value_name = "PATH")]
+    render_video: Option<PathBuf>,
+
+    #[cfg(feature = "render")]
+    #[command(flatten)]
+    render: RenderOptions,
+
+    /// Serve a browser viewer on this address, e.g. 0.0.0.0:8080, and stream particle positions
+    /// to it over a WebSocket at every snapshot.
+    #[cfg(feature = "serve")]
+    #[arg(long, value_name = "ADDR")]
+    serve: Option<String>,
+
+    /// Stream only every Nth particle to the viewer.
+    #[cfg(feature = "serve")]
+    #[arg(long, value_name = "N", default_value_t = 1)]
+    serve_stride: usize,
+
+    #[command(subcommand)]
+    #[serde(skip)]
+    command: Option<Command>,
+}
+
+/// Camera and style of rendered frames, shared by live rendering and the render subcommand.
+#[cfg(feature = "render")]
+#[derive(clap::Args, Serialize, Debug)]
+struct RenderOptions {
+    /// Frame rate of --render-video.
+    #[arg(long, default_value_t = 30)]
+    render_fps: u32,
+
+    /// Width of rendered frames in pixels.
+    #[arg(long, default_value_t = 1280)]
+    render_width: u32,
+
+    /// Height of rendered frames in pixels.
+    #[arg(long, default_value_t = 720)]
+    render_height: u32,
+
+    /// Width of the rendered view in the chosen length units; defaults to the initial box.
+    #[arg(long)]
+    render_extent: Option<f64>,
+
+    /// Camera rotation about the z-axis in degrees.
+    #[arg(long, default_value_t = 0.)]
+    render_yaw: f64,
+
+    /// Camera tilt away from looking down the z-axis in degrees.
+    #[arg(long, default_value_t = 0.)]
+    render_pitch: f64,
+
+    /// Radius of rendered particles in pixels.
+    #[arg(long, default_value_t = 1.)]
+    render_point_size: f64,
+
+    /// Quantity that sets each rendered particle's colour.
+    #[arg(long, value_enum, default_value_t = ColorKind::Speed)]
+    render_color: ColorKind,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Recompute energies, Lagrangian radii, velocity dispersions and density profiles from the
+    /// snapshots of an earlier run instead of simulating.
+    Analyze(AnalyzeCommand),
+    /// Turn the snapshots of an earlier run into PNG frames or a video instead of simulating.
+    #[cfg(feature = "render")]
+    Render(RenderCommand),
+    /// Set up the simulation from the other options, then step through it and inspect it at a
+    /// prompt instead of running it.
+    Repl,
+    /// Run this binary once for every combination of the --vary values, each in its own
+    /// directory, instead of simulating.
+    Sweep(SweepCommand),
+    /// Compare the tree with direct summation on a small halo at several thetas and time steps,
+    /// and report force errors and energy drift, instead of simulating.
+    Verify(VerifyCommand),
+}
+
+#[derive(clap::Args, Debug)]
+struct AnalyzeCommand {
+    /// Output directory of an earlier run, holding CSV or parquet snapshots or a snapshot
+    /// container.
+    #[arg(long, default_value = "output")]
+    input: PathBuf,
+
+    /// Where to write analysis.csv and profiles.csv [default: --input]
+    #[arg(long, value_name = "DIR")]
+    output: Option<PathBuf>,
+
+    /// Opening angle for summing the potential energy.
+    #[arg(long, default_value_t = THETA)]
+    theta: f64,
+
+    /// Shells in each radial profile.
+    #[arg(long, default_value_t = 20)]
+    bins: usize,
+
+    /// Point to measure Lagrangian radii and profiles from.
+    #[arg(long, value_enum, default_value_t = CenterKind::Density)]
+    center: CenterKind,
+
+    /// Neighbours to smooth densities over when finding the density centre.
+    #[arg(long, default_value_t = 32)]
+    center_neighbors: usize,
+
+    /// Catalogue bound pairs with semi-major axes up to this many metres in binaries.csv.
+    #[arg(long, value_name = "A")]
+    binary_max_axis: Option<f64>,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+enum CenterKind {
+    /// Centre of mass.
+    Mass,
+    /// Density-weighted centre, which follows the core.
+    Density,
+}
+
+/// Options of the verify subcommand. The test halo has G, mass and scale radius 1, so times are
+/// in units of its dynamical time and lengths in scale radii.
+#[derive(clap::Args, Debug)]
+struct VerifyCommand {
+    /// Number of particles in the test halo.
+    #[arg(long, default_value_t = 1000)]
+    n: u64,
+
+    /// Opening angles to compare with direct summation.
+    #[arg(long, value_delimiter = ',', default_value = "0.3,0.5,0.7,1.0")]
+    theta: Vec<f64>,
+
+    /// Time steps to integrate with.
+    #[arg(long, value_delimiter = ',', default_value = "0.1,0.05,0.02")]
+    dt: Vec<f64>,
+
+    /// Length of each integration.
+    #[arg(long, default_value_t = 5.)]
+    duration: f64,
+
+    /// Plummer softening length.
+    #[arg(long, default_value_t = 0.05)]
+    softening: f64,
+
+    /// Seed for the test halo.
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
+
+    /// Also write the report as JSON to this file.
+    #[arg(long, value_name = "PATH")]
+    json: Option<PathBuf>,
+}
+
+#[derive(clap::Args, Debug)]
+struct SweepCommand {
+    /// TOML file of options shared by every run, e.g. `steps = 100` or `ic = "disk"`.
+    #[arg(long, value_name = "PATH")]
+    config: Option<PathBuf>,
+
+    /// An option and the values to sweep it over, e.g. theta=0.3,0.5,0.8. Repeat to sweep the
+    /// cross product of several.
+    #[arg(long, value_name = "NAME=VALUES", required = true)]
+    vary: Vec<Vary>,
+
+    /// Number of runs to go at once.
+    #[arg(long, default_value_t = 1)]
+    jobs: usize,
+
+    /// Directory for the runs and manifest.json.
+    #[arg(long, value_name = "DIR", default_value = "sweep")]
+    out: PathBuf,
+}
+
+#[cfg(feature = "render")]
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum FrameFormat {
+    /// One PNG per snapshot.
+    Png,
+    /// An MP4 video, encoded by ffmpeg.
+    Mp4,
+}
+
+#[cfg(feature = "render")]
+#[derive(clap::Args, Debug)]
+struct RenderCommand {
+    /// Directory of snapshots (out-<step>.csv, compressed or not, or .parquet) from an earlier
+    /// run.
+    #[arg(long, default_value = "output")]
+    input: PathBuf,
+
+    /// What to produce.
+    #[arg(long, value_enum, default_value_t = FrameFormat::Mp4)]
+    format: FrameFormat,
+
+    /// Directory for PNG frames or path of the video. Defaults to frames/ or render.mp4.
+    #[arg(long, value_name = "PATH")]
+    output: Option<PathBuf>,
+
+    /// Camera yaw in degrees at the last snapshot; the camera turns steadily from --render-yaw.
+    #[arg(long)]
+    yaw_end: Option<f64>,
+
+    /// Camera pitch in degrees at the last snapshot.
+    #[arg(long)]
+    pitch_end: Option<f64>,
+
+    /// View width at the last snapshot, for zooming in or out over the animation.
+    #[arg(long)]
+    extent_end: Option<f64>,
+
+    #[command(flatten)]
+    render: RenderOptions,
+}
+
+// Parses a command-line value that has to be finite and greater than zero.
+fn positive_finite(s: &str) -> Result<f64, String> {
+    let v: f64 = s.parse().map_err(|e| format!("{}", e))?;
+    if !(v > 0. && v.is_finite()) {
+        return Err(format!("{} is not a finite number greater than zero", v));
+    }
+    return Ok(v);
+}
+
+fn initial_conditions(args: &Args) -> Result<Box<dyn InitialConditions>, Box<dyn Error>> {
+    let units = args.units.units();
+    let (velocity, angular_velocity) = match (&args.bulk_velocity[..], &args.bulk_rotation[..]) {
+        ([vx, vy, vz], [wx, wy, wz]) => (
+            Vec3d::new(
+                units.velocity_to_si(*vx),
+                units.velocity_to_si(*vy),
+                units.velocity_to_si(*vz),
+            ),
+            Vec3d::new(*wx, *wy, *wz) / units.time_to_si(1.),
+        ),
+        _ => return Err("--bulk-velocity and --bulk-rotation take three values".into()),
+    };
+    let ic = generator(args)?;
+    if velocity == Vec3d::new_zero() && angular_velocity == Vec3d::new_zero() {
+        return Ok(ic);
+    }
+    return Ok(Box::new(MovingSystem {
+        motion: BulkMotion {
+            velocity: velocity,
+            angular_velocity: angular_velocity,
+        },
+        system: ic,
+    }));
+}
+
+// The initial conditions picked by --ic or --ic-file, before any bulk motion.
+fn generator(args: &Args) -> Result<Box<dyn InitialConditions>, Box<dyn Error>> {
+    let units = args.units.units();
+    if let Some(path) = &args.ic_file {
+        return Ok(Box::new(InUnits {
+            units: units,
+            ic: load_particles(path)?,
+        }));
+    }
+
+    info!("generating {} particles", NUM_POINTS);
+    let length = |l| units.length_to_si(l);
+    let mass = |m| units.mass_to_si(m);
+    // Generators that depend on G are configured in SI directly.
+    let disk = |n| ExponentialDisk {
+        n: n,
+        total_mass: mass(args.disk_mass),
+        scale_length: length(args.disk_scale_length),
+        scale_height: length(args.disk_scale_height),
+        max_radius: length(GRAPH_SIZE),
+        clockwise: args.disk_clockwise,
+        dispersion: args.disk_dispersion,
+    };
+    let ic: Box<dyn InitialConditions> = match args.ic {
+        IcKind::Sphere => Box::new(InUnits {
+            units: units,
+            ic: UniformSphere {
+                n: NUM_POINTS,
+                radius: GRAPH_SIZE,
+                mass_base: PARTICLE_MASS_BASE,
+                mass_mean: MASS_DIST_MEAN,
+                mass_stddev: MASS_DIST_STDDEV,
+            },
+        }),
+        IcKind::Disk => Box::new(disk(NUM_POINTS)),
+        IcKind::Collision => Box::new(CollidingSystems {
+            collision: Collision {
+                separation: length(args.collision_separation),
+                impact_parameter: length(args.collision_impact),
+                relative_velocity: units.velocity_to_si(args.collision_velocity),
+                inclination: args.collision_inclination.to_radians(),
+                azimuth: args.collision_azimuth.to_radians(),
+            },
+            first: Box::new(disk(NUM_POINTS / 2)),
+            second: Box::new(disk(NUM_POINTS - NUM_POINTS / 2)),
+        }),
+        IcKind::Hernquist => Box::new(Halo {
+            n: NUM_POINTS,
+            total_mass: mass(args.halo_mass),
+            profile: Profile::Hernquist,
+            scale_radius: length(args.halo_scale_radius),
+            max_radius: length(GRAPH_SIZE),
+        }),
+        IcKind::Nfw => Box::new(Halo {
+            n: NUM_POINTS,
+            total_mass: mass(args.halo_mass),
+            profile: Profile::Nfw,
+            scale_radius: length(args.halo_scale_radius),
+            max_radius: length(args.halo_concentration * args.halo_scale_radius),
+        }),
+        IcKind::Solar => Box::new(SolarSystem {
+            bodies: match &args.solar_csv {
+                Some(path) => load_csv(path)?,
+                None => bundled_bodies(),
+            },
+            asteroids: args.asteroids,
+        }),
+        IcKind::Grid => Box::new(InUnits {
+            units: units,
+            ic: PerturbedGrid {
+                n_side: args.grid_side,
+                box_size: 2. * GRAPH_SIZE,
+                total_mass: args.grid_mass,
+                amplitude: args.grid_amplitude,
+            },
+        }),
+    };
+    return Ok(ic);
+}
+
+fn force_law(args: &Args) -> ForceLaw {
+    let units = args.units.units();
+    let mut law = ForceLaw::default();
+    if let Some(g) = args.g {
+        law.g = units.g_to_si(g);
+    }
+    law.exponent = args.force_exponent;
+    if let (Some(alpha), Some(lambda)) = (args.yukawa_alpha, args.yukawa_lambda) {
+        law.yukawa = Some(Yukawa {
+            alpha: alpha,
+            lambda: units.length_to_si(lambda),
+        });
+    }
+    return law;
+}
+
+fn mass_loss(args: &Args) -> Result<Option<MassLoss>, Box<dyn Error>> {
+    let units = args.units.units();
+    if let Some(rate) = args.mass_loss_rate {
+        return Ok(Some(MassLoss::Rate {
+            rate: rate / units.time_to_si(1.),
+        }));
+    }
+    return match &args.mass_loss_table {
+        Some(path) => Ok(Some(MassLoss::Table {
+            table: MassLoss::read_table(path)?
+                .into_iter()
+                .map(|(age, fraction)| (units.time_to_si(age), fraction))
+                .collect(),
+        })),
+        None => Ok(None),
+    };
+}
+
+fn set_merge_policy(args: &Args, sim: &mut Simulation) {
+    let units = args.units.units();
+    match args.merge_policy {
+        MergeKind::Schwarzschild => sim.set_merge_policy(Schwarzschild),
+        MergeKind::Touching => sim.set_merge_policy(Touching),
+        MergeKind::Density => sim.set_merge_policy(DensityThreshold {
+            density: args.merge_density.unwrap_or(f64::INFINITY) * units.mass
+                / units.length.powi(3),
+        }),
+        MergeKind::Never => sim.set_merge_policy(NeverMerge),
+    }
+}
+
+fn collisions(args: &Args) -> CollisionModel {
+    let units = args.units.units();
+    return CollisionModel {
+        outcome: match args.collision {
+            CollisionKind::Merge => CollisionOutcome::Merge,
+            CollisionKind::Bounce => CollisionOutcome::Bounce {
+                restitution: args.restitution,
+            },
+            CollisionKind::PassThrough => CollisionOutcome::PassThrough,
+        },
+        density: args
+            .particle_density
+            .map(|rho| rho * units.mass / units.length.powi(3)),
+    };
+}
+
+fn boundary(args: &Args) -> Boundary {
+    let box_size = args.units.units().length_to_si(2. * GRAPH_SIZE);
+    let kind = if args.periodic {
+        BoundaryKind::Periodic
+    } else {
+        args.boundary
+    };
+    return match kind {
+        BoundaryKind::Open => Boundary::Open,
+        BoundaryKind::Periodic => Boundary::Periodic { box_size: box_size },
+        BoundaryKind::Reflective => Boundary::Reflective { box_size: box_size },
+        BoundaryKind::Absorbing => Boundary::Absorbing { box_size: box_size },
+    };
+}
+
+#[cfg(feature = "render")]
+fn renderer(opts: &RenderOptions, units: Units) -> Renderer {
+    let extent = opts.render_extent.unwrap_or(2. * GRAPH_SIZE);
+    return Renderer {
+        camera: Camera {
+            center: Vec3d::new_zero(),
+            extent: units.length_to_si(extent),
+            yaw: opts.render_yaw.to_radians(),
+            pitch: opts.render_pitch.to_radians(),
+            width: opts.render_width,
+            height: opts.render_height,
+        },
+        point_size: opts.render_point_size,
+        color_by: match opts.render_color {
+            ColorKind::Speed => ColorBy::Speed,
+            ColorKind::Mass => ColorBy::Mass,
+            ColorKind::Density => ColorBy::Density(32),
+        },
+    };
+}
+
+fn output_region(args: &Args) -> Result<Option<Region>, Box<dyn Error>> {
+    let shape = match args.output_region {
+        Some(RegionKind::Sphere) => RegionShape::Sphere,
+        Some(RegionKind::Cube) => RegionShape::Cube,
+        None => return Ok(None),
+    };
+    let units = args.units.units();
+    let center = match args.output_center[..] {
+        [x, y, z] => Vec3d::new(
+            units.length_to_si(x),
+            units.length_to_si(y),
+            units.length_to_si(z),
+        ),
+        _ => return Err("--output-center takes three coordinates".into()),
+    };
+    return Ok(Some(Region {
+        shape: shape,
+        center: center,
+        size: units.length_to_si(args.output_radius),
+        track_densest: args.output_track_densest.then_some(32),
+    }));
+}
+
+fn opening(args: &Args) -> OpeningCriterion {
+    let tolerance = args
+        .units
+        .units()
+        .acceleration_to_si(args.opening_tolerance);
+    return match args.opening {
+        OpeningKind::Geometric => OpeningCriterion::Geometric,
+        OpeningKind::AbsoluteError => OpeningCriterion::AbsoluteError {
+            tolerance: tolerance,
+        },
+        OpeningKind::SalmonWarren => OpeningCriterion::SalmonWarren {
+            tolerance: tolerance,
+        },
+    };
+}
+
+fn theta_schedule(args: &Args) -> ThetaSchedule {
+    return match args.theta_root {
+        Some(root) => ThetaSchedule::Ramp {
+            root: root,
+            levels: args.theta_levels,
+        },
+        None => ThetaSchedule::Uniform,
+    };
+}
+
+fn escapers(args: &Args) -> EscaperPolicy {
+    let radius = match args.escape_radius {
+        Some(r) => args.units.units().length_to_si(r),
+        None => return EscaperPolicy::Keep,
+    };
+    return match args.escapers {
+        EscaperKind::Remove => EscaperPolicy::Remove { radius: radius },
+        EscaperKind::Reflect => EscaperPolicy::Reflect { radius: radius },
+        EscaperKind::Outside => EscaperPolicy::Outside { radius: radius },
+    };
+}
+
+fn add_potential(args: &Args, sim: &mut Simulation) {
+    let units = args.units.units();
+    let length = |l| units.length_to_si(l);
+    match args.potential {
+        None => {}
+        Some(PotentialKind::PointMass) => sim.add_external_potential(PointMass {
+            mass: units.mass_to_si(args.potential_mass),
+            center: Vec3d::new_zero(),
+        }),
+        Some(PotentialKind::LogHalo) => sim.add_external_potential(LogarithmicHalo {
+            v0: units.velocity_to_si(args.potential_velocity),
+            core_radius: length(args.potential_scale),
+            q: 1.,
+        }),
+        Some(PotentialKind::MiyamotoNagai) => sim.add_external_potential(MiyamotoNagai {
+            mass: units.mass_to_si(args.potential_mass),
+            a: length(args.potential_scale),
+            b: length(args.potential_scale_height),
+        }),
+        Some(PotentialKind::Harmonic) => sim.add_external_potential(HarmonicTrap {
+            omega: args.potential_omega / units.time,
+        }),
+    }
+}
+
+// Refuses to start a run that looks like it needs more memory than the system has free, rather
+// than have it killed part way through, unless told to go ahead anyway.
+fn check_memory(needed: Option<usize>, ignore: bool) -> Result<(), Box<dyn Error>> {
+    let (needed, available) = match (needed, available_memory()) {
+        (Some(needed), Some(available)) => (needed as u64, available),
+        _ => return Ok(()),
+    };
+    let mib = |bytes: u64| bytes / (1 << 20);
+    info!(
+        "the run needs about {} MiB of the {} MiB available",
+        mib(needed),
+        mib(available)
+    );
+    if needed <= available {
+        return Ok(());
+    }
+    let message = format!(
+        "the run needs about {} MiB of memory but only {} MiB is available",
+        mib(needed),
+        mib(available)
+    );
+    if ignore {
+        warn!("{}; going ahead anyway", message);
+        return Ok(());
+    }
+    return Err(format!("{}; pass --ignore-memory-check to run anyway", message).into());
+}
+
+// What a checkpoint taken now keeps besides the particles. Buffered output should be flushed
+// first, so that the log offsets cover everything written so far.
+fn run_state(
+    sim: &Simulation,
+    next_snapshot: f64,
+    run_dir: &Path,
+) -> Result<RunState, Box<dyn Error>> {
+    return Ok(RunState {
+        next_id: sim.next_id(),
+        mass_lost: sim.mass_lost(),
+        kick_seed: sim.kicks().map(|k| k.seed),
+        infall_seed: sim.infall().map(|i| i.seed),
+        next_snapshot: next_snapshot,
+        logs: log_offsets(run_dir)?,
+        run_dir: Some(run_dir.canonicalize()?),
+    });
+}
+
+fn perturber(args: &Args) -> Result<Option<Perturber>, Box<dyn Error>> {
+    let units = args.units.units();
+    let position = |v: Vec3d| {
+        let (x, y, z) = v.position();
+        Vec3d::new(
+            units.length_to_si(x),
+            units.length_to_si(y),
+            units.length_to_si(z),
+        )
+    };
+    let trajectory = if let Some(mass) = args.perturber_mass {
+        let (start, velocity) = match (&args.perturber_position[..], &args.perturber_velocity[..]) {
+            ([x, y, z], [vx, vy, vz]) => (Vec3d::new(*x, *y, *z), Vec3d::new(*vx, *vy, *vz)),
+            _ => {
+                return Err(
+                    "--perturber-position and --perturber-velocity take three values".into(),
+                )
+            }
+        };
+        let (vx, vy, vz) = velocity.position();
+        Trajectory::Linear {
+            mass: units.mass_to_si(mass),
+            position: position(start),
+            velocity: Vec3d::new(
+                units.velocity_to_si(vx),
+                units.velocity_to_si(vy),
+                units.velocity_to_si(vz),
+            ),
+        }
+    } else if let Some(path) = &args.perturber_file {
+        Trajectory::Table {
+            table: Trajectory::read_table(path)?
+                .into_iter()
+                .map(|(t, m, x)| (units.time_to_si(t), units.mass_to_si(m), position(x)))
+                .collect(),
+        }
+    } else {
+        return Ok(None);
+    };
+    return Ok(Some(Perturber {
+        trajectory: trajectory,
+        softening: units.length_to_si(args.perturber_softening),
+    }));
+}
+
+fn drag(args: &Args) -> Option<Drag> {
+    let units = args.units.units();
+    if let Some(k) = args.drag {
+        return Some(Drag::Linear {
+            k: k * units.mass / units.time,
+        });
+    }
+    return args.friction_density.map(|rho| Drag::Chandrasekhar {
+        density: rho * units.mass / units.length.powi(3),
+        dispersion: units.velocity_to_si(args.friction_dispersion),
+        coulomb_log: args.coulomb_log,
+    });
+}
+
+fn sph(args: &Args) -> Option<Sph> {
+    let units = args.units.units();
+    let energy = units.velocity_to_si(1.).powi(2);
+    let thermodynamics: Arc<dyn Thermodynamics> = match (args.sound_speed, args.cooling_time) {
+        (Some(c), _) => Arc::new(Isothermal {
+            sound_speed: units.velocity_to_si(c),
+        }),
+        (None, Some(t)) => Arc::new(Cooling {
+            gamma: args.gamma,
+            timescale: units.time_to_si(t),
+            floor: args.cooling_floor * energy,
+        }),
+        (None, None) => Arc::new(Adiabatic { gamma: args.gamma }),
+    };
+    return args.sph_species.map(|species| Sph {
+        species: species,
+        neighbors: args.sph_neighbors,
+        thermodynamics: thermodynamics,
+        alpha: args.viscosity_alpha,
+        beta: args.viscosity_beta,
+        initial_energy: args.gas_energy * energy,
+    });
+}
+
+// Runs with the particles on disk under `dir`, writing CSV snapshots to the output directory.
+fn run_out_of_core(args: &Args, dir: &Path) -> Result<(), Box<dyn Error>> {
+    let units = args.units.units();
+    let species = match &args.species {
+        Some(path) => SpeciesTable::load(path)?,
+        None => SpeciesTable::default(),
+    };
+    let mut ooc = OutOfCore::new(dir, units.time_to_si(args.dt))
+        .with_theta(args.theta)
+        .with_force_law(force_law(args))
+        .with_species(species)
+        .with_chunk(args.out_of_core_chunk);
+    let mut manifest = RunManifest::new(serde_json::to_value(args)?);
+    match &args.ic_file {
+        Some(path) => ooc.load(stream_csv(path)?.map(|p| p.map(|p| units.point_to_si(p))))?,
+        None => {
+            let seed = args.seed.unwrap_or_else(|| thread_rng().gen());
+            info!("generating initial conditions with seed {}", seed);
+            manifest = manifest.with_seed(Some(seed));
+            let points = initial_conditions(args)?.generate(&mut StdRng::seed_from_u64(seed));
+            ooc.load(points.into_iter().map(Ok::<Point, NbodyError>))?;
+        }
+    }
+    info!(
+        "stepping {} particles out of core in {}",
+        ooc.len(),
+        dir.display()
+    );
+
+    let run_dir = match args.timestamped {
+        true => args.out_dir.join(utc_timestamp(manifest.started_at)),
+        false => args.out_dir.clone(),
+    };
+    prepare_run_dir(&run_dir, args.force)
+        .map_err(|e| format!("{}; pass --force to overwrite it", e))?;
+    info!("writing output to {}", run_dir.display());
+    manifest.write(&run_dir)?;
+
+    let mut schedule = SnapshotSchedule::new(args.snapshot_every, args.snapshot_dt)?;
+    let mut retention = Retention::new(args.keep_last.map(|k| k as usize));
+    let progress = ProgressBar::new(args.steps);
+    while ooc.steps() < args.steps {
+        let now = units.time_from_si(ooc.time());
+        if schedule.should_write(ooc.steps(), now) {
+            let path = run_dir.join(format!("out-{}.csv", ooc.steps()));
+            ooc.write_csv(std::io::BufWriter::new(std::fs::File::create(&path)?), now)?;
+            retention.record(path);
+        }
+        ooc.step()?;
+        progress.inc(1);
+    }
+    progress.finish();
+    manifest.finish(ooc.steps(), ooc.len());
+    manifest.write(&run_dir)?;
+    return Ok(());
+}
+
+// Renders every snapshot in the input directory, moving the camera evenly between its start and
+// end settings over the animation.
+#[cfg(feature = "render")]
+fn render_snapshots(cmd: &RenderCommand, units: Units) -> Result<(), Box<dyn Error>> {
+    let snapshots = list_snapshots(&cmd.input)?;
+    if snapshots.is_empty() {
+        return Err(format!("no snapshots found in {}", cmd.input.display()).into());
+    }
+    let start = renderer(&cmd.render, units);
+    let mut end = start.camera;
+    if let Some(yaw) = cmd.yaw_end {
+        end.yaw = yaw.to_radians();
+    }
+    if let Some(pitch) = cmd.pitch_end {
+        end.pitch = pitch.to_radians();
+    }
+    if let Some(extent) = cmd.extent_end {
+        end.extent = units.length_to_si(extent);
+    }
+
+    let mut video = None;
+    let output = match cmd.format {
+        FrameFormat::Png => {
+            let dir = cmd.output.clone().unwrap_or(PathBuf::from("frames"));
+            std::fs::create_dir_all(&dir)?;
+            dir
+        }
+        FrameFormat::Mp4 => {
+            let path = cmd.output.clone().unwrap_or(PathBuf::from("render.mp4"));
+            video = Some(VideoWriter::new(&path, start, cmd.render.render_fps)?);
+            path
+        }
+    };
+
+    let progress = ProgressBar::new(snapshots.len() as u64);
+    let last = (snapshots.len() - 1).max(1) as f64;
+    for (i, (step, path)) in snapshots.iter().enumerate() {
+        let renderer = Renderer {
+            camera: start.camera.lerp(&end, i as f64 / last),
+            ..start
+        };
+        let tree = BHTree::from_points(THETA, load_particles(path)?)?;
+        let frame = renderer.render(&tree);
+        match &mut video {
+            Some(video) => video.write_frame(&frame)?,
+            None => frame.write_png(&output.join(format!("frame-{}.png", step)))?,
+        }
+        progress.inc(1);
+    }
+    progress.finish();
+    info!(
+        "rendered {} snapshots to {}",
+        snapshots.len(),
+        output.display()
+    );
+    return Ok(());
+}
+
+fn analyze(cmd: &AnalyzeCommand) -> Result<(), Box<dyn Error>> {
+    let out = cmd.output.as_ref().unwrap_or(&cmd.input);
+    let center = match cmd.center {
+        CenterKind::Mass => Center::Mass,
+        CenterKind::Density => Center::Density(cmd.center_neighbors),
+    };
+    let summaries = Analysis {
+        theta: cmd.theta,
+        bins: cmd.bins,
+        center: center,
+        max_binary_axis: cmd.binary_max_axis,
+    }
+    .run(&cmd.input, out)?;
+    info!(
+        "wrote {} rows to {}",
+        summaries.len(),
+        out.join("analysis.csv").display()
+    );
+    return Ok(());
+}
+
+fn verify(cmd: &VerifyCommand) -> Result<(), Box<dyn Error>> {
+    let report = Verify {
+        n: cmd.n,
+        thetas: cmd.theta.clone(),
+        dts: cmd.dt.clone(),
+        duration: cmd.duration,
+        softening: cmd.softening,
+        seed: cmd.seed,
+    }
+    .run()?;
+    print!("{}", report.summary());
+    if let Some(path) = &cmd.json {
+        serde_json::to_writer_pretty(std::fs::File::create(path)?, &report)?;
+    }
+    return Ok(());
+}
+
+fn sweep(cmd: &SweepCommand) -> Result<(), Box<dyn Error>> {
+    let runs = Sweep::new(cmd.config.as_deref(), cmd.vary.clone(), cmd.out.clone())?
+        .with_jobs(cmd.jobs)
+        .run(&std::env::current_exe()?)?;
+    let failed: Vec<_> = runs.iter().filter(|r| r.exit_code != Some(0)).collect();
+    for run in failed.iter() {
+        warn!(
+            "run {} failed with {:?}",
+            cmd.out.join(&run.dir).display(),
+            run.exit_code
+        );
+    }
+    if !failed.is_empty() {
+        return Err(format!("{} of {} runs failed", failed.len(), runs.len()).into());
+    }
+    info!("finished {} runs in {}", runs.len(), cmd.out.display());
+    return Ok(());
+}
+
 fn main() -> Result<(), Box<dyn Error>> {
     env_logger::init();
+    let args = Args::parse();
+    let units = args.units.units();
+    match &args.command {
+        #[cfg(feature = "render")]
+        Some(Command::Render(cmd)) => return render_snapshots(cmd, units),
+        Some(Command::Analyze(cmd)) => return analyze(cmd),
+        Some(Command::Sweep(cmd)) => return sweep(cmd),
+        Some(Command::Verify(cmd)) => return verify(cmd),
+        Some(Command::Repl) | None => {}
+    }
+    if let (Some(dir), None) = (&args.out_of_core, &args.command) {
+        return run_out_of_core(&args, dir);
+    }
+    let dt = units.time_to_si(args.dt);
     info!(
-        theta = THETA, 
-        graph_size = GRAPH_SIZE, 
-        dt = TIME_STEP,
-        steps = STEPS,
-        num_points = NUM_POINTS; 
+        theta = args.theta,
+        graph_size = GRAPH_SIZE,
+        dt = args.dt,
+        steps = args.steps,
+        num_points = NUM_POINTS,
+        g = units.g();
         "starting nbody simulation");
 
-    let mut rng = thread_rng();
-    let mut bht = BHTree::new(
-        THETA,
-        2. * GRAPH_SIZE,
-        -GRAPH_SIZE,
-        -GRAPH_SIZE,
-        -GRAPH_SIZE,
-    );
+    let species = match &args.species {
+        Some(path) => SpeciesTable::load(path)?,
+        None => SpeciesTable::default(),
+    };
+    let output_species = match &args.output_species {
+        Some(names) => Some(
+            names
+                .iter()
+                .map(|n| {
+                    species
+                        .index_of(n)
+                        .ok_or_else(|| format!("unknown species {}", n))
+                })
+                .collect::<Result<Vec<u16>, String>>()?,
+        ),
+        None => None,
+    };
+    if let Some(f) = args.output_sample {
+        if !(f > 0. && f <= 1.) {
+            return Err(format!("--output-sample must be in (0, 1], got {}", f).into());
+        }
+    }
+    let output_filter = OutputFilter::new()
+        .with_species(output_species)
+        .with_region(output_region(&args)?)
+        .with_sample(args.output_sample);
 
-    let normal = Normal::new(MASS_DIST_MEAN, MASS_DIST_STDDEV).unwrap();
+    let db = args
+        .persist
+        .as_deref()
+        .map(DbHandle::from_uri)
+        .transpose()?;
+    let resumed = match (&db, &args.resume) {
+        (Some(db), _) => db.latest()?,
+        (None, Some(path)) => Some(Snapshot::read(path)?),
+        (None, None) => None,
+    };
+    let mut manifest = RunManifest::new(serde_json::to_value(&args)?)
+        .with_resumed_from(resumed.as_ref().map(|s| s.step));
+    let state = resumed.as_ref().and_then(|s| s.state.clone());
+    let mut sim = match resumed {
+        Some(snapshot) => {
+            info!(
+                "resuming from snapshot @ step={} t={}",
+                snapshot.step, snapshot.time
+            );
+            check_memory(
+                Some(snapshot.refinement.estimate_memory(snapshot.points.len())),
+                args.ignore_memory_check,
+            )?;
+            Simulation::from_tree(snapshot.to_tree()?, dt, snapshot.step)
+                .with_time(units.time_to_si(snapshot.time))
+        }
+        None => {
+            let mut builder = SimulationBuilder::new()
+                .theta(args.theta)
+                .dt(dt)
+                .force_law(force_law(&args))
+                .species(species)
+                .collisions(collisions(&args))
+                .escapers(escapers(&args))
+                .opening(opening(&args))
+                .theta_schedule(theta_schedule(&args))
+                .compensated_summation(args.compensated_summation)
+                .initial_conditions(initial_conditions(&args)?);
+            if let Some(r) = args.encounter_radius {
+                builder = builder.encounter_radius(units.length_to_si(r));
+            }
+            if let Some(m) = args.central_mass {
+                let center = Point::try_new(units.mass_to_si(m), 0., 0., 0., Vec3d::new_zero())?;
+                builder = builder.initial_conditions(vec![center.with_pinned(true)]);
+            }
+            builder = builder
+                .boundary(boundary(&args))
+                .planar(args.planar)
+                .refinement(Refinement {
+                    bucket_size: args.bucket_size as usize,
+                    max_depth: args.max_depth,
+                });
+            if let Some(drift) = args.reuse_drift {
+                builder = builder.reuse(TreeReuse {
+                    drift: drift,
+                    max_steps: args.reuse_max_steps,
+                });
+            }
+            let seed = args.seed.unwrap_or_else(|| thread_rng().gen());
+            info!("generating initial conditions with seed {}", seed);
+            manifest = manifest.with_seed(Some(seed));
+            check_memory(builder.estimated_memory(), args.ignore_memory_check)?;
+            builder.build(&mut StdRng::seed_from_u64(seed))?
+        }
+    };
+    if let Some(state) = &state {
+        sim = sim
+            .with_next_id(state.next_id)
+            .with_mass_lost(state.mass_lost);
+    }
+    add_potential(&args, &mut sim);
+    if let Some(perturber) = perturber(&args)? {
+        sim.add_perturber(perturber);
+    }
+    set_merge_policy(&args, &mut sim);
+    sim.set_drag(drag(&args));
+    sim.set_post_newtonian(args.post_newtonian.map(|kind| PostNewtonian {
+        first_order: !matches!(kind, PostNewtonianKind::Radiation),
+        radiation: !matches!(kind, PostNewtonianKind::Conservative),
+        within: args.pn_within,
+        min_mass: units.mass_to_si(args.pn_min_mass),
+    }));
+    sim.set_mass_loss(mass_loss(&args)?);
+    sim.set_kicks(args.kick_dispersion.map(|v| {
+        Kicks {
+            min_mass: units.mass_to_si(args.kick_min_mass),
+            max_mass: units.mass_to_si(args.kick_max_mass),
+            min_age: units.time_to_si(args.kick_min_age),
+            max_age: units.time_to_si(args.kick_max_age.unwrap_or(args.kick_min_age)),
+            dispersion: units.velocity_to_si(v),
+            seed: args
+                .kick_seed
+                .or(state.as_ref().and_then(|s| s.kick_seed))
+                .or(args.seed)
+                .unwrap_or(0),
+        }
+    }));
+    sim.set_infall(args.infall_rate.map(|rate| {
+        Infall {
+            rate: rate / units.time_to_si(1.),
+            mass: units.mass_to_si(args.infall_mass.unwrap_or(0.)),
+            radius: units.length_to_si(args.infall_radius.unwrap_or(0.)),
+            speed: units.velocity_to_si(args.infall_speed),
+            seed: args
+                .infall_seed
+                .or(state.as_ref().and_then(|s| s.infall_seed))
+                .or(args.seed)
+                .unwrap_or(0),
+        }
+    }));
+    sim.set_fragmentation(args.split_density.map(|rho| Fragmentation {
+        density: rho * units.mass / units.length.powi(3),
+        neighbors: args.split_neighbors,
+        fragments: args.split_into as usize,
+        min_mass: units.mass_to_si(args.split_min_mass),
+    }));
+    sim.set_sph(sph(&args));
+    let mesh = match (args.pm_cells, sim.tree().periodic()) {
+        (Some(cells), Some(box_size)) => Some(ParticleMesh {
+            cells: cells,
+            split: args.pm_split * box_size / cells as f64,
+        }),
+        (Some(_), None) => return Err("--pm-cells needs a periodic boundary".into()),
+        (None, _) => None,
+    };
+    sim.set_particle_mesh(mesh);
+    if args.ewald && sim.tree().periodic().is_none() {
+        return Err("--ewald needs a periodic boundary".into());
+    }
+    sim.set_ewald(args.ewald);
+    sim.set_recentering(args.recenter);
+    sim.set_interaction_lists(args.interaction_lists);
+    sim.set_paranoid(args.paranoid);
+    #[cfg(feature = "numa")]
+    if args.numa {
+        let topology = nbody::numa::Topology::detect();
+        info!(
+            "splitting the force pass over {} numa nodes",
+            topology.nodes.len()
+        );
+        sim.set_numa(Some(Arc::new(nbody::numa::NumaPools::new(&topology)?)));
+    }
 
-    info!("generating {} particles", NUM_POINTS);
-    for _ in 0..NUM_POINTS {
-        let mut x: f64 = rng.gen_range(-GRAPH_SIZE..GRAPH_SIZE);
-        let mut y: f64 = rng.gen_range(-GRAPH_SIZE..GRAPH_SIZE);
-        let mut z: f64 = rng.gen_range(-GRAPH_SIZE..GRAPH_SIZE);
-        while (x * x + y * y + z * z).sqrt() > GRAPH_SIZE {
-            x = rng.gen_range(-GRAPH_SIZE..GRAPH_SIZE);
-            y = rng.gen_range(-GRAPH_SIZE..GRAPH_SIZE);
-            z = rng.gen_range(-GRAPH_SIZE..GRAPH_SIZE);
+    if let Some(Command::Repl) = args.command {
+        // Ctrl-C pauses a `run` instead of ending the process; quit or end the input to leave.
+        let interrupt = Arc::new(AtomicBool::new(false));
+        let flag = interrupt.clone();
+        ctrlc::set_handler(move || flag.store(true, Ordering::SeqCst))?;
+        println!(
+            "{} particles at step {}, type help for commands",
+            sim.tree().len(),
+            sim.steps()
+        );
+        return Repl::new(interrupt).run(&mut sim, std::io::stdin().lock(), std::io::stdout());
+    }
+
+    let run_dir = match args.timestamped {
+        true => args.out_dir.join(utc_timestamp(manifest.started_at)),
+        false => args.out_dir.clone(),
+    };
+    // A run resumed into the directory its checkpoint was taken in carries on there, keeping the
+    // snapshots it has already written. Anywhere else it starts the directory afresh, like a new
+    // run.
+    let carried_on = state
+        .as_ref()
+        .and_then(|s| s.run_dir.as_ref())
+        .is_some_and(|dir| run_dir.canonicalize().is_ok_and(|d| d == *dir));
+    match carried_on {
+        true => std::fs::create_dir_all(&run_dir)?,
+        false => prepare_run_dir(&run_dir, args.force)
+            .map_err(|e| format!("{}; pass --force to overwrite it", e))?,
+    }
+    info!("writing output to {}", run_dir.display());
+    manifest.write(&run_dir)?;
+    // Drop whatever the interrupted run logged after its checkpoint, before the logs are reopened.
+    if let (Some(state), true) = (&state, carried_on) {
+        rewind_logs(&run_dir, &state.logs)?;
+    }
+
+    let mut schedule = SnapshotSchedule::new(args.snapshot_every, args.snapshot_dt)?;
+    let mut retention = Retention::new(args.keep_last.map(|k| k as usize));
+    if let (Some(step), true) = (manifest.resumed_from, carried_on) {
+        retention = retention.with_written(
+            list_snapshots(&run_dir)?
+                .into_iter()
+                .filter(|(s, _)| *s <= step)
+                .map(|(_, path)| path)
+                .collect(),
+        );
+    }
+    if let Some(state) = &state {
+        schedule = schedule.with_next_time(state.next_snapshot);
+    }
+    // The CSV writer filters rows itself, so that its extra columns still see every particle.
+    let csv = CsvWriter::new(run_dir.clone(), retention)
+        .with_filter(output_filter.clone())
+        .with_compression(match args.compress {
+            CompressionKind::None => Compression::None,
+            CompressionKind::Gzip => Compression::Gzip,
+            CompressionKind::Zstd => Compression::Zstd,
+        })
+        .with_density(args.density_neighbors)
+        .with_potential(args.output_potential);
+    let mut writers: Vec<Box<dyn SnapshotWriter>> = vec![];
+    if let Some(l) = args.fof_linking_length {
+        writers.push(Box::new(GroupCatalogWriter::new(
+            run_dir.clone(),
+            units.length_to_si(l),
+            args.fof_min_members,
+        )));
+    }
+    if let Some(a) = args.binary_max_axis {
+        writers.push(Box::new(BinaryCatalogWriter::new(
+            run_dir.clone(),
+            units.length_to_si(a),
+        )));
+    }
+    #[cfg(feature = "sqlite")]
+    if let Some(path) = &args.sqlite {
+        let config = serde_json::json!({
+            "theta": args.theta,
+            "graph_size": GRAPH_SIZE,
+            "num_points": NUM_POINTS,
+            "args": &args,
+        });
+        writers.push(Box::new(nbody::output::sqlite::SqliteWriter::new(
+            path,
+            &config.to_string(),
+        )?));
+    }
+    #[cfg(feature = "render")]
+    {
+        if let Some(dir) = &args.render_png {
+            std::fs::create_dir_all(dir)?;
+            writers.push(Box::new(PngWriter::new(
+                dir.clone(),
+                renderer(&args.render, units),
+            )));
         }
+        if let Some(path) = &args.render_video {
+            writers.push(Box::new(VideoWriter::new(
+                path,
+                renderer(&args.render, units),
+                args.render.render_fps,
+            )?));
+        }
+    }
+    #[cfg(feature = "serve")]
+    if let Some(addr) = &args.serve {
+        writers.push(Box::new(nbody::output::stream::StreamServer::bind(
+            addr,
+            args.serve_stride,
+        )?));
+    }
+    let snapshots: Box<dyn SnapshotWriter> = match args.snapshot_format {
+        SnapshotFormat::Csv => Box::new(csv),
+        SnapshotFormat::Container => FilteredWriter::wrap(
+            Box::new(ContainerWriter::open(&run_dir.join(CONTAINER_FILE))?),
+            output_filter.clone(),
+        ),
+    };
+    let mut writers: Vec<Box<dyn SnapshotWriter>> = [snapshots]
+        .into_iter()
+        .chain(
+            writers
+                .into_iter()
+                .map(|w| FilteredWriter::wrap(w, output_filter.clone())),
+        )
+        .collect();
+    let control = args
+        .control_addr
+        .as_deref()
+        .map(|addr| ControlServer::bind(addr, args.start_paused))
+        .transpose()?;
+    let mut orbits = match args.track_ids.is_empty() {
+        true => None,
+        false => Some(OrbitWriter::new(&run_dir, &args.track_ids)?),
+    };
+    if args.kick_dispersion.is_some() {
+        let path = run_dir.join(KICK_FILE);
+        let headers = !path.exists();
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)?;
+        let mut log = csv::WriterBuilder::new()
+            .has_headers(headers)
+            .from_writer(file);
+        sim.on_kick(move |kick| {
+            if let Err(e) = log.serialize(kick).and_then(|_| Ok(log.flush()?)) {
+                warn!("failed to log kick to {}: {}", path.display(), e);
+            }
+        });
+    }
+    if args.infall_rate.is_some() {
+        let path = run_dir.join(INJECTION_FILE);
+        let headers = !path.exists();
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)?;
+        let mut log = csv::WriterBuilder::new()
+            .has_headers(headers)
+            .from_writer(file);
+        sim.on_inject(move |injection| {
+            if let Err(e) = log.serialize(injection).and_then(|_| Ok(log.flush()?)) {
+                warn!("failed to log injection to {}: {}", path.display(), e);
+            }
+        });
+    }
+    let mut core = match args.core_every {
+        Some(every) => {
+            // A resumed run appends to the log it already has.
+            let path = run_dir.join(CORE_FILE);
+            let headers = !path.exists();
+            let file = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)?;
+            let log = csv::WriterBuilder::new()
+                .has_headers(headers)
+                .from_writer(file);
+            let threshold = args
+                .core_collapse_density
+                .map(|d| units.mass_to_si(d) / units.length_to_si(1.).powi(3));
+            let tracker = CoreTracker::new(args.core_neighbors).with_threshold(threshold);
+            Some((every.max(1), tracker, log))
+        }
+        None => None,
+    };
+    let mut metrics = MetricsExporter::new(args.metrics_file, args.metrics_addr)?;
+    let mut timings = Timings::new();
+    let progress = ProgressBar::new(args.steps);
+    progress.set_style(ProgressStyle::with_template(
+        "[{elapsed_precise}] {bar:40} {pos}/{len} steps ({per_sec}, ETA {eta}) {msg}",
+    )?);
+    progress.set_position(sim.steps());
+
+    // The first Ctrl-C lets the current step finish and the run wind down with a checkpoint; a
+    // second one gives up on that and exits straight away.
+    let interrupted = Arc::new(AtomicBool::new(false));
+    let flag = interrupted.clone();
+    ctrlc::set_handler(move || {
+        if flag.swap(true, Ordering::SeqCst) {
+            std::process::exit(130);
+        }
+        warn!("interrupted, stopping after the current step (Ctrl-C again to quit now)");
+    })?;
+    // A run paused through the control API stops for Ctrl-C too.
+    let mut control = control.map(|c| c.with_interrupt(interrupted.clone()));
 
-        let mass = PARTICLE_MASS_BASE.powf(normal.sample(&mut rand::thread_rng()).max(1.0));
-        let p = Point::new(mass, x, y, z, Vec3d::new_zero());
-        bht.add_point(p);
+    // Outputs give the time in the run's units.
+    let time = |sim: &Simulation| units.time_from_si(sim.time());
+    // Rows follow each step; a run starting its directory afresh also records where the particles
+    // start.
+    if let (Some(orbits), false) = (&mut orbits, carried_on) {
+        orbits.write(sim.steps(), time(&sim), sim.tree())?;
     }
+    while sim.steps() < args.steps {
+        if interrupted.load(Ordering::SeqCst) {
+            break;
+        }
+        if let Some(control) = &mut control {
+            if !control.serve_pending(&mut sim) {
+                break;
+            }
+        }
+        let t = sim.steps();
+        debug!("starting step {}", t);
+        let step_start = Instant::now();
+        let now = time(&sim);
+        progress.set_message(format!("t={:.3} particles={}", now, sim.tree().len()));
+        let io_start = Instant::now();
+        // The checkpoint is of the state before this step's output, which a resumed run writes
+        // again.
+        let next_snapshot = schedule.next_time();
+        if schedule.should_write(t, now) {
+            if let Some(db) = &db {
+                // Everything written so far has to be on disk for the log offsets to cover it.
+                for writer in writers.iter_mut() {
+                    writer.flush()?;
+                }
+                if let Some(orbits) = &mut orbits {
+                    orbits.flush()?;
+                }
+                if let Some((_, _, log)) = &mut core {
+                    log.flush()?;
+                }
+                let state = run_state(&sim, next_snapshot, &run_dir)?;
+                db.persist_snapshot(&Snapshot::from_tree(t, now, sim.tree()).with_state(state))?;
+            }
+            sim.snapshot(now, &mut writers)?;
+        }
+        let io_time = io_start.elapsed();
 
-    for t in 0..STEPS {
-        info!("starting step {}", t);
-        let filepath = String::from(format!("output/out-{}.csv", t));
-        bht.write_to_csv(filepath)?;
-        bht = bht.next(TIME_STEP);
+        let stats = sim.step()?;
+        let now = time(&sim);
+        timings.record_step(&stats, io_time);
+        metrics.record(t, now, step_start.elapsed(), stats, sim.tree().len())?;
+        progress.inc(1);
+
+        if let Some(orbits) = &mut orbits {
+            orbits.write(sim.steps(), now, sim.tree())?;
+        }
+        if let Some((every, tracker, log)) = &mut core {
+            if sim.steps() % *every == 0 {
+                if let Some(sample) = tracker.observe(sim.steps(), now, sim.tree()) {
+                    log.serialize(&sample)?;
+                }
+                if args.stop_at_core_collapse && tracker.collapse().is_some() {
+                    info!("stopping at core collapse");
+                    break;
+                }
+            }
+        }
+    }
+    progress.finish();
+    for writer in writers.iter_mut() {
+        writer.flush()?;
+    }
+    if let Some(orbits) = &mut orbits {
+        orbits.flush()?;
+    }
+    if let Some((_, tracker, log)) = &mut core {
+        log.flush()?;
+        manifest.core_collapse = tracker.collapse().cloned();
+    }
+    if interrupted.load(Ordering::SeqCst) {
+        let snapshot = Snapshot::from_tree(sim.steps(), time(&sim), sim.tree())
+            .with_state(run_state(&sim, schedule.next_time(), &run_dir)?);
+        match &db {
+            Some(db) => db.persist_snapshot(&snapshot)?,
+            None => {
+                let checkpoint = match &args.checkpoint {
+                    Some(path) => path.clone(),
+                    None => run_dir.join("checkpoint.json"),
+                };
+                if let Some(dir) = checkpoint.parent() {
+                    std::fs::create_dir_all(dir)?;
+                }
+                snapshot.write(&checkpoint)?;
+                info!("resume with --resume {}", checkpoint.display());
+            }
+        }
+    }
+    let tree_stats = sim.tree().stats();
+    info!(
+        "tree: {} nodes, {} leaves, depth {}, {} bytes",
+        tree_stats.nodes, tree_stats.leaves, tree_stats.depth, tree_stats.memory_bytes
+    );
+    if let Some(path) = &args.dump_tree {
+        let file = std::io::BufWriter::new(std::fs::File::create(path)?);
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("dot") | Some("gv") => sim.tree().write_dot(file)?,
+            _ => sim.tree().write_nodes_json(file)?,
+        }
+    }
+    info!("{}", timings.summary());
+    if sim.mass_lost() > 0. {
+        info!(
+            "lost {} of mass, {} left",
+            sim.mass_lost() / units.mass,
+            sim.tree().iter().map(|p| p.mass()).sum::<f64>() / units.mass
+        );
     }
+    manifest.finish(sim.steps(), sim.tree().len());
+    manifest.write(&run_dir)?;
 
     return Ok(());
 }
@@ -0,0 +1,229 @@
+use std::error::Error;
+use std::fmt::Write as _;
+use std::fs;
+use std::io::{Read, Write};
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use log::{info, warn};
+
+use crate::geometry::bh_tree::StepStats;
+
+/// Latest per-step values exposed in the OpenMetrics text format.
+#[derive(Debug, Default, Clone)]
+pub struct Metrics {
+    pub step: u64,
+    pub sim_time: f64,
+    pub step_duration: Duration,
+    pub stats: StepStats,
+    pub particles: usize,
+    pub merges_total: u64,
+    pub rss_bytes: Option<u64>,
+}
+
+impl Metrics {
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let mut gauge = |name: &str, help: &str, value: f64| {
+            let _ = writeln!(out, "# HELP nbody_{} {}", name, help);
+            let _ = writeln!(out, "# TYPE nbody_{} gauge", name);
+            let _ = writeln!(out, "nbody_{} {}", name, value);
+        };
+
+        gauge(
+            "step",
+            "Index of the last completed step.",
+            self.step as f64,
+        );
+        gauge(
+            "sim_time",
+            "Simulated time after the last step.",
+            self.sim_time,
+        );
+        gauge(
+            "step_duration_seconds",
+            "Wall time of the last step.",
+            self.step_duration.as_secs_f64(),
+        );
+        gauge(
+            "tree_build_seconds",
+            "Wall time spent building the tree in the last step.",
+            self.stats.build_time.as_secs_f64(),
+        );
+        gauge(
+            "force_eval_seconds",
+            "Wall time spent evaluating forces in the last step.",
+            self.stats.force_time.as_secs_f64(),
+        );
+        gauge(
+            "integration_seconds",
+            "Wall time spent integrating particles in the last step.",
+            self.stats.integrate_time.as_secs_f64(),
+        );
+        gauge(
+            "force_interactions",
+            "Particles and nodes summed over by the last step's tree walks.",
+            self.stats.interactions as f64,
+        );
+        gauge(
+            "particles",
+            "Particles remaining after merges and removals.",
+            self.particles as f64,
+        );
+        gauge(
+            "step_merges",
+            "Merges during the last step.",
+            self.stats.merges as f64,
+        );
+        gauge(
+            "step_removed",
+            "Particles absorbed by walls or dropped as escapers during the last step.",
+            self.stats.removed as f64,
+        );
+        gauge(
+            "com_drift",
+            "Distance the centre of mass moved in the last step.",
+            self.stats.com_drift,
+        );
+        gauge(
+            "angular_momentum",
+            "Magnitude of the total angular momentum about the origin.",
+            self.stats.angular_momentum,
+        );
+        if let Some(rss) = self.rss_bytes {
+            gauge(
+                "resident_memory_bytes",
+                "Resident set size of the process.",
+                rss as f64,
+            );
+        }
+
+        let _ = writeln!(
+            out,
+            "# HELP nbody_merges Merges since the start of the run."
+        );
+        let _ = writeln!(out, "# TYPE nbody_merges counter");
+        let _ = writeln!(out, "nbody_merges_total {}", self.merges_total);
+        out.push_str("# EOF\n");
+        return out;
+    }
+}
+
+// Memory the system can give to new allocations without swapping, if the platform exposes it.
+pub fn available_memory() -> Option<u64> {
+    let meminfo = fs::read_to_string("/proc/meminfo").ok()?;
+    let line = meminfo.lines().find(|l| l.starts_with("MemAvailable:"))?;
+    let kb: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    return Some(kb * 1024);
+}
+
+// Resident set size of this process, if the platform exposes it.
+pub fn resident_memory() -> Option<u64> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|l| l.starts_with("VmRSS:"))?;
+    let kb: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    return Some(kb * 1024);
+}
+
+/// Publishes metrics to a file that is rewritten every step and/or a plain HTTP endpoint.
+pub struct MetricsExporter {
+    file: Option<PathBuf>,
+    latest: Arc<Mutex<String>>,
+    metrics: Metrics,
+}
+
+impl MetricsExporter {
+    pub fn new(
+        file: Option<PathBuf>,
+        addr: Option<String>,
+    ) -> Result<MetricsExporter, Box<dyn Error>> {
+        let latest = Arc::new(Mutex::new(Metrics::default().render()));
+        if let Some(addr) = addr {
+            let listener = TcpListener::bind(&addr)?;
+            info!("serving metrics on http://{}/metrics", addr);
+            let latest = latest.clone();
+            thread::spawn(move || serve(listener, latest));
+        }
+
+        return Ok(MetricsExporter {
+            file: file,
+            latest: latest,
+            metrics: Metrics::default(),
+        });
+    }
+
+    pub fn record(
+        &mut self,
+        step: u64,
+        sim_time: f64,
+        step_duration: Duration,
+        stats: StepStats,
+        particles: usize,
+    ) -> Result<(), Box<dyn Error>> {
+        self.metrics.step = step;
+        self.metrics.sim_time = sim_time;
+        self.metrics.step_duration = step_duration;
+        self.metrics.stats = stats;
+        self.metrics.particles = particles;
+        self.metrics.merges_total += stats.merges as u64;
+        self.metrics.rss_bytes = resident_memory();
+
+        let text = self.metrics.render();
+        if let Some(path) = &self.file {
+            // Write then rename so scrapers never observe a partial file.
+            let tmp = path.with_extension("tmp");
+            fs::write(&tmp, &text)?;
+            fs::rename(&tmp, path)?;
+        }
+        *self.latest.lock().unwrap() = text;
+        return Ok(());
+    }
+}
+
+fn serve(listener: TcpListener, latest: Arc<Mutex<String>>) {
+    for stream in listener.incoming() {
+        let mut stream = match stream {
+            Ok(s) => s,
+            Err(e) => {
+                warn!("metrics connection failed: {}", e);
+                continue;
+            }
+        };
+
+        // The request itself doesn't matter, every path returns the metrics.
+        let mut buf = [0u8; 1024];
+        let _ = stream.read(&mut buf);
+        let body = latest.lock().unwrap().clone();
+        let response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/openmetrics-text; version=1.0.0; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        if let Err(e) = stream.write_all(response.as_bytes()) {
+            warn!("failed to write metrics response: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::metrics::Metrics;
+
+    #[test]
+    fn test_render() {
+        let m = Metrics {
+            step: 3,
+            particles: 42,
+            merges_total: 7,
+            ..Default::default()
+        };
+        let text = m.render();
+        assert!(text.contains("nbody_step 3\n"));
+        assert!(text.contains("nbody_particles 42\n"));
+        assert!(text.contains("nbody_merges_total 7\n"));
+        assert!(text.ends_with("# EOF\n"));
+    }
+}
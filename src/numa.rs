@@ -0,0 +1,179 @@
+use std::error::Error;
+use std::path::Path;
+
+use core_affinity::CoreId;
+use log::{debug, warn};
+use rayon::{ThreadPool, ThreadPoolBuilder};
+
+use crate::geometry::balance::{map_balanced, split};
+use crate::geometry::bh_tree::{BHTree, TopLevels};
+use crate::Point;
+
+// Levels of the tree each NUMA node keeps its own copy of.
+const TOP_DEPTH: usize = 6;
+
+/// The machine's NUMA nodes, as the CPUs belonging to each.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Topology {
+    pub nodes: Vec<Vec<usize>>,
+}
+
+impl Topology {
+    // Reads the nodes from sysfs. Machines that don't report any, or aren't Linux, are taken to be
+    // one node with every CPU.
+    pub fn detect() -> Topology {
+        let mut nodes = vec![];
+        if let Ok(entries) = std::fs::read_dir("/sys/devices/system/node") {
+            let mut dirs: Vec<(usize, std::path::PathBuf)> = entries
+                .filter_map(|e| e.ok())
+                .filter_map(|e| {
+                    let name = e.file_name().into_string().ok()?;
+                    Some((name.strip_prefix("node")?.parse().ok()?, e.path()))
+                })
+                .collect();
+            dirs.sort();
+            for (_, dir) in dirs {
+                match read_cpulist(&dir.join("cpulist")) {
+                    Some(cpus) if !cpus.is_empty() => nodes.push(cpus),
+                    _ => {}
+                }
+            }
+        }
+        if nodes.is_empty() {
+            let n = std::thread::available_parallelism().map_or(1, |n| n.get());
+            nodes.push((0..n).collect());
+        }
+        return Topology { nodes: nodes };
+    }
+}
+
+fn read_cpulist(path: &Path) -> Option<Vec<usize>> {
+    return parse_cpulist(std::fs::read_to_string(path).ok()?.trim());
+}
+
+// Parses a kernel CPU list such as "0-3,8,10-11".
+pub fn parse_cpulist(list: &str) -> Option<Vec<usize>> {
+    let mut cpus = vec![];
+    for part in list.split(',').filter(|p| !p.is_empty()) {
+        match part.split_once('-') {
+            Some((from, to)) => cpus.extend(from.parse::<usize>().ok()?..=to.parse().ok()?),
+            None => cpus.push(part.parse().ok()?),
+        }
+    }
+    return Some(cpus);
+}
+
+/// Splits the force pass over NUMA nodes. Each node gets a thread pool pinned to its CPUs and a
+/// spatially contiguous share of the particles, of about equal expected cost, and walks its own
+/// copy of the top of the tree, so that most of the memory a walk touches is local. The forces
+/// are the same as from a single pool, bit for bit.
+#[derive(Debug)]
+pub struct NumaPools {
+    pools: Vec<ThreadPool>,
+}
+
+impl NumaPools {
+    pub fn new(topology: &Topology) -> Result<NumaPools, Box<dyn Error>> {
+        let mut pools = vec![];
+        for (node, cpus) in topology.nodes.iter().enumerate() {
+            let cpus = cpus.clone();
+            let pool = ThreadPoolBuilder::new()
+                .num_threads(cpus.len())
+                .thread_name(move |i| format!("numa{}-{}", node, i))
+                .start_handler(move |i| {
+                    if !core_affinity::set_for_current(CoreId { id: cpus[i] }) {
+                        warn!("couldn't pin a thread to cpu {}", cpus[i]);
+                    }
+                })
+                .build()?;
+            pools.push(pool);
+        }
+        debug!("{} numa nodes", pools.len());
+        return Ok(NumaPools { pools: pools });
+    }
+
+    pub fn len(&self) -> usize {
+        return self.pools.len();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.pools.is_empty();
+    }
+
+    // `force(top, p)` for each of `points`, in order, where `costs` are the expected costs of the
+    // points' walks. The points should be in the tree's order, so that each node's share is one
+    // region of space.
+    pub fn map<T, F>(&self, tree: &BHTree, points: &[Point], costs: &[u64], force: F) -> Vec<T>
+    where
+        T: Send,
+        F: Fn(&TopLevels, &Point) -> T + Sync,
+    {
+        let force = &force;
+        let parts: Vec<Vec<T>> = std::thread::scope(|s| {
+            let handles: Vec<_> = self
+                .pools
+                .iter()
+                .zip(split(costs, self.len()))
+                .map(|(pool, share)| {
+                    s.spawn(move || {
+                        pool.install(|| {
+                            // Made on one of the node's threads, so it lives in the node's memory.
+                            let top = tree.top_levels(TOP_DEPTH);
+                            map_balanced(&points[share.clone()], &costs[share], |p| force(&top, p))
+                        })
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|h| h.join().expect("numa force pass panicked"))
+                .collect()
+        });
+        return parts.into_iter().flatten().collect();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    use crate::geometry::bh_tree::BHTree;
+    use crate::ic::halo::{Halo, Profile};
+    use crate::numa::{parse_cpulist, NumaPools, Topology};
+
+    #[test]
+    fn test_numa() {
+        assert_eq!(
+            parse_cpulist("0-3,8,10-11"),
+            Some(vec![0, 1, 2, 3, 8, 10, 11])
+        );
+        assert_eq!(parse_cpulist(""), Some(vec![]));
+        assert_eq!(parse_cpulist("0-x"), None);
+        assert!(!Topology::detect().nodes.is_empty());
+
+        let points = Halo {
+            n: 2000,
+            total_mass: 1e20,
+            profile: Profile::Hernquist,
+            scale_radius: 1e3,
+            max_radius: 2e4,
+        }
+        .generate(&mut StdRng::seed_from_u64(3));
+        let tree = BHTree::from_points(0.5, points).unwrap();
+        let expected = tree.next(1e3).unwrap().points();
+
+        // Three nodes sharing the first CPU split the particles three ways.
+        let pools = NumaPools::new(&Topology {
+            nodes: vec![vec![0], vec![0, 0], vec![0]],
+        })
+        .unwrap();
+        let split = tree.with_numa(Some(Arc::new(pools)));
+        let top = split.top_levels(2);
+        let p = split.points()[17];
+        assert_eq!(top.force_on(p), split.force_on(p));
+        assert_eq!(split.next(1e3).unwrap().points(), expected);
+    }
+}
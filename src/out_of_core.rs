@@ -0,0 +1,508 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use log::debug;
+use memmap2::{Mmap, MmapMut};
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+
+use crate::error::NbodyError;
+use crate::geometry::bh_tree::{csv_record, BHTree, CSV_HEADER};
+use crate::geometry::force::ForceLaw;
+use crate::geometry::species::SpeciesTable;
+use crate::{Point, Vec3d};
+
+// Bytes per particle on disk: id, mass, position, velocity and radius, then the species and the
+// pinned and external flags packed into one word, all little-endian.
+const RECORD_SIZE: usize = 80;
+
+// The finest grid has at most 2^MAX_LEVEL cells on a side.
+const MAX_LEVEL: u32 = 7;
+
+// Particles sorted by cell, and where each step writes them before they are sorted again.
+const PARTICLES_FILE: &str = "particles.bin";
+const SCRATCH_FILE: &str = "scratch.bin";
+
+fn encode(p: &Point, out: &mut [u8]) {
+    let (x, y, z) = p.position();
+    let (vx, vy, vz) = p.velocity().position();
+    let flags = p.species() as u64 | ((p.pinned() as u64) << 16) | ((p.external() as u64) << 17);
+    out[..8].copy_from_slice(&p.id().to_le_bytes());
+    for (i, v) in [p.mass(), x, y, z, vx, vy, vz, p.radius()]
+        .iter()
+        .enumerate()
+    {
+        out[8 * (i + 1)..8 * (i + 2)].copy_from_slice(&v.to_le_bytes());
+    }
+    out[72..80].copy_from_slice(&flags.to_le_bytes());
+}
+
+fn decode(bytes: &[u8]) -> Point {
+    let word = |i: usize| u64::from_le_bytes(bytes[8 * i..8 * (i + 1)].try_into().unwrap());
+    let f = |i: usize| f64::from_bits(word(i));
+    let flags = word(9);
+    return Point::new(f(1), f(2), f(3), f(4), Vec3d::new(f(5), f(6), f(7)))
+        .with_id(word(0))
+        .with_radius(f(8))
+        .with_species(flags as u16)
+        .with_pinned((flags >> 16) & 1 == 1)
+        .with_external((flags >> 17) & 1 == 1);
+}
+
+// A cube split into 2^level cells on a side, numbered along z fastest, then y, then x.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Grid {
+    origin: [f64; 3],
+    width: f64,
+    level: u32,
+}
+
+impl Grid {
+    // The smallest cube holding every position between `min` and `max`.
+    fn fitted(min: [f64; 3], max: [f64; 3], level: u32) -> Grid {
+        let extent = (0..3).map(|i| max[i] - min[i]).fold(0., f64::max);
+        // Widened a little so that the particles on the far faces still fall inside.
+        let width = match extent > 0. {
+            true => extent * (1. + 1e-9),
+            false => 1.,
+        };
+        return Grid {
+            origin: min,
+            width: width,
+            level: level,
+        };
+    }
+
+    fn side(&self) -> usize {
+        return 1 << self.level;
+    }
+
+    fn coords(&self, p: &Point) -> [usize; 3] {
+        let (x, y, z) = p.position();
+        let side = self.side();
+        let axis = |v: f64, i: usize| {
+            let c = ((v - self.origin[i]) / self.width * side as f64).floor();
+            c.clamp(0., (side - 1) as f64) as usize
+        };
+        return [axis(x, 0), axis(y, 1), axis(z, 2)];
+    }
+
+    fn index(&self, c: [usize; 3]) -> usize {
+        return index(c, self.side());
+    }
+}
+
+fn index(c: [usize; 3], side: usize) -> usize {
+    return (c[0] * side + c[1]) * side + c[2];
+}
+
+// Cells within one of `c` along every axis, on a grid `side` cells wide, including `c` itself.
+fn neighbors(c: [usize; 3], side: usize) -> impl Iterator<Item = [usize; 3]> {
+    let span = move |v: usize| v.saturating_sub(1)..(v + 2).min(side);
+    return span(c[0])
+        .flat_map(move |x| span(c[1]).flat_map(move |y| span(c[2]).map(move |z| [x, y, z])));
+}
+
+/// Steps a system too large to keep in memory. The particles live in a file under `dir`, sorted
+/// by cell on a uniform grid, and are mapped in a cell at a time; only per-cell masses and
+/// centres of mass are held in memory throughout.
+///
+/// Each particle feels the particles in its own and the neighbouring cells through a tree built
+/// over just those, and everything further away through the cells' centres of mass, taking the
+/// coarsest cells that are still well separated from it. The grid is chosen so that cells hold
+/// about `chunk` particles, so the working set is a cell's neighbourhood, which is larger where
+/// the system is clustered. Only gravity is integrated: particles don't merge, and there are no
+/// boundaries, external potentials or hooks.
+pub struct OutOfCore {
+    dir: PathBuf,
+    dt: f64,
+    theta: f64,
+    force_law: ForceLaw,
+    species: SpeciesTable,
+    chunk: usize,
+    len: usize,
+    grid: Grid,
+    // Where each finest cell's particles start in the particle file, and the total at the end.
+    cells: Vec<usize>,
+    // Mass and centre of mass of each cell at every level of the grid, coarsest first.
+    levels: Vec<Vec<(f64, Vec3d)>>,
+    steps: u64,
+    time: f64,
+}
+
+impl OutOfCore {
+    pub fn new(dir: &Path, dt: f64) -> OutOfCore {
+        return OutOfCore {
+            dir: dir.to_path_buf(),
+            dt: dt,
+            theta: 0.5,
+            force_law: ForceLaw::default(),
+            species: SpeciesTable::default(),
+            chunk: 1 << 20,
+            len: 0,
+            grid: Grid::fitted([0.; 3], [0.; 3], 0),
+            cells: vec![0, 0],
+            levels: vec![],
+            steps: 0,
+            time: 0.,
+        };
+    }
+
+    pub fn with_theta(mut self, theta: f64) -> OutOfCore {
+        self.theta = theta;
+        return self;
+    }
+
+    pub fn with_force_law(mut self, force_law: ForceLaw) -> OutOfCore {
+        self.force_law = force_law;
+        return self;
+    }
+
+    pub fn with_species(mut self, species: SpeciesTable) -> OutOfCore {
+        self.species = species;
+        return self;
+    }
+
+    // About how many particles each cell should hold.
+    pub fn with_chunk(mut self, chunk: usize) -> OutOfCore {
+        self.chunk = chunk.max(1);
+        return self;
+    }
+
+    pub fn len(&self) -> usize {
+        return self.len;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.len == 0;
+    }
+
+    pub fn steps(&self) -> u64 {
+        return self.steps;
+    }
+
+    pub fn time(&self) -> f64 {
+        return self.time;
+    }
+
+    // Writes `points` to disk, replacing any particles already there. They are streamed through,
+    // so they needn't fit in memory either.
+    pub fn load<I, E>(&mut self, points: I) -> Result<(), NbodyError>
+    where
+        I: IntoIterator<Item = Result<Point, E>>,
+        NbodyError: From<E>,
+    {
+        std::fs::create_dir_all(&self.dir)?;
+        let mut out = BufWriter::new(File::create(self.dir.join(SCRATCH_FILE))?);
+        let mut record = [0; RECORD_SIZE];
+        for p in points {
+            encode(&p?, &mut record);
+            out.write_all(&record)?;
+        }
+        out.flush()?;
+        return self.sort();
+    }
+
+    // Calls `f` on every particle, in no particular order.
+    pub fn visit<F: FnMut(Point)>(&self, mut f: F) -> Result<(), NbodyError> {
+        if self.is_empty() {
+            return Ok(());
+        }
+        let particles = map(&self.dir.join(PARTICLES_FILE))?;
+        particles
+            .chunks_exact(RECORD_SIZE)
+            .for_each(|r| f(decode(r)));
+        return Ok(());
+    }
+
+    // Writes every particle as a CSV snapshot, with the same columns as an in-memory run's.
+    pub fn write_csv<W: Write>(&self, w: W, time: f64) -> Result<(), NbodyError> {
+        let mut wtr = csv::Writer::from_writer(w);
+        let mut header = CSV_HEADER.to_vec();
+        header.push("time");
+        wtr.write_record(header)?;
+        let time = time.to_string();
+        let mut result = Ok(());
+        self.visit(|p| {
+            if result.is_ok() {
+                let mut record = csv_record(&p);
+                record.push(time.clone());
+                result = wtr.write_record(record);
+            }
+        })?;
+        result?;
+        wtr.flush()?;
+        return Ok(());
+    }
+
+    pub fn step(&mut self) -> Result<(), NbodyError> {
+        let start = Instant::now();
+        if !self.is_empty() {
+            let particles = map(&self.dir.join(PARTICLES_FILE))?;
+            let mut scratch = map_mut(&self.dir.join(SCRATCH_FILE), self.len)?;
+            for cell in 0..self.cells.len() - 1 {
+                let range = self.cells[cell] * RECORD_SIZE..self.cells[cell + 1] * RECORD_SIZE;
+                if !range.is_empty() {
+                    let moved = self.step_cell(cell, &particles)?;
+                    for (p, out) in moved
+                        .iter()
+                        .zip(scratch[range].chunks_exact_mut(RECORD_SIZE))
+                    {
+                        encode(p, out);
+                    }
+                }
+            }
+            scratch.flush()?;
+        }
+        self.sort()?;
+        self.steps += 1;
+        self.time += self.dt;
+        debug!("out-of-core step {} took {:?}", self.steps, start.elapsed());
+        return Ok(());
+    }
+
+    // The particles of cell `cell`, in file order, moved on by one step.
+    fn step_cell(&self, cell: usize, particles: &[u8]) -> Result<Vec<Point>, NbodyError> {
+        let side = self.grid.side();
+        let c = [cell / (side * side), cell / side % side, cell % side];
+        let records = |n: usize| {
+            let range = self.cells[n] * RECORD_SIZE..self.cells[n + 1] * RECORD_SIZE;
+            particles[range].chunks_exact(RECORD_SIZE).map(decode)
+        };
+        let targets: Vec<Point> = records(cell).collect();
+        let near: Vec<Point> = neighbors(c, side)
+            .map(|n| index(n, side))
+            .flat_map(records)
+            .collect();
+        let tree = BHTree::from_points_with_species(self.theta, near, self.species.clone())?
+            .with_force_law(self.force_law);
+
+        // As in the in-memory step, tracers move as unit masses.
+        let proxy = |p: &Point| if p.is_tracer() { p.with_mass(1.) } else { *p };
+        let advance = |p: &Point| {
+            let force = tree.force_on(proxy(p)) + self.far_force(proxy(p), c);
+            let moved = proxy(p).apply_force(self.dt, force);
+            if p.is_tracer() {
+                moved.with_mass(0.)
+            } else {
+                moved
+            }
+        };
+        #[cfg(feature = "parallel")]
+        let moved = targets.par_iter().map(advance).collect();
+        #[cfg(not(feature = "parallel"))]
+        let moved = targets.iter().map(advance).collect();
+        return Ok(moved);
+    }
+
+    // Force on `p`, in finest cell `c`, from everything outside that cell's neighbours. At each
+    // level it takes the cells that are neighbours of its parent's neighbours but not of its own,
+    // which between them cover the rest of the system exactly once.
+    fn far_force(&self, p: Point, c: [usize; 3]) -> Vec3d {
+        let softening = self.species.softening(p.species());
+        let mut force = Vec3d::new_zero();
+        for level in 1..=self.grid.level {
+            let side = 1 << level;
+            let shift = self.grid.level - level;
+            let own = c.map(|v| v >> shift);
+            let span = |v: usize| (v >> 1).saturating_sub(1) * 2..((v >> 1) + 2) * 2;
+            for x in span(own[0]).filter(|x| *x < side) {
+                for y in span(own[1]).filter(|y| *y < side) {
+                    for z in span(own[2]).filter(|z| *z < side) {
+                        let apart = [x, y, z]
+                            .iter()
+                            .zip(own.iter())
+                            .any(|(a, b)| a.abs_diff(*b) > 1);
+                        let (mass, com) = self.levels[level as usize][index([x, y, z], side)];
+                        if apart && mass > 0. {
+                            let (cx, cy, cz) = com.position();
+                            let q = Point::new(mass, cx, cy, cz, Vec3d::new_zero());
+                            force += self.force_law.softened_force(p, q, softening);
+                        }
+                    }
+                }
+            }
+        }
+        return force;
+    }
+
+    // Sorts the particles in the scratch file into the particle file by cell, on a grid fitted to
+    // where they are now, and sums up the cells.
+    fn sort(&mut self) -> Result<(), NbodyError> {
+        let scratch_path = self.dir.join(SCRATCH_FILE);
+        self.len = std::fs::metadata(&scratch_path)?.len() as usize / RECORD_SIZE;
+        if self.is_empty() {
+            self.grid = Grid::fitted([0.; 3], [0.; 3], 0);
+            self.cells = vec![0, 0];
+            self.levels = vec![vec![(0., Vec3d::new_zero())]];
+            return Ok(());
+        }
+        let scratch = map(&scratch_path)?;
+        let records = || scratch.chunks_exact(RECORD_SIZE);
+
+        let mut min = [f64::MAX; 3];
+        let mut max = [f64::MIN; 3];
+        for p in records().map(decode) {
+            let (x, y, z) = p.position();
+            for (i, v) in [x, y, z].into_iter().enumerate() {
+                min[i] = min[i].min(v);
+                max[i] = max[i].max(v);
+            }
+        }
+        let mut level = 0;
+        while level < MAX_LEVEL && self.chunk << (3 * level) < self.len {
+            level += 1;
+        }
+        let grid = Grid::fitted(min, max, level);
+
+        let cell_count = grid.side().pow(3);
+        let mut cells = vec![0; cell_count + 1];
+        for p in records().map(decode) {
+            cells[grid.index(grid.coords(&p)) + 1] += 1;
+        }
+        for i in 1..cells.len() {
+            cells[i] += cells[i - 1];
+        }
+
+        let mut particles = map_mut(&self.dir.join(PARTICLES_FILE), self.len)?;
+        let mut next = cells.clone();
+        let mut sums = vec![(0., Vec3d::new_zero()); cell_count];
+        for record in records() {
+            let p = decode(record);
+            let cell = grid.index(grid.coords(&p));
+            let at = next[cell] * RECORD_SIZE;
+            particles[at..at + RECORD_SIZE].copy_from_slice(record);
+            next[cell] += 1;
+            let (x, y, z) = p.position();
+            sums[cell].0 += p.mass();
+            sums[cell].1 += p.mass() * Vec3d::new(x, y, z);
+        }
+        particles.flush()?;
+
+        // Each coarser level sums the level below it, eight cells to one.
+        let mut levels = vec![sums];
+        for level in (0..grid.level).rev() {
+            let side = 1 << level;
+            let finer = &levels[0];
+            let mut coarse = vec![(0., Vec3d::new_zero()); side * side * side];
+            for (i, (mass, moment)) in finer.iter().enumerate() {
+                let c = [
+                    i / (4 * side * side),
+                    i / (2 * side) % (2 * side),
+                    i % (2 * side),
+                ];
+                let parent = &mut coarse[index(c.map(|v| v >> 1), side)];
+                parent.0 += mass;
+                parent.1 += *moment;
+            }
+            levels.insert(0, coarse);
+        }
+        for level in levels.iter_mut() {
+            for (mass, moment) in level.iter_mut() {
+                if *mass > 0. {
+                    *moment /= *mass;
+                }
+            }
+        }
+
+        self.grid = grid;
+        self.cells = cells;
+        self.levels = levels;
+        return Ok(());
+    }
+}
+
+// Safety, for both maps: the files belong to one store, which neither truncates nor writes them
+// through any other handle while they are mapped.
+fn map(path: &Path) -> Result<Mmap, NbodyError> {
+    let file = File::open(path)?;
+    return Ok(unsafe { Mmap::map(&file)? });
+}
+
+// Maps `path` for writing, sized to hold `len` particles.
+fn map_mut(path: &Path, len: usize) -> Result<MmapMut, NbodyError> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)?;
+    file.set_len((len * RECORD_SIZE) as u64)?;
+    return Ok(unsafe { MmapMut::map_mut(&file)? });
+}
+
+#[cfg(test)]
+mod test {
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    use crate::geometry::bh_tree::BHTree;
+    use crate::ic::halo::{Halo, Profile};
+    use crate::out_of_core::{decode, encode, OutOfCore, RECORD_SIZE};
+    use crate::{NbodyError, Point, Vec3d};
+
+    #[test]
+    fn test_out_of_core() {
+        let p = Point::new(2., 1., -2., 3., Vec3d::new(4., 5., -6.))
+            .with_id(9)
+            .with_species(3)
+            .with_pinned(true)
+            .with_radius(0.5);
+        let mut record = [0; RECORD_SIZE];
+        encode(&p, &mut record);
+        assert_eq!(decode(&record), p);
+        let external = p.with_external(true);
+        encode(&external, &mut record);
+        assert_eq!(decode(&record), external);
+
+        // A halo small enough to step in memory too, on a grid of up to 512 cells.
+        let points = Halo {
+            n: 600,
+            total_mass: 1e20,
+            profile: Profile::Hernquist,
+            scale_radius: 1e3,
+            max_radius: 2e4,
+        }
+        .generate(&mut StdRng::seed_from_u64(1));
+        let mut tree = BHTree::from_points(0.3, points.clone()).unwrap();
+        let dir = std::env::temp_dir().join(format!("nbody-ooc-{}", std::process::id()));
+        let mut ooc = OutOfCore::new(&dir, 1e3).with_theta(0.3).with_chunk(2);
+        ooc.load(points.iter().map(|p| Ok::<Point, NbodyError>(*p)))
+            .unwrap();
+        assert_eq!(ooc.len(), 600);
+        assert_eq!(ooc.grid.level, 3);
+        for _ in 0..3 {
+            ooc.step().unwrap();
+            tree = tree.next(1e3).unwrap();
+        }
+        assert_eq!((ooc.steps(), ooc.time()), (3, 3e3));
+
+        // Far cells only stand in for their particles, so the two agree closely but not exactly.
+        let mut moved = vec![];
+        ooc.visit(|p| moved.push(p)).unwrap();
+        moved.sort_by_key(|p| p.id());
+        let mut expected: Vec<Point> = tree.iter().copied().collect();
+        expected.sort_by_key(|p| p.id());
+        assert_eq!(moved.len(), expected.len());
+        let mut worst: f64 = 0.;
+        for (a, b) in moved.iter().zip(expected.iter()) {
+            let (ax, ay, az) = a.position();
+            let (bx, by, bz) = b.position();
+            let (sx, sy, sz) = points[a.id() as usize].position();
+            let step = Vec3d::new(bx - sx, by - sy, bz - sz).magnitude();
+            let off = Vec3d::new(ax - bx, ay - by, az - bz).magnitude();
+            worst = worst.max(off / step);
+        }
+        assert!(worst < 0.05, "out-of-core positions off by {}", worst);
+
+        let mut csv = vec![];
+        ooc.write_csv(&mut csv, 3.).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        assert!(csv.starts_with("mass,x_pos,"));
+        assert!(csv.lines().next().unwrap().ends_with(",time"));
+        assert_eq!(csv.lines().count(), 601);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,849 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::thread::JoinHandle;
+
+use log::{debug, warn};
+
+use crate::analysis::CORE_FILE;
+use crate::binaries::find_binaries;
+use crate::geometry::bh_tree::{BHTree, StepScratch};
+use crate::geometry::infall::INJECTION_FILE;
+use crate::geometry::kicks::KICK_FILE;
+use crate::groups::find_groups;
+use crate::output::compress::{Compression, Sink};
+use crate::output::container::CONTAINER_FILE;
+use crate::output::manifest::MANIFEST_FILE;
+use crate::output::orbits::ORBIT_PREFIX;
+use crate::{NbodyError, Point, Vec3d};
+
+pub mod compress;
+pub mod container;
+pub mod manifest;
+pub mod orbits;
+#[cfg(feature = "render")]
+pub mod render;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+#[cfg(feature = "serve")]
+pub mod stream;
+
+/// A destination for periodic snapshots of the simulation.
+pub trait SnapshotWriter {
+    fn write(&mut self, step: u64, time: f64, tree: &BHTree) -> Result<(), Box<dyn Error>>;
+
+    // Waits until everything written so far has reached its destination.
+    fn flush(&mut self) -> Result<(), Box<dyn Error>> {
+        return Ok(());
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RegionShape {
+    Sphere,
+    Cube,
+}
+
+/// A region of interest: a sphere of radius `size` or a cube of half-width `size` about `center`.
+/// A tracking region is moved onto the densest particle of every snapshot instead.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Region {
+    pub shape: RegionShape,
+    pub center: Vec3d,
+    pub size: f64,
+    // Tracks the particle with the highest SPH density over this many neighbours, if set.
+    pub track_densest: Option<usize>,
+}
+
+impl Region {
+    pub fn contains(&self, p: &Point) -> bool {
+        let (x, y, z) = p.position();
+        let (cx, cy, cz) = self.center.position();
+        let (dx, dy, dz) = (x - cx, y - cy, z - cz);
+        return match self.shape {
+            RegionShape::Sphere => dx * dx + dy * dy + dz * dz <= self.size * self.size,
+            RegionShape::Cube => dx.abs().max(dy.abs()).max(dz.abs()) <= self.size,
+        };
+    }
+
+    // This region as placed for a snapshot of `tree`.
+    pub fn placed_for(&self, tree: &BHTree) -> Region {
+        let k = match self.track_densest {
+            Some(k) => k,
+            None => return *self,
+        };
+        let densest = tree
+            .points()
+            .into_iter()
+            .zip(tree.densities(k))
+            .max_by(|(_, a), (_, b)| a.total_cmp(b));
+        return match densest {
+            Some((p, _)) => {
+                let (x, y, z) = p.position();
+                Region {
+                    center: Vec3d::new(x, y, z),
+                    ..*self
+                }
+            }
+            None => *self,
+        };
+    }
+}
+
+/// Which particles snapshots include: those of the chosen species inside the region of interest
+/// and, of those, a fixed fraction picked by id, so that a sampled particle is in every snapshot
+/// it is in the region for.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct OutputFilter {
+    species: Option<Vec<u16>>,
+    region: Option<Region>,
+    sample: Option<f64>,
+}
+
+impl OutputFilter {
+    pub fn new() -> OutputFilter {
+        return OutputFilter::default();
+    }
+
+    // Only particles of these species, if set.
+    pub fn with_species(mut self, species: Option<Vec<u16>>) -> OutputFilter {
+        self.species = species;
+        return self;
+    }
+
+    // Only particles inside this region, if set.
+    pub fn with_region(mut self, region: Option<Region>) -> OutputFilter {
+        self.region = region;
+        return self;
+    }
+
+    // Only about this fraction of particles, if set.
+    pub fn with_sample(mut self, fraction: Option<f64>) -> OutputFilter {
+        self.sample = fraction;
+        return self;
+    }
+
+    pub fn keeps(&self, p: &Point) -> bool {
+        if let Some(species) = &self.species {
+            if !species.contains(&p.species()) {
+                return false;
+            }
+        }
+        if self.region.is_some_and(|r| !r.contains(p)) {
+            return false;
+        }
+        return match self.sample {
+            // The top 53 bits as a uniform number in [0, 1).
+            Some(fraction) => ((mix(p.id()) >> 11) as f64) / ((1u64 << 53) as f64) < fraction,
+            None => true,
+        };
+    }
+
+    pub fn keeps_all(&self) -> bool {
+        return self.species.is_none() && self.region.is_none() && self.sample.is_none();
+    }
+
+    // This filter with its region placed for a snapshot of `tree`. Call it once per snapshot
+    // before `keeps`.
+    pub fn placed_for(&self, tree: &BHTree) -> OutputFilter {
+        let mut filter = self.clone();
+        filter.region = self.region.map(|r| r.placed_for(tree));
+        return filter;
+    }
+}
+
+// Scrambles a particle id into 64 well-mixed bits (the splitmix64 finalizer), so that consecutive
+// ids are sampled independently.
+fn mix(id: u64) -> u64 {
+    let mut z = id.wrapping_add(0x9e3779b97f4a7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    return z ^ (z >> 31);
+}
+
+/// Hands another writer a tree of only the particles an `OutputFilter` keeps.
+pub struct FilteredWriter {
+    writer: Box<dyn SnapshotWriter>,
+    filter: OutputFilter,
+}
+
+impl FilteredWriter {
+    // Wraps `writer`, or returns it as it is if the filter keeps everything.
+    pub fn wrap(writer: Box<dyn SnapshotWriter>, filter: OutputFilter) -> Box<dyn SnapshotWriter> {
+        if filter.keeps_all() {
+            return writer;
+        }
+        return Box::new(FilteredWriter {
+            writer: writer,
+            filter: filter,
+        });
+    }
+}
+
+impl SnapshotWriter for FilteredWriter {
+    fn write(&mut self, step: u64, time: f64, tree: &BHTree) -> Result<(), Box<dyn Error>> {
+        let filter = self.filter.placed_for(tree);
+        let kept = tree.iter().filter(|p| filter.keeps(p)).copied().collect();
+        return self.writer.write(step, time, &tree.rebuild(kept)?);
+    }
+
+    fn flush(&mut self) -> Result<(), Box<dyn Error>> {
+        return self.writer.flush();
+    }
+}
+
+/// Writes one CSV file per snapshot into a directory, pruning old files per `Retention`.
+pub struct CsvWriter {
+    dir: PathBuf,
+    retention: Retention,
+    // Which particles get a row. Extra columns are still computed over all of them.
+    filter: OutputFilter,
+    // Adds an SPH density column smoothed over this many neighbours, if set.
+    density_neighbors: Option<usize>,
+    // Adds a column with the gravitational potential per unit mass.
+    potential: bool,
+    compression: Compression,
+    // Threads still writing earlier snapshots, by file.
+    pending: Vec<(PathBuf, JoinHandle<Result<(), NbodyError>>)>,
+    // Row buffers handed back by those threads, for the next snapshots.
+    scratch: StepScratch,
+}
+
+impl CsvWriter {
+    pub fn new(dir: PathBuf, retention: Retention) -> CsvWriter {
+        return CsvWriter {
+            dir: dir,
+            retention: retention,
+            filter: OutputFilter::default(),
+            density_neighbors: None,
+            potential: false,
+            compression: Compression::None,
+            pending: vec![],
+            scratch: StepScratch::new(),
+        };
+    }
+
+    pub fn with_filter(mut self, filter: OutputFilter) -> CsvWriter {
+        self.filter = filter;
+        return self;
+    }
+
+    pub fn with_potential(mut self, potential: bool) -> CsvWriter {
+        self.potential = potential;
+        return self;
+    }
+
+    // Compresses each file as it is written, naming it `out-<step>.csv.gz` or `.csv.zst`.
+    pub fn with_compression(mut self, compression: Compression) -> CsvWriter {
+        self.compression = compression;
+        return self;
+    }
+
+    pub fn with_density(mut self, neighbors: Option<usize>) -> CsvWriter {
+        self.density_neighbors = neighbors;
+        return self;
+    }
+}
+
+impl SnapshotWriter for CsvWriter {
+    fn write(&mut self, step: u64, time: f64, tree: &BHTree) -> Result<(), Box<dyn Error>> {
+        let path = self
+            .dir
+            .join(format!("out-{}.csv{}", step, self.compression.suffix()));
+        // CSV has nowhere else to put the snapshot's time, so every row carries it.
+        let mut columns = vec![("time", vec![time; tree.len()])];
+        if let Some(k) = self.density_neighbors {
+            columns.push(("density", tree.densities(k)));
+        }
+        if self.potential {
+            columns.push(("potential", tree.potentials()));
+        }
+        let filter = self.filter.placed_for(tree);
+        let handle = tree.write_csv_with(
+            self.compression.create(&path)?,
+            path.display().to_string(),
+            |p| filter.keeps(p),
+            columns,
+            Sink::finish,
+            &self.scratch,
+        )?;
+        // Errors from earlier files surface here, on the next write, rather than being lost.
+        let (done, pending) = self.pending.drain(..).partition(|(_, h)| h.is_finished());
+        self.pending = pending;
+        self.pending.push((path.clone(), handle));
+        self.retention.record(path);
+        for (path, handle) in done {
+            join_writer(path, handle)?;
+        }
+        return Ok(());
+    }
+
+    fn flush(&mut self) -> Result<(), Box<dyn Error>> {
+        for (path, handle) in self.pending.drain(..) {
+            join_writer(path, handle)?;
+        }
+        return Ok(());
+    }
+}
+
+// Waits for a background CSV write and returns whatever went wrong with it.
+fn join_writer(
+    path: PathBuf,
+    handle: JoinHandle<Result<(), NbodyError>>,
+) -> Result<(), NbodyError> {
+    return handle
+        .join()
+        .map_err(|_| NbodyError::WriterPanicked(path.display().to_string()))?;
+}
+
+/// Writes a friends-of-friends group catalog per snapshot, as a JSON array of groups.
+pub struct GroupCatalogWriter {
+    dir: PathBuf,
+    linking_length: f64,
+    min_members: usize,
+}
+
+impl GroupCatalogWriter {
+    pub fn new(dir: PathBuf, linking_length: f64, min_members: usize) -> GroupCatalogWriter {
+        return GroupCatalogWriter {
+            dir: dir,
+            linking_length: linking_length,
+            min_members: min_members,
+        };
+    }
+}
+
+impl SnapshotWriter for GroupCatalogWriter {
+    fn write(&mut self, step: u64, _time: f64, tree: &BHTree) -> Result<(), Box<dyn Error>> {
+        let groups = find_groups(tree, self.linking_length, self.min_members);
+        debug!("found {} groups at step {}", groups.len(), step);
+        let path = self.dir.join(format!("groups-{}.json", step));
+        serde_json::to_writer(fs::File::create(path)?, &groups)?;
+        return Ok(());
+    }
+}
+
+/// Writes a catalog of bound pairs per snapshot, as a CSV file of `Binary` rows.
+pub struct BinaryCatalogWriter {
+    dir: PathBuf,
+    max_semi_major_axis: f64,
+}
+
+impl BinaryCatalogWriter {
+    pub fn new(dir: PathBuf, max_semi_major_axis: f64) -> BinaryCatalogWriter {
+        return BinaryCatalogWriter {
+            dir: dir,
+            max_semi_major_axis: max_semi_major_axis,
+        };
+    }
+}
+
+impl SnapshotWriter for BinaryCatalogWriter {
+    fn write(&mut self, step: u64, time: f64, tree: &BHTree) -> Result<(), Box<dyn Error>> {
+        let binaries = find_binaries(step, Some(time), tree, self.max_semi_major_axis);
+        debug!("found {} binaries at step {}", binaries.len(), step);
+        let mut file = csv::Writer::from_path(self.dir.join(format!("binaries-{}.csv", step)))?;
+        for binary in binaries {
+            file.serialize(binary)?;
+        }
+        file.flush()?;
+        return Ok(());
+    }
+}
+
+/// Decides which steps get written out, independently of the integration time step.
+#[derive(Debug)]
+pub struct SnapshotSchedule {
+    every: Option<u64>,
+    dt: Option<f64>,
+    next_time: f64,
+}
+
+impl SnapshotSchedule {
+    // Fails unless `dt`, if given, is finite and greater than zero; anything else would never
+    // reach the next snapshot time.
+    pub fn new(every: Option<u64>, dt: Option<f64>) -> Result<SnapshotSchedule, Box<dyn Error>> {
+        if let Some(dt) = dt.filter(|dt| !dt.is_finite() || *dt <= 0.) {
+            return Err(format!("invalid snapshot interval {}", dt).into());
+        }
+        return Ok(SnapshotSchedule {
+            every: every,
+            dt: dt,
+            next_time: 0.,
+        });
+    }
+
+    // Carries on a schedule whose next snapshot by time was due at `next_time`, e.g. in a resumed
+    // run.
+    pub fn with_next_time(mut self, next_time: f64) -> SnapshotSchedule {
+        self.next_time = next_time;
+        return self;
+    }
+
+    // When the next snapshot by time is due.
+    pub fn next_time(&self) -> f64 {
+        return self.next_time;
+    }
+
+    // Returns true if a snapshot should be written for the given step and simulation time. With no
+    // cadence configured every step is written. The first step is always written.
+    pub fn should_write(&mut self, step: u64, time: f64) -> bool {
+        let mut write = step == 0;
+
+        if let Some(every) = self.every {
+            write |= step.is_multiple_of(every.max(1));
+        }
+
+        if let Some(dt) = self.dt {
+            if time >= self.next_time {
+                write = true;
+                // The first multiple of dt after `time`, skipping any boundaries the simulation
+                // stepped over entirely.
+                self.next_time = ((time / dt).floor() + 1.) * dt;
+            }
+        }
+
+        if self.every.is_none() && self.dt.is_none() {
+            write = true;
+        }
+
+        return write;
+    }
+}
+
+// Snapshot files named like those `CsvWriter` writes (`out-<step>.<ext>`, with any number of
+// extensions) in `dir`, ordered by step. Other files are ignored.
+pub fn list_snapshots(dir: &Path) -> Result<Vec<(u64, PathBuf)>, Box<dyn Error>> {
+    let mut snapshots = vec![];
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let step = path
+            .file_name()
+            .and_then(|s| s.to_str())
+            .and_then(|s| s.strip_prefix("out-"))
+            .and_then(|s| s.split_once('.'))
+            .and_then(|(s, _)| s.parse::<u64>().ok());
+        if let Some(step) = step {
+            snapshots.push((step, path));
+        }
+    }
+    snapshots.sort();
+    return Ok(snapshots);
+}
+
+// Makes `dir` ready for a new run's output, creating it if need be. A directory that already
+// holds a run, i.e. a run.json, a snapshot container or snapshots, is only reused with
+// `overwrite`, and then its old snapshots and catalogs are deleted so they can't be
+// mistaken for the new run's.
+pub fn prepare_run_dir(dir: &Path, overwrite: bool) -> Result<(), Box<dyn Error>> {
+    fs::create_dir_all(dir)?;
+    let snapshots = list_snapshots(dir)?;
+    let container = dir.join(CONTAINER_FILE);
+    let has_run = !snapshots.is_empty() || container.exists() || dir.join(MANIFEST_FILE).exists();
+    if !overwrite && has_run {
+        return Err(format!("{} already holds a run", dir.display()).into());
+    }
+    for path in event_logs(dir)? {
+        fs::remove_file(&path)?;
+    }
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+        let groups = name.starts_with("groups-") && name.ends_with(".json");
+        let binaries = name.starts_with("binaries-") && name.ends_with(".csv");
+        if groups || binaries {
+            fs::remove_file(&path)?;
+        }
+    }
+    for (_, path) in snapshots {
+        fs::remove_file(&path)?;
+    }
+    return Ok(());
+}
+
+// The files in `dir` that a run appends to as it goes, and a resumed run carries on: the snapshot
+// container and the core, kick, injection and orbit logs. Only those that exist.
+pub fn event_logs(dir: &Path) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    let mut logs: Vec<PathBuf> = [CONTAINER_FILE, CORE_FILE, KICK_FILE, INJECTION_FILE]
+        .iter()
+        .map(|name| dir.join(name))
+        .filter(|path| path.exists())
+        .collect();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+        if name.starts_with(ORBIT_PREFIX) && name.ends_with(".csv") {
+            logs.push(path);
+        }
+    }
+    return Ok(logs);
+}
+
+// Length in bytes of each event log in `dir`, by file name, for a checkpoint to record. Buffered
+// writers should be flushed first.
+pub fn log_offsets(dir: &Path) -> Result<BTreeMap<String, u64>, Box<dyn Error>> {
+    let mut offsets = BTreeMap::new();
+    for path in event_logs(dir)? {
+        let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+        offsets.insert(name.to_string(), fs::metadata(&path)?.len());
+    }
+    return Ok(offsets);
+}
+
+// Cuts the event logs in `dir` back to the lengths a checkpoint recorded, dropping whatever the
+// interrupted run wrote after it so the resumed run doesn't repeat it. Logs the checkpoint didn't
+// know about were started after it and are removed.
+pub fn rewind_logs(dir: &Path, offsets: &BTreeMap<String, u64>) -> Result<(), Box<dyn Error>> {
+    for path in event_logs(dir)? {
+        let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+        match offsets.get(name) {
+            Some(&len) => {
+                let file = fs::OpenOptions::new().write(true).open(&path)?;
+                if file.metadata()?.len() > len {
+                    debug!("rewinding {} to {} bytes", path.display(), len);
+                    file.set_len(len)?;
+                }
+            }
+            None => {
+                debug!("removing {}, started after the checkpoint", path.display());
+                fs::remove_file(&path)?;
+            }
+        }
+    }
+    return Ok(());
+}
+
+/// Keeps only the most recent snapshot files on disk, deleting older ones as new ones arrive.
+#[derive(Debug)]
+pub struct Retention {
+    keep: Option<usize>,
+    written: VecDeque<PathBuf>,
+}
+
+impl Retention {
+    // The snapshot just written is always kept, so keeping none means keeping one.
+    pub fn new(keep: Option<usize>) -> Retention {
+        return Retention {
+            keep: keep.map(|k| k.max(1)),
+            written: VecDeque::new(),
+        };
+    }
+
+    // Counts snapshots already on disk, e.g. those a resumed run wrote before it was interrupted,
+    // oldest first.
+    pub fn with_written(mut self, paths: Vec<PathBuf>) -> Retention {
+        self.written.extend(paths);
+        return self;
+    }
+
+    pub fn record(&mut self, path: PathBuf) {
+        // A snapshot written again over the same file counts once.
+        self.written.retain(|p| *p != path);
+        self.written.push_back(path);
+        let keep = match self.keep {
+            Some(k) => k,
+            None => return,
+        };
+
+        while self.written.len() > keep {
+            let old = self.written.pop_front().unwrap();
+            debug!("removing old snapshot {}", old.display());
+            if let Err(e) = fs::remove_file(&old) {
+                warn!("failed to remove old snapshot {}: {}", old.display(), e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+    use std::error::Error;
+    use std::path::PathBuf;
+    use std::rc::Rc;
+
+    use crate::geometry::bh_tree::BHTree;
+    use crate::output::{
+        list_snapshots, log_offsets, prepare_run_dir, rewind_logs, CsvWriter, FilteredWriter,
+        OutputFilter, Region, RegionShape, Retention, SnapshotSchedule, SnapshotWriter,
+    };
+    use crate::{Point, Vec3d};
+
+    // Remembers the ids each snapshot held.
+    struct Recorder(Rc<RefCell<Vec<Vec<u64>>>>);
+
+    impl SnapshotWriter for Recorder {
+        fn write(&mut self, _step: u64, _time: f64, tree: &BHTree) -> Result<(), Box<dyn Error>> {
+            let mut ids: Vec<u64> = tree.iter().map(|p| p.id()).collect();
+            ids.sort();
+            self.0.borrow_mut().push(ids);
+            return Ok(());
+        }
+    }
+
+    #[test]
+    fn test_default_writes_every_step() {
+        let mut s = SnapshotSchedule::new(None, None).unwrap();
+        for step in 0..10 {
+            assert!(s.should_write(step, step as f64 * 0.1));
+        }
+    }
+
+    #[test]
+    fn test_every_n_steps() {
+        let mut s = SnapshotSchedule::new(Some(3), None).unwrap();
+        let written: Vec<u64> = (0..10).filter(|step| s.should_write(*step, 0.)).collect();
+        assert_eq!(written, vec![0, 3, 6, 9]);
+    }
+
+    #[test]
+    fn test_every_dt() {
+        let dt = 0.25;
+        let mut s = SnapshotSchedule::new(None, Some(1.0)).unwrap();
+        let written: Vec<u64> = (0..13)
+            .filter(|step| s.should_write(*step, *step as f64 * dt))
+            .collect();
+        assert_eq!(written, vec![0, 4, 8, 12]);
+
+        for dt in [0., -1., f64::NAN, f64::INFINITY] {
+            assert!(SnapshotSchedule::new(None, Some(dt)).is_err());
+        }
+
+        // Far enough into a run that adding the interval no longer changes the time, or long after
+        // the last snapshot of a resumed one, the next is still found in one go.
+        let mut s = SnapshotSchedule::new(None, Some(1.0)).unwrap();
+        assert!(s.should_write(1, 1e17));
+        assert!(s.next_time() >= 1e17);
+        let mut s = SnapshotSchedule::new(None, Some(1e-9))
+            .unwrap()
+            .with_next_time(1e-9);
+        assert!(s.should_write(1, 1e6));
+        assert!(s.next_time() > 1e6 && s.next_time() <= 1e6 + 2e-9);
+    }
+
+    #[test]
+    fn test_list_snapshots_in_step_order() {
+        let dir = std::env::temp_dir().join(format!("nbody-snapshots-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for name in [
+            "out-10.csv",
+            "out-9.csv",
+            "out-100.parquet",
+            "out-11.csv.zst",
+            "groups-9.json",
+            "notes.txt",
+        ] {
+            std::fs::write(dir.join(name), "").unwrap();
+        }
+        let steps: Vec<u64> = list_snapshots(&dir)
+            .unwrap()
+            .into_iter()
+            .map(|(step, _)| step)
+            .collect();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(steps, vec![9, 10, 11, 100]);
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn test_compressed_csv() {
+        use crate::ic::file::load;
+        use crate::output::compress::Compression;
+
+        let points: Vec<Point> = (0..2000)
+            .map(|i| Point::new(1., i as f64, (i % 7) as f64, 0., Vec3d::new_zero()).with_id(i))
+            .collect();
+        let tree = BHTree::from_points(0.5, points).unwrap();
+        let dir = std::env::temp_dir().join(format!("nbody-compressed-{}", std::process::id()));
+        let mut sizes = vec![];
+        for (step, compression) in [Compression::None, Compression::Gzip, Compression::Zstd]
+            .into_iter()
+            .enumerate()
+        {
+            std::fs::create_dir_all(&dir).unwrap();
+            let mut writer =
+                CsvWriter::new(dir.clone(), Retention::new(None)).with_compression(compression);
+            writer.write(step as u64, 0., &tree).unwrap();
+            writer.flush().unwrap();
+            let path = dir.join(format!("out-{}.csv{}", step, compression.suffix()));
+            sizes.push(std::fs::metadata(&path).unwrap().len());
+            assert_eq!(load(&path).unwrap().len(), tree.len());
+        }
+        let steps: Vec<u64> = list_snapshots(&dir)
+            .unwrap()
+            .into_iter()
+            .map(|(step, _)| step)
+            .collect();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(steps, vec![0, 1, 2]);
+        assert!(
+            sizes[1] * 3 < sizes[0] && sizes[2] * 3 < sizes[0],
+            "{:?}",
+            sizes
+        );
+    }
+
+    #[test]
+    fn test_prepare_run_dir() {
+        let dir = std::env::temp_dir()
+            .join(format!("nbody-run-dir-{}", std::process::id()))
+            .join("run");
+        prepare_run_dir(&dir, false).unwrap();
+        prepare_run_dir(&dir, false).unwrap();
+        for name in [
+            "out-0.csv",
+            "groups-0.json",
+            "binaries-0.csv",
+            "snapshots.nbc",
+            "notes.txt",
+        ] {
+            std::fs::write(dir.join(name), "").unwrap();
+        }
+        assert!(prepare_run_dir(&dir, false).is_err());
+        prepare_run_dir(&dir, true).unwrap();
+        let mut left: Vec<_> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect();
+        std::fs::remove_dir_all(dir.parent().unwrap()).unwrap();
+        left.sort();
+        assert_eq!(left, vec!["notes.txt"]);
+    }
+
+    #[test]
+    fn test_rewind_logs() {
+        let dir = std::env::temp_dir().join(format!("nbody-rewind-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("kicks.csv"), "step,id\n1,4\n").unwrap();
+        std::fs::write(dir.join("orbit-3.csv"), "step,x\n").unwrap();
+        std::fs::write(dir.join("out-0.csv"), "mass\n1\n").unwrap();
+        let offsets = log_offsets(&dir).unwrap();
+        assert_eq!(
+            offsets.keys().collect::<Vec<_>>(),
+            vec!["kicks.csv", "orbit-3.csv"]
+        );
+
+        // What the run goes on to log after the checkpoint is undone.
+        std::fs::write(dir.join("kicks.csv"), "step,id\n1,4\n2,5\n").unwrap();
+        std::fs::write(dir.join("injections.csv"), "step,id\n2,6\n").unwrap();
+        rewind_logs(&dir, &offsets).unwrap();
+        let kicks = std::fs::read_to_string(dir.join("kicks.csv")).unwrap();
+        let injections = dir.join("injections.csv").exists();
+        let snapshot = dir.join("out-0.csv").exists();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(kicks, "step,id\n1,4\n");
+        assert!(!injections && snapshot);
+
+        // Snapshots written again by a resumed run count once towards --keep-last.
+        let mut retention = Retention::new(Some(2)).with_written(vec!["a".into(), "b".into()]);
+        retention.record("b".into());
+        assert_eq!(retention.written, [PathBuf::from("a"), PathBuf::from("b")]);
+
+        // Keeping none would delete each snapshot as it's written.
+        let mut retention = Retention::new(Some(0));
+        retention.record("c".into());
+        assert_eq!(retention.written, [PathBuf::from("c")]);
+    }
+
+    #[test]
+    fn test_csv_writer_flush_and_errors() {
+        let tree = BHTree::from_points(
+            0.5,
+            vec![Point::new(1., 0., 0., 0., Vec3d::new_zero()).with_id(0)],
+        )
+        .unwrap();
+        let dir = std::env::temp_dir().join(format!("nbody-csv-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut writer = CsvWriter::new(dir.clone(), Retention::new(None));
+        writer.write(3, 0., &tree).unwrap();
+        writer.flush().unwrap();
+        let written = std::fs::read_to_string(dir.join("out-3.csv")).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(written.lines().count(), 2);
+
+        let mut missing = CsvWriter::new(dir.join("missing"), Retention::new(None));
+        assert!(missing.write(0, 0., &tree).is_err());
+    }
+
+    #[test]
+    fn test_output_filter() {
+        let points: Vec<Point> = (0..10_000)
+            .map(|i| {
+                let c = |k: u64| ((i * k) % 1000) as f64;
+                Point::new(1., i as f64, c(7), c(13), Vec3d::new_zero())
+                    .with_id(i)
+                    .with_species((i % 2) as u16)
+            })
+            .collect();
+        assert!(OutputFilter::new().keeps_all());
+        let sample = OutputFilter::new().with_sample(Some(0.1));
+        let kept = points.iter().filter(|p| sample.keeps(p)).count();
+        assert!((900..1100).contains(&kept), "kept {}", kept);
+        let both = sample.clone().with_species(Some(vec![1]));
+        assert!(points
+            .iter()
+            .all(|p| both.keeps(p) == (sample.keeps(p) && p.species() == 1)));
+
+        // The same particles are kept from one snapshot to the next, wherever they have moved.
+        let mut tree = BHTree::from_points(0.5, points.clone()).unwrap();
+        let written = Rc::new(RefCell::new(vec![]));
+        let mut writer = FilteredWriter::wrap(Box::new(Recorder(written.clone())), both.clone());
+        writer.write(0, 0., &tree).unwrap();
+        tree = tree.step(1.).unwrap().0;
+        writer.write(1, 1., &tree).unwrap();
+        let written = written.borrow();
+        assert_eq!(written[0], written[1]);
+        let expected: Vec<u64> = points
+            .iter()
+            .filter(|p| both.keeps(p))
+            .map(|p| p.id())
+            .collect();
+        assert_eq!(written[0], expected);
+    }
+
+    #[test]
+    fn test_region_of_interest() {
+        // A sparse cloud left of x = 25 and a tight clump around (50, 0, 0).
+        let points: Vec<Point> = (0..1000)
+            .map(|i| {
+                let c = |k: u64| ((i * k) % 101) as f64 - 50.;
+                let p = if i < 200 {
+                    let g = |k: u64| ((i / k) % 10) as f64 * 0.1;
+                    Point::new(1., 50. + g(1), g(10), g(100), Vec3d::new_zero())
+                } else {
+                    Point::new(1., 0.75 * c(3) - 12.5, c(7), c(13), Vec3d::new_zero())
+                };
+                p.with_id(i)
+            })
+            .collect();
+        let tree = BHTree::from_points(0.5, points.clone()).unwrap();
+        let sphere = Region {
+            shape: RegionShape::Sphere,
+            center: Vec3d::new_zero(),
+            size: 10.,
+            track_densest: None,
+        };
+        let cube = Region {
+            shape: RegionShape::Cube,
+            ..sphere
+        };
+        let corner = Point::new(1., 9., 9., -9., Vec3d::new_zero());
+        assert!(cube.contains(&corner) && !sphere.contains(&corner));
+        assert_eq!(sphere.placed_for(&tree), sphere);
+
+        let tracking = OutputFilter::new()
+            .with_region(Some(Region {
+                track_densest: Some(32),
+                ..sphere
+            }))
+            .placed_for(&tree);
+        assert!(!tracking.keeps_all());
+        let kept: Vec<u64> = points
+            .iter()
+            .filter(|p| tracking.keeps(p))
+            .map(|p| p.id())
+            .collect();
+        assert_eq!(kept, (0..200).collect::<Vec<u64>>());
+    }
+}
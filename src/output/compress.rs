@@ -0,0 +1,123 @@
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+/// How snapshot files are compressed. Compressed CSV files get a `.gz` or `.zst` suffix and
+/// otherwise hold the same CSV as uncompressed ones.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Compression {
+    #[default]
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    // The suffix added to compressed files' names, including its dot.
+    pub fn suffix(&self) -> &'static str {
+        return match self {
+            Compression::None => "",
+            Compression::Gzip => ".gz",
+            Compression::Zstd => ".zst",
+        };
+    }
+
+    // The compression of a file, going by its name.
+    pub fn of_path(path: &Path) -> Compression {
+        return match path.extension().and_then(|e| e.to_str()) {
+            Some("gz") => Compression::Gzip,
+            Some("zst") => Compression::Zstd,
+            _ => Compression::None,
+        };
+    }
+
+    // Creates `path` and returns a writer that compresses into it as it goes.
+    pub fn create(&self, path: &Path) -> io::Result<Sink> {
+        let file = File::create(path)?;
+        return match self {
+            Compression::None => Ok(Sink::Plain(file)),
+            #[cfg(feature = "compression")]
+            Compression::Gzip => Ok(Sink::Gzip(flate2::write::GzEncoder::new(
+                file,
+                flate2::Compression::default(),
+            ))),
+            #[cfg(feature = "compression")]
+            Compression::Zstd => Ok(Sink::Zstd(zstd::Encoder::new(file, 0)?)),
+            #[cfg(not(feature = "compression"))]
+            _ => Err(unsupported()),
+        };
+    }
+}
+
+/// A file being written, compressed or not. `finish` ends the compressed stream; a sink dropped
+/// without it may leave a truncated file.
+pub enum Sink {
+    Plain(File),
+    #[cfg(feature = "compression")]
+    Gzip(flate2::write::GzEncoder<File>),
+    #[cfg(feature = "compression")]
+    Zstd(zstd::Encoder<'static, File>),
+}
+
+impl Sink {
+    pub fn finish(self) -> io::Result<()> {
+        match self {
+            Sink::Plain(_) => {}
+            #[cfg(feature = "compression")]
+            Sink::Gzip(encoder) => {
+                encoder.finish()?;
+            }
+            #[cfg(feature = "compression")]
+            Sink::Zstd(encoder) => {
+                encoder.finish()?;
+            }
+        }
+        return Ok(());
+    }
+}
+
+impl Write for Sink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        return match self {
+            Sink::Plain(file) => file.write(buf),
+            #[cfg(feature = "compression")]
+            Sink::Gzip(encoder) => encoder.write(buf),
+            #[cfg(feature = "compression")]
+            Sink::Zstd(encoder) => encoder.write(buf),
+        };
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        return match self {
+            Sink::Plain(file) => file.flush(),
+            #[cfg(feature = "compression")]
+            Sink::Gzip(encoder) => encoder.flush(),
+            #[cfg(feature = "compression")]
+            Sink::Zstd(encoder) => encoder.flush(),
+        };
+    }
+}
+
+// Opens `path` for reading, decompressing it if its name says it is compressed.
+pub fn open(path: &Path) -> io::Result<Box<dyn Read>> {
+    let file = BufReader::new(File::open(path)?);
+    return match Compression::of_path(path) {
+        Compression::None => Ok(Box::new(file)),
+        #[cfg(feature = "compression")]
+        Compression::Gzip => Ok(Box::new(flate2::read::MultiGzDecoder::new(file))),
+        #[cfg(feature = "compression")]
+        Compression::Zstd => Ok(Box::new(zstd::Decoder::with_buffer(file)?)),
+        #[cfg(not(feature = "compression"))]
+        _ => Err(unsupported()),
+    };
+}
+
+#[cfg(not(feature = "compression"))]
+fn unsupported() -> io::Error {
+    return io::Error::new(
+        io::ErrorKind::Unsupported,
+        "compressed snapshots require building with --features compression",
+    );
+}
@@ -0,0 +1,340 @@
+use std::error::Error;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use log::{info, warn};
+
+use crate::geometry::bh_tree::BHTree;
+use crate::output::SnapshotWriter;
+use crate::{NbodyError, Point, Vec3d};
+
+pub const CONTAINER_FILE: &str = "snapshots.nbc";
+
+const MAGIC: &[u8; 8] = b"NBODYSNP";
+const VERSION: u32 = 1;
+const HEADER_LEN: u64 = 12;
+// Step, time and particle count.
+const FRAME_HEADER_LEN: u64 = 24;
+// Id, mass, position, velocity and radius, then species and flags.
+const RECORD_LEN: u64 = 9 * 8 + 2 + 1;
+// Bits of a record's flags byte.
+const PINNED: u8 = 1;
+const EXTERNAL: u8 = 2;
+
+/// Where one snapshot sits in a container.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FrameInfo {
+    pub step: u64,
+    pub time: f64,
+    pub particles: u64,
+    offset: u64,
+}
+
+/// Appends every snapshot of a run to a single file instead of writing one file per snapshot.
+///
+/// The file is little-endian: the magic `NBODYSNP` and a format version (u32), then one frame
+/// per snapshot. A frame is its length in bytes not counting the length itself (u64), the step
+/// (u64), time (f64) and particle count (u64), then for each particle its id (u64), mass,
+/// position, velocity and radius (f64s), species (u16) and flags (u8): 1 if it is pinned, plus 2
+/// if it is external.
+///
+/// Opening an existing container appends to it, after dropping any frame cut short by a crash. A
+/// resumed run may write steps the file already has; readers take the later frames.
+pub struct ContainerWriter {
+    path: PathBuf,
+    file: BufWriter<File>,
+}
+
+impl ContainerWriter {
+    pub fn open(path: &Path) -> Result<ContainerWriter, Box<dyn Error>> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        if file.metadata()?.len() == 0 {
+            file.write_all(MAGIC)?;
+            file.write_all(&VERSION.to_le_bytes())?;
+        } else {
+            let (frames, end) = scan(&mut BufReader::new(&mut file))?;
+            info!(
+                "appending to {} after {} snapshots",
+                path.display(),
+                frames.len()
+            );
+            file.set_len(end)?;
+        }
+        file.seek(SeekFrom::End(0))?;
+        return Ok(ContainerWriter {
+            path: path.to_path_buf(),
+            file: BufWriter::new(file),
+        });
+    }
+
+    pub fn path(&self) -> &Path {
+        return &self.path;
+    }
+}
+
+impl SnapshotWriter for ContainerWriter {
+    fn write(&mut self, step: u64, time: f64, tree: &BHTree) -> Result<(), Box<dyn Error>> {
+        let count = tree.len() as u64;
+        let len = FRAME_HEADER_LEN + RECORD_LEN * count;
+        let mut frame = Vec::with_capacity(8 + len as usize);
+        frame.extend_from_slice(&len.to_le_bytes());
+        frame.extend_from_slice(&step.to_le_bytes());
+        frame.extend_from_slice(&time.to_le_bytes());
+        frame.extend_from_slice(&count.to_le_bytes());
+        for p in tree.iter() {
+            let (x, y, z) = p.position();
+            let (vx, vy, vz) = p.velocity().position();
+            frame.extend_from_slice(&p.id().to_le_bytes());
+            for v in [p.mass(), x, y, z, vx, vy, vz, p.radius()] {
+                frame.extend_from_slice(&v.to_le_bytes());
+            }
+            frame.extend_from_slice(&p.species().to_le_bytes());
+            frame.push((p.pinned() as u8 * PINNED) | (p.external() as u8 * EXTERNAL));
+        }
+        self.file.write_all(&frame)?;
+        return Ok(());
+    }
+
+    fn flush(&mut self) -> Result<(), Box<dyn Error>> {
+        self.file.flush()?;
+        return Ok(());
+    }
+}
+
+impl Drop for ContainerWriter {
+    fn drop(&mut self) {
+        if let Err(e) = self.file.flush() {
+            warn!("failed to flush {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+/// Random access to the snapshots in a container, by position, step or time.
+pub struct ContainerReader {
+    file: BufReader<File>,
+    frames: Vec<FrameInfo>,
+}
+
+impl ContainerReader {
+    pub fn open(path: &Path) -> Result<ContainerReader, Box<dyn Error>> {
+        let mut file = BufReader::new(File::open(path)?);
+        let (frames, _) = scan(&mut file)?;
+        return Ok(ContainerReader {
+            file: file,
+            frames: frames,
+        });
+    }
+
+    // Every snapshot, in step order.
+    pub fn frames(&self) -> &[FrameInfo] {
+        return &self.frames;
+    }
+
+    pub fn read(&mut self, frame: &FrameInfo) -> Result<Vec<Point>, Box<dyn Error>> {
+        self.file
+            .seek(SeekFrom::Start(frame.offset + 8 + FRAME_HEADER_LEN))?;
+        let mut buf = vec![0; (RECORD_LEN * frame.particles) as usize];
+        self.file.read_exact(&mut buf)?;
+        // A corrupt or foreign file can hold any mass, which is an error rather than a panic.
+        let points = buf
+            .chunks_exact(RECORD_LEN as usize)
+            .map(|r| {
+                let f = |i: usize| f64::from_le_bytes(r[8 * i..8 * i + 8].try_into().unwrap());
+                let id = u64::from_le_bytes(r[..8].try_into().unwrap());
+                let p = Point::try_new(f(1), f(2), f(3), f(4), Vec3d::new(f(5), f(6), f(7)))
+                    .map_err(|_| NbodyError::InvalidMass { id: id, mass: f(1) })?;
+                return Ok(p
+                    .with_id(id)
+                    .with_radius(f(8))
+                    .with_species(u16::from_le_bytes([r[72], r[73]]))
+                    .with_pinned(r[74] & PINNED != 0)
+                    .with_external(r[74] & EXTERNAL != 0));
+            })
+            .collect::<Result<Vec<Point>, NbodyError>>()?;
+        return Ok(points);
+    }
+
+    // The snapshot taken at `step`, if there is one.
+    pub fn at_step(&self, step: u64) -> Option<FrameInfo> {
+        return self
+            .frames
+            .binary_search_by_key(&step, |f| f.step)
+            .ok()
+            .map(|i| self.frames[i]);
+    }
+
+    // The last snapshot taken at or before `time`, if any.
+    pub fn at_time(&self, time: f64) -> Option<FrameInfo> {
+        let after = self.frames.partition_point(|f| f.time <= time);
+        return after.checked_sub(1).map(|i| self.frames[i]);
+    }
+}
+
+// Reads the frame headers of a container from the start, skipping over the particles, and
+// returns the index and where the last complete frame ends. A frame that runs past the end of
+// the file is left out. A frame whose step isn't past every earlier one replaces them from that
+// step on, as happens when a run is resumed from an earlier checkpoint.
+fn scan<R: Read + Seek>(file: &mut R) -> Result<(Vec<FrameInfo>, u64), Box<dyn Error>> {
+    let len = file.seek(SeekFrom::End(0))?;
+    file.seek(SeekFrom::Start(0))?;
+    let mut header = [0; HEADER_LEN as usize];
+    file.read_exact(&mut header)?;
+    if &header[..8] != MAGIC {
+        return Err("not a snapshot container".into());
+    }
+    let version = u32::from_le_bytes(header[8..].try_into().unwrap());
+    if version != VERSION {
+        return Err(format!("unsupported snapshot container version {}", version).into());
+    }
+
+    let mut frames: Vec<FrameInfo> = vec![];
+    let mut offset = HEADER_LEN;
+    loop {
+        let mut head = [0; (8 + FRAME_HEADER_LEN) as usize];
+        match file.read_exact(&mut head) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e.into()),
+        }
+        let word = |i: usize| u64::from_le_bytes(head[8 * i..8 * i + 8].try_into().unwrap());
+        // A corrupt header can give lengths that overflow, which is as good as truncated.
+        let frame_len = word(0);
+        let end = (offset + 8).checked_add(frame_len);
+        let expected = RECORD_LEN
+            .checked_mul(word(3))
+            .and_then(|n| n.checked_add(FRAME_HEADER_LEN));
+        let end = match end {
+            Some(end) if end <= len && expected == Some(frame_len) => end,
+            _ => break,
+        };
+        let frame = FrameInfo {
+            step: word(1),
+            time: f64::from_bits(word(2)),
+            particles: word(3),
+            offset: offset,
+        };
+        let stale = frames.partition_point(|f| f.step < frame.step);
+        frames.truncate(stale);
+        frames.push(frame);
+        offset = end;
+        file.seek(SeekFrom::Start(offset))?;
+    }
+    if offset < len {
+        warn!("ignoring {} bytes of an incomplete snapshot", len - offset);
+    }
+    return Ok((frames, offset));
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::OpenOptions;
+    use std::io::Cursor;
+
+    use crate::geometry::bh_tree::BHTree;
+    use crate::output::container::{
+        scan, ContainerReader, ContainerWriter, FRAME_HEADER_LEN, HEADER_LEN, MAGIC, VERSION,
+    };
+    use crate::output::SnapshotWriter;
+    use crate::{Point, Vec3d};
+
+    #[test]
+    fn test_container() {
+        let path = std::env::temp_dir().join(format!("nbody-container-{}.nbc", std::process::id()));
+        let points: Vec<Point> = (0..50)
+            .map(|i| {
+                Point::new(
+                    1. + i as f64,
+                    i as f64,
+                    0.5,
+                    -2.,
+                    Vec3d::new(0., 1., i as f64),
+                )
+                .with_id(i)
+                .with_species((i % 3) as u16)
+                .with_radius(0.1)
+                .with_pinned(i == 7)
+                .with_external(i == 8)
+            })
+            .collect();
+        let tree = |n: usize| BHTree::from_points(0.5, points[..n].to_vec()).unwrap();
+
+        {
+            let mut writer = ContainerWriter::open(&path).unwrap();
+            for step in 0..5 {
+                writer
+                    .write(step * 10, step as f64, &tree(10 + step as usize))
+                    .unwrap();
+            }
+        }
+        // A crash half way through a frame, then a run resumed from step 20.
+        let len = std::fs::metadata(&path).unwrap().len();
+        OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(len - 30)
+            .unwrap();
+        {
+            let mut writer = ContainerWriter::open(&path).unwrap();
+            writer.write(20, 2., &tree(50)).unwrap();
+            writer.write(30, 3., &tree(50)).unwrap();
+        }
+
+        let mut reader = ContainerReader::open(&path).unwrap();
+        let steps: Vec<u64> = reader.frames().iter().map(|f| f.step).collect();
+        assert_eq!(steps, vec![0, 10, 20, 30]);
+        let frame = reader.at_step(10).unwrap();
+        assert_eq!(reader.at_time(1.5), Some(frame));
+        assert_eq!(reader.at_time(-1.), None);
+        assert_eq!(reader.at_step(40), None);
+        let mut read = reader.read(&frame).unwrap();
+        read.sort_by_key(|p| p.id());
+        assert_eq!(read, points[..11].to_vec());
+        let last = reader.frames()[3];
+        assert_eq!(reader.read(&last).unwrap().len(), 50);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_corrupt_frame_header() {
+        // Lengths and particle counts big enough to overflow are taken as a truncated frame.
+        for (frame_len, particles) in [(u64::MAX, 1), (u64::MAX - 10, u64::MAX / 2)] {
+            let mut bytes = MAGIC.to_vec();
+            bytes.extend_from_slice(&VERSION.to_le_bytes());
+            for word in [frame_len, 3, 0, particles] {
+                bytes.extend_from_slice(&word.to_le_bytes());
+            }
+            let (frames, end) = scan(&mut Cursor::new(bytes)).unwrap();
+            assert!(frames.is_empty());
+            assert_eq!(end, HEADER_LEN);
+        }
+    }
+
+    #[test]
+    fn test_corrupt_record() {
+        let path = std::env::temp_dir().join(format!("nbody-corrupt-{}.nbc", std::process::id()));
+        let p = Point::new(1., 0., 0., 0., Vec3d::new_zero()).with_id(4);
+        ContainerWriter::open(&path)
+            .unwrap()
+            .write(0, 0., &BHTree::from_points(0.5, vec![p]).unwrap())
+            .unwrap();
+        // The mass follows the frame's length and header and the particle's id.
+        let at = (HEADER_LEN + 8 + FRAME_HEADER_LEN + 8) as usize;
+        for mass in [-1., f64::NAN, f64::INFINITY] {
+            let mut bytes = std::fs::read(&path).unwrap();
+            bytes[at..at + 8].copy_from_slice(&f64::to_le_bytes(mass));
+            std::fs::write(&path, bytes).unwrap();
+            let mut reader = ContainerReader::open(&path).unwrap();
+            let frame = reader.frames()[0];
+            let e = reader.read(&frame).unwrap_err();
+            assert!(e.to_string().contains("particle 4"), "{}", e);
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+}
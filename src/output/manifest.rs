@@ -0,0 +1,158 @@
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::analysis::CoreSample;
+
+/// Where a run's output came from, written as `run.json` into its output directory when the run
+/// starts and rewritten with the outcome when it ends, so that output directories can still be
+/// told apart long after the fact.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RunManifest {
+    pub version: String,
+    // Commit the binary was built from, if it was built from a git checkout.
+    pub git_commit: Option<String>,
+    pub hostname: Option<String>,
+    // Seed of the initial conditions. Resumed runs have none.
+    pub seed: Option<u64>,
+    // Step the run resumed from, if it did.
+    pub resumed_from: Option<u64>,
+    // Seconds since the Unix epoch.
+    pub started_at: u64,
+    pub config: serde_json::Value,
+    pub finished_at: Option<u64>,
+    // Steps taken by the end of the run, and particles left.
+    pub steps: Option<u64>,
+    pub particles: Option<usize>,
+    // When the core collapsed, if the run watched for it and it did.
+    #[serde(default)]
+    pub core_collapse: Option<CoreSample>,
+}
+
+pub const MANIFEST_FILE: &str = "run.json";
+
+impl RunManifest {
+    pub fn new(config: serde_json::Value) -> RunManifest {
+        return RunManifest {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            git_commit: option_env!("NBODY_GIT_COMMIT").map(String::from),
+            hostname: hostname(),
+            seed: None,
+            resumed_from: None,
+            started_at: now(),
+            config: config,
+            finished_at: None,
+            steps: None,
+            particles: None,
+            core_collapse: None,
+        };
+    }
+
+    pub fn with_seed(mut self, seed: Option<u64>) -> RunManifest {
+        self.seed = seed;
+        return self;
+    }
+
+    pub fn with_resumed_from(mut self, step: Option<u64>) -> RunManifest {
+        self.resumed_from = step;
+        return self;
+    }
+
+    // Records how the run ended.
+    pub fn finish(&mut self, steps: u64, particles: usize) {
+        self.finished_at = Some(now());
+        self.steps = Some(steps);
+        self.particles = Some(particles);
+    }
+
+    // Writes `run.json` into `dir`, under a temporary name first so that it is never left half
+    // written.
+    pub fn write(&self, dir: &Path) -> Result<(), Box<dyn Error>> {
+        let path = dir.join(MANIFEST_FILE);
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_vec_pretty(self)?)?;
+        fs::rename(&tmp, &path)?;
+        return Ok(());
+    }
+
+    pub fn read(dir: &Path) -> Result<RunManifest, Box<dyn Error>> {
+        return Ok(serde_json::from_slice(&fs::read(dir.join(MANIFEST_FILE))?)?);
+    }
+}
+
+// A UTC time as `YYYYMMDD-HHMMSS`, for naming run directories.
+pub fn utc_timestamp(secs: u64) -> String {
+    let (days, secs) = (secs / 86400, secs % 86400);
+    // Howard Hinnant's days-to-civil algorithm, in eras of 400 years starting on 1 March.
+    let z = days + 719468;
+    let (era, doe) = (z / 146097, z % 146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = era * 400 + yoe + (month <= 2) as u64;
+    return format!(
+        "{:04}{:02}{:02}-{:02}{:02}{:02}",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    );
+}
+
+fn now() -> u64 {
+    return SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+}
+
+fn hostname() -> Option<String> {
+    let name = fs::read_to_string("/proc/sys/kernel/hostname")
+        .or_else(|_| fs::read_to_string("/etc/hostname"))
+        .ok()
+        .or_else(|| std::env::var("HOSTNAME").ok())
+        .or_else(|| std::env::var("COMPUTERNAME").ok())?;
+    let name = name.trim();
+    return (!name.is_empty()).then(|| name.to_string());
+}
+
+#[cfg(test)]
+mod test {
+    use crate::output::manifest::{utc_timestamp, RunManifest};
+
+    #[test]
+    fn test_utc_timestamp() {
+        assert_eq!(utc_timestamp(0), "19700101-000000");
+        assert_eq!(utc_timestamp(951782400 + 3723), "20000229-010203");
+        assert_eq!(utc_timestamp(1792164615), "20261016-153015");
+    }
+
+    #[test]
+    fn test_manifest_round_trip() {
+        let dir = std::env::temp_dir().join(format!("nbody-manifest-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut manifest = RunManifest::new(serde_json::json!({ "theta": 0.5 }))
+            .with_seed(Some(7))
+            .with_resumed_from(None);
+        manifest.write(&dir).unwrap();
+        let started = RunManifest::read(&dir).unwrap();
+        assert_eq!(started, manifest);
+        assert_eq!(started.version, env!("CARGO_PKG_VERSION"));
+        assert_eq!(started.finished_at, None);
+
+        manifest.finish(100, 42);
+        manifest.write(&dir).unwrap();
+        let finished = RunManifest::read(&dir).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(finished.steps, Some(100));
+        assert_eq!(finished.particles, Some(42));
+        assert!(finished.finished_at.unwrap() >= finished.started_at);
+        assert_eq!(finished.config["theta"], 0.5);
+    }
+}
@@ -0,0 +1,136 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::{File, OpenOptions};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::geometry::bh_tree::BHTree;
+use crate::output::SnapshotWriter;
+
+/// One row of an orbit file: where a particle was at the end of a step, in SI units apart from the
+/// time, which is in the units the run uses.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct OrbitRecord {
+    pub step: u64,
+    pub time: f64,
+    pub x: f64,
+    pub y: f64,
+    pub z: f64,
+    pub vx: f64,
+    pub vy: f64,
+    pub vz: f64,
+}
+
+// Orbit files are named `orbit-<id>.csv`.
+pub const ORBIT_PREFIX: &str = "orbit-";
+
+pub fn orbit_path(dir: &Path, id: u64) -> PathBuf {
+    return dir.join(format!("{}{}.csv", ORBIT_PREFIX, id));
+}
+
+/// Follows a handful of particles by id, appending a row to each one's orbit file every time it
+/// is written to. Meant to be written every step, unlike snapshots, so that single orbits can be
+/// studied at full time resolution without storing every particle.
+///
+/// Existing orbit files are appended to, so a resumed run carries on where it left off. Once a
+/// particle is gone, e.g. merged or removed as an escaper, its file just stops.
+pub struct OrbitWriter {
+    files: HashMap<u64, csv::Writer<File>>,
+}
+
+impl OrbitWriter {
+    pub fn new(dir: &Path, ids: &[u64]) -> Result<OrbitWriter, Box<dyn Error>> {
+        let mut files = HashMap::new();
+        for &id in ids {
+            let path = orbit_path(dir, id);
+            let headers = !path.exists();
+            let file = OpenOptions::new().create(true).append(true).open(&path)?;
+            let writer = csv::WriterBuilder::new()
+                .has_headers(headers)
+                .from_writer(file);
+            files.insert(id, writer);
+        }
+        return Ok(OrbitWriter { files: files });
+    }
+}
+
+impl SnapshotWriter for OrbitWriter {
+    fn write(&mut self, step: u64, time: f64, tree: &BHTree) -> Result<(), Box<dyn Error>> {
+        for p in tree.iter() {
+            let file = match self.files.get_mut(&p.id()) {
+                Some(file) => file,
+                None => continue,
+            };
+            let (x, y, z) = p.position();
+            let (vx, vy, vz) = p.velocity().position();
+            file.serialize(OrbitRecord {
+                step: step,
+                time: time,
+                x: x,
+                y: y,
+                z: z,
+                vx: vx,
+                vy: vy,
+                vz: vz,
+            })?;
+        }
+        return Ok(());
+    }
+
+    fn flush(&mut self) -> Result<(), Box<dyn Error>> {
+        for file in self.files.values_mut() {
+            file.flush()?;
+        }
+        return Ok(());
+    }
+}
+
+// Every row of the orbit file for `id` in `dir`.
+pub fn read_orbit(dir: &Path, id: u64) -> Result<Vec<OrbitRecord>, Box<dyn Error>> {
+    let mut reader = csv::Reader::from_path(orbit_path(dir, id))?;
+    return Ok(reader.deserialize().collect::<Result<Vec<_>, _>>()?);
+}
+
+#[cfg(test)]
+mod test {
+    use crate::geometry::bh_tree::BHTree;
+    use crate::output::orbits::{read_orbit, OrbitWriter};
+    use crate::output::SnapshotWriter;
+    use crate::{Point, Vec3d};
+
+    #[test]
+    fn test_orbit_writer() {
+        let dir = std::env::temp_dir().join(format!("nbody-orbits-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let points = vec![
+            Point::new(1e20, 0., 0., 0., Vec3d::new_zero()).with_id(0),
+            Point::new(1., 1e6, 0., 0., Vec3d::new(0., 1e2, 0.)).with_id(1),
+            Point::new(1., -1e6, 0., 0., Vec3d::new(0., -1e2, 0.)).with_id(2),
+        ];
+        let mut tree = BHTree::from_points(0.5, points).unwrap();
+
+        let mut orbits = OrbitWriter::new(&dir, &[1, 7]).unwrap();
+        for step in 0..3 {
+            orbits.write(step, step as f64, &tree).unwrap();
+            tree = tree.next(1.).unwrap();
+        }
+        drop(orbits);
+        // Reopening appends, without a second header.
+        let mut orbits = OrbitWriter::new(&dir, &[1]).unwrap();
+        orbits.write(3, 3., &tree).unwrap();
+        orbits.flush().unwrap();
+
+        let rows = read_orbit(&dir, 1).unwrap();
+        let missing = read_orbit(&dir, 7).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(
+            rows.iter().map(|r| r.step).collect::<Vec<u64>>(),
+            vec![0, 1, 2, 3]
+        );
+        assert_eq!((rows[0].x, rows[0].vy), (1e6, 1e2));
+        assert!(rows[3].y > rows[1].y && rows[1].y > 0.);
+        assert!(missing.is_empty());
+    }
+}
@@ -0,0 +1,315 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::geometry::bh_tree::BHTree;
+use crate::output::SnapshotWriter;
+use crate::{Point, Vec3d};
+
+/// Orthographic camera looking at `center`. It starts looking down the z-axis, turns by `yaw`
+/// about z and then tilts by `pitch` about the screen's horizontal axis, both in radians. `extent`
+/// is the width of the view in simulation units.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Camera {
+    pub center: Vec3d,
+    pub extent: f64,
+    pub yaw: f64,
+    pub pitch: f64,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Camera {
+    // The camera a fraction `t` of the way from `self` to `other`. The extent changes
+    // geometrically so a zoom runs at a steady rate.
+    pub fn lerp(&self, other: &Camera, t: f64) -> Camera {
+        let mix = |a: f64, b: f64| a + t * (b - a);
+        return Camera {
+            center: self.center + t * (other.center - self.center),
+            extent: self.extent * (other.extent / self.extent).powf(t),
+            yaw: mix(self.yaw, other.yaw),
+            pitch: mix(self.pitch, other.pitch),
+            width: self.width,
+            height: self.height,
+        };
+    }
+
+    // Pixel coordinates of `p`, which may be off screen.
+    fn project(&self, p: &Point) -> (f64, f64) {
+        let (x, y, z) = p.position();
+        let (x, y, z) = (Vec3d::new(x, y, z) - self.center).position();
+        let (sy, cy) = self.yaw.sin_cos();
+        let (x, y) = (cy * x + sy * y, -sy * x + cy * y);
+        let (sp, cp) = self.pitch.sin_cos();
+        let y = cp * y - sp * z;
+
+        let scale = self.width as f64 / self.extent;
+        let px = self.width as f64 / 2. + x * scale;
+        let py = self.height as f64 / 2. - y * scale;
+        return (px, py);
+    }
+}
+
+/// The per-particle quantity mapped to colour.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum ColorBy {
+    Speed,
+    Mass,
+    // SPH density over the given number of neighbours.
+    Density(usize),
+}
+
+/// An RGB image.
+pub struct Frame {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<u8>,
+}
+
+impl Frame {
+    pub fn write_png(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        let mut encoder =
+            png::Encoder::new(BufWriter::new(File::create(path)?), self.width, self.height);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&self.pixels)?;
+        return Ok(());
+    }
+}
+
+// A dark-to-bright ramp through purple and orange, for t in [0, 1].
+fn colormap(t: f64) -> [u8; 3] {
+    const STOPS: [[f64; 3]; 4] = [
+        [40., 10., 80.],
+        [150., 30., 130.],
+        [240., 110., 40.],
+        [255., 250., 180.],
+    ];
+    let t = t.clamp(0., 1.) * (STOPS.len() - 1) as f64;
+    let i = (t.floor() as usize).min(STOPS.len() - 2);
+    let f = t - i as f64;
+    let mut rgb = [0u8; 3];
+    for (c, out) in rgb.iter_mut().enumerate() {
+        *out = (STOPS[i][c] + f * (STOPS[i + 1][c] - STOPS[i][c])).round() as u8;
+    }
+    return rgb;
+}
+
+/// Rasterizes snapshots as discs of `point_size` pixels on a black background.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Renderer {
+    pub camera: Camera,
+    pub point_size: f64,
+    pub color_by: ColorBy,
+}
+
+impl Renderer {
+    pub fn render(&self, tree: &BHTree) -> Frame {
+        let points = tree.points();
+        // Mass and density span orders of magnitude, so they're coloured on a log scale.
+        let values: Vec<f64> = match self.color_by {
+            ColorBy::Speed => points.iter().map(|p| p.velocity().magnitude()).collect(),
+            ColorBy::Mass => points
+                .iter()
+                .map(|p| p.mass().max(f64::MIN_POSITIVE).log10())
+                .collect(),
+            ColorBy::Density(k) => tree
+                .densities(k)
+                .iter()
+                .map(|rho| rho.max(f64::MIN_POSITIVE).log10())
+                .collect(),
+        };
+        let lo = values.iter().copied().fold(f64::INFINITY, f64::min);
+        let hi = values.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+        let range = if hi > lo { hi - lo } else { 1. };
+
+        let (width, height) = (self.camera.width, self.camera.height);
+        // In usize, since the product can overflow u32 for large frames.
+        let mut pixels = vec![0u8; width as usize * height as usize * 3];
+        let r = self.point_size.max(0.5);
+        for (p, value) in points.iter().zip(values) {
+            let (px, py) = self.camera.project(p);
+            let color = colormap((value - lo) / range);
+            let x0 = (px - r).floor().max(0.) as i64;
+            let x1 = (px + r).ceil().min(width as f64 - 1.) as i64;
+            let y0 = (py - r).floor().max(0.) as i64;
+            let y1 = (py + r).ceil().min(height as f64 - 1.) as i64;
+            for y in y0..=y1 {
+                for x in x0..=x1 {
+                    let (dx, dy) = (x as f64 + 0.5 - px, y as f64 + 0.5 - py);
+                    if dx * dx + dy * dy > r * r {
+                        continue;
+                    }
+                    let at = (y as usize * width as usize + x as usize) * 3;
+                    pixels[at..at + 3].copy_from_slice(&color);
+                }
+            }
+        }
+        return Frame {
+            width: width,
+            height: height,
+            pixels: pixels,
+        };
+    }
+}
+
+/// Writes every snapshot as `frame-<step>.png` in a directory.
+pub struct PngWriter {
+    dir: PathBuf,
+    renderer: Renderer,
+}
+
+impl PngWriter {
+    pub fn new(dir: PathBuf, renderer: Renderer) -> PngWriter {
+        return PngWriter {
+            dir: dir,
+            renderer: renderer,
+        };
+    }
+}
+
+impl SnapshotWriter for PngWriter {
+    fn write(&mut self, step: u64, _time: f64, tree: &BHTree) -> Result<(), Box<dyn Error>> {
+        let path = self.dir.join(format!("frame-{}.png", step));
+        return self.renderer.render(tree).write_png(&path);
+    }
+}
+
+/// Pipes rendered frames to ffmpeg, which must be on the PATH, to encode a video. The video is
+/// finalized when the writer is dropped.
+pub struct VideoWriter {
+    renderer: Renderer,
+    ffmpeg: Child,
+}
+
+impl VideoWriter {
+    pub fn new(path: &Path, renderer: Renderer, fps: u32) -> Result<VideoWriter, Box<dyn Error>> {
+        let size = format!("{}x{}", renderer.camera.width, renderer.camera.height);
+        let ffmpeg = Command::new("ffmpeg")
+            .args([
+                "-y",
+                "-loglevel",
+                "error",
+                "-f",
+                "rawvideo",
+                "-pix_fmt",
+                "rgb24",
+            ])
+            .args(["-s", &size, "-r", &fps.to_string(), "-i", "-"])
+            .args([
+                "-pix_fmt",
+                "yuv420p",
+                "-vf",
+                "pad=ceil(iw/2)*2:ceil(ih/2)*2",
+            ])
+            .arg(path)
+            .stdin(Stdio::piped())
+            .spawn()
+            .map_err(|e| format!("failed to start ffmpeg: {}", e))?;
+        info!("encoding frames to {}", path.display());
+        return Ok(VideoWriter {
+            renderer: renderer,
+            ffmpeg: ffmpeg,
+        });
+    }
+
+    pub fn write_frame(&mut self, frame: &Frame) -> Result<(), Box<dyn Error>> {
+        let stdin = self.ffmpeg.stdin.as_mut().ok_or("ffmpeg stdin is closed")?;
+        stdin.write_all(&frame.pixels)?;
+        return Ok(());
+    }
+}
+
+impl SnapshotWriter for VideoWriter {
+    fn write(&mut self, _step: u64, _time: f64, tree: &BHTree) -> Result<(), Box<dyn Error>> {
+        let frame = self.renderer.render(tree);
+        return self.write_frame(&frame);
+    }
+}
+
+impl Drop for VideoWriter {
+    fn drop(&mut self) {
+        // Closing stdin tells ffmpeg there are no more frames.
+        drop(self.ffmpeg.stdin.take());
+        match self.ffmpeg.wait() {
+            Ok(status) if !status.success() => warn!("ffmpeg exited with {}", status),
+            Err(e) => warn!("failed to wait for ffmpeg: {}", e),
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::geometry::bh_tree::BHTree;
+    use crate::output::render::{Camera, ColorBy, Renderer};
+    use crate::{Point, Vec3d};
+
+    #[test]
+    fn test_render() {
+        let points = vec![
+            Point::new(1., 0., 0., 0., Vec3d::new_zero()).with_id(0),
+            Point::new(100., 5., 0., 0., Vec3d::new_zero()).with_id(1),
+        ];
+        let renderer = Renderer {
+            camera: Camera {
+                center: Vec3d::new_zero(),
+                extent: 20.,
+                yaw: 0.,
+                pitch: 0.,
+                width: 40,
+                height: 20,
+            },
+            point_size: 1.,
+            color_by: ColorBy::Mass,
+        };
+        let frame = renderer.render(&BHTree::from_points(0.5, points).unwrap());
+        assert_eq!(frame.pixels.len(), 40 * 20 * 3);
+
+        let pixel = |x: u32, y: u32| {
+            let at = ((y * 40 + x) * 3) as usize;
+            return [frame.pixels[at], frame.pixels[at + 1], frame.pixels[at + 2]];
+        };
+        // The origin lands in the middle, and x = 5 ten pixels to its right. The heavier particle
+        // is brighter.
+        assert_ne!(pixel(20, 10), [0, 0, 0]);
+        assert_ne!(pixel(30, 10), [0, 0, 0]);
+        assert!(pixel(30, 10)[1] > pixel(20, 10)[1]);
+        assert_eq!(pixel(0, 0), [0, 0, 0]);
+
+        let path = std::env::temp_dir().join(format!("nbody-frame-{}.png", std::process::id()));
+        frame.write_png(&path).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(&bytes[1..4], b"PNG");
+    }
+
+    #[test]
+    fn test_camera_path() {
+        let start = Camera {
+            center: Vec3d::new_zero(),
+            extent: 100.,
+            yaw: 0.,
+            pitch: 0.,
+            width: 10,
+            height: 10,
+        };
+        let end = Camera {
+            center: Vec3d::new(2., 0., 0.),
+            extent: 1.,
+            yaw: 1.,
+            ..start
+        };
+        assert_eq!(start.lerp(&end, 0.), start);
+        let mid = start.lerp(&end, 0.5);
+        assert_eq!(mid.center, Vec3d::new(1., 0., 0.));
+        assert!((mid.extent - 10.).abs() < 1e-12);
+        assert_eq!(mid.yaw, 0.5);
+    }
+}
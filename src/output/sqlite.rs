@@ -0,0 +1,129 @@
+use std::error::Error;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::info;
+use rusqlite::{params, Connection};
+
+use crate::geometry::bh_tree::BHTree;
+use crate::output::SnapshotWriter;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS runs (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    started_at INTEGER NOT NULL,
+    config TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS particles (
+    run_id INTEGER NOT NULL REFERENCES runs(id),
+    step INTEGER NOT NULL,
+    time REAL NOT NULL,
+    particle_id INTEGER NOT NULL,
+    mass REAL NOT NULL,
+    x REAL NOT NULL,
+    y REAL NOT NULL,
+    z REAL NOT NULL,
+    vx REAL NOT NULL,
+    vy REAL NOT NULL,
+    vz REAL NOT NULL,
+    PRIMARY KEY (run_id, step, particle_id)
+);
+";
+
+/// Appends snapshots to an SQLite database so histories can be queried with SQL. Each run gets a
+/// row in `runs` holding its configuration, and every snapshot is inserted in one transaction.
+pub struct SqliteWriter {
+    conn: Connection,
+    run_id: i64,
+}
+
+impl SqliteWriter {
+    pub fn new(path: &Path, config: &str) -> Result<SqliteWriter, Box<dyn Error>> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(SCHEMA)?;
+
+        let started_at = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+        conn.execute(
+            "INSERT INTO runs (started_at, config) VALUES (?1, ?2)",
+            params![started_at, config],
+        )?;
+        let run_id = conn.last_insert_rowid();
+        info!("writing snapshots to {} as run {}", path.display(), run_id);
+
+        return Ok(SqliteWriter {
+            conn: conn,
+            run_id: run_id,
+        });
+    }
+
+    pub fn run_id(&self) -> i64 {
+        return self.run_id;
+    }
+}
+
+impl SnapshotWriter for SqliteWriter {
+    fn write(&mut self, step: u64, time: f64, tree: &BHTree) -> Result<(), Box<dyn Error>> {
+        let tx = self.conn.transaction()?;
+        {
+            let mut stmt = tx.prepare_cached(
+                "INSERT INTO particles (run_id, step, time, particle_id, mass, x, y, z, vx, vy, vz)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+            )?;
+            for p in tree.iter() {
+                let (x, y, z) = p.position();
+                let (vx, vy, vz) = p.velocity().position();
+                stmt.execute(params![
+                    self.run_id,
+                    step as i64,
+                    time,
+                    p.id() as i64,
+                    p.mass(),
+                    x,
+                    y,
+                    z,
+                    vx,
+                    vy,
+                    vz
+                ])?;
+            }
+        }
+        tx.commit()?;
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::geometry::bh_tree::BHTree;
+    use crate::output::sqlite::SqliteWriter;
+    use crate::output::SnapshotWriter;
+    use crate::{Point, Vec3d};
+
+    #[test]
+    fn test_write_snapshots() {
+        let dir = std::env::temp_dir().join(format!("nbody-sqlite-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("run.db");
+
+        let mut bht = BHTree::new(0.5, 5., 0., 0., 0.);
+        bht.add_point(Point::new(1., 1., 1., 1., Vec3d::new_zero()).with_id(1))
+            .unwrap();
+        bht.add_point(Point::new(1., 3., 3., 3., Vec3d::new_zero()).with_id(2))
+            .unwrap();
+
+        let mut w = SqliteWriter::new(&path, "{}").unwrap();
+        w.write(0, 0., &bht).unwrap();
+        w.write(1, 0.5, &bht).unwrap();
+
+        let count: i64 = w
+            .conn
+            .query_row(
+                "SELECT COUNT(*) FROM particles WHERE run_id = ?1",
+                [w.run_id()],
+                |r| r.get(0),
+            )
+            .unwrap();
+        assert_eq!(count, 4);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
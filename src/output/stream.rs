@@ -0,0 +1,212 @@
+use std::error::Error;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use log::{debug, info, warn};
+use tungstenite::{Message, WebSocket};
+
+use crate::geometry::bh_tree::BHTree;
+use crate::output::SnapshotWriter;
+
+const VIEWER: &str = include_str!("viewer.html");
+
+// A client that stops reading for this long is dropped rather than stalling the simulation.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+// How long a new connection gets to send its request and finish the WebSocket handshake.
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Serves a minimal browser viewer over HTTP and streams each snapshot to every connected
+/// WebSocket client.
+pub struct StreamServer {
+    addr: SocketAddr,
+    // Only every `stride`th particle is sent, to keep messages small for big runs.
+    stride: usize,
+    clients: Arc<Mutex<Vec<WebSocket<TcpStream>>>>,
+}
+
+impl StreamServer {
+    pub fn bind(addr: &str, stride: usize) -> Result<StreamServer, Box<dyn Error>> {
+        let listener = TcpListener::bind(addr)?;
+        let addr = listener.local_addr()?;
+        let clients = Arc::new(Mutex::new(vec![]));
+        let accepted = clients.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let clients = accepted.clone();
+                thread::spawn(move || handle(stream, clients));
+            }
+        });
+        info!("serving the viewer on http://{}", addr);
+        return Ok(StreamServer {
+            addr: addr,
+            stride: stride.max(1),
+            clients: clients,
+        });
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        return self.addr;
+    }
+
+    pub fn clients(&self) -> usize {
+        return self.clients.lock().unwrap().len();
+    }
+}
+
+// Upgrades WebSocket requests and answers anything else with the viewer page.
+fn handle(mut stream: TcpStream, clients: Arc<Mutex<Vec<WebSocket<TcpStream>>>>) {
+    if let Err(e) = stream.set_read_timeout(Some(READ_TIMEOUT)) {
+        debug!("dropping connection: {}", e);
+        return;
+    }
+    let mut head = [0u8; 4096];
+    let n = match stream.peek(&mut head) {
+        Ok(n) => n,
+        Err(e) => {
+            debug!("dropping connection: {}", e);
+            return;
+        }
+    };
+    let request = String::from_utf8_lossy(&head[..n]).to_ascii_lowercase();
+    if request.contains("upgrade: websocket") {
+        let _ = stream.set_write_timeout(Some(WRITE_TIMEOUT));
+        match tungstenite::accept(stream) {
+            Ok(ws) => clients.lock().unwrap().push(ws),
+            Err(e) => warn!("websocket handshake failed: {}", e),
+        }
+        return;
+    }
+
+    let _ = stream.read(&mut head);
+    let response = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: text/html; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        VIEWER.len(),
+        VIEWER
+    );
+    if let Err(e) = stream.write_all(response.as_bytes()) {
+        debug!("failed to send the viewer: {}", e);
+    }
+}
+
+// Packs every `stride`th particle's position into the little-endian message the viewer reads:
+// step (u64), time (f64) and count (u32), then x, y, z as f32 per particle.
+pub fn pack(step: u64, time: f64, tree: &BHTree, stride: usize) -> Vec<u8> {
+    let sent: Vec<_> = tree.iter().step_by(stride.max(1)).collect();
+    let mut msg = Vec::with_capacity(20 + 12 * sent.len());
+    msg.extend_from_slice(&step.to_le_bytes());
+    msg.extend_from_slice(&time.to_le_bytes());
+    msg.extend_from_slice(&(sent.len() as u32).to_le_bytes());
+    for p in sent {
+        let (x, y, z) = p.position();
+        for c in [x, y, z] {
+            msg.extend_from_slice(&(c as f32).to_le_bytes());
+        }
+    }
+    return msg;
+}
+
+// Reads whatever a client has sent without waiting for more, so that its pings are answered and
+// its close frames honoured. False once the connection is closed or broken.
+fn drain(ws: &mut WebSocket<TcpStream>) -> bool {
+    if let Err(e) = ws.get_mut().set_nonblocking(true) {
+        debug!("dropping viewer: {}", e);
+        return false;
+    }
+    let open = loop {
+        match ws.read() {
+            Ok(_) => continue,
+            Err(tungstenite::Error::Io(e)) if e.kind() == ErrorKind::WouldBlock => break true,
+            Err(e) => {
+                debug!("dropping viewer: {}", e);
+                break false;
+            }
+        }
+    };
+    return open && ws.get_mut().set_nonblocking(false).is_ok();
+}
+
+impl SnapshotWriter for StreamServer {
+    fn write(&mut self, step: u64, time: f64, tree: &BHTree) -> Result<(), Box<dyn Error>> {
+        let mut clients = self.clients.lock().unwrap();
+        if clients.is_empty() {
+            return Ok(());
+        }
+        let msg = pack(step, time, tree, self.stride);
+        clients.retain_mut(drain);
+        clients.retain_mut(|ws| match ws.send(Message::Binary(msg.clone())) {
+            Ok(()) => true,
+            Err(e) => {
+                debug!("dropping viewer: {}", e);
+                false
+            }
+        });
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::thread;
+    use std::time::Duration;
+
+    use crate::geometry::bh_tree::BHTree;
+    use crate::output::stream::{StreamServer, READ_TIMEOUT};
+    use crate::output::SnapshotWriter;
+    use crate::{Point, Vec3d};
+
+    #[test]
+    fn test_viewer_and_stream() {
+        let points: Vec<Point> = (0..10)
+            .map(|i| Point::new(1., i as f64, 0., 0., Vec3d::new_zero()).with_id(i))
+            .collect();
+        let tree = BHTree::from_points(0.5, points).unwrap();
+        let mut server = StreamServer::bind("127.0.0.1:0", 3).unwrap();
+        let addr = server.local_addr();
+
+        let mut http = TcpStream::connect(addr).unwrap();
+        http.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+        let mut page = String::new();
+        http.read_to_string(&mut page).unwrap();
+        assert!(page.starts_with("HTTP/1.1 200 OK"));
+        assert!(page.contains("new WebSocket"));
+
+        let stream = TcpStream::connect(addr).unwrap();
+        let (mut ws, _) = tungstenite::client(format!("ws://{}/ws", addr), stream).unwrap();
+        for _ in 0..100 {
+            if server.clients() == 1 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(server.clients(), 1);
+
+        server.write(7, 0.5, &tree).unwrap();
+        let msg = ws.read().unwrap().into_data();
+        assert_eq!(u64::from_le_bytes(msg[0..8].try_into().unwrap()), 7);
+        assert_eq!(f64::from_le_bytes(msg[8..16].try_into().unwrap()), 0.5);
+        // Every third of ten particles.
+        assert_eq!(u32::from_le_bytes(msg[16..20].try_into().unwrap()), 4);
+        assert_eq!(msg.len(), 20 + 4 * 12);
+
+        // A client that closes is dropped at the next snapshot.
+        ws.close(None).unwrap();
+        ws.flush().unwrap();
+        thread::sleep(Duration::from_millis(50));
+        server.write(8, 0.6, &tree).unwrap();
+        assert_eq!(server.clients(), 0);
+
+        // One that connects and never sends a request is hung up on.
+        let mut silent = TcpStream::connect(addr).unwrap();
+        silent
+            .set_read_timeout(Some(READ_TIMEOUT + Duration::from_secs(5)))
+            .unwrap();
+        assert_eq!(silent.read(&mut [0; 16]).unwrap(), 0);
+    }
+}
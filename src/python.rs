@@ -0,0 +1,156 @@
+use numpy::ndarray::Array2;
+use numpy::{IntoPyArray, PyArray1, PyArray2, PyReadonlyArray1, PyReadonlyArray2};
+use pyo3::exceptions::{PyRuntimeError, PyValueError};
+use pyo3::prelude::*;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+
+use crate::geometry::force::ForceLaw;
+use crate::geometry::vec3d::G;
+use crate::ic::{from_arrays, UniformSphere};
+use crate::simulation::{Simulation, SimulationBuilder};
+use crate::{NbodyError, Point};
+
+/// A `Simulation` for Python. Quantities are in SI unless a different `g` is passed. Positions and
+/// velocities come back as new (n, 3) NumPy arrays ordered by particle id; the tree doesn't keep
+/// particles in one contiguous buffer, so they are copies rather than views.
+#[pyclass(name = "Simulation", module = "nbody", unsendable)]
+pub struct PySimulation {
+    sim: Simulation,
+}
+
+#[pymethods]
+impl PySimulation {
+    #[new]
+    #[pyo3(signature = (masses, positions, velocities, dt, g = G, theta = 0.5))]
+    fn new(
+        masses: PyReadonlyArray1<'_, f64>,
+        positions: PyReadonlyArray2<'_, f64>,
+        velocities: PyReadonlyArray2<'_, f64>,
+        dt: f64,
+        g: f64,
+        theta: f64,
+    ) -> PyResult<PySimulation> {
+        let flat = |a: &PyReadonlyArray2<'_, f64>| a.as_array().iter().copied().collect::<Vec<_>>();
+        if positions.as_array().ncols() != 3 || velocities.as_array().ncols() != 3 {
+            return Err(PyValueError::new_err(
+                "positions and velocities must have shape (n, 3)",
+            ));
+        }
+        let masses: Vec<f64> = masses.as_array().iter().copied().collect();
+        let points = from_arrays(&masses, &flat(&positions), &flat(&velocities))
+            .map_err(PyValueError::new_err)?;
+        return Ok(PySimulation {
+            sim: build(points, dt, g, theta)?,
+        });
+    }
+
+    // `n` equal masses at rest, spread uniformly through a sphere. The same seed always gives the
+    // same particles.
+    #[staticmethod]
+    #[pyo3(signature = (n, radius, mass, dt, g = G, theta = 0.5, seed = 0))]
+    fn uniform_sphere(
+        n: u64,
+        radius: f64,
+        mass: f64,
+        dt: f64,
+        g: f64,
+        theta: f64,
+        seed: u64,
+    ) -> PyResult<PySimulation> {
+        let sphere = UniformSphere {
+            n: n,
+            radius: radius,
+            mass_base: mass,
+            mass_mean: 1.,
+            mass_stddev: 0.,
+        };
+        let points = sphere.generate(&mut StdRng::seed_from_u64(seed));
+        return Ok(PySimulation {
+            sim: build(points, dt, g, theta)?,
+        });
+    }
+
+    #[pyo3(signature = (steps = 1))]
+    fn step(&mut self, steps: u64) -> PyResult<()> {
+        for _ in 0..steps {
+            self.sim.step().map_err(runtime_error)?;
+        }
+        return Ok(());
+    }
+
+    #[getter]
+    fn steps(&self) -> u64 {
+        return self.sim.steps();
+    }
+
+    #[getter]
+    fn time(&self) -> f64 {
+        return self.sim.time();
+    }
+
+    #[getter]
+    fn dt(&self) -> f64 {
+        return self.sim.dt();
+    }
+
+    #[setter]
+    fn set_dt(&mut self, dt: f64) {
+        self.sim.set_dt(dt);
+    }
+
+    fn __len__(&self) -> usize {
+        return self.sim.tree().len();
+    }
+
+    fn ids<'py>(&self, py: Python<'py>) -> Bound<'py, PyArray1<u64>> {
+        let ids: Vec<u64> = self.points().iter().map(|p| p.id()).collect();
+        return ids.into_pyarray(py);
+    }
+
+    fn masses<'py>(&self, py: Python<'py>) -> Bound<'py, PyArray1<f64>> {
+        let masses: Vec<f64> = self.points().iter().map(|p| p.mass()).collect();
+        return masses.into_pyarray(py);
+    }
+
+    fn positions<'py>(&self, py: Python<'py>) -> Bound<'py, PyArray2<f64>> {
+        return rows(self.points().iter().map(|p| p.position())).into_pyarray(py);
+    }
+
+    fn velocities<'py>(&self, py: Python<'py>) -> Bound<'py, PyArray2<f64>> {
+        return rows(self.points().iter().map(|p| p.velocity().position())).into_pyarray(py);
+    }
+}
+
+impl PySimulation {
+    fn points(&self) -> Vec<Point> {
+        let mut points = self.sim.tree().points();
+        points.sort_by_key(|p| p.id());
+        return points;
+    }
+}
+
+fn build(points: Vec<Point>, dt: f64, g: f64, theta: f64) -> PyResult<Simulation> {
+    return SimulationBuilder::new()
+        .theta(theta)
+        .dt(dt)
+        .force_law(ForceLaw::newtonian(g))
+        .initial_conditions(points)
+        .build(&mut StdRng::seed_from_u64(0))
+        .map_err(runtime_error);
+}
+
+fn runtime_error(e: NbodyError) -> PyErr {
+    return PyRuntimeError::new_err(e.to_string());
+}
+
+fn rows<I: Iterator<Item = (f64, f64, f64)>>(values: I) -> Array2<f64> {
+    let flat: Vec<f64> = values.flat_map(|(x, y, z)| [x, y, z]).collect();
+    return Array2::from_shape_vec((flat.len() / 3, 3), flat).unwrap();
+}
+
+#[pymodule]
+fn nbody(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PySimulation>()?;
+    return Ok(());
+}
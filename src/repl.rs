@@ -0,0 +1,247 @@
+use std::error::Error;
+use std::io::{BufRead, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use crate::simulation::Simulation;
+
+const HELP: &str = "\
+step [N]       take N steps (default 1)
+run [N]        step until N steps are taken or Ctrl-C pauses the run (default no limit)
+status         step, time, dt, theta and particle count
+particle ID    mass, position and velocity of the particle with this id
+energy         kinetic, potential and total energy and the virial ratio
+dt [DT]        show or set the time step
+theta [THETA]  show or set the opening angle
+help           this message
+quit           leave (or end the input)";
+
+/// What a console command asks for.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Reply {
+    Text(String),
+    Quit,
+}
+
+/// A line-based console for stepping through a simulation by hand and poking at it between steps,
+/// for debugging pathological configurations. Everything it reads and prints is in SI.
+///
+/// The simulation only advances when asked to. `run` keeps going until `interrupt` is set, e.g.
+/// by a Ctrl-C handler, which pauses it and returns to the prompt.
+pub struct Repl {
+    interrupt: Arc<AtomicBool>,
+}
+
+fn parse<T: std::str::FromStr>(arg: Option<&str>, default: T) -> Result<T, String> {
+    return match arg {
+        Some(s) => s.parse().map_err(|_| format!("can't parse {:?}", s)),
+        None => Ok(default),
+    };
+}
+
+impl Repl {
+    pub fn new(interrupt: Arc<AtomicBool>) -> Repl {
+        return Repl {
+            interrupt: interrupt,
+        };
+    }
+
+    // Carries out one command line. Errors are meant to be shown to the user, who can try again.
+    pub fn execute(&mut self, sim: &mut Simulation, line: &str) -> Result<Reply, String> {
+        let mut words = line.split_whitespace();
+        let command = match words.next() {
+            Some(command) => command,
+            None => return Ok(Reply::Text(String::new())),
+        };
+        let arg = words.next();
+        let text = match command {
+            "step" | "s" => {
+                let n = parse(arg, 1u64)?;
+                let taken = sim.run(n).map_err(|e| e.to_string())?;
+                format!("took {} steps, now at step {}", taken, sim.steps())
+            }
+            "run" | "r" => {
+                let n = parse(arg, u64::MAX)?;
+                self.interrupt.store(false, Ordering::SeqCst);
+                let mut taken = 0;
+                while taken < n && !sim.stopped() && !self.interrupt.load(Ordering::SeqCst) {
+                    sim.step().map_err(|e| e.to_string())?;
+                    taken += 1;
+                }
+                let paused = match taken < n && !sim.stopped() {
+                    true => ", paused",
+                    false => "",
+                };
+                format!(
+                    "took {} steps{}, now at step {}",
+                    taken,
+                    paused,
+                    sim.steps()
+                )
+            }
+            "status" => format!(
+                "step {}, t = {}, dt = {}, theta = {}, {} particles",
+                sim.steps(),
+                sim.time(),
+                sim.dt(),
+                sim.tree().theta(),
+                sim.tree().len()
+            ),
+            "particle" | "p" => {
+                let id: u64 = parse(Some(arg.ok_or("which particle?")?), 0)?;
+                let p = sim
+                    .tree()
+                    .iter()
+                    .find(|p| p.id() == id)
+                    .ok_or(format!("no particle {}", id))?;
+                let (x, y, z) = p.position();
+                let (vx, vy, vz) = p.velocity().position();
+                format!(
+                    "particle {}: m = {}, x = ({}, {}, {}), v = ({}, {}, {}){}",
+                    id,
+                    p.mass(),
+                    x,
+                    y,
+                    z,
+                    vx,
+                    vy,
+                    vz,
+                    if p.pinned() { ", pinned" } else { "" }
+                )
+            }
+            "energy" | "e" => {
+                let tree = sim.tree();
+                let kinetic: f64 = tree.iter().map(|p| p.kinetic_energy()).sum();
+                let potential: f64 = tree
+                    .iter()
+                    .zip(tree.potentials())
+                    .map(|(p, phi)| 0.5 * p.mass() * phi)
+                    .sum();
+                format!(
+                    "K = {}, W = {}, E = {}, 2K/|W| = {}",
+                    kinetic,
+                    potential,
+                    kinetic + potential,
+                    2. * kinetic / potential.abs()
+                )
+            }
+            "dt" => {
+                if let Some(dt) = arg {
+                    let dt: f64 = parse(Some(dt), 0.)?;
+                    if !dt.is_finite() || dt <= 0. {
+                        return Err("dt must be positive and finite".to_string());
+                    }
+                    sim.set_dt(dt);
+                }
+                format!("dt = {}", sim.dt())
+            }
+            "theta" => {
+                if let Some(theta) = arg {
+                    let theta: f64 = parse(Some(theta), 0.)?;
+                    if !theta.is_finite() || theta < 0. {
+                        return Err("theta must be finite and not negative".to_string());
+                    }
+                    sim.set_theta(theta).map_err(|e| e.to_string())?;
+                }
+                format!("theta = {}", sim.tree().theta())
+            }
+            "help" | "?" => HELP.to_string(),
+            "quit" | "exit" | "q" => return Ok(Reply::Quit),
+            _ => return Err(format!("unknown command {:?}; try help", command)),
+        };
+        return Ok(Reply::Text(text));
+    }
+
+    // Prompts for commands on `output` and carries them out until `quit` or the end of `input`.
+    pub fn run<R: BufRead, W: Write>(
+        &mut self,
+        sim: &mut Simulation,
+        input: R,
+        mut output: W,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut lines = input.lines();
+        loop {
+            write!(output, "nbody> ")?;
+            output.flush()?;
+            let line = match lines.next() {
+                Some(line) => line?,
+                None => break,
+            };
+            match self.execute(sim, &line) {
+                Ok(Reply::Quit) => break,
+                Ok(Reply::Text(text)) if text.is_empty() => {}
+                Ok(Reply::Text(text)) => writeln!(output, "{}", text)?,
+                Err(e) => writeln!(output, "error: {}", e)?,
+            }
+        }
+        writeln!(output)?;
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Arc;
+
+    use crate::geometry::bh_tree::BHTree;
+    use crate::repl::{Repl, Reply};
+    use crate::simulation::{Flow, Simulation};
+    use crate::{Point, Vec3d};
+
+    #[test]
+    fn test_repl() {
+        let points = vec![
+            Point::new(1e20, 0., 0., 0., Vec3d::new_zero()).with_id(0),
+            Point::new(1., 1e6, 0., 0., Vec3d::new(0., 1e2, 0.)).with_id(7),
+        ];
+        let mut sim = Simulation::from_tree(BHTree::from_points(0.5, points).unwrap(), 1., 0);
+        let interrupt = Arc::new(AtomicBool::new(false));
+        let mut repl = Repl::new(interrupt.clone());
+
+        let text = |reply: Result<Reply, String>| match reply.unwrap() {
+            Reply::Text(text) => text,
+            Reply::Quit => panic!("quit"),
+        };
+        assert_eq!(
+            text(repl.execute(&mut sim, "step 3")),
+            "took 3 steps, now at step 3"
+        );
+        assert!(text(repl.execute(&mut sim, "p 7")).starts_with("particle 7: m = 1, x = ("));
+        assert!(repl.execute(&mut sim, "p 8").is_err());
+        assert_eq!(text(repl.execute(&mut sim, "dt 0.5")), "dt = 0.5");
+        assert!(repl.execute(&mut sim, "dt -1").is_err());
+        assert!(repl.execute(&mut sim, "dt inf").is_err());
+        assert!(repl.execute(&mut sim, "theta inf").is_err());
+        assert!(repl.execute(&mut sim, "theta NaN").is_err());
+        assert_eq!(text(repl.execute(&mut sim, "theta 0.7")), "theta = 0.7");
+        assert_eq!(sim.tree().theta(), 0.7);
+        assert!(text(repl.execute(&mut sim, "energy")).contains("2K/|W| = "));
+        assert!(repl.execute(&mut sim, "jump").is_err());
+        assert_eq!(repl.execute(&mut sim, "quit"), Ok(Reply::Quit));
+
+        // An observer stands in for Ctrl-C, pausing an open-ended run after two more steps.
+        let flag = interrupt.clone();
+        sim.on_step(move |event| {
+            if event.step == 5 {
+                flag.store(true, std::sync::atomic::Ordering::SeqCst);
+            }
+            return Flow::Continue;
+        });
+        assert_eq!(
+            text(repl.execute(&mut sim, "run")),
+            "took 2 steps, paused, now at step 5"
+        );
+        assert_eq!(
+            text(repl.execute(&mut sim, "status")),
+            "step 5, t = 4, dt = 0.5, theta = 0.7, 2 particles"
+        );
+
+        let mut out = vec![];
+        repl.run(&mut sim, "step\n\nbogus\nquit\nstep\n".as_bytes(), &mut out)
+            .unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("now at step 6") && out.contains("error: unknown command"));
+        assert_eq!(sim.steps(), 6);
+    }
+}
@@ -0,0 +1,1283 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::Arc;
+
+use log::{debug, info};
+use rand::RngCore;
+
+use crate::geometry::bh_tree::{BHTree, Merge, StepScratch, StepStats, TreeReuse};
+use crate::geometry::boundary::Boundary;
+use crate::geometry::contact::CollisionModel;
+use crate::geometry::drag::Drag;
+use crate::geometry::escape::EscaperPolicy;
+use crate::geometry::force::ForceLaw;
+use crate::geometry::infall::{Infall, Injection};
+use crate::geometry::kicks::{Kick, Kicks};
+use crate::geometry::mass_loss::MassLoss;
+use crate::geometry::merge::{MergePolicy, Schwarzschild};
+use crate::geometry::octree::Refinement;
+use crate::geometry::opening::{OpeningCriterion, ThetaSchedule};
+use crate::geometry::perturber::Perturber;
+use crate::geometry::pm::ParticleMesh;
+use crate::geometry::pn::PostNewtonian;
+use crate::geometry::potential::ExternalPotential;
+use crate::geometry::species::SpeciesTable;
+use crate::geometry::sph::Sph;
+use crate::geometry::split::Fragmentation;
+use crate::ic::{InitialConditions, ParticleSet};
+use crate::output::SnapshotWriter;
+use crate::{NbodyError, Point, Vec3d};
+
+/// What step observers see after each step.
+pub struct StepEvent<'a> {
+    // Number of steps taken, including this one.
+    pub step: u64,
+    // Simulated time at the end of the step.
+    pub time: f64,
+    pub tree: &'a BHTree,
+    pub stats: &'a StepStats,
+}
+
+/// Whether a step observer wants the run to go on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flow {
+    Continue,
+    Stop,
+}
+
+type StepHook = Box<dyn FnMut(&StepEvent) -> Flow + Send>;
+type MergeHook = Box<dyn FnMut(u64, &Merge) + Send>;
+type KickHook = Box<dyn FnMut(&Kick) + Send>;
+type InjectHook = Box<dyn FnMut(&Injection) + Send>;
+type SnapshotHook = Box<dyn FnMut(u64, f64, &BHTree) + Send>;
+
+/// A tree together with the state needed to keep stepping it.
+pub struct Simulation {
+    tree: BHTree,
+    dt: f64,
+    steps: u64,
+    // Simulated time since the start of the run. Taken to be steps * dt for a restored tree.
+    time: f64,
+    potentials: Vec<Box<dyn ExternalPotential>>,
+    perturbers: Vec<Perturber>,
+    drag: Option<Drag>,
+    post_newtonian: Option<PostNewtonian>,
+    fragmentation: Option<Fragmentation>,
+    sph: Option<Sph>,
+    mesh: Option<ParticleMesh>,
+    // Internal energy per unit mass of each gas particle, by id.
+    energies: HashMap<u64, f64>,
+    mass_loss: Option<MassLoss>,
+    // Mass taken away by `mass_loss` so far.
+    mass_lost: f64,
+    kicks: Option<Kicks>,
+    infall: Option<Infall>,
+    // Lowest id no particle has had yet, so injected particles never reuse the id of one that
+    // merged or escaped.
+    next_id: u64,
+    // Moves the centre of mass back to the origin, at rest, after every step.
+    recenter: bool,
+    step_hooks: Vec<StepHook>,
+    merge_hooks: Vec<MergeHook>,
+    kick_hooks: Vec<KickHook>,
+    inject_hooks: Vec<InjectHook>,
+    snapshot_hooks: Vec<SnapshotHook>,
+    // Set once a step observer returns `Flow::Stop`.
+    stopped: bool,
+    // Buffers reused from step to step.
+    scratch: StepScratch,
+}
+
+impl Simulation {
+    // Wraps an existing tree, e.g. one restored from a checkpoint after `steps` steps.
+    pub fn from_tree(tree: BHTree, dt: f64, steps: u64) -> Simulation {
+        let next_id = tree.iter().map(|p| p.id() + 1).max().unwrap_or(0);
+        return Simulation {
+            tree: tree,
+            dt: dt,
+            steps: steps,
+            time: steps as f64 * dt,
+            potentials: vec![],
+            perturbers: vec![],
+            drag: None,
+            post_newtonian: None,
+            fragmentation: None,
+            sph: None,
+            mesh: None,
+            energies: HashMap::new(),
+            mass_loss: None,
+            mass_lost: 0.,
+            kicks: None,
+            infall: None,
+            next_id: next_id,
+            recenter: false,
+            step_hooks: vec![],
+            merge_hooks: vec![],
+            kick_hooks: vec![],
+            inject_hooks: vec![],
+            snapshot_hooks: vec![],
+            stopped: false,
+            scratch: StepScratch::new(),
+        };
+    }
+
+    // Sets the simulated time so far, e.g. from a checkpoint taken after steps of varying length.
+    pub fn with_time(mut self, time: f64) -> Simulation {
+        self.time = time;
+        return self;
+    }
+
+    // Calls `hook` after every step. Returning `Flow::Stop` ends `run` and sets `stopped`, for
+    // early-stopping criteria.
+    pub fn on_step<F: FnMut(&StepEvent) -> Flow + Send + 'static>(&mut self, hook: F) {
+        self.step_hooks.push(Box::new(hook));
+    }
+
+    // Calls `hook` with the step number for every pair of particles that merges.
+    pub fn on_merge<F: FnMut(u64, &Merge) + Send + 'static>(&mut self, hook: F) {
+        self.merge_hooks.push(Box::new(hook));
+    }
+
+    // Calls `hook` for every kick given to a particle.
+    pub fn on_kick<F: FnMut(&Kick) + Send + 'static>(&mut self, hook: F) {
+        self.kick_hooks.push(Box::new(hook));
+    }
+
+    // Calls `hook` for every particle added by `inject_particles`, including the infall stream.
+    pub fn on_inject<F: FnMut(&Injection) + Send + 'static>(&mut self, hook: F) {
+        self.inject_hooks.push(Box::new(hook));
+    }
+
+    // Calls `hook` with the step number, time and tree whenever `snapshot` is taken.
+    pub fn on_snapshot<F: FnMut(u64, f64, &BHTree) + Send + 'static>(&mut self, hook: F) {
+        self.snapshot_hooks.push(Box::new(hook));
+    }
+
+    // Whether a step observer has asked the run to stop.
+    pub fn stopped(&self) -> bool {
+        return self.stopped;
+    }
+
+    // Steps until `steps` more have been taken or an observer stops the run. Returns the number
+    // taken.
+    pub fn run(&mut self, steps: u64) -> Result<u64, NbodyError> {
+        let mut taken = 0;
+        while taken < steps && !self.stopped {
+            self.step()?;
+            taken += 1;
+        }
+        return Ok(taken);
+    }
+
+    // Writes the current state through every writer, then tells the snapshot observers.
+    pub fn snapshot(
+        &mut self,
+        time: f64,
+        writers: &mut [Box<dyn SnapshotWriter>],
+    ) -> Result<(), Box<dyn Error>> {
+        for w in writers.iter_mut() {
+            w.write(self.steps, time, &self.tree)?;
+        }
+        for hook in self.snapshot_hooks.iter_mut() {
+            hook(self.steps, time, &self.tree);
+        }
+        return Ok(());
+    }
+
+    // Keeps the centre of mass at the origin and at rest. Has no effect while any particle is
+    // pinned, since pinned particles fix the frame themselves.
+    pub fn set_recentering(&mut self, recenter: bool) {
+        self.recenter = recenter;
+    }
+
+    pub fn set_drag(&mut self, drag: Option<Drag>) {
+        self.drag = drag;
+    }
+
+    // Corrects the attraction between close, massive pairs for general relativity.
+    pub fn set_post_newtonian(&mut self, post_newtonian: Option<PostNewtonian>) {
+        self.post_newtonian = post_newtonian;
+    }
+
+    // Splits particles that get too dense after each step.
+    pub fn set_fragmentation(&mut self, fragmentation: Option<Fragmentation>) {
+        self.fragmentation = fragmentation;
+    }
+
+    // Treats the particles of one species as gas, pushed around by pressure as well as gravity.
+    pub fn set_sph(&mut self, sph: Option<Sph>) {
+        self.sph = sph;
+    }
+
+    // Adds the long-range force from a particle mesh and cuts the tree's force law down to the
+    // short-range rest. Only applies in a periodic box, with Newtonian gravity.
+    pub fn set_particle_mesh(&mut self, mesh: Option<ParticleMesh>) {
+        let mesh = mesh.filter(|_| self.tree.periodic().is_some());
+        let law = self.tree.force_law();
+        self.tree.set_force_law(match mesh {
+            Some(mesh) => mesh.short_range(law),
+            None => ForceLaw {
+                short_range: None,
+                ..law
+            },
+        });
+        self.mesh = mesh;
+    }
+
+    // Internal energy per unit mass of each gas particle by id, as of the end of the last step.
+    // Empty until the first step.
+    pub fn energies(&self) -> &HashMap<u64, f64> {
+        return &self.energies;
+    }
+
+    // Takes mass away from every particle but the pinned ones after each step.
+    pub fn set_mass_loss(&mut self, mass_loss: Option<MassLoss>) {
+        self.mass_loss = mass_loss;
+    }
+
+    // Kicks particles as they reach their time to go off.
+    pub fn set_kicks(&mut self, kicks: Option<Kicks>) {
+        self.kicks = kicks;
+    }
+
+    // Injects a stream of infalling particles as their arrival times come up.
+    pub fn set_infall(&mut self, infall: Option<Infall>) {
+        self.infall = infall;
+    }
+
+    pub fn kicks(&self) -> Option<Kicks> {
+        return self.kicks;
+    }
+
+    pub fn infall(&self) -> Option<Infall> {
+        return self.infall;
+    }
+
+    // Lowest id no particle has had yet, which the next injected particle gets.
+    pub fn next_id(&self) -> u64 {
+        return self.next_id;
+    }
+
+    // Carries on the id space of a run restored from a checkpoint, in which particles with higher
+    // ids than any left may have merged or escaped.
+    pub fn with_next_id(mut self, next_id: u64) -> Simulation {
+        self.next_id = self.next_id.max(next_id);
+        return self;
+    }
+
+    // Carries on the mass loss tally of a run restored from a checkpoint.
+    pub fn with_mass_lost(mut self, mass_lost: f64) -> Simulation {
+        self.mass_lost = mass_lost;
+        return self;
+    }
+
+    // Total mass taken away by mass loss so far. Together with the mass in the tree it makes up
+    // the mass the run started with, apart from whatever escaped or hit a wall.
+    pub fn mass_lost(&self) -> f64 {
+        return self.mass_lost;
+    }
+
+    // Adds a fixed background potential acting on every particle from the next step on.
+    pub fn add_external_potential<P: ExternalPotential + 'static>(&mut self, potential: P) {
+        self.potentials.push(Box::new(potential));
+    }
+
+    // Adds a body that pulls on every particle from the next step on while following its own
+    // trajectory.
+    pub fn add_perturber(&mut self, perturber: Perturber) {
+        self.perturbers.push(perturber);
+    }
+
+    pub fn tree(&self) -> &BHTree {
+        return &self.tree;
+    }
+
+    // Approximate bytes held by the tree's nodes and particles.
+    pub fn memory_usage(&self) -> usize {
+        return self.tree.stats().memory_bytes;
+    }
+
+    pub fn dt(&self) -> f64 {
+        return self.dt;
+    }
+
+    pub fn set_dt(&mut self, dt: f64) {
+        self.dt = dt;
+    }
+
+    // Adds particles between steps. They're renumbered to follow the existing ids, keeping their
+    // order.
+    pub fn add_particles(&mut self, points: Vec<Point>) -> Result<(), NbodyError> {
+        return self.inject_particles(points).map(|_| ());
+    }
+
+    // Adds particles between steps like `add_particles`, telling the inject observers about each
+    // one. Returns the ids they were given, which no earlier particle has had.
+    pub fn inject_particles(&mut self, points: Vec<Point>) -> Result<Vec<u64>, NbodyError> {
+        if points.is_empty() {
+            return Ok(vec![]);
+        }
+        let mut all = self.tree.points();
+        let next = all
+            .iter()
+            .map(|p| p.id() + 1)
+            .max()
+            .unwrap_or(0)
+            .max(self.next_id);
+        let injected: Vec<Point> = points
+            .into_iter()
+            .enumerate()
+            .map(|(i, p)| p.with_id(next + i as u64))
+            .collect();
+        all.extend(injected.iter().copied());
+        self.tree = self.tree.rebuild(all)?;
+        self.next_id = next + injected.len() as u64;
+
+        for p in injected.iter() {
+            let injection = Injection::of(self.steps, self.time, p);
+            debug!(id = p.id(), mass = p.mass(); "injected");
+            for hook in self.inject_hooks.iter_mut() {
+                hook(&injection);
+            }
+        }
+        return Ok(injected.iter().map(|p| p.id()).collect());
+    }
+
+    // Replaces the external particles with `points`, which another code has positioned for the
+    // next step. They pull on the particles here but aren't moved by the integrator. The i-th
+    // point takes the id of the i-th external particle set before, so a caller passing them in the
+    // same order each time keeps the same ids, and any more get new ids following the existing
+    // ones. Returns the ids, in order.
+    pub fn set_external(&mut self, points: Vec<Point>) -> Result<Vec<u64>, NbodyError> {
+        let (previous, mut all): (Vec<Point>, Vec<Point>) =
+            self.tree.points().into_iter().partition(|p| p.external());
+        // New ids are handed out in order, so sorting recovers the order they were set in.
+        let mut ids: Vec<u64> = previous.iter().map(|p| p.id()).collect();
+        ids.sort();
+        let next = all
+            .iter()
+            .chain(previous.iter())
+            .map(|p| p.id() + 1)
+            .max()
+            .unwrap_or(0)
+            .max(self.next_id);
+        let extra = points.len().saturating_sub(ids.len());
+        ids.truncate(points.len());
+        ids.extend((0..extra).map(|i| next + i as u64));
+        all.extend(
+            points
+                .into_iter()
+                .zip(ids.iter())
+                .map(|(p, &id)| p.with_id(id).with_external(true)),
+        );
+        self.tree = self.tree.rebuild(all)?;
+        self.next_id = next + extra as u64;
+        return Ok(ids);
+    }
+
+    // Replaces the rule for which particles merge, from the next step on.
+    pub fn set_merge_policy<P: MergePolicy + 'static>(&mut self, policy: P) {
+        self.tree.set_merge_policy(Arc::new(policy));
+    }
+
+    // Splits each step's force pass over the NUMA nodes of `numa`.
+    #[cfg(feature = "numa")]
+    pub fn set_numa(&mut self, numa: Option<Arc<crate::numa::NumaPools>>) {
+        self.tree.set_numa(numa);
+    }
+
+    // Gathers each particle's interaction list before summing it. See
+    // `BHTree::with_interaction_lists`.
+    pub fn set_interaction_lists(&mut self, lists: bool) {
+        self.tree.set_interaction_lists(lists);
+    }
+
+    // Stops a step at the first particle that goes NaN or infinite, saying what did it. See
+    // `BHTree::with_paranoid`.
+    pub fn set_paranoid(&mut self, paranoid: bool) {
+        self.tree.set_paranoid(paranoid);
+    }
+
+    // Adds the pull of the periodic images beyond the nearest. See `BHTree::with_ewald`.
+    pub fn set_ewald(&mut self, ewald: bool) {
+        self.tree.set_ewald(ewald);
+    }
+
+    // Rebuilds the tree with a new opening angle, used from the next step on.
+    pub fn set_theta(&mut self, theta: f64) -> Result<(), NbodyError> {
+        return self.tree.set_theta(theta);
+    }
+
+    // Number of steps taken so far.
+    pub fn steps(&self) -> u64 {
+        return self.steps;
+    }
+
+    // Simulated time since the start of the run, summed step by step so it stays right when dt
+    // changes.
+    pub fn time(&self) -> f64 {
+        return self.time;
+    }
+
+    pub fn step(&mut self) -> Result<StepStats, NbodyError> {
+        let potentials = &self.potentials;
+        let perturbers = &self.perturbers;
+        let time = self.time;
+        let drag = self.drag;
+        let tree = &self.tree;
+        let pn = self.post_newtonian.map(|pn| (pn, pn.reach(tree)));
+        let hydro = self.sph.as_ref().map(|sph| {
+            let gas = sph.gas(tree, &self.energies);
+            let hydro = sph.hydro(&gas);
+            (gas, hydro)
+        });
+        let mesh = match (self.mesh, tree.periodic()) {
+            (Some(mesh), Some(box_size)) => {
+                Some(mesh.field(tree.iter(), box_size, tree.force_law().g))
+            }
+            _ => None,
+        };
+        let (tree, stats) = self.tree.step_in(self.dt, &mut self.scratch, |p| {
+            let (x, y, z) = p.position();
+            let pos = Vec3d::new(x, y, z);
+            let mut force = Vec3d::new_zero();
+            for potential in potentials {
+                force += p.mass() * potential.acceleration(pos);
+            }
+            for perturber in perturbers {
+                force += p.mass() * perturber.acceleration(pos, time);
+            }
+            if let Some(drag) = drag {
+                force += drag.force(p);
+            }
+            if let Some((pn, reach)) = pn {
+                force += pn.force_from(p, tree, reach);
+            }
+            if let Some(mesh) = &mesh {
+                force += p.mass() * mesh.acceleration(p.position());
+            }
+            if let Some(h) = hydro.as_ref().and_then(|(_, hydro)| hydro.get(&p.id())) {
+                force += h.force;
+            }
+            force
+        })?;
+        let done = std::mem::replace(&mut self.tree, tree);
+        self.scratch.recycle(done);
+        if let (Some(sph), Some((gas, hydro))) = (&self.sph, &hydro) {
+            self.energies = sph.heated(&self.tree, gas, hydro, self.dt);
+        }
+        self.steps += 1;
+        self.time += self.dt;
+        debug!(com_drift = stats.com_drift, angular_momentum = stats.angular_momentum;
+            "step {} done", self.steps);
+
+        for merge in self.tree.merges() {
+            for hook in self.merge_hooks.iter_mut() {
+                hook(self.steps, merge);
+            }
+        }
+        // Recentering and everything below rebuild the tree, which forgets the step's merges, so
+        // they come after the merge hooks.
+        if self.recenter && !self.tree.iter().any(|p| p.pinned()) {
+            let com = self.tree.center_of_mass();
+            let (x, y, z) = com.position();
+            self.tree = self.tree.shifted(Vec3d::new(-x, -y, -z), -com.velocity())?;
+        }
+        if let Some(points) = self
+            .fragmentation
+            .and_then(|f| f.apply(&self.tree, self.next_id))
+        {
+            let before = self.tree.len();
+            self.tree = self.tree.rebuild(points)?;
+            let added = self.tree.len() - before;
+            // The fragments' ids follow on from every id handed out so far.
+            self.next_id = self
+                .tree
+                .iter()
+                .map(|p| p.id() + 1)
+                .max()
+                .unwrap_or(0)
+                .max(self.next_id);
+            debug!("fragmentation added {} particles", added);
+        }
+        if let Some(mass_loss) = &self.mass_loss {
+            let factor = mass_loss.factor(self.time - self.dt, self.dt);
+            if factor != 1. {
+                let mut lost = 0.;
+                let points = self
+                    .tree
+                    .points()
+                    .into_iter()
+                    .map(|p| match p.pinned() {
+                        true => p,
+                        false => {
+                            lost += p.mass() * (1. - factor);
+                            p.with_mass(p.mass() * factor)
+                        }
+                    })
+                    .collect();
+                self.tree = self.tree.rebuild(points)?;
+                self.mass_lost += lost;
+                debug!(lost = lost, total_lost = self.mass_lost,
+                    mass = self.tree.iter().map(|p| p.mass()).sum::<f64>(); "mass loss");
+            }
+        }
+        if let Some(kicks) = self.kicks {
+            let from = self.time - self.dt;
+            if let Some((points, kicked)) =
+                kicks.apply(self.steps, from, self.time, self.tree.points())
+            {
+                self.tree = self.tree.rebuild(points)?;
+                for kick in kicked.iter() {
+                    debug!(id = kick.id, dvx = kick.dvx, dvy = kick.dvy, dvz = kick.dvz; "kick");
+                    for hook in self.kick_hooks.iter_mut() {
+                        hook(kick);
+                    }
+                }
+            }
+        }
+        if let Some(infall) = self.infall {
+            let arrivals = infall.arrivals(self.time - self.dt, self.time);
+            if !arrivals.is_empty() {
+                self.inject_particles(arrivals.map(|k| infall.draw(k)).collect())?;
+            }
+        }
+        let event = StepEvent {
+            step: self.steps,
+            time: self.time,
+            tree: &self.tree,
+            stats: &stats,
+        };
+        for hook in self.step_hooks.iter_mut() {
+            if hook(&event) == Flow::Stop {
+                info!("stopped by a step observer after step {}", self.steps);
+                self.stopped = true;
+            }
+        }
+        return Ok(stats);
+    }
+}
+
+/// Assembles a `Simulation` from one or more initial-condition generators. Sets from successive
+/// generators are concatenated, with ids shifted so they stay unique.
+pub struct SimulationBuilder {
+    theta: f64,
+    dt: f64,
+    boundary: Boundary,
+    force_law: ForceLaw,
+    potentials: Vec<Box<dyn ExternalPotential>>,
+    perturbers: Vec<Perturber>,
+    drag: Option<Drag>,
+    post_newtonian: Option<PostNewtonian>,
+    fragmentation: Option<Fragmentation>,
+    sph: Option<Sph>,
+    particle_mesh: Option<ParticleMesh>,
+    mass_loss: Option<MassLoss>,
+    kicks: Option<Kicks>,
+    infall: Option<Infall>,
+    species: SpeciesTable,
+    encounter_radius: Option<f64>,
+    collisions: CollisionModel,
+    merge_policy: Arc<dyn MergePolicy>,
+    escapers: EscaperPolicy,
+    planar: bool,
+    refinement: Refinement,
+    reuse: Option<TreeReuse>,
+    opening: OpeningCriterion,
+    theta_schedule: ThetaSchedule,
+    compensated: bool,
+    ewald: bool,
+    initial_conditions: Vec<Box<dyn InitialConditions>>,
+}
+
+impl Default for SimulationBuilder {
+    fn default() -> SimulationBuilder {
+        return SimulationBuilder {
+            theta: 0.5,
+            dt: 0.5,
+            boundary: Boundary::Open,
+            force_law: ForceLaw::default(),
+            potentials: vec![],
+            perturbers: vec![],
+            drag: None,
+            post_newtonian: None,
+            fragmentation: None,
+            sph: None,
+            particle_mesh: None,
+            mass_loss: None,
+            kicks: None,
+            infall: None,
+            species: SpeciesTable::default(),
+            encounter_radius: None,
+            collisions: CollisionModel::default(),
+            merge_policy: Arc::new(Schwarzschild),
+            escapers: EscaperPolicy::default(),
+            planar: false,
+            refinement: Refinement::default(),
+            reuse: None,
+            opening: OpeningCriterion::default(),
+            theta_schedule: ThetaSchedule::default(),
+            compensated: false,
+            ewald: false,
+            initial_conditions: vec![],
+        };
+    }
+}
+
+impl SimulationBuilder {
+    pub fn new() -> SimulationBuilder {
+        return SimulationBuilder::default();
+    }
+
+    pub fn theta(mut self, theta: f64) -> SimulationBuilder {
+        self.theta = theta;
+        return self;
+    }
+
+    pub fn dt(mut self, dt: f64) -> SimulationBuilder {
+        self.dt = dt;
+        return self;
+    }
+
+    // Use periodic boundaries on a box of side `box_size` centered on the origin.
+    pub fn periodic(self, box_size: f64) -> SimulationBuilder {
+        return self.boundary(Boundary::Periodic { box_size: box_size });
+    }
+
+    pub fn boundary(mut self, boundary: Boundary) -> SimulationBuilder {
+        self.boundary = boundary;
+        return self;
+    }
+
+    pub fn force_law(mut self, force_law: ForceLaw) -> SimulationBuilder {
+        self.force_law = force_law;
+        return self;
+    }
+
+    pub fn external_potential<P: ExternalPotential + 'static>(
+        mut self,
+        potential: P,
+    ) -> SimulationBuilder {
+        self.potentials.push(Box::new(potential));
+        return self;
+    }
+
+    pub fn perturber(mut self, perturber: Perturber) -> SimulationBuilder {
+        self.perturbers.push(perturber);
+        return self;
+    }
+
+    pub fn species(mut self, species: SpeciesTable) -> SimulationBuilder {
+        self.species = species;
+        return self;
+    }
+
+    // Advance mutually nearest pairs closer than `radius` as isolated two-body orbits.
+    pub fn encounter_radius(mut self, radius: f64) -> SimulationBuilder {
+        self.encounter_radius = Some(radius);
+        return self;
+    }
+
+    pub fn collisions(mut self, collisions: CollisionModel) -> SimulationBuilder {
+        self.collisions = collisions;
+        return self;
+    }
+
+    // Which particles merge after each step. Defaults to those within a Schwarzschild radius.
+    pub fn merge_policy<P: MergePolicy + 'static>(mut self, policy: P) -> SimulationBuilder {
+        self.merge_policy = Arc::new(policy);
+        return self;
+    }
+
+    pub fn escapers(mut self, escapers: EscaperPolicy) -> SimulationBuilder {
+        self.escapers = escapers;
+        return self;
+    }
+
+    // Run in two dimensions, in the z = 0 plane.
+    pub fn planar(mut self, planar: bool) -> SimulationBuilder {
+        self.planar = planar;
+        return self;
+    }
+
+    // Leaf bucket size and tree depth limit.
+    pub fn refinement(mut self, refinement: Refinement) -> SimulationBuilder {
+        self.refinement = refinement;
+        return self;
+    }
+
+    // Keep the tree for several steps while particles barely move, refreshing only its centres of
+    // mass.
+    pub fn reuse(mut self, reuse: TreeReuse) -> SimulationBuilder {
+        self.reuse = Some(reuse);
+        return self;
+    }
+
+    // When a node's centre of mass may stand in for its particles. Defaults to the theta test.
+    pub fn opening(mut self, opening: OpeningCriterion) -> SimulationBuilder {
+        self.opening = opening;
+        return self;
+    }
+
+    // How theta varies with the depth of a node. Defaults to the same theta everywhere.
+    pub fn theta_schedule(mut self, theta_schedule: ThetaSchedule) -> SimulationBuilder {
+        self.theta_schedule = theta_schedule;
+        return self;
+    }
+
+    // Sum forces and centres of mass with compensated summation, for systems whose masses span
+    // many orders of magnitude.
+    pub fn compensated_summation(mut self, compensated: bool) -> SimulationBuilder {
+        self.compensated = compensated;
+        return self;
+    }
+
+    // Pull on particles in a periodic box with every image of the others, not just the nearest.
+    // See `BHTree::with_ewald`.
+    pub fn ewald(mut self, ewald: bool) -> SimulationBuilder {
+        self.ewald = ewald;
+        return self;
+    }
+
+    pub fn drag(mut self, drag: Drag) -> SimulationBuilder {
+        self.drag = Some(drag);
+        return self;
+    }
+
+    pub fn post_newtonian(mut self, post_newtonian: PostNewtonian) -> SimulationBuilder {
+        self.post_newtonian = Some(post_newtonian);
+        return self;
+    }
+
+    pub fn fragmentation(mut self, fragmentation: Fragmentation) -> SimulationBuilder {
+        self.fragmentation = Some(fragmentation);
+        return self;
+    }
+
+    pub fn sph(mut self, sph: Sph) -> SimulationBuilder {
+        self.sph = Some(sph);
+        return self;
+    }
+
+    // Solves for long-range gravity on a mesh, TreePM style. Needs a periodic boundary.
+    pub fn particle_mesh(mut self, mesh: ParticleMesh) -> SimulationBuilder {
+        self.particle_mesh = Some(mesh);
+        return self;
+    }
+
+    pub fn mass_loss(mut self, mass_loss: MassLoss) -> SimulationBuilder {
+        self.mass_loss = Some(mass_loss);
+        return self;
+    }
+
+    pub fn kicks(mut self, kicks: Kicks) -> SimulationBuilder {
+        self.kicks = Some(kicks);
+        return self;
+    }
+
+    pub fn infall(mut self, infall: Infall) -> SimulationBuilder {
+        self.infall = Some(infall);
+        return self;
+    }
+
+    pub fn initial_conditions<I: InitialConditions + 'static>(
+        mut self,
+        ic: I,
+    ) -> SimulationBuilder {
+        self.initial_conditions.push(Box::new(ic));
+        return self;
+    }
+
+    // Rough peak memory in bytes of stepping the particles the initial conditions will make, if
+    // they all know how many that is.
+    pub fn estimated_memory(&self) -> Option<usize> {
+        let mut n = 0;
+        for ic in &self.initial_conditions {
+            n += ic.count()?;
+        }
+        return Some(self.refinement.estimate_memory(n));
+    }
+
+    pub fn build(self, rng: &mut dyn RngCore) -> Result<Simulation, NbodyError> {
+        let mut points: ParticleSet = vec![];
+        for ic in &self.initial_conditions {
+            let id_offset = points.iter().map(|p| p.id() + 1).max().unwrap_or(0);
+            points.extend(
+                ic.generate(rng)
+                    .into_iter()
+                    .map(|p| p.with_id(p.id() + id_offset)),
+            );
+        }
+        info!("generated {} particles", points.len());
+
+        let tree = BHTree::new_bounded(self.theta, self.boundary)
+            .with_force_law(self.force_law)
+            .with_species(self.species)
+            .with_encounter_radius(self.encounter_radius)
+            .with_collisions(self.collisions)
+            .with_merge_policy(self.merge_policy)
+            .with_escapers(self.escapers)
+            .with_planar(self.planar)
+            .with_compensated_summation(self.compensated)
+            .with_ewald(self.ewald)
+            .with_refinement(self.refinement)
+            .with_reuse(self.reuse)
+            .with_opening(self.opening)
+            .with_theta_schedule(self.theta_schedule)
+            .rebuild(points)?;
+        let mut sim = Simulation::from_tree(tree, self.dt, 0);
+        sim.potentials = self.potentials;
+        sim.perturbers = self.perturbers;
+        sim.drag = self.drag;
+        sim.post_newtonian = self.post_newtonian;
+        sim.fragmentation = self.fragmentation;
+        sim.sph = self.sph;
+        sim.set_particle_mesh(self.particle_mesh);
+        sim.mass_loss = self.mass_loss;
+        sim.kicks = self.kicks;
+        sim.infall = self.infall;
+        return Ok(sim);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rand::rngs::StdRng;
+    use rand::{RngCore, SeedableRng};
+
+    use std::f64::consts::PI;
+    use std::sync::{Arc, Mutex};
+
+    use crate::geometry::bh_tree::BHTree;
+    use crate::geometry::force::ForceLaw;
+    use crate::geometry::infall::Infall;
+    use crate::geometry::split::Fragmentation;
+    use crate::ic::{InitialConditions, ParticleSet, UniformSphere};
+    use crate::simulation::{Flow, Simulation, SimulationBuilder};
+    use crate::{Point, Vec3d};
+
+    struct Pair;
+
+    impl InitialConditions for Pair {
+        fn generate(&self, _rng: &mut dyn RngCore) -> ParticleSet {
+            return vec![
+                Point::new(1., -5., 0., 0., Vec3d::new_zero()).with_id(0),
+                Point::new(1., 5., 0., 0., Vec3d::new_zero()).with_id(1),
+            ];
+        }
+    }
+
+    // Integrates a light body on a Kepler orbit of semi-major axis 1 and eccentricity `e` about a
+    // heavy one, with G = 1, starting at pericentre. Returns the largest deviations of the
+    // semi-major axis and eccentricity from their starting values and the mean time between
+    // passes of the pericentre direction over the analytic period.
+    fn kepler_orbit(e: f64, orbits: u32, steps_per_orbit: u32) -> (f64, f64, f64) {
+        let (m1, m2): (f64, f64) = (1., 1e-3);
+        let mu = m1 + m2;
+        let period = 2. * PI / mu.sqrt();
+        let r = 1. - e;
+        let v = (mu * (1. + e) / r).sqrt();
+        let points: ParticleSet = vec![
+            Point::new(m1, -m2 / mu * r, 0., 0., Vec3d::new(0., -m2 / mu * v, 0.)).with_id(0),
+            Point::new(m2, m1 / mu * r, 0., 0., Vec3d::new(0., m1 / mu * v, 0.)).with_id(1),
+        ];
+        let dt = period / steps_per_orbit as f64;
+        let mut sim = SimulationBuilder::new()
+            .dt(dt)
+            .force_law(ForceLaw::newtonian(1.))
+            .initial_conditions(points)
+            .build(&mut StdRng::seed_from_u64(0))
+            .unwrap();
+
+        let (mut da, mut de) = (0_f64, 0_f64);
+        let mut passes = vec![];
+        let mut last_y = 0.;
+        // Half an orbit more, so the last pass isn't lost to rounding.
+        for step in 1..=orbits * steps_per_orbit + steps_per_orbit / 2 {
+            sim.step().unwrap();
+            let mut points = sim.tree().points();
+            points.sort_by_key(|p| p.id());
+            let (x0, y0, z0) = points[0].position();
+            let (x1, y1, z1) = points[1].position();
+            let r = Vec3d::new(x1 - x0, y1 - y0, z1 - z0);
+            let v = points[1].velocity() - points[0].velocity();
+
+            let a = -mu / (2. * (0.5 * v.magnitude_squared() - mu / r.magnitude()));
+            let ecc = (v.cross(r.cross(v)) / mu - r.normalize()).magnitude();
+            da = da.max((a - 1.).abs());
+            de = de.max((ecc - e).abs());
+
+            let (x, y, _) = r.position();
+            if last_y < 0. && y >= 0. && x > 0. {
+                let t = (step - 1) as f64 * dt + dt * -last_y / (y - last_y);
+                passes.push(t);
+            }
+            last_y = y;
+        }
+        assert_eq!(passes.len(), orbits as usize);
+        let measured = (passes[passes.len() - 1] - passes[0]) / (passes.len() - 1) as f64;
+        return (da, de, measured / period);
+    }
+
+    #[test]
+    fn test_circular_kepler_orbit() {
+        let (da, de, period) = kepler_orbit(0., 10, 1000);
+        assert!(da < 1e-4, "semi-major axis off by {}", da);
+        // A first-order step stretches the circle into an ellipse of eccentricity ~2 pi dt / T.
+        assert!(de < 1e-2, "eccentricity off by {}", de);
+        assert!((period - 1.).abs() < 1e-4, "period off by {}", period - 1.);
+    }
+
+    #[test]
+    fn test_elliptical_kepler_orbit() {
+        let (da, de, period) = kepler_orbit(0.5, 10, 2000);
+        assert!(da < 1e-2, "semi-major axis off by {}", da);
+        assert!(de < 1e-2, "eccentricity off by {}", de);
+        assert!((period - 1.).abs() < 1e-3, "period off by {}", period - 1.);
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn test_steps_do_not_depend_on_thread_count() {
+        use crate::ic::halo::{Halo, Profile};
+
+        let run = |threads: usize| {
+            let pool = rayon::ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .build()
+                .unwrap();
+            return pool.install(|| {
+                let halo = Halo {
+                    n: 500,
+                    total_mass: 1e20,
+                    profile: Profile::Hernquist,
+                    scale_radius: 1e3,
+                    max_radius: 2e4,
+                };
+                let mut sim = SimulationBuilder::new()
+                    .dt(100.)
+                    .initial_conditions(halo)
+                    .build(&mut StdRng::seed_from_u64(3))
+                    .unwrap();
+                for _ in 0..3 {
+                    sim.step().unwrap();
+                }
+                return sim
+                    .tree()
+                    .iter()
+                    .map(|p| {
+                        let (x, y, z) = p.position();
+                        let (vx, vy, vz) = p.velocity().position();
+                        [
+                            p.id(),
+                            x.to_bits(),
+                            y.to_bits(),
+                            z.to_bits(),
+                            vx.to_bits(),
+                            vy.to_bits(),
+                            vz.to_bits(),
+                        ]
+                    })
+                    .collect::<Vec<_>>();
+            });
+        };
+        let serial = run(1);
+        assert_eq!(serial.len(), 500);
+        assert!(serial == run(4));
+    }
+
+    #[test]
+    fn test_recentering() {
+        let points: ParticleSet = vec![
+            Point::new(1., 5., 0., 0., Vec3d::new(1., 1., 0.)).with_id(0),
+            Point::new(3., 9., 0., 0., Vec3d::new(1., -1., 0.)).with_id(1),
+        ];
+        let mut sim = SimulationBuilder::new()
+            .dt(1.)
+            .initial_conditions(points)
+            .build(&mut StdRng::seed_from_u64(0))
+            .unwrap();
+        sim.set_recentering(true);
+        let stats = sim.step().unwrap();
+        assert!(stats.com_drift > 0.);
+        assert!(stats.angular_momentum > 0.);
+
+        let com = sim.tree().center_of_mass();
+        let (x, y, z) = com.position();
+        assert!(x.abs() < 1e-9 && y.abs() < 1e-9 && z.abs() < 1e-9);
+        assert!(com.velocity().magnitude() < 1e-9);
+        assert_eq!(com.mass(), 4.);
+
+        // Merges in a recentred step still reach the hooks.
+        let touching: ParticleSet = vec![
+            Point::new(1., 0., 0., 0., Vec3d::new_zero())
+                .with_id(0)
+                .with_radius(1.),
+            Point::new(2., 0.5, 0., 0., Vec3d::new_zero())
+                .with_id(1)
+                .with_radius(1.),
+        ];
+        let mut sim = SimulationBuilder::new()
+            .dt(1e-3)
+            .initial_conditions(touching)
+            .build(&mut StdRng::seed_from_u64(0))
+            .unwrap();
+        sim.set_recentering(true);
+        let merges = Arc::new(Mutex::new(vec![]));
+        let seen = merges.clone();
+        sim.on_merge(move |step, merge| seen.lock().unwrap().push((step, merge.survivor)));
+        sim.step().unwrap();
+        assert_eq!(sim.tree().len(), 1);
+        assert_eq!(*merges.lock().unwrap(), vec![(1, 1)]);
+    }
+
+    #[test]
+    fn test_custom_initial_conditions() {
+        let extra = vec![Point::new(1., 0., 5., 0., Vec3d::new_zero()).with_id(0)];
+        let mut sim = SimulationBuilder::new()
+            .theta(0.3)
+            .dt(0.1)
+            .initial_conditions(Pair)
+            .initial_conditions(extra)
+            .build(&mut StdRng::seed_from_u64(1))
+            .unwrap();
+
+        let mut ids: Vec<u64> = sim.tree().iter().map(|p| p.id()).collect();
+        ids.sort();
+        assert_eq!(ids, vec![0, 1, 2]);
+        assert_eq!(sim.tree().theta(), 0.3);
+
+        sim.step().unwrap();
+        assert_eq!(sim.steps(), 1);
+        assert_eq!(sim.tree().len(), 3);
+    }
+
+    #[test]
+    fn test_observers() {
+        let points: ParticleSet = vec![
+            Point::new(1., 0., 0., 0., Vec3d::new_zero())
+                .with_id(0)
+                .with_radius(1.),
+            Point::new(2., 0.5, 0., 0., Vec3d::new_zero())
+                .with_id(1)
+                .with_radius(1.),
+            Point::new(1., 1e3, 0., 0., Vec3d::new_zero()).with_id(2),
+        ];
+        let mut sim = SimulationBuilder::new()
+            .dt(1e-3)
+            .initial_conditions(points)
+            .build(&mut StdRng::seed_from_u64(0))
+            .unwrap();
+
+        let merges = Arc::new(Mutex::new(vec![]));
+        let seen = merges.clone();
+        sim.on_merge(move |step, merge| {
+            seen.lock()
+                .unwrap()
+                .push((step, merge.survivor, merge.absorbed))
+        });
+        sim.on_step(|event| {
+            if event.step >= 2 {
+                Flow::Stop
+            } else {
+                Flow::Continue
+            }
+        });
+        let snapshots = Arc::new(Mutex::new(vec![]));
+        let seen = snapshots.clone();
+        sim.on_snapshot(move |step, time, tree| {
+            seen.lock().unwrap().push((step, time, tree.len()))
+        });
+
+        assert_eq!(sim.run(10).unwrap(), 2);
+        assert!(sim.stopped());
+        assert_eq!(*merges.lock().unwrap(), vec![(1, 1, 0)]);
+
+        sim.snapshot(0.5, &mut []).unwrap();
+        assert_eq!(*snapshots.lock().unwrap(), vec![(2, 0.5, 2)]);
+    }
+
+    #[test]
+    fn test_time() {
+        let points = vec![
+            Point::new(1., 0., 0., 0., Vec3d::new_zero()).with_id(0),
+            Point::new(1., 1e3, 0., 0., Vec3d::new_zero()).with_id(1),
+        ];
+        let tree = BHTree::from_points(0.5, points).unwrap();
+        // A restored run is taken to have stepped at the current dt, unless told otherwise.
+        let sim = Simulation::from_tree(tree, 2., 4);
+        assert_eq!(sim.time(), 8.);
+        let mut sim = sim.with_time(10.);
+
+        let times = Arc::new(Mutex::new(vec![]));
+        let seen = times.clone();
+        sim.on_step(move |event| {
+            seen.lock().unwrap().push(event.time);
+            Flow::Continue
+        });
+        sim.step().unwrap();
+        sim.set_dt(0.5);
+        sim.step().unwrap();
+        assert_eq!(sim.time(), 12.5);
+        assert_eq!(*times.lock().unwrap(), vec![12., 12.5]);
+    }
+
+    #[test]
+    fn test_inject_particles() {
+        let points: ParticleSet = vec![
+            Point::new(2., 0., 0., 0., Vec3d::new_zero())
+                .with_id(0)
+                .with_radius(1.),
+            Point::new(1., 0.5, 0., 0., Vec3d::new_zero())
+                .with_id(1)
+                .with_radius(1.),
+        ];
+        let mut sim = SimulationBuilder::new()
+            .dt(1.)
+            .infall(Infall {
+                rate: 0.5,
+                mass: 1e-3,
+                radius: 1e3,
+                speed: 0.,
+                seed: 0,
+            })
+            .initial_conditions(points)
+            .build(&mut StdRng::seed_from_u64(0))
+            .unwrap();
+        let injections = Arc::new(Mutex::new(vec![]));
+        let seen = injections.clone();
+        sim.on_inject(move |injection| seen.lock().unwrap().push(*injection));
+
+        // The merge takes id 1 out of the run, and it isn't handed out again.
+        sim.step().unwrap();
+        assert_eq!(sim.tree().len(), 1);
+        let far = vec![
+            Point::new(1., 50., 0., 0., Vec3d::new_zero()),
+            Point::new(1., -50., 0., 0., Vec3d::new_zero()),
+        ];
+        assert_eq!(sim.inject_particles(far).unwrap(), vec![2, 3]);
+        assert_eq!(sim.tree().len(), 3);
+
+        // One infalling particle arrives every other unit of time.
+        sim.run(4).unwrap();
+        assert_eq!(sim.tree().len(), 5);
+        let log = injections.lock().unwrap();
+        let logged: Vec<(u64, f64, u64)> = log.iter().map(|i| (i.step, i.time, i.id)).collect();
+        assert_eq!(logged, vec![(1, 1., 2), (1, 1., 3), (2, 2., 4), (4, 4., 5)]);
+        assert!(
+            ((log[2].x.powi(2) + log[2].y.powi(2) + log[2].z.powi(2)).sqrt() - 1e3).abs() < 1e-6
+        );
+    }
+
+    #[test]
+    fn test_memory_estimate() {
+        let sphere = UniformSphere {
+            n: 20000,
+            radius: 1e3,
+            mass_base: 2.,
+            mass_mean: 2.,
+            mass_stddev: 1.,
+        };
+        let builder = SimulationBuilder::new().initial_conditions(sphere);
+        let estimate = builder.estimated_memory().unwrap();
+        let sim = builder.build(&mut StdRng::seed_from_u64(0)).unwrap();
+
+        // A step holds two trees and copies of the particles besides, but not many times more.
+        let tree = sim.memory_usage();
+        assert!(
+            estimate > 2 * tree && estimate < 5 * tree,
+            "{} {}",
+            estimate,
+            tree
+        );
+
+        // Nothing can be said for generators that don't know their size up front.
+        let builder = SimulationBuilder::new()
+            .initial_conditions(vec![Point::new_zero()])
+            .initial_conditions(Pair);
+        assert_eq!(builder.estimated_memory(), None);
+    }
+
+    #[test]
+    fn test_fragment_ids() {
+        // A tight clump of heavy particles that splits, in a sparse field of light ones.
+        let mut points: Vec<Point> = (0..8)
+            .map(|i| {
+                let (x, y, z) = ((i & 1) as f64, ((i >> 1) & 1) as f64, (i >> 2) as f64);
+                Point::new(1e3, x, y, z, Vec3d::new_zero())
+            })
+            .collect();
+        points.extend((0..64).map(|i| {
+            let (x, y, z) = ((i % 4) as f64, (i / 4 % 4) as f64, (i / 16) as f64);
+            Point::new(1., 100. * x + 50., 100. * y, 100. * z, Vec3d::new_zero())
+        }));
+        let points = points
+            .into_iter()
+            .enumerate()
+            .map(|(i, p)| p.with_id(i as u64))
+            .collect();
+        // Particles up to id 99 have been and gone.
+        let mut sim = Simulation::from_tree(BHTree::from_points(0.5, points).unwrap(), 1., 0)
+            .with_next_id(100);
+        sim.set_fragmentation(Some(Fragmentation {
+            density: 1.,
+            neighbors: 7,
+            fragments: 3,
+            min_mass: 500.,
+        }));
+        sim.run(1).unwrap();
+        assert_eq!(sim.tree().len(), 72 + 8 * 2);
+        let mut new: Vec<u64> = sim
+            .tree()
+            .iter()
+            .map(|p| p.id())
+            .filter(|id| *id >= 72)
+            .collect();
+        new.sort();
+        assert_eq!(new, (100..116).collect::<Vec<_>>());
+        assert_eq!(sim.next_id(), 116);
+        let injected = sim
+            .inject_particles(vec![Point::new(1., 0., 500., 0., Vec3d::new_zero())])
+            .unwrap();
+        assert_eq!(injected, vec![116]);
+    }
+
+    #[test]
+    fn test_external_particles() {
+        let mut sim = SimulationBuilder::new()
+            .dt(0.1)
+            .force_law(ForceLaw::newtonian(1.))
+            .initial_conditions(vec![Point::new(1., 0., 0., 0., Vec3d::new_zero())])
+            .build(&mut StdRng::seed_from_u64(0))
+            .unwrap();
+        let external = |x: f64, mass: f64| Point::new(mass, x, 0., 0., Vec3d::new(0., 1., 0.));
+        assert_eq!(
+            sim.set_external(vec![external(10., 10.), external(-10., 1.)])
+                .unwrap(),
+            vec![1, 2]
+        );
+
+        // The heavier one pulls the particle its way, and neither external particle moves.
+        sim.run(3).unwrap();
+        let points = sim.tree().points();
+        let find = |id: u64| *points.iter().find(|p| p.id() == id).unwrap();
+        assert!(find(0).position().0 > 0.);
+        assert_eq!(find(1).position(), (10., 0., 0.));
+        assert_eq!(find(2).position(), (-10., 0., 0.));
+        assert!(find(1).external() && find(1).pinned() && !find(0).external());
+
+        // Ids follow the order they're passed in; any more get new ones.
+        assert_eq!(sim.set_external(vec![external(5., 10.)]).unwrap(), vec![1]);
+        assert_eq!(sim.tree().len(), 2);
+        assert_eq!(
+            sim.set_external(vec![external(5., 10.), external(0., 1.), external(-5., 1.)])
+                .unwrap(),
+            vec![1, 3, 4]
+        );
+        // One sits on the particle without merging with it.
+        sim.step().unwrap();
+        assert_eq!(sim.tree().len(), 4);
+        assert!(sim.set_external(vec![]).unwrap().is_empty());
+        assert_eq!(sim.tree().len(), 1);
+    }
+}
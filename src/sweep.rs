@@ -0,0 +1,314 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+
+use log::{info, warn};
+use serde::Serialize;
+
+/// One swept parameter and the values it takes, parsed from `name=v1,v2,...`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Vary {
+    pub name: String,
+    pub values: Vec<String>,
+}
+
+impl std::str::FromStr for Vary {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Vary, String> {
+        let (name, values) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected name=v1,v2,... but got {:?}", s))?;
+        let values: Vec<String> = values
+            .split(',')
+            .map(|v| v.trim().to_string())
+            .filter(|v| !v.is_empty())
+            .collect();
+        if name.trim().is_empty() || values.is_empty() {
+            return Err(format!("expected name=v1,v2,... but got {:?}", s));
+        }
+        return Ok(Vary {
+            name: name.trim().to_string(),
+            values: values,
+        });
+    }
+}
+
+/// One run of a sweep, as recorded in its manifest.
+#[derive(Debug, Clone, Serialize)]
+pub struct SweepRun {
+    // Directory of the run, relative to the sweep's.
+    pub dir: PathBuf,
+    // The swept parameters' values for this run.
+    pub params: BTreeMap<String, String>,
+    // Every argument the run was started with.
+    pub args: Vec<String>,
+    // Exit code of the run, or None if it couldn't be started or was killed by a signal.
+    pub exit_code: Option<i32>,
+}
+
+/// Runs the simulation binary once for every combination of the swept values, each in its own
+/// directory under `out_dir`, and writes `manifest.json` there listing them. Runs start in their
+/// directory, so each one's `output/` and checkpoint land inside it.
+pub struct Sweep {
+    base: Vec<(String, toml::Value)>,
+    vary: Vec<Vary>,
+    out_dir: PathBuf,
+    jobs: usize,
+}
+
+impl Sweep {
+    // `base` holds the arguments every run shares, as a TOML table of long option names (with
+    // underscores or dashes) to values. Relative paths in it that name existing files are taken
+    // relative to the config file.
+    pub fn new(
+        base: Option<&Path>,
+        vary: Vec<Vary>,
+        out_dir: PathBuf,
+    ) -> Result<Sweep, Box<dyn Error>> {
+        let mut table = vec![];
+        if let Some(path) = base {
+            let config: toml::Table = toml::from_str(&fs::read_to_string(path)?)?;
+            let dir = path.parent().unwrap_or(Path::new("."));
+            for (key, value) in config {
+                table.push((key, resolve_paths(value, dir)));
+            }
+        }
+        return Ok(Sweep {
+            base: table,
+            vary: vary,
+            out_dir: out_dir,
+            jobs: 1,
+        });
+    }
+
+    // How many runs go at once.
+    pub fn with_jobs(mut self, jobs: usize) -> Sweep {
+        self.jobs = jobs.max(1);
+        return self;
+    }
+
+    // Every run in the sweep, the first parameter varying slowest, without starting any.
+    pub fn runs(&self) -> Vec<SweepRun> {
+        let mut combinations: Vec<Vec<(String, String)>> = vec![vec![]];
+        for vary in self.vary.iter() {
+            combinations = combinations
+                .into_iter()
+                .flat_map(|c| {
+                    vary.values.iter().map(move |v| {
+                        let mut c = c.clone();
+                        c.push((vary.name.clone(), v.clone()));
+                        c
+                    })
+                })
+                .collect();
+        }
+
+        let width = combinations
+            .len()
+            .saturating_sub(1)
+            .to_string()
+            .len()
+            .max(3);
+        return combinations
+            .into_iter()
+            .enumerate()
+            .map(|(i, params)| {
+                let mut args = vec![];
+                for (key, value) in self.base.iter() {
+                    if !params.iter().any(|(name, _)| same_option(name, key)) {
+                        push_arg(&mut args, key, value);
+                    }
+                }
+                for (name, value) in params.iter() {
+                    args.push(format!("--{}={}", option_name(name), value));
+                }
+                SweepRun {
+                    dir: PathBuf::from(format!("run-{:0width$}", i, width = width)),
+                    params: params.into_iter().collect(),
+                    args: args,
+                    exit_code: None,
+                }
+            })
+            .collect();
+    }
+
+    // Runs `exe` for every combination and returns the finished runs. Fails only if the sweep
+    // itself can't be set up; a run that fails is recorded with its exit code.
+    pub fn run(&self, exe: &Path) -> Result<Vec<SweepRun>, Box<dyn Error>> {
+        fs::create_dir_all(&self.out_dir)?;
+        let runs = Mutex::new(self.runs());
+        let total = runs.lock().unwrap().len();
+        info!(
+            "sweeping {} runs into {}, {} at a time",
+            total,
+            self.out_dir.display(),
+            self.jobs
+        );
+        self.write_manifest(&runs.lock().unwrap())?;
+
+        let next = AtomicUsize::new(0);
+        thread::scope(|s| {
+            for _ in 0..self.jobs.min(total) {
+                s.spawn(|| loop {
+                    let i = next.fetch_add(1, Ordering::SeqCst);
+                    if i >= total {
+                        return;
+                    }
+                    let (dir, args) = {
+                        let runs = runs.lock().unwrap();
+                        (self.out_dir.join(&runs[i].dir), runs[i].args.clone())
+                    };
+                    let code = match run_one(exe, &dir, &args) {
+                        Ok(code) => code,
+                        Err(e) => {
+                            warn!("couldn't start run {}: {}", dir.display(), e);
+                            None
+                        }
+                    };
+                    info!("run {} of {} finished with {:?}", i + 1, total, code);
+                    let mut runs = runs.lock().unwrap();
+                    runs[i].exit_code = code;
+                    if let Err(e) = self.write_manifest(&runs) {
+                        warn!("couldn't update the sweep manifest: {}", e);
+                    }
+                });
+            }
+        });
+        return Ok(runs.into_inner().unwrap());
+    }
+
+    fn write_manifest(&self, runs: &[SweepRun]) -> Result<(), Box<dyn Error>> {
+        let manifest = serde_json::json!({
+            "base": self.base.iter().cloned().collect::<BTreeMap<_, _>>(),
+            "vary": self.vary.iter().map(|v| (v.name.clone(), v.values.clone())).collect::<BTreeMap<_, _>>(),
+            "runs": runs,
+        });
+        fs::write(
+            self.out_dir.join("manifest.json"),
+            serde_json::to_vec_pretty(&manifest)?,
+        )?;
+        return Ok(());
+    }
+}
+
+// Starts one run in `dir` with its log in `dir/log.txt`, and waits for it.
+fn run_one(exe: &Path, dir: &Path, args: &[String]) -> Result<Option<i32>, Box<dyn Error>> {
+    fs::create_dir_all(dir)?;
+    let log = fs::File::create(dir.join("log.txt"))?;
+    let status = Command::new(exe)
+        .args(args)
+        .current_dir(dir)
+        .stdin(Stdio::null())
+        .stdout(log.try_clone()?)
+        .stderr(log)
+        .status()?;
+    return Ok(status.code());
+}
+
+fn option_name(key: &str) -> String {
+    return key.replace('_', "-");
+}
+
+fn same_option(a: &str, b: &str) -> bool {
+    return option_name(a) == option_name(b);
+}
+
+// Appends `value` for option `key`: a flag for true, nothing for false, and one comma-separated
+// value for arrays.
+fn push_arg(args: &mut Vec<String>, key: &str, value: &toml::Value) {
+    let name = option_name(key);
+    let text = match value {
+        toml::Value::Boolean(true) => {
+            args.push(format!("--{}", name));
+            return;
+        }
+        toml::Value::Boolean(false) => return,
+        toml::Value::String(s) => s.clone(),
+        toml::Value::Array(values) => values
+            .iter()
+            .map(|v| match v {
+                toml::Value::String(s) => s.clone(),
+                v => v.to_string(),
+            })
+            .collect::<Vec<_>>()
+            .join(","),
+        v => v.to_string(),
+    };
+    args.push(format!("--{}={}", name, text));
+}
+
+fn resolve_paths(value: toml::Value, dir: &Path) -> toml::Value {
+    return match value {
+        toml::Value::String(s) => {
+            let path = dir.join(&s);
+            if Path::new(&s).is_relative() && path.exists() {
+                match path.canonicalize() {
+                    Ok(path) => toml::Value::String(path.display().to_string()),
+                    Err(_) => toml::Value::String(s),
+                }
+            } else {
+                toml::Value::String(s)
+            }
+        }
+        v => v,
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::path::PathBuf;
+
+    use crate::sweep::{Sweep, Vary};
+
+    #[test]
+    fn test_runs_cover_the_cross_product() {
+        assert!("theta".parse::<Vary>().is_err());
+        assert!("theta=".parse::<Vary>().is_err());
+
+        let dir = std::env::temp_dir().join(format!("nbody-sweep-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("stars.csv"), "").unwrap();
+        let config = dir.join("base.toml");
+        fs::write(
+            &config,
+            "steps = 20\ntheta = 0.7\nplanar = true\nrecenter = false\nic_file = \"stars.csv\"\noutput_species = [\"gas\", \"stars\"]\n",
+        )
+        .unwrap();
+
+        let sweep = Sweep::new(
+            Some(&config),
+            vec![
+                "theta=0.3,0.5,0.8".parse().unwrap(),
+                "dt=0.01, 0.05".parse().unwrap(),
+            ],
+            PathBuf::from("sweep"),
+        )
+        .unwrap();
+        let runs = sweep.runs();
+        assert_eq!(runs.len(), 6);
+        assert_eq!(runs[0].dir, PathBuf::from("run-000"));
+        assert_eq!(runs[3].params["theta"], "0.5");
+        assert_eq!(runs[3].params["dt"], "0.05");
+
+        let args = &runs[3].args;
+        let stars = dir.join("stars.csv").canonicalize().unwrap();
+        assert!(args.contains(&"--steps=20".to_string()));
+        assert!(args.contains(&"--planar".to_string()));
+        assert!(!args.iter().any(|a| a.starts_with("--recenter")));
+        assert!(args.contains(&format!("--ic-file={}", stars.display())));
+        assert!(args.contains(&"--output-species=gas,stars".to_string()));
+        // Swept values replace the base ones.
+        assert!(args.contains(&"--theta=0.5".to_string()));
+        assert!(!args.contains(&"--theta=0.7".to_string()));
+        assert!(args.contains(&"--dt=0.05".to_string()));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
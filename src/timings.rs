@@ -0,0 +1,85 @@
+use std::fmt::Write as _;
+use std::time::Duration;
+
+use log::debug;
+
+use crate::geometry::bh_tree::StepStats;
+
+/// Accumulates wall time per simulation phase across a run.
+#[derive(Debug, Default, Clone)]
+pub struct Timings {
+    steps: u64,
+    tree_build: Duration,
+    force_eval: Duration,
+    integration: Duration,
+    io: Duration,
+}
+
+impl Timings {
+    pub fn new() -> Timings {
+        return Timings::default();
+    }
+
+    pub fn record_step(&mut self, stats: &StepStats, io: Duration) {
+        debug!(
+            "step {} timings: build={:?} force={:?} integrate={:?} io={:?}",
+            self.steps, stats.build_time, stats.force_time, stats.integrate_time, io,
+        );
+        self.steps += 1;
+        self.tree_build += stats.build_time;
+        self.force_eval += stats.force_time;
+        self.integration += stats.integrate_time;
+        self.io += io;
+    }
+
+    pub fn total(&self) -> Duration {
+        return self.tree_build + self.force_eval + self.integration + self.io;
+    }
+
+    pub fn summary(&self) -> String {
+        let total = self.total().as_secs_f64().max(f64::MIN_POSITIVE);
+        let steps = self.steps.max(1) as f64;
+        let mut out = format!("timings over {} steps:\n", self.steps);
+        for (name, d) in [
+            ("tree build", self.tree_build),
+            ("force eval", self.force_eval),
+            ("integration", self.integration),
+            ("i/o", self.io),
+        ] {
+            let secs = d.as_secs_f64();
+            let _ = writeln!(
+                out,
+                "  {:<12} {:>10.3}s total {:>10.3}ms/step {:>5.1}%",
+                name,
+                secs,
+                secs * 1e3 / steps,
+                100. * secs / total,
+            );
+        }
+        return out;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use crate::geometry::bh_tree::StepStats;
+    use crate::timings::Timings;
+
+    #[test]
+    fn test_accumulates() {
+        let mut t = Timings::new();
+        let stats = StepStats {
+            force_time: Duration::from_millis(3),
+            integrate_time: Duration::from_millis(1),
+            build_time: Duration::from_millis(2),
+            merges: 0,
+            ..Default::default()
+        };
+        t.record_step(&stats, Duration::from_millis(4));
+        t.record_step(&stats, Duration::from_millis(4));
+        assert_eq!(t.total(), Duration::from_millis(20));
+        assert!(t.summary().contains("over 2 steps"));
+    }
+}
@@ -0,0 +1,161 @@
+use rand::RngCore;
+use serde::Serialize;
+
+use crate::geometry::vec3d::G;
+use crate::ic::{InitialConditions, ParticleSet};
+use crate::{Point, Vec3d};
+
+const KILOPARSEC: f64 = 3.085677581491367e19;
+const SOLAR_MASS: f64 = 1.98847e30;
+const MEGAYEAR: f64 = 3.15576e13;
+
+/// A system of units, given as the size of each base unit in SI. The simulation itself always runs
+/// in SI so that G and the speed of light keep their physical values; configuration and input
+/// files are converted at the edge.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Units {
+    // Metres per length unit.
+    pub length: f64,
+    // Kilograms per mass unit.
+    pub mass: f64,
+    // Seconds per time unit.
+    pub time: f64,
+}
+
+impl Units {
+    pub const SI: Units = Units {
+        length: 1.,
+        mass: 1.,
+        time: 1.,
+    };
+
+    /// Kiloparsecs, solar masses and megayears.
+    pub const GALACTIC: Units = Units {
+        length: KILOPARSEC,
+        mass: SOLAR_MASS,
+        time: MEGAYEAR,
+    };
+
+    /// Dimensionless units with G = 1: metres and kilograms, with the time unit chosen to match.
+    pub fn nbody() -> Units {
+        return Units {
+            length: 1.,
+            mass: 1.,
+            time: 1. / G.sqrt(),
+        };
+    }
+
+    // The gravitational constant expressed in these units.
+    pub fn g(&self) -> f64 {
+        return G * self.mass * self.time * self.time / (self.length * self.length * self.length);
+    }
+
+    // Converts a gravitational constant given in these units into SI.
+    pub fn g_to_si(&self, g: f64) -> f64 {
+        return g * G / self.g();
+    }
+
+    pub fn length_to_si(&self, l: f64) -> f64 {
+        return l * self.length;
+    }
+
+    pub fn mass_to_si(&self, m: f64) -> f64 {
+        return m * self.mass;
+    }
+
+    pub fn time_to_si(&self, t: f64) -> f64 {
+        return t * self.time;
+    }
+
+    pub fn time_from_si(&self, t: f64) -> f64 {
+        return t / self.time;
+    }
+
+    pub fn velocity_to_si(&self, v: f64) -> f64 {
+        return v * self.length / self.time;
+    }
+
+    pub fn acceleration_to_si(&self, a: f64) -> f64 {
+        return a * self.length / (self.time * self.time);
+    }
+
+    // Converts a point whose mass, position and velocity are in these units into SI.
+    pub fn point_to_si(&self, p: Point) -> Point {
+        let (x, y, z) = p.position();
+        let (vx, vy, vz) = p.velocity().position();
+        let vel = Vec3d::new(
+            self.velocity_to_si(vx),
+            self.velocity_to_si(vy),
+            self.velocity_to_si(vz),
+        );
+        return Point::new(
+            self.mass_to_si(p.mass()),
+            self.length_to_si(x),
+            self.length_to_si(y),
+            self.length_to_si(z),
+            vel,
+        )
+        .with_id(p.id())
+        .with_pinned(p.pinned())
+        .with_external(p.external())
+        .with_species(p.species())
+        .with_radius(self.length_to_si(p.radius()));
+    }
+}
+
+impl Default for Units {
+    fn default() -> Units {
+        return Units::SI;
+    }
+}
+
+/// Adapts a generator whose particles come out in `units` so that it produces SI particles. Only
+/// suitable for generators that don't depend on G, since they see lengths and masses in `units`.
+pub struct InUnits<I> {
+    pub units: Units,
+    pub ic: I,
+}
+
+impl<I: InitialConditions> InitialConditions for InUnits<I> {
+    fn generate(&self, rng: &mut dyn RngCore) -> ParticleSet {
+        return self
+            .ic
+            .generate(rng)
+            .into_iter()
+            .map(|p| self.units.point_to_si(p))
+            .collect();
+    }
+
+    fn count(&self) -> Option<usize> {
+        return self.ic.count();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::units::Units;
+    use crate::{Point, Vec3d};
+
+    #[test]
+    fn test_gravitational_constant() {
+        assert_eq!(Units::SI.g(), 6.67430e-11);
+        assert!((Units::nbody().g() - 1.).abs() < 1e-12);
+        // G is about 4.50e-12 kpc^3 / (Msun Myr^2).
+        assert!((Units::GALACTIC.g() / 4.498e-12 - 1.).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_point_to_si() {
+        let u = Units {
+            length: 10.,
+            mass: 2.,
+            time: 5.,
+        };
+        let p = Point::new(3., 1., 2., 3., Vec3d::new(5., 0., -5.)).with_id(7);
+        let si = u.point_to_si(p);
+        assert_eq!(si.id(), 7);
+        assert_eq!(si.mass(), 6.);
+        assert_eq!(si.position(), (10., 20., 30.));
+        assert_eq!(si.velocity().position(), (10., 0., -10.));
+    }
+}
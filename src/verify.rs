@@ -0,0 +1,229 @@
+use std::fmt::Write as _;
+use std::time::Instant;
+
+use log::info;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use serde::Serialize;
+
+use crate::geometry::bh_tree::BHTree;
+use crate::geometry::species::{Species, SpeciesTable};
+use crate::ic::halo::{Halo, Profile};
+use crate::simulation::SimulationBuilder;
+use crate::units::Units;
+use crate::{NbodyError, Point};
+
+/// An accuracy check of the tree against direct summation on a Hernquist halo, in N-body units:
+/// G, the halo's mass and its scale radius are all 1, so the time unit is its dynamical time.
+/// Thetas and time steps that do well here are a starting point for runs of other systems
+/// measured in their own dynamical times.
+#[derive(Debug, Clone)]
+pub struct Verify {
+    pub n: u64,
+    pub thetas: Vec<f64>,
+    pub dts: Vec<f64>,
+    // How long each integration runs.
+    pub duration: f64,
+    // Plummer softening of every particle.
+    pub softening: f64,
+    pub seed: u64,
+}
+
+/// How far the tree's forces are from direct summation, relative to the direct force on each
+/// particle.
+#[derive(Debug, Clone, Serialize)]
+pub struct ForceError {
+    pub theta: f64,
+    pub median: f64,
+    pub p99: f64,
+    pub max: f64,
+    // Wall time to evaluate every particle's force.
+    pub seconds: f64,
+}
+
+/// How well one integration kept the total energy. A theta of 0 is direct summation.
+#[derive(Debug, Clone, Serialize)]
+pub struct EnergyDrift {
+    pub theta: f64,
+    pub dt: f64,
+    pub steps: u64,
+    // Largest |E - E0| / |E0| over the checks made during the run.
+    pub max_drift: f64,
+    // |E - E0| / |E0| at the end.
+    pub final_drift: f64,
+    pub seconds: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct VerifyReport {
+    pub forces: Vec<ForceError>,
+    pub energy: Vec<EnergyDrift>,
+}
+
+// Times during an integration at which the energy is checked, on top of the start and end. Each
+// check sums the potential directly.
+const ENERGY_CHECKS: u64 = 10;
+
+impl Verify {
+    pub fn run(&self) -> Result<VerifyReport, NbodyError> {
+        let units = Units::nbody();
+        let halo = Halo {
+            n: self.n,
+            total_mass: units.mass_to_si(1.),
+            profile: Profile::Hernquist,
+            scale_radius: units.length_to_si(1.),
+            max_radius: units.length_to_si(20.),
+        };
+        let points = halo.generate(&mut StdRng::seed_from_u64(self.seed));
+        let species = SpeciesTable::new(vec![Species {
+            softening: units.length_to_si(self.softening),
+            ..Default::default()
+        }]);
+
+        let direct = BHTree::from_points_with_species(0., points.clone(), species.clone())?;
+        let exact: Vec<_> = points.iter().map(|p| direct.force_on(*p)).collect();
+        let mut forces = vec![];
+        for &theta in self.thetas.iter() {
+            let start = Instant::now();
+            let tree = BHTree::from_points_with_species(theta, points.clone(), species.clone())?;
+            let mut errors: Vec<f64> = points
+                .iter()
+                .zip(exact.iter())
+                .map(|(p, f)| (tree.force_on(*p) - *f).magnitude() / f.magnitude())
+                .filter(|e| e.is_finite())
+                .collect();
+            let seconds = start.elapsed().as_secs_f64();
+            errors.sort_by(f64::total_cmp);
+            let quantile = |q: f64| match errors.len() {
+                0 => 0.,
+                len => errors[((len - 1) as f64 * q).round() as usize],
+            };
+            info!("theta {}: median force error {:e}", theta, quantile(0.5));
+            forces.push(ForceError {
+                theta: theta,
+                median: quantile(0.5),
+                p99: quantile(0.99),
+                max: quantile(1.),
+                seconds: seconds,
+            });
+        }
+
+        let mut energy = vec![];
+        for &theta in [0.].iter().chain(self.thetas.iter()) {
+            for &dt in self.dts.iter() {
+                let steps = (self.duration / dt).round().max(1.) as u64;
+                let start = Instant::now();
+                let mut sim = SimulationBuilder::new()
+                    .theta(theta)
+                    .dt(units.time_to_si(dt))
+                    .species(species.clone())
+                    .initial_conditions(points.clone())
+                    .build(&mut StdRng::seed_from_u64(self.seed))?;
+                let e0 = total_energy(sim.tree().points(), &species)?;
+                let mut max_drift: f64 = 0.;
+                let mut drift = 0.;
+                for step in 1..=steps {
+                    sim.step()?;
+                    if step == steps || step % (steps / ENERGY_CHECKS).max(1) == 0 {
+                        let e = total_energy(sim.tree().points(), &species)?;
+                        drift = ((e - e0) / e0).abs();
+                        max_drift = max_drift.max(drift);
+                    }
+                }
+                info!("theta {} dt {}: energy drift {:e}", theta, dt, max_drift);
+                energy.push(EnergyDrift {
+                    theta: theta,
+                    dt: dt,
+                    steps: steps,
+                    max_drift: max_drift,
+                    final_drift: drift,
+                    seconds: start.elapsed().as_secs_f64(),
+                });
+            }
+        }
+
+        return Ok(VerifyReport {
+            forces: forces,
+            energy: energy,
+        });
+    }
+}
+
+impl VerifyReport {
+    pub fn summary(&self) -> String {
+        let mut out = String::from("force error relative to direct summation:\n");
+        let _ = writeln!(
+            out,
+            "  {:>8} {:>10} {:>10} {:>10} {:>10}",
+            "theta", "median", "99%", "max", "seconds"
+        );
+        for f in self.forces.iter() {
+            let _ = writeln!(
+                out,
+                "  {:>8} {:>10.2e} {:>10.2e} {:>10.2e} {:>10.3}",
+                f.theta, f.median, f.p99, f.max, f.seconds
+            );
+        }
+        out.push_str("relative energy drift:\n");
+        let _ = writeln!(
+            out,
+            "  {:>8} {:>8} {:>8} {:>10} {:>10} {:>10}",
+            "theta", "dt", "steps", "max", "final", "seconds"
+        );
+        for e in self.energy.iter() {
+            let theta = match e.theta {
+                0. => "direct".to_string(),
+                theta => theta.to_string(),
+            };
+            let _ = writeln!(
+                out,
+                "  {:>8} {:>8} {:>8} {:>10.2e} {:>10.2e} {:>10.3}",
+                theta, e.dt, e.steps, e.max_drift, e.final_drift, e.seconds
+            );
+        }
+        return out;
+    }
+}
+
+// Kinetic plus potential energy, with the potential summed directly.
+fn total_energy(points: Vec<Point>, species: &SpeciesTable) -> Result<f64, NbodyError> {
+    let kinetic: f64 = points.iter().map(|p| p.kinetic_energy()).sum();
+    let tree = BHTree::from_points_with_species(0., points, species.clone())?;
+    let potential: f64 = tree
+        .iter()
+        .zip(tree.potentials())
+        .map(|(p, phi)| 0.5 * p.mass() * phi)
+        .sum();
+    return Ok(kinetic + potential);
+}
+
+#[cfg(test)]
+mod test {
+    use crate::verify::Verify;
+
+    #[test]
+    fn test_report() {
+        let report = Verify {
+            n: 200,
+            thetas: vec![0.3, 1.],
+            dts: vec![0.05],
+            duration: 0.5,
+            softening: 0.05,
+            seed: 0,
+        }
+        .run()
+        .unwrap();
+
+        assert_eq!(report.forces.len(), 2);
+        assert!(report.forces[0].median > 0.);
+        assert!(report.forces[0].median < report.forces[1].median);
+        assert!(report.forces[1].median < 0.1);
+        assert_eq!(report.energy.len(), 3);
+        assert_eq!(report.energy[0].steps, 10);
+        assert!(report.energy.iter().all(|e| e.max_drift < 1e-2));
+
+        let summary = report.summary();
+        assert!(summary.contains("direct"));
+        assert_eq!(summary.lines().count(), 2 + 2 + 2 + 3);
+    }
+}
@@ -0,0 +1,157 @@
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use wasm_bindgen::prelude::*;
+
+use crate::geometry::force::ForceLaw;
+use crate::ic::{from_arrays, UniformSphere};
+use crate::simulation::{Simulation, SimulationBuilder};
+use crate::Point;
+
+/// A `Simulation` for JavaScript. Arrays cross the boundary flattened, as x, y, z per particle,
+/// and every quantity is in whatever units match the chosen `g`.
+#[wasm_bindgen(js_name = Simulation)]
+pub struct WasmSimulation {
+    sim: Simulation,
+}
+
+#[wasm_bindgen(js_class = Simulation)]
+impl WasmSimulation {
+    // `n` equal masses at rest, spread uniformly through a sphere. The same seed always gives the
+    // same particles.
+    #[wasm_bindgen(constructor)]
+    pub fn new(
+        n: u32,
+        radius: f64,
+        mass: f64,
+        dt: f64,
+        g: f64,
+        seed: u32,
+    ) -> Result<WasmSimulation, JsError> {
+        let sphere = UniformSphere {
+            n: n as u64,
+            radius: radius,
+            mass_base: mass,
+            mass_mean: 1.,
+            mass_stddev: 0.,
+        };
+        let sim = SimulationBuilder::new()
+            .dt(dt)
+            .force_law(ForceLaw::newtonian(g))
+            .initial_conditions(sphere)
+            .build(&mut StdRng::seed_from_u64(seed as u64))?;
+        return Ok(WasmSimulation { sim: sim });
+    }
+
+    // Particles given as one mass and three position and velocity components each.
+    #[wasm_bindgen(js_name = fromArrays)]
+    pub fn from_arrays(
+        masses: &[f64],
+        positions: &[f64],
+        velocities: &[f64],
+        dt: f64,
+        g: f64,
+    ) -> Result<WasmSimulation, JsError> {
+        let points = from_arrays(masses, positions, velocities).map_err(|e| JsError::new(&e))?;
+        let sim = SimulationBuilder::new()
+            .dt(dt)
+            .force_law(ForceLaw::newtonian(g))
+            .initial_conditions(points)
+            .build(&mut StdRng::seed_from_u64(0))?;
+        return Ok(WasmSimulation { sim: sim });
+    }
+
+    pub fn step(&mut self, steps: u32) -> Result<(), JsError> {
+        for _ in 0..steps {
+            self.sim.step()?;
+        }
+        return Ok(());
+    }
+
+    pub fn steps(&self) -> f64 {
+        return self.sim.steps() as f64;
+    }
+
+    pub fn time(&self) -> f64 {
+        return self.sim.time();
+    }
+
+    pub fn len(&self) -> usize {
+        return self.sim.tree().len();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.len() == 0;
+    }
+
+    pub fn positions(&self) -> Vec<f64> {
+        return self
+            .points()
+            .iter()
+            .flat_map(|p| {
+                let (x, y, z) = p.position();
+                [x, y, z]
+            })
+            .collect();
+    }
+
+    pub fn velocities(&self) -> Vec<f64> {
+        return self
+            .points()
+            .iter()
+            .flat_map(|p| {
+                let (x, y, z) = p.velocity().position();
+                [x, y, z]
+            })
+            .collect();
+    }
+
+    pub fn masses(&self) -> Vec<f64> {
+        return self.points().iter().map(|p| p.mass()).collect();
+    }
+}
+
+impl WasmSimulation {
+    // Ordered by id so the arrays line up between calls even though the tree reorders particles.
+    fn points(&self) -> Vec<Point> {
+        let mut points = self.sim.tree().points();
+        points.sort_by_key(|p| p.id());
+        return points;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::wasm::WasmSimulation;
+
+    #[test]
+    fn test_flat_arrays() {
+        let mut sim = WasmSimulation::new(50, 1., 1., 0.001, 1., 7).ok().unwrap();
+        assert_eq!(sim.len(), 50);
+        assert_eq!(sim.positions().len(), 150);
+        assert!(sim.masses().iter().all(|m| *m == 1.));
+
+        let before = sim.positions();
+        sim.step(2).ok().unwrap();
+        assert_eq!(sim.steps(), 2.);
+        assert_ne!(sim.positions(), before);
+        assert_eq!(
+            WasmSimulation::new(50, 1., 1., 0.001, 1., 7)
+                .ok()
+                .unwrap()
+                .positions(),
+            before
+        );
+
+        let pair = WasmSimulation::from_arrays(
+            &[1., 2.],
+            &[0., 0., 0., 1., 0., 0.],
+            &[0., 0., 0., 0., 1., 0.],
+            0.1,
+            1.,
+        )
+        .ok()
+        .unwrap();
+        assert_eq!(pair.masses(), vec![1., 2.]);
+        assert_eq!(pair.velocities()[4], 1.);
+    }
+}
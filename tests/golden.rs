@@ -0,0 +1,107 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+
+use nbody::geometry::species::{Species, SpeciesTable};
+use nbody::ic::file;
+use nbody::ic::halo::{Halo, Profile};
+use nbody::simulation::SimulationBuilder;
+use nbody::units::Units;
+use nbody::Point;
+
+// A fixed-seed run of a small halo whose final state is checked in, so that anything that changes
+// the physics shows up as a failure here. Rounding differences, e.g. from another platform's libm,
+// grow to well under the tolerance over this many steps; a real change to the forces or the
+// integrator moves particles much further.
+//
+// After a change that's meant to move the particles, rewrite the golden file with
+// `NBODY_BLESS=1 cargo test --test golden` and check the diff in with it.
+const N: u64 = 1000;
+const STEPS: u64 = 100;
+const SEED: u64 = 1337;
+// Largest difference allowed in any coordinate, in N-body units: the halo's scale radius and the
+// velocity a particle on a circular orbit there would have with all of its mass inside.
+const TOLERANCE: f64 = 1e-8;
+
+fn golden_path() -> PathBuf {
+    return PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/golden/halo-1000.csv");
+}
+
+// The state after STEPS steps of a Hernquist halo in N-body units, by id.
+fn run() -> Vec<Point> {
+    let units = Units::nbody();
+    let halo = Halo {
+        n: N,
+        total_mass: units.mass_to_si(1.),
+        profile: Profile::Hernquist,
+        scale_radius: units.length_to_si(1.),
+        max_radius: units.length_to_si(20.),
+    };
+    let species = SpeciesTable::new(vec![Species {
+        softening: units.length_to_si(0.05),
+        ..Default::default()
+    }]);
+    let mut rng = StdRng::seed_from_u64(SEED);
+    let mut sim = SimulationBuilder::new()
+        .theta(0.5)
+        .dt(units.time_to_si(0.01))
+        .species(species)
+        .initial_conditions(halo.generate(&mut rng))
+        .build(&mut rng)
+        .unwrap();
+    for _ in 0..STEPS {
+        sim.step().unwrap();
+    }
+    let mut points = sim.tree().points();
+    points.sort_by_key(|p| p.id());
+    return points;
+}
+
+fn bless(points: &[Point]) {
+    let path = golden_path();
+    std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+    let mut out = csv::Writer::from_path(&path).unwrap();
+    out.write_record(["id", "mass", "x", "y", "z", "vx", "vy", "vz"])
+        .unwrap();
+    for p in points {
+        let (x, y, z) = p.position();
+        let (vx, vy, vz) = p.velocity().position();
+        let mut row = vec![p.id().to_string()];
+        row.extend([p.mass(), x, y, z, vx, vy, vz].map(|v| v.to_string()));
+        out.write_record(&row).unwrap();
+    }
+    out.flush().unwrap();
+}
+
+#[test]
+fn test_golden_run() {
+    let points = run();
+    if std::env::var_os("NBODY_BLESS").is_some() {
+        return bless(&points);
+    }
+
+    let golden: HashMap<u64, Point> = file::load(&golden_path())
+        .expect("no golden file; write one with NBODY_BLESS=1")
+        .into_iter()
+        .map(|p| (p.id(), p))
+        .collect();
+    assert_eq!(points.len(), golden.len());
+    let speed = Units::nbody().velocity_to_si(1.);
+    for p in points.iter() {
+        let want = golden
+            .get(&p.id())
+            .unwrap_or_else(|| panic!("particle {} isn't in the golden run", p.id()));
+        assert_eq!(p.mass(), want.mass(), "mass of particle {}", p.id());
+        let moved = p.distance_to(*want);
+        let sped = (p.velocity() - want.velocity()).magnitude() / speed;
+        assert!(
+            moved < TOLERANCE && sped < TOLERANCE,
+            "particle {} is {:e} and {:e} off the golden run",
+            p.id(),
+            moved,
+            sped
+        );
+    }
+}